@@ -0,0 +1,68 @@
+//! Prints the information of ROM files
+//!
+//! Usage: `cargo run --example rom_info -- [--json] [--db nes20db.xml] <ROM>...`
+
+use sabicom::{header_db::HeaderDatabase, rom::Rom};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut json = false;
+    let mut db = None;
+    let mut files = vec![];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--db" => {
+                let xml = std::fs::read_to_string(args.next().ok_or("--db needs a file")?)?;
+                db = Some(HeaderDatabase::from_xml(&xml)?);
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        eprintln!("Usage: rom_info [--json] [--db nes20db.xml] <ROM>...");
+        std::process::exit(1);
+    }
+
+    for file in files {
+        let info = Rom::from_bytes(&std::fs::read(&file)?)?.info(db.as_ref());
+        if json {
+            println!("{}", serde_json::to_string_pretty(&info)?);
+            continue;
+        }
+
+        println!("{file}:");
+        println!("  Format:       {:?}", info.format);
+        println!("  Mapper:       {} ({})", info.mapper_id, info.submapper_id);
+        println!("  Board:        {}", info.board_name.unwrap_or("Unknown"));
+        println!("  Mirroring:    {:?}", info.mirroring);
+        println!("  Console Type: {:?}", info.console_type);
+        println!("  Timing Mode:  {:?}", info.timing_mode);
+        println!("  Battery:      {}", info.has_battery);
+        println!("  Trainer:      {}", info.has_trainer);
+        println!("  Input Device: {:?}", info.input_device);
+        println!("  PRG ROM:      {} bytes", info.prg_rom_size);
+        println!("  CHR ROM:      {} bytes", info.chr_rom_size);
+        println!(
+            "  PRG RAM:      {} + {} bytes",
+            info.prg_ram_size, info.prg_nvram_size
+        );
+        println!(
+            "  CHR RAM:      {} + {} bytes",
+            info.chr_ram_size, info.chr_nvram_size
+        );
+        println!(
+            "  Misc ROMs:    {} ({} bytes)",
+            info.misc_rom_count, info.misc_rom_size
+        );
+        println!("  PRG+CHR CRC:  {:08X}", info.prg_chr_crc32);
+        for (i, crc) in info.prg_bank_crc32.iter().enumerate() {
+            println!("  PRG bank {i:3}: {crc:08X}");
+        }
+        for (i, crc) in info.chr_bank_crc32.iter().enumerate() {
+            println!("  CHR bank {i:3}: {crc:08X}");
+        }
+        println!("  In header DB: {}", info.in_header_database);
+    }
+    Ok(())
+}
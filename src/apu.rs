@@ -1,18 +1,52 @@
 use bitvec::prelude::*;
 use meru_interface::{AudioBuffer, AudioSample};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    consts::{LINES_PER_FRAME, PPU_CLOCK_PER_CPU_CLOCK, PPU_CLOCK_PER_LINE},
+    blip::BlipBuffer,
+    consts::{Region, PPU_CLOCK_PER_LINE},
     context::{self, IrqSource},
-    util::{trait_alias, Input},
+    expansion::ExpansionDevice,
+    logging::core_log,
+    util::{trait_alias, Input, InputProvider},
 };
 
-trait_alias!(pub trait Context = context::Mapper + context::Interrupt);
+trait_alias!(pub trait Context = context::Mapper + context::Interrupt + context::Timing);
 
 const AUDIO_FREQUENCY: u64 = 48000;
-const SAMPLE_PER_FRAME: u64 = AUDIO_FREQUENCY / 60;
-const STEP_FRAME: [usize; 5] = [7457, 14913, 22371, 29829, 37281];
+/// CPU cycles of the frame counter steps after a reset in 4-step and 5-step modes.
+/// The sequence starts over at the last step.
+const STEP_FRAME: [[usize; 6]; 2] = [
+    [7457, 14913, 22371, 29828, 29829, 29830],
+    [7457, 14913, 22371, 29829, 37281, 37282],
+];
+const PAL_STEP_FRAME: [[usize; 6]; 2] = [
+    [8313, 16627, 24939, 33252, 33253, 33254],
+    [8313, 16627, 24939, 33253, 41565, 41566],
+];
+
+const NOISE_PERIOD: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_NOISE_PERIOD: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+/// CPU cycles per output bit of the DMC
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_RATE_TABLE: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+fn step_frame(region: Region) -> &'static [[usize; 6]; 2] {
+    match region {
+        Region::Ntsc => &STEP_FRAME,
+        Region::Pal => &PAL_STEP_FRAME,
+    }
+}
 
 #[rustfmt::skip]
 const LENGTH_TABLE: [u8; 32] = [
@@ -20,18 +54,382 @@ const LENGTH_TABLE: [u8; 32] = [
     12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
 ];
 
+/// Sound chips on Famicom cartridges which output audio through the expansion port
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExpansionChip {
+    Vrc6,
+    Vrc7,
+    Fds,
+    Mmc5,
+    Namco163,
+    Sunsoft5b,
+}
+
+/// Mixing levels of expansion audio in dB, relative to the nominal level of each chip
+#[derive(Clone, Debug, JsonSchema, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExpansionMixLevels {
+    pub vrc6: f32,
+    pub vrc7: f32,
+    pub fds: f32,
+    pub mmc5: f32,
+    pub namco163: f32,
+    pub sunsoft5b: f32,
+}
+
+impl Default for ExpansionMixLevels {
+    fn default() -> Self {
+        // Namco 163 and Sunsoft 5B boards are mixed notably louder than the 2A03
+        Self {
+            vrc6: 0.0,
+            vrc7: 0.0,
+            fds: 0.0,
+            mmc5: 0.0,
+            namco163: -6.0,
+            sunsoft5b: -3.0,
+        }
+    }
+}
+
+impl ExpansionMixLevels {
+    pub fn gain(&self, chip: ExpansionChip) -> f32 {
+        let db = match chip {
+            ExpansionChip::Vrc6 => self.vrc6,
+            ExpansionChip::Vrc7 => self.vrc7,
+            ExpansionChip::Fds => self.fds,
+            ExpansionChip::Mmc5 => self.mmc5,
+            ExpansionChip::Namco163 => self.namco163,
+            ExpansionChip::Sunsoft5b => self.sunsoft5b,
+        };
+        10.0_f32.powf(db / 20.0)
+    }
+}
+
+/// Sound channel which can be muted in the mix
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    /// Cartridge expansion audio
+    Expansion,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 6] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+        Channel::Expansion,
+    ];
+}
+
+/// Stereo position of each channel, from -1.0 (left) to 1.0 (right).
+/// The output is mono when all of them are centered.
+#[derive(Default, Clone, PartialEq, Debug, JsonSchema, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelPanning {
+    pub pulse1: f32,
+    pub pulse2: f32,
+    pub triangle: f32,
+    pub noise: f32,
+    pub dmc: f32,
+    /// Cartridge expansion audio, which can be separated from the 2A03 channels
+    pub expansion: f32,
+}
+
+impl ChannelPanning {
+    pub fn pan(&self, channel: Channel) -> f32 {
+        let pan = match channel {
+            Channel::Pulse1 => self.pulse1,
+            Channel::Pulse2 => self.pulse2,
+            Channel::Triangle => self.triangle,
+            Channel::Noise => self.noise,
+            Channel::Dmc => self.dmc,
+            Channel::Expansion => self.expansion,
+        };
+        pan.clamp(-1.0, 1.0)
+    }
+
+    fn is_mono(&self) -> bool {
+        Channel::ALL.iter().all(|&ch| self.pan(ch) == 0.0)
+    }
+
+    /// Gains of `channel` in the left and right outputs. Centered channels keep
+    /// their full level on both sides like the mono output.
+    fn gains(&self, channel: Channel) -> (f32, f32) {
+        let pan = self.pan(channel);
+        ((1.0 - pan).min(1.0), (1.0 + pan).min(1.0))
+    }
+}
+
+/// Output samples of each channel, taken along with the mixed output.
+/// Oscilloscope views of frontends can draw them.
+#[derive(Default, Clone)]
+pub struct ChannelStreams {
+    samples: [Vec<i16>; 6],
+}
+
+impl ChannelStreams {
+    /// Samples of `channel` at the output sample rate, in the scale of the linear mixer.
+    /// They are taken before muting, band limiting and filtering.
+    pub fn samples(&self, channel: Channel) -> &[i16] {
+        &self.samples[channel as usize]
+    }
+
+    pub fn clear(&mut self) {
+        for s in &mut self.samples {
+            s.clear();
+        }
+    }
+}
+
+/// Formula which mixes the outputs of the APU channels
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug, JsonSchema, Serialize, Deserialize)]
+pub enum Mixer {
+    /// Linear approximation, where channels do not affect each other's volume
+    #[default]
+    Linear,
+    /// Lookup tables of the non-linear DAC, where loud channels (typically DMC)
+    /// attenuate the others
+    Nonlinear,
+}
+
+/// Weights of the pulse 1, pulse 2, triangle, noise and DMC outputs in the linear mixer
+const LINEAR_WEIGHTS: [f32; 5] = [0.00752, 0.00752, 0.00851, 0.00494, 0.00335];
+
+/// Output of the pulse DAC for the sum of both pulse channels (0 to 30)
+const PULSE_TABLE: [f32; 31] = dac_table(95.52, 8128.0);
+/// Output of the triangle/noise/DMC DAC for `3 * triangle + 2 * noise + dmc` (0 to 202)
+const TND_TABLE: [f32; 203] = dac_table(163.67, 24329.0);
+
+const fn dac_table<const N: usize>(scale: f32, divisor: f32) -> [f32; N] {
+    let mut table = [0.0; N];
+    let mut n = 1;
+    while n < N {
+        table[n] = scale / (divisor / n as f32 + 100.0);
+        n += 1;
+    }
+    table
+}
+
+/// First-order filters of the audio output circuit of the NES
+#[derive(Default, Serialize, Deserialize)]
+struct OutputFilters {
+    high_pass_90: HighPass,
+    high_pass_440: HighPass,
+    low_pass_14k: LowPass,
+}
+
+impl OutputFilters {
+    fn apply(&mut self, input: f32) -> f32 {
+        let output = self.high_pass_90.apply(input, 90.0);
+        let output = self.high_pass_440.apply(output, 440.0);
+        self.low_pass_14k.apply(output, 14000.0)
+    }
+}
+
+/// Time constant of a first-order filter with cutoff frequency `freq`,
+/// relative to the sample period
+fn time_constant(freq: f32) -> f32 {
+    AUDIO_FREQUENCY as f32 / (2.0 * std::f32::consts::PI * freq)
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct HighPass {
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl HighPass {
+    fn apply(&mut self, input: f32, freq: f32) -> f32 {
+        let rc = time_constant(freq);
+        let output = rc / (rc + 1.0) * (self.prev_output + input - self.prev_input);
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct LowPass {
+    prev_output: f32,
+}
+
+impl LowPass {
+    fn apply(&mut self, input: f32, freq: f32) -> f32 {
+        let rc = time_constant(freq);
+        self.prev_output += (input - self.prev_output) / (rc + 1.0);
+        self.prev_output
+    }
+}
+
+fn to_i16(output: f32) -> i16 {
+    (output * 32000.0) as i16
+}
+
+/// Output level above which the soft clipper compresses peaks
+const SOFT_CLIP_KNEE: f32 = 0.8;
+
+/// Compresses peaks above the knee smoothly, so the output approaches the full scale
+/// without exceeding it
+fn soft_clip(output: f32) -> f32 {
+    let level = output.abs();
+    if level <= SOFT_CLIP_KNEE {
+        return output;
+    }
+    let headroom = 1.0 - SOFT_CLIP_KNEE;
+    let level = SOFT_CLIP_KNEE + headroom * ((level - SOFT_CLIP_KNEE) / headroom).tanh();
+    level.copysign(output)
+}
+
+/// Looks up `table` with linear interpolation, since the center levels removed as DC offset
+/// are not always integers
+fn lookup(table: &[f32], index: f32) -> f32 {
+    let index = index.clamp(0.0, (table.len() - 1) as f32);
+    let i = (index as usize).min(table.len() - 2);
+    let frac = index - i as f32;
+    table[i] + (table[i + 1] - table[i]) * frac
+}
+
+/// Console hardware variant, which differs in the wiring of $4016/$4017
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug, JsonSchema, Serialize, Deserialize)]
+pub enum ConsoleModel {
+    Famicom,
+    #[default]
+    NesFrontLoader,
+    NesTopLoader,
+}
+
+impl ConsoleModel {
+    /// Bits of $4016/$4017 (port 0/1) connected to the controller ports
+    pub fn controller_bits(&self, port: usize) -> u8 {
+        match (self, port) {
+            // Controllers are hardwired, and the second one has a microphone
+            (ConsoleModel::Famicom, 0) => 0x05,
+            (ConsoleModel::Famicom, _) => 0x01,
+            (ConsoleModel::NesFrontLoader | ConsoleModel::NesTopLoader, _) => 0x19,
+        }
+    }
+
+    /// Bits of $4016/$4017 (port 0/1) connected to the expansion port
+    pub fn expansion_bits(&self, port: usize) -> u8 {
+        match (self, port) {
+            (ConsoleModel::NesTopLoader, _) => 0x00,
+            (_, 0) => 0x02,
+            (_, _) => 0x1e,
+        }
+    }
+
+    pub fn has_microphone(&self) -> bool {
+        *self == ConsoleModel::Famicom
+    }
+}
+
+/// Adapter which connects controllers 3 and 4
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug, JsonSchema, Serialize, Deserialize)]
+pub enum FourPlayerAdapter {
+    #[default]
+    None,
+    /// NES Four Score, which reads controllers 3 and 4 after 1 and 2 on bit 0,
+    /// followed by its signature
+    FourScore,
+    /// Famicom controllers on the expansion port, read on bit 1
+    Famicom,
+}
+
+/// Signature of the Four Score on $4016/$4017, read after the 16 buttons
+const FOUR_SCORE_SIGNATURE: [u32; 2] = [0x10, 0x20];
+
+/// Coin slots, service button and DIP switches of the Vs. System,
+/// which are read through the unused bits of $4016/$4017
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct VsSwitches {
+    /// Remaining frames the coin switch of each slot is held
+    pub coin_timer: [u8; 2],
+    pub service: bool,
+    pub dip_switches: u8,
+}
+
+impl VsSwitches {
+    /// Number of frames a coin switch is held after inserting a coin
+    pub const COIN_FRAMES: u8 = 4;
+
+    pub fn insert_coin(&mut self, slot: usize) {
+        self.coin_timer[slot] = Self::COIN_FRAMES;
+    }
+
+    pub fn end_frame(&mut self) {
+        for t in &mut self.coin_timer {
+            *t = t.saturating_sub(1);
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Apu {
     controller_latch: bool,
     expansion_latch: u8,
-    pad_buf: [u8; 2],
+    // Shift registers read on bit 0 and bit 1 of $4016/$4017, which are filled with 1s
+    pad_buf: [u32; 2],
+    expansion_pad_buf: [u8; 2],
     reg: Register,
     frame_counter_reset_delay: usize,
+    // Mode written to $4017, which takes effect with the reset
+    frame_counter_next_mode: bool,
+    // Cycles during which the frame counter can't clock the units again
+    frame_clock_block: u8,
+    // Frame IRQ flag set on this cycle, which reaches the IRQ line on the next one
+    frame_irq_pending: bool,
     frame_counter: usize,
     input: Input,
+    // Frames since the turbo buttons were last pressed
+    turbo_frame: u32,
     counter: u64,
     sampler_counter: u64,
+    blip: BlipBuffer,
+    filters: OutputFilters,
+    blip_right: BlipBuffer,
+    filters_right: OutputFilters,
+    expansion_input: Option<(ExpansionChip, f32)>,
+    vs_switches: Option<VsSwitches>,
+    #[serde(skip)]
+    console_model: ConsoleModel,
+    #[serde(skip)]
+    four_player: FourPlayerAdapter,
+    #[serde(skip)]
+    turbo_period: u32,
+    #[serde(skip)]
+    expansion_device: Option<Box<dyn ExpansionDevice>>,
+    #[serde(skip)]
+    input_provider: Option<InputProvider>,
+    #[serde(skip)]
+    expansion_levels: ExpansionMixLevels,
+    #[serde(skip)]
+    mixer: Mixer,
+    #[serde(skip)]
+    panning: ChannelPanning,
     #[serde(skip)]
+    volume_gain: f32,
+    #[serde(skip)]
+    soft_clipping: bool,
+    #[serde(skip)]
+    filters_enabled: bool,
+    #[serde(skip)]
+    dmc_dma_conflicts: bool,
+    #[serde(skip)]
+    open_bus_enabled: bool,
+    #[serde(skip)]
+    muted: [bool; 6],
+    #[serde(skip)]
+    mute_ultrasonic_triangle: bool,
+    #[serde(skip)]
+    channel_streams: Option<ChannelStreams>,
+    #[serde(with = "crate::util::audio_buffer_serde")]
     audio_buffer: AudioBuffer,
 }
 
@@ -57,6 +455,56 @@ impl Register {
     }
 }
 
+/// Length counter of pulse, triangle and noise channels
+///
+/// Reloads and halt flag changes are applied after the frame counter clocks
+/// in the same cycle, so a reload coinciding with a length clock is ignored
+/// when the counter was non-zero.
+#[derive(Default, Debug, Serialize, Deserialize)]
+struct LengthCounter {
+    counter: u8,
+    halt: bool,
+    pending_halt: Option<bool>,
+    // (reload value, counter value at the time of the write)
+    pending_reload: Option<(u8, u8)>,
+}
+
+impl LengthCounter {
+    fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+
+    fn set_halt(&mut self, halt: bool) {
+        self.pending_halt = Some(halt);
+    }
+
+    fn reload(&mut self, index: u8) {
+        self.pending_reload = Some((LENGTH_TABLE[index as usize], self.counter));
+    }
+
+    fn clear(&mut self) {
+        self.counter = 0;
+        self.pending_reload = None;
+    }
+
+    fn clock(&mut self) {
+        if self.counter > 0 && !self.halt {
+            self.counter -= 1;
+        }
+    }
+
+    fn apply_pending(&mut self) {
+        if let Some((value, prev)) = self.pending_reload.take() {
+            if self.counter == prev {
+                self.counter = value;
+            }
+        }
+        if let Some(halt) = self.pending_halt.take() {
+            self.halt = halt;
+        }
+    }
+}
+
 #[derive(Default, Debug, Serialize, Deserialize)]
 struct Pulse {
     ch: usize,
@@ -74,7 +522,7 @@ struct Pulse {
     length_counter_load: u8,
 
     sequencer_counter: u16,
-    length_counter: u8,
+    length: LengthCounter,
     envelope_start: bool,
     envelope_counter: u8,
     decay_level: u8,
@@ -116,7 +564,7 @@ impl Pulse {
         };
         let target_period = self.target_period();
         let sweep_muting = self.sweep_enabled && !(8..=0x7ff).contains(&target_period);
-        if !(self.length_counter == 0 || sweep_muting || self.timer < 8) {
+        if !(!self.length.is_active() || sweep_muting || self.timer < 8) {
             let bias = if correct_bias { -0.5 } else { 0.0 };
             volume as f32 * (PULSE_WAVEFORM[self.duty as usize][self.phase as usize] as f32 + bias)
         } else {
@@ -133,7 +581,7 @@ struct Triangle {
     timer: u16,
     length_counter_load: u8,
 
-    length_counter: u8,
+    length: LengthCounter,
     phase: u8,
     linear_counter: u8,
     linear_counter_reload: bool,
@@ -141,15 +589,18 @@ struct Triangle {
 }
 
 impl Triangle {
-    fn sample(&self, correct_bias: bool) -> f32 {
+    fn sample(&self, correct_bias: bool, mute_ultrasonic: bool) -> f32 {
         #[rustfmt::skip]
         const TRIANGLE_WAVEFORM: [u8; 32] = [
             15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
             0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
         ];
 
-        // mute when timer value is too small because it produces ultrasonic
-        if self.linear_counter == 0 || self.length_counter == 0 || self.timer <= 2 {
+        // Small timer values produce ultrasonic tones, which the band-limited synthesis
+        // and the output filters reduce to their average level like the real console.
+        // Muting them instead is cheaper but makes a pop.
+        let ultrasonic = mute_ultrasonic && self.timer <= 2;
+        if self.linear_counter == 0 || !self.length.is_active() || ultrasonic {
             0.0
         } else {
             let bias = if correct_bias { -8.0 } else { 0.0 };
@@ -168,7 +619,7 @@ struct Noise {
     noise_period: u8,
     length_counter_load: u8,
 
-    length_counter: u8,
+    length: LengthCounter,
     envelope_start: bool,
     envelope_counter: u8,
     decay_level: u8,
@@ -190,7 +641,7 @@ impl Noise {
         } else {
             self.decay_level
         };
-        if self.length_counter != 0 {
+        if self.length.is_active() {
             let b = self.shift_register & 1;
             let bias = if correct_bias { -0.5 } else { 0.0 };
             volume as f32 * (b as f32 + bias)
@@ -215,6 +666,8 @@ struct Dmc {
     shiftreg: u8,
     shiftreg_remain: u8,
     buffer: Option<u8>,
+    // Cycles until the DMA started by $4015 fetches the first sample byte
+    dma_delay: u8,
     silence: bool,
     output_level: u8,
 }
@@ -239,12 +692,39 @@ impl Default for Apu {
             controller_latch: false,
             expansion_latch: 0,
             pad_buf: [0; 2],
+            expansion_pad_buf: [0; 2],
             reg: Register::new(),
             frame_counter_reset_delay: 0,
+            frame_counter_next_mode: false,
+            frame_clock_block: 0,
+            frame_irq_pending: false,
             frame_counter: 0,
             counter: 0,
             sampler_counter: 0,
+            blip: BlipBuffer::default(),
+            blip_right: BlipBuffer::default(),
+            filters: OutputFilters::default(),
+            filters_right: OutputFilters::default(),
             input: Input::default(),
+            turbo_frame: 0,
+            turbo_period: 2,
+            expansion_device: None,
+            input_provider: None,
+            expansion_input: None,
+            vs_switches: None,
+            console_model: ConsoleModel::default(),
+            four_player: FourPlayerAdapter::default(),
+            expansion_levels: ExpansionMixLevels::default(),
+            mixer: Mixer::Linear,
+            panning: ChannelPanning::default(),
+            volume_gain: 1.0,
+            soft_clipping: false,
+            filters_enabled: false,
+            dmc_dma_conflicts: false,
+            open_bus_enabled: false,
+            muted: [false; 6],
+            mute_ultrasonic_triangle: false,
+            channel_streams: None,
             audio_buffer: AudioBuffer::new(48000, 2),
         }
     }
@@ -259,59 +739,189 @@ impl Apu {
         &mut self.audio_buffer
     }
 
+    pub fn set_mixer(&mut self, mixer: Mixer) {
+        self.mixer = mixer;
+    }
+
+    pub fn set_panning(&mut self, panning: &ChannelPanning) {
+        self.panning = panning.clone();
+    }
+
+    /// Sets the master volume in dB
+    pub fn set_volume(&mut self, db: f32) {
+        self.volume_gain = 10.0_f32.powf(db / 20.0);
+    }
+
+    /// Compresses loud peaks instead of clipping them at the full scale
+    pub fn set_soft_clipping(&mut self, enable: bool) {
+        self.soft_clipping = enable;
+    }
+
+    /// Applies the master volume and clipping to an output level
+    fn to_output(&self, output: f32) -> i16 {
+        let output = output * self.volume_gain;
+        if self.soft_clipping {
+            to_i16(soft_clip(output))
+        } else {
+            to_i16(output)
+        }
+    }
+
+    /// Enables or mutes a channel in the mix. The channel keeps running and
+    /// its registers behave the same.
+    pub fn set_channel_enabled(&mut self, channel: Channel, enable: bool) {
+        self.muted[channel as usize] = !enable;
+    }
+
+    pub fn channel_enabled(&self, channel: Channel) -> bool {
+        !self.muted[channel as usize]
+    }
+
+    /// Silences the triangle channel at ultrasonic frequencies instead of playing them
+    pub fn set_mute_ultrasonic_triangle(&mut self, enable: bool) {
+        self.mute_ultrasonic_triangle = enable;
+    }
+
+    /// Records the output of each channel along with the mixed output
+    pub fn set_channel_streams_enabled(&mut self, enable: bool) {
+        if enable != self.channel_streams.is_some() {
+            self.channel_streams = enable.then(ChannelStreams::default);
+        }
+    }
+
+    pub fn channel_streams(&self) -> Option<&ChannelStreams> {
+        self.channel_streams.as_ref()
+    }
+
+    pub fn channel_streams_mut(&mut self) -> Option<&mut ChannelStreams> {
+        self.channel_streams.as_mut()
+    }
+
+    /// Applies the high-pass and low-pass filters of the console's audio output
+    pub fn set_filters_enabled(&mut self, enable: bool) {
+        self.filters_enabled = enable;
+    }
+
+    /// Makes the CPU read stalled by DMC DMA repeat its access, as the 2A03 does
+    pub fn set_dmc_dma_conflicts(&mut self, enable: bool) {
+        self.dmc_dma_conflicts = enable;
+    }
+
+    pub fn dmc_dma_conflicts(&self) -> bool {
+        self.dmc_dma_conflicts
+    }
+
+    /// Returns the last value on the CPU data bus for bits no device drives.
+    /// Otherwise they read the upper byte of the address, as is the case for most reads.
+    pub fn set_open_bus_enabled(&mut self, enable: bool) {
+        self.open_bus_enabled = enable;
+    }
+
+    /// Address of the sample byte which the DMC is waiting for, if any
+    pub fn dmc_dma_addr(&self) -> Option<u16> {
+        let r = &self.reg.dmc;
+        (r.buffer.is_none() && r.length_counter != 0 && r.dma_delay == 0).then_some(r.cur_addr)
+    }
+
+    /// Fills the sample buffer with the byte fetched by DMC DMA
+    pub fn complete_dmc_dma(&mut self, ctx: &mut impl Context, data: u8) {
+        let r = &mut self.reg.dmc;
+        r.buffer = Some(data);
+
+        r.cur_addr = r.cur_addr.wrapping_add(1);
+        if r.cur_addr == 0 {
+            r.cur_addr = 0x8000;
+        }
+        r.length_counter -= 1;
+        if r.length_counter == 0 {
+            if r.loop_enabled {
+                r.cur_addr = r.sample_addr;
+                r.length_counter = r.sample_length;
+            } else if r.irq_enabled {
+                ctx.set_irq_source(IrqSource::ApuDmc, true);
+            }
+        }
+    }
+
+    pub fn set_expansion_levels(&mut self, levels: &ExpansionMixLevels) {
+        self.expansion_levels = levels.clone();
+    }
+
+    /// Sets the current output of the cartridge sound chip, in the same scale as the 2A03 mixer output
+    pub fn set_expansion_input(&mut self, chip: ExpansionChip, output: f32) {
+        self.expansion_input = Some((chip, output));
+    }
+
+    /// The 4-step sequence raises the frame IRQ on each of its last three cycles
+    fn raises_frame_irq(&self, region: Region, frame_counter: usize) -> bool {
+        let steps = &step_frame(region)[0];
+        !self.reg.frame_counter_mode
+            && !self.reg.frame_counter_irq
+            && steps[3..].contains(&frame_counter)
+    }
+
+    /// Clears the frame IRQ flag, including the one being raised on this cycle
+    pub fn acknowledge_frame_irq(&mut self, ctx: &mut impl Context) {
+        self.frame_irq_pending = false;
+        ctx.set_irq_source(IrqSource::ApuFrame, false);
+    }
+
     pub fn tick(&mut self, ctx: &mut impl Context) {
+        let region = ctx.region();
         self.frame_counter += 1;
+        self.frame_clock_block = self.frame_clock_block.saturating_sub(1);
 
         let mut quarter_frame = false;
         let mut half_frame = false;
 
-        if self.frame_counter == STEP_FRAME[0] {
-            quarter_frame = true;
-        }
-        if self.frame_counter == STEP_FRAME[1] {
-            quarter_frame = true;
-            half_frame = true;
-        }
-        if self.frame_counter == STEP_FRAME[2] {
-            quarter_frame = true;
+        if std::mem::take(&mut self.frame_irq_pending) {
+            ctx.set_irq_source(IrqSource::ApuFrame, true);
         }
-        if !self.reg.frame_counter_mode && self.frame_counter == STEP_FRAME[3] {
-            quarter_frame = true;
-            half_frame = true;
 
-            if !self.reg.frame_counter_irq {
-                // log::info!("APU frame counter IRQ set");
-                ctx.set_irq_source(IrqSource::ApuFrame, true);
-            }
-
-            self.frame_counter = 0;
+        if self.raises_frame_irq(region, self.frame_counter) {
+            self.frame_irq_pending = true;
         }
-        if self.frame_counter == STEP_FRAME[4] {
-            quarter_frame = true;
-            half_frame = true;
 
-            self.frame_counter = 0;
+        let steps = &step_frame(region)[self.reg.frame_counter_mode as usize];
+        let step = steps.iter().position(|&c| c == self.frame_counter);
+
+        match step {
+            Some(0 | 2) => quarter_frame = true,
+            Some(1 | 4) => {
+                quarter_frame = true;
+                half_frame = true;
+            }
+            Some(5) => self.frame_counter = 0,
+            _ => {}
         }
 
         if self.frame_counter_reset_delay > 0 {
             self.frame_counter_reset_delay -= 1;
             if self.frame_counter_reset_delay == 0 {
+                self.reg.frame_counter_mode = self.frame_counter_next_mode;
                 self.frame_counter = 0;
-                if self.reg.frame_counter_mode {
+                // 5-step mode clocks the units right away, unless the sequence just did
+                if self.reg.frame_counter_mode && self.frame_clock_block == 0 {
                     quarter_frame = true;
                     half_frame = true;
                 }
             }
         }
 
-        // FIXME: delay clock frame
         if quarter_frame {
+            self.frame_clock_block = 2;
             self.clock_quarter_frame();
         }
         if half_frame {
             self.clock_half_frame();
         }
 
+        for r in &mut self.reg.pulse {
+            r.length.apply_pending();
+        }
+        self.reg.triangle.length.apply_pending();
+        self.reg.noise.length.apply_pending();
+
         self.counter += 1;
 
         if self.counter % 2 == 1 {
@@ -326,7 +936,7 @@ impl Apu {
             }
         }
 
-        if self.reg.triangle.linear_counter != 0 && self.reg.triangle.length_counter != 0 {
+        if self.reg.triangle.linear_counter != 0 && self.reg.triangle.length.is_active() {
             let r = &mut self.reg.triangle;
             if r.sequencer_counter == 0 {
                 r.sequencer_counter = r.timer;
@@ -337,13 +947,14 @@ impl Apu {
         }
 
         if self.counter % 2 == 1 {
-            const NOISE_PERIOD: [u16; 16] = [
-                4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
-            ];
+            let noise_period = match region {
+                Region::Ntsc => &NOISE_PERIOD,
+                Region::Pal => &PAL_NOISE_PERIOD,
+            };
 
             let r = &mut self.reg.noise;
             if r.sequencer_counter == 0 {
-                r.sequencer_counter = NOISE_PERIOD[r.noise_period as usize];
+                r.sequencer_counter = noise_period[r.noise_period as usize];
                 let fb = if !r.noise_mode {
                     (r.shift_register & 1) ^ ((r.shift_register >> 1) & 1)
                 } else {
@@ -356,13 +967,17 @@ impl Apu {
         }
 
         {
-            const RATE_TABLE: [u16; 16] = [
-                428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
-            ];
+            let rate_table = match region {
+                Region::Ntsc => &RATE_TABLE,
+                Region::Pal => &PAL_RATE_TABLE,
+            };
 
             let r = &mut self.reg.dmc;
+            r.dma_delay = r.dma_delay.saturating_sub(1);
+
+            // The timer outputs a bit every `rate` cycles
             if r.shifter_counter == 0 {
-                r.shifter_counter = RATE_TABLE[r.rate_index as usize];
+                r.shifter_counter = rate_table[r.rate_index as usize] - 1;
 
                 if !r.silence {
                     if r.shiftreg & 1 != 0 {
@@ -391,35 +1006,46 @@ impl Apu {
                 r.shifter_counter -= 1;
             }
 
-            if r.buffer.is_none() && r.length_counter != 0 {
-                r.buffer = Some(ctx.read_prg_mapper(r.cur_addr));
+            // An empty sample buffer is filled by DMC DMA, which the CPU side performs
+            // by halting on its next read cycle
+        }
 
-                r.cur_addr = r.cur_addr.wrapping_add(1);
-                if r.cur_addr == 0 {
-                    r.cur_addr = 0x8000;
-                }
-                r.length_counter -= 1;
-                if r.length_counter == 0 {
-                    if r.loop_enabled {
-                        r.cur_addr = r.sample_addr;
-                        r.length_counter = r.sample_length;
-                    } else if r.irq_enabled {
-                        ctx.set_irq_source(IrqSource::ApuDmc, true);
-                    }
+        // PPU clocks per frame <-> samples per frame, counted in PPU clocks * den
+
+        let region = ctx.region();
+        let (num, den) = region.ppu_clock_ratio();
+        let ppu_clock_per_frame = PPU_CLOCK_PER_LINE * region.lines_per_frame() as u64 * den;
+        self.sampler_counter += AUDIO_FREQUENCY / region.frame_rate() * num;
+
+        // Output changes are placed at CPU clock resolution between output samples
+        let pos = self.sampler_counter as f32 / ppu_clock_per_frame as f32;
+        let (left, right) = self.output();
+        self.blip.set_amplitude(pos, left);
+        self.blip_right.set_amplitude(pos, right);
+
+        if self.sampler_counter >= ppu_clock_per_frame {
+            self.sampler_counter -= ppu_clock_per_frame;
+            let mut left = self.blip.read_sample();
+            let mut right = self.blip_right.read_sample();
+            if self.filters_enabled {
+                left = self.filters.apply(left);
+                right = self.filters_right.apply(right);
+            }
+            self.audio_buffer.samples.push(AudioSample::new(
+                self.to_output(left),
+                self.to_output(right),
+            ));
+
+            let outputs = self
+                .channel_streams
+                .is_some()
+                .then(|| self.channel_outputs());
+            if let (Some(streams), Some(outputs)) = (&mut self.channel_streams, outputs) {
+                for (stream, output) in streams.samples.iter_mut().zip(outputs) {
+                    stream.push(to_i16(output));
                 }
             }
         }
-
-        // PPU_CLOCK_PER_LINE * LINES_PER_FRAME <-> 800 * 3
-
-        self.sampler_counter += SAMPLE_PER_FRAME * PPU_CLOCK_PER_CPU_CLOCK;
-        if self.sampler_counter >= PPU_CLOCK_PER_LINE * LINES_PER_FRAME as u64 {
-            self.sampler_counter -= PPU_CLOCK_PER_LINE * LINES_PER_FRAME as u64;
-            let sample = self.sample();
-            self.audio_buffer
-                .samples
-                .push(AudioSample::new(sample, sample));
-        }
     }
 
     pub fn clock_quarter_frame(&mut self) {
@@ -475,9 +1101,7 @@ impl Apu {
         for ch in 0..2 {
             let r = &mut self.reg.pulse[ch];
             let target_period = r.target_period();
-            if r.length_counter > 0 && !r.length_counter_halt {
-                r.length_counter -= 1;
-            }
+            r.length.clock();
 
             let enabled = r.sweep_enabled && r.sweep_shift != 0;
             let muting = !(8..=0x7ff).contains(&target_period);
@@ -493,101 +1117,279 @@ impl Apu {
                 r.sweep_counter -= 1;
             }
         }
-        if self.reg.triangle.length_counter > 0 && !self.reg.triangle.length_counter_halt {
-            self.reg.triangle.length_counter -= 1;
-        }
-        if self.reg.noise.length_counter > 0 && !self.reg.noise.length_counter_halt {
-            self.reg.noise.length_counter -= 1;
-        }
+        self.reg.triangle.length.clock();
+        self.reg.noise.length.clock();
     }
 
+    /// Returns the current mono output level without band limiting
     pub fn sample(&self) -> i16 {
-        // let pulse = [
-        //     self.reg.pulse[0].sample(false),
-        //     self.reg.pulse[1].sample(false),
-        // ];
-        // let triangle = self.reg.triangle.sample(false);
-        // let noise = self.reg.noise.sample(false);
-        // let dmc = self.reg.dmc.sample(false);
-
-        // let pulse_out = if pulse[0] == 0.0 && pulse[1] == 0.0 {
-        //     0.0
-        // } else {
-        //     95.88 / (8128.0 / (pulse[0] as f64 + pulse[1] as f64) + 100.0)
-        // };
-
-        // let tnd_out = if triangle == 0.0 && noise == 0.0 && dmc == 0.0 {
-        //     0.0
-        // } else {
-        //     let t = triangle as f64 / 8227.0 + noise as f64 / 12241.0 + dmc as f64 / 22638.0;
-        //     159.79 / (1.0 / t + 100.0)
-        // };
-
-        // // TODO: highpass filter & lowpass filter
-        // ((pulse_out + tnd_out) * 30000.0).round() as i16
-
-        let pulse = [
-            self.reg.pulse[0].sample(true),
-            self.reg.pulse[1].sample(true),
+        self.to_output(self.mix(&self.channel_gains(None)))
+    }
+
+    /// Returns the current left and right output levels
+    fn output(&self) -> (f32, f32) {
+        if self.panning.is_mono() {
+            let output = self.mix(&self.channel_gains(None));
+            (output, output)
+        } else {
+            (
+                self.mix(&self.channel_gains(Some(false))),
+                self.mix(&self.channel_gains(Some(true))),
+            )
+        }
+    }
+
+    /// Returns the gain of each channel in the mono output, or the right or left output
+    /// with panning. Muted channels have no gain.
+    fn channel_gains(&self, right: Option<bool>) -> [f32; 6] {
+        std::array::from_fn(|i| {
+            if self.muted[i] {
+                return 0.0;
+            }
+            let (left_gain, right_gain) = self.panning.gains(Channel::ALL[i]);
+            match right {
+                None => 1.0,
+                Some(false) => left_gain,
+                Some(true) => right_gain,
+            }
+        })
+    }
+
+    fn mix(&self, gains: &[f32; 6]) -> f32 {
+        let (pulse_out, tnd_out) = match self.mixer {
+            Mixer::Linear => self.mix_linear(gains),
+            Mixer::Nonlinear => self.mix_nonlinear(gains),
+        };
+
+        let expansion_out = self.expansion_input.map_or(0.0, |(chip, output)| {
+            output * self.expansion_levels.gain(chip)
+        });
+
+        pulse_out + tnd_out + expansion_out * gains[Channel::Expansion as usize]
+    }
+
+    /// Returns the output of each channel and the level of its center, which is removed as
+    /// DC offset. The outputs are scaled by `gains` around the center, so muted channels
+    /// stay at the center.
+    fn channel_levels(&self, gains: &[f32; 6]) -> [(f32, f32); 5] {
+        let mute_ultrasonic = self.mute_ultrasonic_triangle;
+        let levels = [
+            (
+                self.reg.pulse[0].sample(false),
+                self.reg.pulse[0].sample(true),
+            ),
+            (
+                self.reg.pulse[1].sample(false),
+                self.reg.pulse[1].sample(true),
+            ),
+            (
+                self.reg.triangle.sample(false, mute_ultrasonic),
+                self.reg.triangle.sample(true, mute_ultrasonic),
+            ),
+            (self.reg.noise.sample(false), self.reg.noise.sample(true)),
+            (self.reg.dmc.sample(false), self.reg.dmc.sample(true)),
         ];
-        let triangle = self.reg.triangle.sample(true);
-        let noise = self.reg.noise.sample(true);
-        let dmc = self.reg.dmc.sample(true);
+        std::array::from_fn(|i| {
+            let (raw, biased) = levels[i];
+            let center = raw - biased;
+            (center + biased * gains[i], center)
+        })
+    }
 
-        // Linear approximation
+    fn mix_linear(&self, gains: &[f32; 6]) -> (f32, f32) {
+        let [pulse1, pulse2, triangle, noise, dmc] =
+            self.channel_levels(gains).map(|(raw, center)| raw - center);
+        let [w_pulse1, w_pulse2, w_triangle, w_noise, w_dmc] = LINEAR_WEIGHTS;
 
-        let pulse_out = 0.00752 * (pulse[0] + pulse[1]);
-        let tnd_out = 0.00851 * triangle + 0.00494 * noise + 0.00335 * dmc;
-        let output = pulse_out + tnd_out;
+        let pulse_out = w_pulse1 * pulse1 + w_pulse2 * pulse2;
+        let tnd_out = w_triangle * triangle + w_noise * noise + w_dmc * dmc;
+        (pulse_out, tnd_out)
+    }
 
-        (output * 32000.0) as i16
+    /// Returns the output of each channel in the scale of the linear mixer, ignoring muting
+    fn channel_outputs(&self) -> [f32; 6] {
+        let r = &self.reg;
+        let levels = [
+            r.pulse[0].sample(true),
+            r.pulse[1].sample(true),
+            r.triangle.sample(true, self.mute_ultrasonic_triangle),
+            r.noise.sample(true),
+            r.dmc.sample(true),
+        ];
+        let expansion = self.expansion_input.map_or(0.0, |(chip, output)| {
+            output * self.expansion_levels.gain(chip)
+        });
+
+        std::array::from_fn(|i| {
+            if i < levels.len() {
+                levels[i] * LINEAR_WEIGHTS[i]
+            } else {
+                expansion
+            }
+        })
+    }
+
+    fn mix_nonlinear(&self, gains: &[f32; 6]) -> (f32, f32) {
+        fn mix(pulse: f32, triangle: f32, noise: f32, dmc: f32) -> (f32, f32) {
+            (
+                lookup(&PULSE_TABLE, pulse),
+                lookup(&TND_TABLE, 3.0 * triangle + 2.0 * noise + dmc),
+            )
+        }
+
+        let [pulse1, pulse2, triangle, noise, dmc] = self.channel_levels(gains);
+
+        // Remove the DC offset by subtracting the output at the center level of each channel,
+        // which is the same bias the linear mixer removes
+        let (pulse_out, tnd_out) = mix(pulse1.0 + pulse2.0, triangle.0, noise.0, dmc.0);
+        let (pulse_center, tnd_center) = mix(pulse1.1 + pulse2.1, triangle.1, noise.1, dmc.1);
+        (pulse_out - pulse_center, tnd_out - tnd_center)
+    }
+
+    pub fn input(&self) -> &Input {
+        &self.input
     }
 
     pub fn set_input(&mut self, input: &Input) {
         self.input = input.clone();
     }
 
-    pub fn read(&mut self, ctx: &mut impl Context, addr: u16) -> u8 {
+    /// Sets how many frames the turbo buttons stay pressed, and then released
+    pub fn set_turbo_period(&mut self, frames: u32) {
+        self.turbo_period = frames.max(1);
+    }
+
+    /// Advances the turbo buttons, which toggle on frame boundaries
+    pub fn end_frame(&mut self) {
+        self.turbo_frame = (self.turbo_frame + 1) % (self.turbo_period * 2);
+    }
+
+    /// Sets a callback which replaces the input on each strobe
+    pub fn set_input_provider(&mut self, provider: Option<InputProvider>) {
+        self.input_provider = provider;
+    }
+
+    pub fn take_input_provider(&mut self) -> Option<InputProvider> {
+        self.input_provider.take()
+    }
+
+    pub fn set_expansion_device(&mut self, device: Option<Box<dyn ExpansionDevice>>) {
+        self.expansion_device = device;
+    }
+
+    pub fn take_expansion_device(&mut self) -> Option<Box<dyn ExpansionDevice>> {
+        self.expansion_device.take()
+    }
+
+    pub fn set_four_player_adapter(&mut self, adapter: FourPlayerAdapter) {
+        self.four_player = adapter;
+    }
+
+    pub fn set_console_model(&mut self, model: ConsoleModel) {
+        self.console_model = model;
+    }
+
+    /// Connects the Vs. System coin slots and DIP switches to $4016/$4017
+    pub fn enable_vs_switches(&mut self) {
+        self.vs_switches = Some(VsSwitches::default());
+    }
+
+    pub fn vs_switches(&self) -> Option<&VsSwitches> {
+        self.vs_switches.as_ref()
+    }
+
+    pub fn vs_switches_mut(&mut self) -> Option<&mut VsSwitches> {
+        self.vs_switches.as_mut()
+    }
+
+    pub fn read(&mut self, ctx: &mut impl Context, addr: u16, open_bus: u8) -> u8 {
+        let open_bus = if self.open_bus_enabled {
+            open_bus
+        } else {
+            (addr >> 8) as u8
+        };
+
         let ret = match addr {
             0x4015 => {
                 // Status
-                let mut ret = 0;
+                let mut ret = open_bus & 0x20;
                 let r = ret.view_bits_mut::<Lsb0>();
                 r.set(7, ctx.irq_source(IrqSource::ApuDmc));
-                r.set(6, ctx.irq_source(IrqSource::ApuFrame));
+                // A read on the cycle the flag is raised sees it set, but can't clear it
+                let raising = self.raises_frame_irq(ctx.region(), self.frame_counter + 1);
+                r.set(
+                    6,
+                    ctx.irq_source(IrqSource::ApuFrame) || self.frame_irq_pending || raising,
+                );
                 r.set(4, self.reg.dmc.length_counter > 0);
-                r.set(3, self.reg.noise.length_counter > 0);
-                r.set(2, self.reg.triangle.length_counter > 0);
-                r.set(1, self.reg.pulse[1].length_counter > 0);
-                r.set(0, self.reg.pulse[0].length_counter > 0);
+                r.set(3, self.reg.noise.length.is_active());
+                r.set(2, self.reg.triangle.length.is_active());
+                r.set(1, self.reg.pulse[1].length.is_active());
+                r.set(0, self.reg.pulse[0].length.is_active());
 
-                ctx.set_irq_source(IrqSource::ApuFrame, false);
+                if !raising {
+                    self.acknowledge_frame_irq(ctx);
+                }
                 ret
             }
 
             0x4016 | 0x4017 => {
                 let ix = (addr - 0x4016) as usize;
 
-                if self.controller_latch {
+                let mut ret = if self.controller_latch {
                     0x00
                 } else {
-                    let ret = self.pad_buf[ix] & 1 != 0;
-                    self.pad_buf[ix] = self.pad_buf[ix] >> 1 | 0x80;
-                    ret as u8
+                    let ret = self.pad_buf[ix] as u8 & 1;
+                    self.pad_buf[ix] = self.pad_buf[ix] >> 1 | 0x8000_0000;
+                    if self.four_player == FourPlayerAdapter::Famicom {
+                        let exp = self.expansion_pad_buf[ix] & 1;
+                        self.expansion_pad_buf[ix] = self.expansion_pad_buf[ix] >> 1 | 0x80;
+                        ret | exp << 1
+                    } else {
+                        ret
+                    }
+                };
+
+                if ix == 0 && self.console_model.has_microphone() && self.input.microphone {
+                    ret |= 0x04;
+                }
+
+                if let Some(device) = &mut self.expansion_device {
+                    ret |= device.read(ix) & self.console_model.expansion_bits(ix);
                 }
+
+                // The upper bits are not connected to the input buffers, and keep the
+                // last value on the bus
+                let driven =
+                    self.console_model.controller_bits(ix) | self.console_model.expansion_bits(ix);
+                ret |= open_bus & 0xe0 & !driven;
+
+                if let Some(vs) = &self.vs_switches {
+                    let r = ret.view_bits_mut::<Lsb0>();
+                    if ix == 0 {
+                        r.set(2, vs.service);
+                        r[3..5].store(vs.dip_switches & 3);
+                        r.set(5, vs.coin_timer[0] > 0);
+                        r.set(6, vs.coin_timer[1] > 0);
+                    } else {
+                        r[2..8].store(vs.dip_switches >> 2);
+                    }
+                }
+
+                ret
             }
 
             _ => {
-                log::info!("Read APU ${addr:04X}");
-                0
+                core_log!(ApuReg, Info, "Read APU ${addr:04X}");
+                open_bus
             }
         };
-        log::trace!("Read APU ${addr:04X} = {ret:02X}");
+        core_log!(ApuReg, Trace, "Read APU ${addr:04X} = {ret:02X}");
         ret
     }
 
     pub fn write(&mut self, ctx: &mut impl Context, addr: u16, data: u8) {
-        log::trace!("Write APU ${addr:04X} = ${data:02X}");
+        core_log!(ApuReg, Trace, "Write APU ${addr:04X} = ${data:02X}");
 
         match addr {
             // Pulse
@@ -597,10 +1399,13 @@ impl Apu {
                 let v = data.view_bits::<Lsb0>();
                 r.duty = v[6..8].load();
                 r.length_counter_halt = v[5];
+                r.length.set_halt(v[5]);
                 r.constant_volume = v[4];
                 r.volume = v[0..4].load();
 
-                log::trace!(
+                core_log!(
+                    ApuReg,
+                    Trace,
                     "Pulse #{ch}: duty={}, inflen={}, constvol={}, vol={}",
                     r.duty,
                     r.length_counter_halt,
@@ -618,7 +1423,9 @@ impl Apu {
                 r.sweep_shift = v[0..3].load();
                 r.sweep_reload = true;
 
-                log::trace!(
+                core_log!(
+                    ApuReg,
+                    Trace,
                     "Pulse #{ch}: swenable={}, swperiod={}, swneg={}, swshft={}, swreload={}",
                     r.sweep_enabled,
                     r.sweep_period,
@@ -632,7 +1439,13 @@ impl Apu {
                 let r = &mut self.reg.pulse[ch as usize];
                 r.timer.view_bits_mut::<Lsb0>()[0..8].store(data);
 
-                log::trace!("Pulse #{ch}: timer_low={}, timer={}", data, r.timer);
+                core_log!(
+                    ApuReg,
+                    Trace,
+                    "Pulse #{ch}: timer_low={}, timer={}",
+                    data,
+                    r.timer
+                );
             }
             0x4003 | 0x4007 => {
                 let ch = (addr - 0x4000) / 4;
@@ -642,13 +1455,14 @@ impl Apu {
                 r.length_counter_load = v[3..8].load();
 
                 if r.enable {
-                    r.length_counter = LENGTH_TABLE[r.length_counter_load as usize];
-                    log::trace!("PULSE {ch}: length: {}", r.length_counter);
+                    r.length.reload(r.length_counter_load);
                 }
                 r.envelope_start = true;
                 r.phase = 0;
 
-                log::trace!(
+                core_log!(
+                    ApuReg,
+                    Trace,
                     "Pulse #{ch}: timer_high={}, timer={}, length={}, enabled={}",
                     v[0..3].load::<u8>(),
                     r.timer,
@@ -662,10 +1476,11 @@ impl Apu {
                 let r = &mut self.reg.triangle;
                 let v = data.view_bits::<Lsb0>();
                 r.length_counter_halt = v[7];
+                r.length.set_halt(v[7]);
                 r.linear_counter_load = v[0..7].load();
             }
             0x4009 => {
-                log::warn!("Write APU ${addr:04X} = ${data:02X}");
+                core_log!(ApuReg, Warn, "Write APU ${addr:04X} = ${data:02X}");
             }
             0x400A => {
                 let r = &mut self.reg.triangle;
@@ -677,7 +1492,7 @@ impl Apu {
                 r.timer.view_bits_mut::<Lsb0>()[8..].store(v[0..3].load::<u8>());
                 r.length_counter_load = v[3..8].load();
                 if r.enable {
-                    r.length_counter = LENGTH_TABLE[r.length_counter_load as usize];
+                    r.length.reload(r.length_counter_load);
                 }
                 r.linear_counter_reload = true;
             }
@@ -687,11 +1502,12 @@ impl Apu {
                 let r = &mut self.reg.noise;
                 let v = data.view_bits::<Lsb0>();
                 r.length_counter_halt = v[5];
+                r.length.set_halt(v[5]);
                 r.constant_volume = v[4];
                 r.volume = v[0..4].load();
             }
             0x400D => {
-                log::warn!("Write APU ${addr:04X} = ${data:02X}");
+                core_log!(ApuReg, Warn, "Write APU ${addr:04X} = ${data:02X}");
             }
             0x400E => {
                 let r = &mut self.reg.noise;
@@ -704,7 +1520,7 @@ impl Apu {
                 let v = data.view_bits::<Lsb0>();
                 r.length_counter_load = v[3..8].load();
                 if r.enable {
-                    r.length_counter = LENGTH_TABLE[r.length_counter_load as usize];
+                    r.length.reload(r.length_counter_load);
                 }
                 r.envelope_start = true;
             }
@@ -745,21 +1561,28 @@ impl Apu {
 
                 for i in 0..2 {
                     if !self.reg.pulse[i].enable {
-                        self.reg.pulse[i].length_counter = 0;
+                        self.reg.pulse[i].length.clear();
                     }
                 }
                 if !self.reg.triangle.enable {
-                    self.reg.triangle.length_counter = 0;
+                    self.reg.triangle.length.clear();
                 }
                 if !self.reg.noise.enable {
-                    self.reg.noise.length_counter = 0;
+                    self.reg.noise.length.clear();
                 }
 
-                if !self.reg.dmc.enable {
-                    self.reg.dmc.length_counter = 0;
-                } else if self.reg.dmc.length_counter == 0 {
-                    self.reg.dmc.cur_addr = self.reg.dmc.sample_addr;
-                    self.reg.dmc.length_counter = self.reg.dmc.sample_length;
+                let r = &mut self.reg.dmc;
+                if !r.enable {
+                    // The sample buffer keeps its byte, which is still played
+                    r.length_counter = 0;
+                } else if r.length_counter == 0 {
+                    r.cur_addr = r.sample_addr;
+                    r.length_counter = r.sample_length;
+                    // Unlike the refills during playback, the DMA to fill an empty buffer
+                    // starts some cycles after the write
+                    if r.buffer.is_none() {
+                        r.dma_delay = if self.counter.is_multiple_of(2) { 2 } else { 3 };
+                    }
                 }
 
                 ctx.set_irq_source(IrqSource::ApuDmc, false);
@@ -769,36 +1592,110 @@ impl Apu {
                 let v = data.view_bits::<Lsb0>();
                 self.controller_latch = v[0];
                 self.expansion_latch = v[1..3].load_le();
+                if let Some(device) = &mut self.expansion_device {
+                    device.strobe(data & 7);
+                }
 
                 if self.controller_latch {
-                    for (i, pad) in self.input.pad.iter().take(2).enumerate() {
-                        let r = self.pad_buf[i].view_bits_mut::<Lsb0>();
-                        r.set(0, pad.a);
-                        r.set(1, pad.b);
-                        r.set(2, pad.select);
-                        r.set(3, pad.start);
-                        r.set(4, pad.up);
-                        r.set(5, pad.down);
-                        r.set(6, pad.left);
-                        r.set(7, pad.right);
+                    if let Some(provider) = &mut self.input_provider {
+                        self.input = provider(ctx.now());
+                    }
+                    let turbo = self.turbo_frame < self.turbo_period;
+                    for (i, signature) in FOUR_SCORE_SIGNATURE.into_iter().enumerate() {
+                        let pad = self.input.pad[i].bits(turbo) as u32;
+                        let extra = self.input.pad[i + 2].bits(turbo);
+                        self.pad_buf[i] = match self.four_player {
+                            FourPlayerAdapter::FourScore => {
+                                pad | (extra as u32) << 8 | signature << 16 | 0xff00_0000
+                            }
+                            _ => pad | 0xffff_ff00,
+                        };
+                        self.expansion_pad_buf[i] = extra;
                     }
                 }
             }
             0x4017 => {
                 let v = data.view_bits::<Lsb0>();
-                self.reg.frame_counter_mode = v[7];
+                self.frame_counter_next_mode = v[7];
                 self.reg.frame_counter_irq = v[6];
 
                 if self.reg.frame_counter_irq {
-                    ctx.set_irq_source(IrqSource::ApuFrame, false);
+                    self.acknowledge_frame_irq(ctx);
                 }
 
-                self.frame_counter_reset_delay = 3;
+                // The reset takes effect 3 CPU cycles after a write on an APU cycle,
+                // and 4 cycles after a write between APU cycles
+                self.frame_counter_reset_delay = if self.counter.is_multiple_of(2) { 3 } else { 4 };
             }
 
             _ => {
-                log::warn!("Write APU ${addr:04X} = ${data:02X}");
+                core_log!(ApuReg, Warn, "Write APU ${addr:04X} = ${data:02X}");
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter(value: u8) -> LengthCounter {
+        LengthCounter {
+            counter: value,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn length_reload_without_clock() {
+        let mut r = counter(5);
+        r.reload(1);
+        r.apply_pending();
+        assert_eq!(r.counter, LENGTH_TABLE[1]);
+    }
+
+    #[test]
+    fn length_reload_during_clock_is_ignored() {
+        let mut r = counter(5);
+        r.reload(1);
+        r.clock();
+        r.apply_pending();
+        assert_eq!(r.counter, 4);
+    }
+
+    #[test]
+    fn length_reload_during_clock_at_zero() {
+        let mut r = counter(0);
+        r.reload(1);
+        r.clock();
+        r.apply_pending();
+        assert_eq!(r.counter, LENGTH_TABLE[1]);
+    }
+
+    #[test]
+    fn length_halt_during_clock_uses_old_value() {
+        let mut r = counter(5);
+        r.set_halt(true);
+        r.clock();
+        r.apply_pending();
+        assert_eq!(r.counter, 4);
+        r.clock();
+        assert_eq!(r.counter, 4);
+
+        r.set_halt(false);
+        r.clock();
+        r.apply_pending();
+        assert_eq!(r.counter, 4);
+        r.clock();
+        assert_eq!(r.counter, 3);
+    }
+
+    #[test]
+    fn length_clear_cancels_pending_reload() {
+        let mut r = counter(5);
+        r.reload(1);
+        r.clear();
+        r.apply_pending();
+        assert_eq!(r.counter, 0);
+    }
+}
@@ -0,0 +1,46 @@
+//! Loading ROMs from zip and gzip archives
+
+use std::io::{Cursor, Read};
+
+use crate::rom::RomError;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Extensions of the files taken from zip archives
+const ROM_EXTENSIONS: &[&str] = &["nes", "nsf", "nsfe"];
+
+pub fn is_archive(dat: &[u8]) -> bool {
+    dat.starts_with(ZIP_MAGIC) || dat.starts_with(GZIP_MAGIC)
+}
+
+/// Extracts the ROM from an archive. For zip archives, it is the first entry with
+/// an extension of a ROM file.
+pub fn extract(dat: &[u8]) -> Result<Vec<u8>, RomError> {
+    let mut ret = vec![];
+    if dat.starts_with(GZIP_MAGIC) {
+        flate2::read::MultiGzDecoder::new(dat)
+            .read_to_end(&mut ret)
+            .map_err(|err| RomError::InvalidArchive(err.to_string()))?;
+        return Ok(ret);
+    }
+
+    let mut zip = zip::ZipArchive::new(Cursor::new(dat))
+        .map_err(|err| RomError::InvalidArchive(err.to_string()))?;
+
+    let is_rom = |name: &str| {
+        name.rsplit_once('.')
+            .is_some_and(|(_, ext)| ROM_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+    };
+    let Some(index) = (0..zip.len()).find(|&i| zip.name_for_index(i).is_some_and(is_rom)) else {
+        Err(RomError::InvalidArchive(
+            "no ROM file in the archive".into(),
+        ))?
+    };
+
+    zip.by_index(index)
+        .map_err(|err| RomError::InvalidArchive(err.to_string()))?
+        .read_to_end(&mut ret)
+        .map_err(|err| RomError::InvalidArchive(err.to_string()))?;
+    Ok(ret)
+}
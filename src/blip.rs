@@ -0,0 +1,120 @@
+//! Band-limited step synthesis
+//!
+//! Channel outputs change in steps at CPU clock resolution. Sampling them directly at the
+//! output rate aliases every harmonic above the Nyquist frequency back into the audible band.
+//! Instead, each step is added to the output as a band-limited step, whose impulse response
+//! is a windowed sinc cut off below the Nyquist frequency.
+
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+
+/// Taps of the impulse response, which delays the output by half of them
+const TAPS: usize = 16;
+/// Fractional positions of a step within an output sample
+const PHASES: usize = 64;
+/// Cutoff frequency relative to the Nyquist frequency
+const CUTOFF: f64 = 0.9;
+
+/// Impulse responses for each phase, normalized so that a step settles at its exact height
+static KERNEL: LazyLock<Vec<[f32; TAPS]>> = LazyLock::new(|| {
+    (0..PHASES)
+        .map(|phase| {
+            let center = (TAPS / 2) as f64 - 1.0 + phase as f64 / PHASES as f64;
+            let taps: [f64; TAPS] = std::array::from_fn(|i| {
+                let x = i as f64 - center;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    let t = std::f64::consts::PI * x * CUTOFF;
+                    t.sin() / t
+                };
+                // Blackman window over the taps
+                let w = 2.0 * std::f64::consts::PI * (x + TAPS as f64 / 2.0) / TAPS as f64;
+                let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+                sinc * window.max(0.0)
+            });
+            let sum: f64 = taps.iter().sum();
+            std::array::from_fn(|i| (taps[i] / sum) as f32)
+        })
+        .collect()
+});
+
+/// Accumulates amplitude steps and produces band-limited output samples
+#[derive(Default, Serialize, Deserialize)]
+pub struct BlipBuffer {
+    // Differences of the upcoming output samples
+    deltas: Vec<f32>,
+    // Output of the last sample
+    integrator: f32,
+    // Current amplitude of the input
+    amplitude: f32,
+}
+
+impl BlipBuffer {
+    /// Sets the input amplitude at `pos` samples after the start of the next output sample.
+    /// `pos` must be less than 2.
+    pub fn set_amplitude(&mut self, pos: f32, amplitude: f32) {
+        let delta = amplitude - self.amplitude;
+        if delta == 0.0 {
+            return;
+        }
+        self.amplitude = amplitude;
+
+        if self.deltas.len() < TAPS + 2 {
+            self.deltas.resize(TAPS + 2, 0.0);
+        }
+
+        let pos = pos.clamp(0.0, 1.999);
+        let offset = pos as usize;
+        let phase = ((pos.fract() * PHASES as f32) as usize).min(PHASES - 1);
+        for (d, k) in self.deltas[offset..].iter_mut().zip(&KERNEL[phase]) {
+            *d += delta * k;
+        }
+    }
+
+    /// Finishes the next output sample and returns it
+    pub fn read_sample(&mut self) -> f32 {
+        if self.deltas.is_empty() {
+            return self.integrator;
+        }
+        self.integrator += self.deltas.remove(0);
+        self.deltas.push(0.0);
+        self.integrator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_settles_at_amplitude() {
+        let mut blip = BlipBuffer::default();
+        blip.set_amplitude(0.3, 1.0);
+        let out = (0..TAPS * 2)
+            .map(|_| blip.read_sample())
+            .collect::<Vec<_>>();
+        assert!((out[TAPS * 2 - 1] - 1.0).abs() < 1e-5);
+        // Ringing stays within the Gibbs overshoot of about 10%
+        assert!(out.iter().all(|&s| (-0.15..1.15).contains(&s)));
+    }
+
+    #[test]
+    fn tone_above_nyquist_is_attenuated() {
+        // A square wave at 0.7 times the output rate, which aliases at full level
+        // when it is sampled directly
+        let mut blip = BlipBuffer::default();
+        let steps_per_sample = 100;
+        let mut out = vec![];
+        for t in 0..1000 {
+            for i in 0..steps_per_sample {
+                let pos = i as f32 / steps_per_sample as f32;
+                let half_periods = ((t as f32 + pos) * 0.7 * 2.0) as i64;
+                blip.set_amplitude(pos, if half_periods % 2 == 0 { 1.0 } else { -1.0 });
+            }
+            out.push(blip.read_sample());
+        }
+        let peak = out[TAPS..].iter().fold(0.0_f32, |m, s| m.max(s.abs()));
+        assert!(peak < 0.1);
+    }
+}
@@ -1,3 +1,5 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::ops::Range;
 
 pub const PPU_CLOCK_PER_LINE: u64 = 341;
@@ -12,3 +14,53 @@ pub const LINES_PER_FRAME: usize = SCREEN_RANGE.end - SCREEN_RANGE.start + VBLAN
 
 pub const SCREEN_WIDTH: usize = 256;
 pub const SCREEN_HEIGHT: usize = 240;
+
+pub const PAL_LINES_PER_FRAME: usize =
+    SCREEN_RANGE.end - SCREEN_RANGE.start + PAL_VBLANK_LINES + 1 + 1;
+pub const PAL_VBLANK_LINES: usize = 70;
+
+/// Video timing of the console
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug, JsonSchema, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    pub fn lines_per_frame(&self) -> usize {
+        match self {
+            Region::Ntsc => LINES_PER_FRAME,
+            Region::Pal => PAL_LINES_PER_FRAME,
+        }
+    }
+
+    pub fn pre_render_line(&self) -> usize {
+        self.lines_per_frame() - 1
+    }
+
+    /// PPU clocks per CPU clock as (numerator, denominator)
+    pub fn ppu_clock_ratio(&self) -> (u64, u64) {
+        match self {
+            Region::Ntsc => (PPU_CLOCK_PER_CPU_CLOCK, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    /// CPU clocks per second
+    pub fn cpu_clock_rate(&self) -> u64 {
+        match self {
+            Region::Ntsc => 1_789_773,
+            Region::Pal => 1_662_607,
+        }
+    }
+
+    /// Nominal frames per second
+    pub fn frame_rate(&self) -> u64 {
+        match self {
+            Region::Ntsc => 60,
+            Region::Pal => 50,
+        }
+    }
+}
@@ -2,7 +2,9 @@ use ambassador::{delegatable_trait, Delegate};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    apu, cpu,
+    apu,
+    consts::Region,
+    cpu,
     mapper::{self, create_mapper},
     memory,
     nes::Error,
@@ -24,7 +26,12 @@ pub trait Bus {
     fn read_pure(&self, addr: u16) -> Option<u8>;
     fn write(&mut self, addr: u16, data: u8);
     fn tick_bus(&mut self);
-    fn cpu_stall(&mut self) -> u64;
+    /// Takes the page of OAM DMA requested by a $4014 write, which the CPU performs
+    /// on its next read cycle
+    fn take_oam_dma(&mut self) -> Option<u8>;
+    fn dmc_dma_pending(&self) -> bool;
+    fn dmc_dma_halt(&mut self, addr: u16);
+    fn dmc_dma_fetch(&mut self);
 }
 
 #[delegatable_trait]
@@ -42,18 +49,25 @@ pub trait Apu {
     fn apu(&self) -> &apu::Apu;
     fn apu_mut(&mut self) -> &mut apu::Apu;
 
-    fn read_apu(&mut self, addr: u16) -> u8;
+    fn read_apu(&mut self, addr: u16, open_bus: u8) -> u8;
     fn write_apu(&mut self, addr: u16, data: u8);
     fn tick_apu(&mut self);
+    fn complete_dmc_dma(&mut self, data: u8);
+    fn acknowledge_frame_irq(&mut self);
 }
 
 #[delegatable_trait]
 pub trait Mapper {
-    fn read_prg_mapper(&self, addr: u16) -> u8;
+    fn mapper(&self) -> &mapper::Mapper;
+    fn mapper_mut(&mut self) -> &mut mapper::Mapper;
+
+    fn read_prg_mapper(&mut self, addr: u16) -> u8;
+    fn peek_prg_mapper(&self, addr: u16) -> u8;
     fn write_prg_mapper(&mut self, addr: u16, data: u8);
     fn read_chr_mapper(&mut self, addr: u16) -> u8;
     fn write_chr_mapper(&mut self, addr: u16, data: u8);
     fn tick_mapper(&mut self);
+    fn tick_mapper_cpu(&mut self);
 }
 
 #[delegatable_trait]
@@ -77,18 +91,27 @@ pub trait Rom {
     fn rom_mut(&mut self) -> &mut rom::Rom;
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum IrqSource {
     ApuFrame = 0,
     ApuDmc = 1,
     Mapper = 2,
 }
 
+impl IrqSource {
+    pub const ALL: [IrqSource; 3] = [IrqSource::ApuFrame, IrqSource::ApuDmc, IrqSource::Mapper];
+}
+
 #[delegatable_trait]
 pub trait Interrupt {
-    fn rst(&mut self) -> bool;
-    fn nmi(&mut self) -> bool;
+    fn rst(&self) -> bool;
+    fn nmi(&self) -> bool;
     fn set_nmi(&mut self, nmi: bool);
-    fn irq(&mut self) -> bool;
+    /// Returns whether a falling edge of NMI has been latched and not yet serviced
+    fn nmi_edge(&self) -> bool;
+    /// Clears the latched NMI edge, when the CPU services it or the PPU suppresses it
+    fn clear_nmi_edge(&mut self);
+    fn irq(&self) -> bool;
     fn irq_source(&self, source: IrqSource) -> bool;
     fn set_irq_source(&mut self, source: IrqSource, irq: bool);
 }
@@ -97,6 +120,7 @@ pub trait Interrupt {
 pub trait Timing {
     fn now(&self) -> u64;
     fn elapse(&mut self, elapsed: u64);
+    fn region(&self) -> Region;
 }
 
 #[derive(Delegate, Serialize, Deserialize)]
@@ -159,8 +183,20 @@ impl Bus for Inner {
         self.mem.tick(&mut self.inner);
     }
 
-    fn cpu_stall(&mut self) -> u64 {
-        self.mem.cpu_stall()
+    fn take_oam_dma(&mut self) -> Option<u8> {
+        self.mem.take_oam_dma()
+    }
+
+    fn dmc_dma_pending(&self) -> bool {
+        self.mem.dmc_dma_pending(&self.inner)
+    }
+
+    fn dmc_dma_halt(&mut self, addr: u16) {
+        self.mem.dmc_dma_halt(&mut self.inner, addr);
+    }
+
+    fn dmc_dma_fetch(&mut self) {
+        self.mem.dmc_dma_fetch(&mut self.inner);
     }
 }
 
@@ -201,8 +237,8 @@ impl Apu for Inner2 {
     fn apu_mut(&mut self) -> &mut apu::Apu {
         &mut self.apu
     }
-    fn read_apu(&mut self, addr: u16) -> u8 {
-        self.apu.read(&mut self.inner, addr)
+    fn read_apu(&mut self, addr: u16, open_bus: u8) -> u8 {
+        self.apu.read(&mut self.inner, addr, open_bus)
     }
     fn write_apu(&mut self, addr: u16, data: u8) {
         self.apu.write(&mut self.inner, addr, data);
@@ -210,6 +246,12 @@ impl Apu for Inner2 {
     fn tick_apu(&mut self) {
         self.apu.tick(&mut self.inner);
     }
+    fn complete_dmc_dma(&mut self, data: u8) {
+        self.apu.complete_dmc_dma(&mut self.inner, data);
+    }
+    fn acknowledge_frame_irq(&mut self) {
+        self.apu.acknowledge_frame_irq(&mut self.inner);
+    }
 }
 
 #[derive(Delegate, Serialize, Deserialize)]
@@ -223,7 +265,17 @@ struct Inner3 {
 }
 
 impl Mapper for Inner3 {
-    fn read_prg_mapper(&self, addr: u16) -> u8 {
+    fn mapper(&self) -> &mapper::Mapper {
+        &self.mapper
+    }
+    fn mapper_mut(&mut self) -> &mut mapper::Mapper {
+        &mut self.mapper
+    }
+    fn read_prg_mapper(&mut self, addr: u16) -> u8 {
+        use mapper::MapperTrait;
+        self.mapper.read_prg_mut(&mut self.inner, addr)
+    }
+    fn peek_prg_mapper(&self, addr: u16) -> u8 {
         use mapper::MapperTrait;
         self.mapper.read_prg(&self.inner, addr)
     }
@@ -243,6 +295,10 @@ impl Mapper for Inner3 {
         use mapper::MapperTrait;
         self.mapper.tick(&mut self.inner)
     }
+    fn tick_mapper_cpu(&mut self) {
+        use mapper::MapperTrait;
+        self.mapper.tick_cpu(&mut self.inner)
+    }
 }
 
 #[derive(Delegate, Serialize, Deserialize)]
@@ -254,6 +310,7 @@ struct Inner4 {
     rom: rom::Rom,
     signales: Signales,
     now: u64,
+    region: Region,
 }
 
 impl MemoryController for Inner4 {
@@ -301,20 +358,31 @@ impl Rom for rom::Rom {
 struct Signales {
     rst: bool,
     nmi: bool,
+    nmi_edge: bool,
     irq_source: [bool; 3],
 }
 
 impl Interrupt for Signales {
-    fn rst(&mut self) -> bool {
+    fn rst(&self) -> bool {
         self.rst
     }
-    fn nmi(&mut self) -> bool {
+    fn nmi(&self) -> bool {
         self.nmi
     }
     fn set_nmi(&mut self, nmi: bool) {
+        // NMI is active low
+        if self.nmi && !nmi {
+            self.nmi_edge = true;
+        }
         self.nmi = nmi;
     }
-    fn irq(&mut self) -> bool {
+    fn nmi_edge(&self) -> bool {
+        self.nmi_edge
+    }
+    fn clear_nmi_edge(&mut self) {
+        self.nmi_edge = false;
+    }
+    fn irq(&self) -> bool {
         self.irq_source.iter().any(|r| *r)
     }
     fn irq_source(&self, source: IrqSource) -> bool {
@@ -332,14 +400,33 @@ impl Timing for Inner4 {
     fn elapse(&mut self, elapsed: u64) {
         self.now += elapsed;
     }
+    fn region(&self) -> Region {
+        self.region
+    }
 }
 
 impl Context {
     pub fn new(rom: rom::Rom, backup: Option<Vec<u8>>) -> Result<Context, Error> {
         let cpu = cpu::Cpu::default();
         let mem = memory::MemoryMap::default();
-        let ppu = ppu::Ppu::default();
-        let apu = apu::Apu::default();
+        let mut ppu = ppu::Ppu::default();
+        let mut apu = apu::Apu::default();
+
+        match rom.console_type {
+            rom::ConsoleType::VsSystem { ppu_type, .. } => {
+                ppu.set_model(ppu::PpuModel::from_vs_ppu_type(ppu_type));
+                apu.enable_vs_switches();
+            }
+            // Only the game side of the board is emulated, which uses an RGB PPU
+            rom::ConsoleType::Playchoice10 => ppu.set_model(ppu::PpuModel::Rp2c03),
+            _ => (),
+        }
+
+        let region = match rom.timing_mode {
+            rom::TimingMode::Pal => Region::Pal,
+            _ => Region::Ntsc,
+        };
+
         let mem_ctrl = memory::MemoryController::new(&rom, backup)?;
         let signales = Signales::default();
 
@@ -348,11 +435,12 @@ impl Context {
             rom,
             signales,
             now: 0,
+            region: Region::Ntsc,
         };
 
         let mapper = create_mapper(&mut inner)?;
 
-        Ok(Context {
+        let mut ret = Context {
             cpu,
             inner: Inner {
                 mem,
@@ -362,6 +450,21 @@ impl Context {
                     inner: Inner3 { mapper, inner },
                 },
             },
-        })
+        };
+        ret.set_region(region);
+        Ok(ret)
+    }
+
+    /// Switches the video timing. This should be called only at power-on.
+    pub fn set_region(&mut self, region: Region) {
+        self.inner.inner.inner.inner.region = region;
+
+        // Consoles of each region have their own PPU, unless it is an RGB one
+        let ppu = &mut self.inner.inner.ppu;
+        match (region, ppu.model()) {
+            (Region::Pal, ppu::PpuModel::Rp2c02) => ppu.set_model(ppu::PpuModel::Rp2c07),
+            (Region::Ntsc, ppu::PpuModel::Rp2c07) => ppu.set_model(ppu::PpuModel::Rp2c02),
+            _ => (),
+        }
     }
 }
@@ -1,19 +1,230 @@
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 
-use crate::{context, util::trait_alias};
+use crate::{
+    context,
+    disasm::{AddrMode, Instruction, INSTR_TABLE},
+    logging::{self, core_log, Category},
+    profiler::{ProfileAddr, Profiler},
+    util::trait_alias,
+};
 
-trait_alias!(pub trait Context = context::Bus + context::MemoryController + context::Mapper + context::Interrupt + context::Timing);
+trait_alias!(pub trait Context = context::Bus + context::MemoryController + context::Mapper + context::Ppu + context::Interrupt + context::Timing);
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct Cpu {
     world: u64,
     counter: u64,
     reg: Register,
-    nmi_prev: bool,
-    i_flag_prev: bool,
+    // Interrupts polled at the end of the last cycle and the one before it
+    poll: InterruptPoll,
+    prev_poll: InterruptPoll,
+    // Stopped by a KIL opcode until reset
+    jammed: bool,
+    // Instruction stopped in the middle by a pause, which the next tick resumes
+    #[serde(default)]
+    suspended: Option<Suspended>,
+    #[serde(skip)]
+    pause_at: Option<u64>,
+    #[serde(skip)]
+    pause_hit: bool,
+    #[serde(skip)]
+    journal: Journal,
+    // Bits which XAA and ATX OR into A, which depend on the chip
+    #[serde(skip)]
+    magic: u8,
+    #[serde(skip)]
+    trace_hook: Option<TraceHook>,
+    #[serde(skip)]
+    profiler: Option<Profiler>,
+    #[serde(skip)]
+    history: History,
 }
 
+/// Write made by an instruction, recorded by `History`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct CpuWrite {
+    /// Address of the instruction, or the one before an interrupt for its pushes
+    pub pc: u16,
+    pub addr: u16,
+    pub data: u8,
+    pub cycle: u64,
+}
+
+/// Ring buffers of the most recently executed instructions and CPU writes,
+/// which tell how a game reached a crash. Each is disabled while its capacity is 0.
+#[derive(Default)]
+pub struct History {
+    pc_capacity: usize,
+    pcs: VecDeque<u16>,
+    write_capacity: usize,
+    writes: VecDeque<CpuWrite>,
+    // Instruction being executed
+    pc: u16,
+}
+
+impl History {
+    pub fn set_pc_capacity(&mut self, capacity: usize) {
+        self.pc_capacity = capacity;
+        while self.pcs.len() > capacity {
+            self.pcs.pop_front();
+        }
+    }
+
+    pub fn set_write_capacity(&mut self, capacity: usize) {
+        self.write_capacity = capacity;
+        while self.writes.len() > capacity {
+            self.writes.pop_front();
+        }
+    }
+
+    /// Returns the addresses of executed instructions from oldest to newest
+    pub fn pcs(&self) -> impl Iterator<Item = u16> + '_ {
+        self.pcs.iter().copied()
+    }
+
+    /// Returns the writes from oldest to newest
+    pub fn writes(&self) -> impl Iterator<Item = &CpuWrite> {
+        self.writes.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.pcs.clear();
+        self.writes.clear();
+    }
+
+    fn record_pc(&mut self, pc: u16) {
+        self.pc = pc;
+        if self.pc_capacity == 0 {
+            return;
+        }
+        if self.pcs.len() == self.pc_capacity {
+            self.pcs.pop_front();
+        }
+        self.pcs.push_back(pc);
+    }
+
+    fn record_write(&mut self, addr: u16, data: u8, cycle: u64) {
+        if self.write_capacity == 0 {
+            return;
+        }
+        if self.writes.len() == self.write_capacity {
+            self.writes.pop_front();
+        }
+        self.writes.push_back(CpuWrite {
+            pc: self.pc,
+            addr,
+            data,
+            cycle,
+        });
+    }
+}
+
+/// Callback invoked before each instruction
+pub type TraceHook = Box<dyn FnMut(&TraceRecord) + Send>;
+
+/// Instruction about to be executed, passed to the trace hook
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct TraceRecord {
+    pub pc: u16,
+    /// Opcode and operands, of which `len` bytes are valid
+    pub bytes: [u8; 3],
+    pub len: usize,
+    /// Registers before the instruction
+    pub state: CpuState,
+    /// Position of the PPU when the instruction starts
+    pub frame: u64,
+    pub line: usize,
+    pub dot: usize,
+}
+
+impl TraceRecord {
+    pub fn opcode_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Instruction stopped between two bus cycles by a pause. The registers are left as they
+/// were before it, and resuming it replays the cycles done so far with the values they
+/// got, without touching the rest of the console again.
 #[derive(Default, Serialize, Deserialize)]
+struct Suspended {
+    cycles: u64,
+    values: Vec<u16>,
+}
+
+/// Values which the running instruction gets from the rest of the console
+#[derive(Default)]
+enum Journal {
+    /// No pause is set, so nothing is recorded
+    #[default]
+    Off,
+    Record(Suspended),
+    /// Replaying a resumed instruction, at the index of the next value
+    Replay(Suspended, usize),
+    /// The pause has been hit, and the rest of the instruction touches nothing
+    Stopped(Suspended),
+}
+
+impl Journal {
+    fn resume(suspended: Suspended) -> Journal {
+        if suspended.values.is_empty() {
+            Journal::Record(suspended)
+        } else {
+            Journal::Replay(suspended, 0)
+        }
+    }
+
+    /// Returns whether accesses reach the rest of the console
+    fn live(&self) -> bool {
+        matches!(self, Journal::Off | Journal::Record(_))
+    }
+}
+
+/// Value which an instruction gets from the rest of the console, recorded to be replayed
+trait Replayed: Copy + Default {
+    fn encode(self) -> u16;
+    fn decode(value: u16) -> Self;
+}
+
+impl Replayed for u8 {
+    fn encode(self) -> u16 {
+        self as u16
+    }
+
+    fn decode(value: u16) -> Self {
+        value as u8
+    }
+}
+
+impl Replayed for bool {
+    fn encode(self) -> u16 {
+        self as u16
+    }
+
+    fn decode(value: u16) -> Self {
+        value != 0
+    }
+}
+
+impl Replayed for Option<u8> {
+    fn encode(self) -> u16 {
+        self.map_or(0x100, |v| v as u16)
+    }
+
+    fn decode(value: u16) -> Self {
+        (value < 0x100).then_some(value as u8)
+    }
+}
+
+/// Interrupts which the CPU sees on its interrupt lines at the end of a cycle
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+struct InterruptPoll {
+    nmi: bool,
+    irq: bool,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 struct Register {
     a: u8,
     x: u8,
@@ -23,7 +234,7 @@ struct Register {
     flag: Flag,
 }
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 struct Flag {
     c: bool,
     z: bool,
@@ -61,6 +272,46 @@ impl Flag {
     }
 }
 
+/// Registers of the CPU for debuggers and test harnesses
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub pc: u16,
+    /// Status flags as pushed by PHP, with the B and unused bits cleared
+    pub p: u8,
+    /// CPU cycles since power-on, which only reading the state returns
+    pub cycle: u64,
+}
+
+impl CpuState {
+    pub fn carry(&self) -> bool {
+        self.p & 0x01 != 0
+    }
+
+    pub fn zero(&self) -> bool {
+        self.p & 0x02 != 0
+    }
+
+    pub fn interrupt_disable(&self) -> bool {
+        self.p & 0x04 != 0
+    }
+
+    pub fn decimal(&self) -> bool {
+        self.p & 0x08 != 0
+    }
+
+    pub fn overflow(&self) -> bool {
+        self.p & 0x40 != 0
+    }
+
+    pub fn negative(&self) -> bool {
+        self.p & 0x80 != 0
+    }
+}
+
 #[derive(Debug)]
 pub enum Interrupt {
     Rst,
@@ -80,35 +331,231 @@ impl Interrupt {
 
 impl Cpu {
     pub fn reset(&mut self, ctx: &mut impl Context) {
+        self.jammed = false;
+        self.suspended = None;
         self.exec_interrupt(ctx, Interrupt::Rst, false);
     }
 
+    pub fn pc(&self) -> u16 {
+        self.reg.pc
+    }
+
+    /// Sets PC, abandoning the rest of an instruction stopped by a pause
     pub fn set_pc(&mut self, pc: u16) {
         self.reg.pc = pc;
+        self.suspended = None;
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            a: self.reg.a,
+            x: self.reg.x,
+            y: self.reg.y,
+            s: self.reg.s,
+            pc: self.reg.pc,
+            p: self.reg.flag.get_u8(0) & !0x30,
+            cycle: self.counter,
+        }
+    }
+
+    /// Overwrites the registers. The cycle counter is kept to stay in sync with the other devices.
+    /// The rest of an instruction stopped by a pause is abandoned.
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.suspended = None;
+        self.reg.a = state.a;
+        self.reg.x = state.x;
+        self.reg.y = state.y;
+        self.reg.s = state.s;
+        self.reg.pc = state.pc;
+        self.reg.flag.set_u8(state.p);
+    }
+
+    /// Sets the chip dependent value which XAA (ANE) and ATX (LXA) OR into A
+    pub fn set_unstable_opcode_magic(&mut self, magic: u8) {
+        self.magic = magic;
+    }
+
+    pub fn set_trace_hook(&mut self, hook: Option<TraceHook>) {
+        self.trace_hook = hook;
+    }
+
+    pub fn take_trace_hook(&mut self) -> Option<TraceHook> {
+        self.trace_hook.take()
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn profiler_mut(&mut self) -> Option<&mut Profiler> {
+        self.profiler.as_mut()
+    }
+
+    /// Starts profiling with `profiler`, or stops it with `None`
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    pub fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+    }
+
+    /// Makes the next tick start an instruction. Otherwise the ticks are spent catching up
+    /// with the cycles which the last instruction has run ahead.
+    pub fn sync_clock(&mut self) {
+        self.world = self.world.max(self.counter);
+    }
+
+    /// Stops the CPU right after the bus cycle which brings the cycle counter to `cycle`,
+    /// even in the middle of an instruction
+    pub fn set_pause(&mut self, cycle: Option<u64>) {
+        self.pause_at = cycle;
+    }
+
+    /// Returns whether the pause has stopped the CPU since the last call
+    pub fn take_pause_hit(&mut self) -> bool {
+        std::mem::take(&mut self.pause_hit)
+    }
+
+    /// Returns whether a pause has stopped the CPU in the middle of an instruction.
+    /// The registers read as they were before the instruction until it resumes.
+    pub fn suspended(&self) -> bool {
+        self.suspended.is_some()
+    }
+
+    /// Returns whether a KIL opcode has stopped the CPU, which only reset recovers
+    pub fn jammed(&self) -> bool {
+        self.jammed
+    }
+
+    pub fn debug_dump(&self) -> serde_json::Value {
+        serde_json::json!({
+            "reg": &self.reg,
+            "p": self.reg.flag.get_u8(2),
+            "cycle": self.counter,
+            "jammed": self.jammed,
+        })
     }
 
     fn exec_interrupt(&mut self, ctx: &mut impl Context, interrupt: Interrupt, brk: bool) {
-        log::info!("Interrupt: {:?}", interrupt);
+        core_log!(Interrupt, Info, "Interrupt: {:?}", interrupt);
 
-        let vector = interrupt.vector_addr();
+        // Hardware interrupts read the next opcode twice without executing it,
+        // where BRK fetches its opcode and padding byte
+        if matches!(interrupt, Interrupt::Irq | Interrupt::Nmi) && !brk {
+            let _ = self.read(ctx, self.reg.pc);
+            let _ = self.read(ctx, self.reg.pc);
+        }
 
         self.push16(ctx, self.reg.pc);
+
+        // An NMI detected while pushing PC hijacks the vector fetch of BRK and IRQ.
+        // The pushed flags still tell BRK apart.
+        let vector = if matches!(interrupt, Interrupt::Irq)
+            && self.poll.nmi
+            && self.replayed(|| ctx.nmi_edge())
+        {
+            core_log!(Interrupt, Info, "Interrupt: NMI hijacked {:?}", interrupt);
+            if self.journal.live() {
+                ctx.clear_nmi_edge();
+            }
+            Interrupt::Nmi.vector_addr()
+        } else {
+            interrupt.vector_addr()
+        };
+
         self.push8(ctx, self.reg.flag.get_u8(if brk { 3 } else { 2 }));
-        self.reg.pc = self.read(ctx, vector) as u16 | (self.read(ctx, vector + 1) as u16) << 8;
         self.reg.flag.i = true;
+        self.reg.pc = self.read(ctx, vector) as u16 | (self.read(ctx, vector + 1) as u16) << 8;
+
+        // The first instruction of the handler runs before another interrupt
+        self.prev_poll = InterruptPoll::default();
+    }
+
+    /// Runs OAM DMA and DMC DMA, which halt the CPU on a read of `addr`.
+    /// The DMAs share the halted cycles: reads are done on even (get) cycles and OAM writes
+    /// on odd (put) cycles, and DMC DMA takes the get cycle when both want it.
+    fn dma(&mut self, ctx: &mut impl Context, addr: u16, mut oam_page: Option<u8>) {
+        // Reads and writes done by OAM DMA, and the byte being copied
+        let mut oam_count: u16 = 0;
+        let mut oam_data = 0;
+        // Cycles until DMC DMA can fetch. It needs a halt cycle and a dummy cycle,
+        // which the cycles of OAM DMA count as.
+        let mut dmc_wait: Option<u8> = None;
+        let mut halted = false;
+
+        loop {
+            if matches!(self.journal, Journal::Stopped(_)) {
+                return;
+            }
+            if dmc_wait.is_none() && self.replayed(|| ctx.dmc_dma_pending()) {
+                dmc_wait = Some(2);
+            }
+            if oam_page.is_none() && dmc_wait.is_none() {
+                break;
+            }
+
+            let get = self.replayed(|| ctx.now().is_multiple_of(2));
+            if !halted {
+                // DMC DMA alone halts the CPU in the middle of its read, which registers
+                // with read side effects see
+                if oam_page.is_none() && self.journal.live() {
+                    ctx.dmc_dma_halt(addr);
+                }
+                halted = true;
+            } else if get && dmc_wait == Some(0) {
+                if self.journal.live() {
+                    ctx.dmc_dma_fetch();
+                }
+                dmc_wait = None;
+            } else if let Some(page) = oam_page.filter(|_| get == oam_count.is_multiple_of(2)) {
+                if get {
+                    let addr = u16::from_be_bytes([page, (oam_count / 2) as u8]);
+                    oam_data = self.replayed(|| ctx.read(addr));
+                } else if self.journal.live() {
+                    ctx.write(0x2004, oam_data);
+                }
+                oam_count += 1;
+                if oam_count == 512 {
+                    oam_page = None;
+                }
+            }
+            // Other cycles are dummy cycles and alignment cycles
+
+            if let Some(wait) = &mut dmc_wait {
+                *wait = wait.saturating_sub(1);
+            }
+            self.tick_bus(ctx);
+        }
     }
 
     fn read(&mut self, ctx: &mut impl Context, addr: u16) -> u8 {
-        let ret = ctx.read(addr);
+        // DMA can only halt the CPU on a read cycle
+        let oam_page = self.replayed(|| ctx.take_oam_dma());
+        if oam_page.is_some() || self.replayed(|| ctx.dmc_dma_pending()) {
+            self.dma(ctx, addr, oam_page);
+        }
+        let ret = self.replayed(|| ctx.read(addr));
         self.tick_bus(ctx);
-        log::trace!(target: "prgmem", "[${addr:04X}] -> ${ret:02X}");
+        core_log!(PrgMem, Trace, "[${addr:04X}] -> ${ret:02X}");
         ret
     }
 
     fn write(&mut self, ctx: &mut impl Context, addr: u16, data: u8) {
-        ctx.write(addr, data);
+        if self.journal.live() {
+            self.history.record_write(addr, data, self.counter);
+            ctx.write(addr, data);
+        }
         self.tick_bus(ctx);
-        log::trace!(target: "prgmem", "[${addr:04X}] <- ${data:02X}");
+        core_log!(PrgMem, Trace, "[${addr:04X}] <- ${data:02X}");
     }
 
     fn fetch8(&mut self, ctx: &mut impl Context) -> u8 {
@@ -145,84 +592,54 @@ impl Cpu {
     }
 }
 
-#[allow(clippy::upper_case_acronyms)]
-enum AddrMode {
-    IMP, // Implicit
-    ACC, // Accumulator
-    IMM, // Immediate: #v
-    ZPG, // Zero Page: d
-    ABS, // Absolute: a
-    REL, // Relative: label
-    IND, // Indirect: (d)
-    ZPX, // Zero Page indexed: d,X
-    ZPY, // Zero Page indexed: d,Y
-    ABX, // Absolute indexed: a,X
-    ABY, // Absolute indexed: a,Y
-    INX, // Indirect indexed: (d,X)
-    INY, // Indirect indexed: (d),Y
-    UNK,
-}
-
-impl AddrMode {
-    fn len(&self) -> usize {
-        use AddrMode::*;
-        match self {
-            IMP | ACC => 1,
-            IMM | ZPG | REL | ZPX | ZPY | INX | INY => 2,
-            ABS | IND | ABX | ABY => 3,
-            UNK => 1,
-        }
-    }
-}
-
 macro_rules! instructions {
     ($cont:ident) => {
         $cont! {
-            0x00: BRK IMP, 0x01: ORA INX, 0x02: UNK UNK, 0x03:*SLO INX,
+            0x00: BRK IMP, 0x01: ORA INX, 0x02:*KIL IMP, 0x03:*SLO INX,
             0x04:*NOP ZPG, 0x05: ORA ZPG, 0x06: ASL ZPG, 0x07:*SLO ZPG,
             0x08: PHP IMP, 0x09: ORA IMM, 0x0A: ASL ACC, 0x0B:*AAC IMM,
             0x0C:*NOP ABS, 0x0D: ORA ABS, 0x0E: ASL ABS, 0x0F:*SLO ABS,
-            0x10: BPL REL, 0x11: ORA INY, 0x12: UNK UNK, 0x13:*SLO INY,
+            0x10: BPL REL, 0x11: ORA INY, 0x12:*KIL IMP, 0x13:*SLO INY,
             0x14:*NOP ZPX, 0x15: ORA ZPX, 0x16: ASL ZPX, 0x17:*SLO ZPX,
             0x18: CLC IMP, 0x19: ORA ABY, 0x1A:*NOP IMP, 0x1B:*SLO ABY,
             0x1C:*NOP ABX, 0x1D: ORA ABX, 0x1E: ASL ABX, 0x1F:*SLO ABX,
-            0x20: JSR ABS, 0x21: AND INX, 0x22: UNK UNK, 0x23:*RLA INX,
+            0x20: JSR ABS, 0x21: AND INX, 0x22:*KIL IMP, 0x23:*RLA INX,
             0x24: BIT ZPG, 0x25: AND ZPG, 0x26: ROL ZPG, 0x27:*RLA ZPG,
             0x28: PLP IMP, 0x29: AND IMM, 0x2A: ROL ACC, 0x2B:*AAC IMM,
             0x2C: BIT ABS, 0x2D: AND ABS, 0x2E: ROL ABS, 0x2F:*RLA ABS,
-            0x30: BMI REL, 0x31: AND INY, 0x32: UNK UNK, 0x33:*RLA INY,
+            0x30: BMI REL, 0x31: AND INY, 0x32:*KIL IMP, 0x33:*RLA INY,
             0x34:*NOP ZPX, 0x35: AND ZPX, 0x36: ROL ZPX, 0x37:*RLA ZPX,
             0x38: SEC IMP, 0x39: AND ABY, 0x3A:*NOP IMP, 0x3B:*RLA ABY,
             0x3C:*NOP ABX, 0x3D: AND ABX, 0x3E: ROL ABX, 0x3F:*RLA ABX,
-            0x40: RTI IMP, 0x41: EOR INX, 0x42: UNK UNK, 0x43:*SRE INX,
+            0x40: RTI IMP, 0x41: EOR INX, 0x42:*KIL IMP, 0x43:*SRE INX,
             0x44:*NOP ZPG, 0x45: EOR ZPG, 0x46: LSR ZPG, 0x47:*SRE ZPG,
             0x48: PHA IMP, 0x49: EOR IMM, 0x4A: LSR ACC, 0x4B:*ASR IMM,
             0x4C: JMP ABS, 0x4D: EOR ABS, 0x4E: LSR ABS, 0x4F:*SRE ABS,
-            0x50: BVC REL, 0x51: EOR INY, 0x52: UNK UNK, 0x53:*SRE INY,
+            0x50: BVC REL, 0x51: EOR INY, 0x52:*KIL IMP, 0x53:*SRE INY,
             0x54:*NOP ZPX, 0x55: EOR ZPX, 0x56: LSR ZPX, 0x57:*SRE ZPX,
             0x58: CLI IMP, 0x59: EOR ABY, 0x5A:*NOP IMP, 0x5B:*SRE ABY,
             0x5C:*NOP ABX, 0x5D: EOR ABX, 0x5E: LSR ABX, 0x5F:*SRE ABX,
-            0x60: RTS IMP, 0x61: ADC INX, 0x62: UNK UNK, 0x63:*RRA INX,
+            0x60: RTS IMP, 0x61: ADC INX, 0x62:*KIL IMP, 0x63:*RRA INX,
             0x64:*NOP ZPG, 0x65: ADC ZPG, 0x66: ROR ZPG, 0x67:*RRA ZPG,
             0x68: PLA IMP, 0x69: ADC IMM, 0x6A: ROR ACC, 0x6B:*ARR IMM,
             0x6C: JMP IND, 0x6D: ADC ABS, 0x6E: ROR ABS, 0x6F:*RRA ABS,
-            0x70: BVS REL, 0x71: ADC INY, 0x72: UNK UNK, 0x73:*RRA INY,
+            0x70: BVS REL, 0x71: ADC INY, 0x72:*KIL IMP, 0x73:*RRA INY,
             0x74:*NOP ZPX, 0x75: ADC ZPX, 0x76: ROR ZPX, 0x77:*RRA ZPX,
             0x78: SEI IMP, 0x79: ADC ABY, 0x7A:*NOP IMP, 0x7B:*RRA ABY,
             0x7C:*NOP ABX, 0x7D: ADC ABX, 0x7E: ROR ABX, 0x7F:*RRA ABX,
             0x80:*NOP IMM, 0x81: STA INX, 0x82:*NOP IMM, 0x83:*SAX INX,
             0x84: STY ZPG, 0x85: STA ZPG, 0x86: STX ZPG, 0x87:*SAX ZPG,
-            0x88: DEY IMP, 0x89:*NOP IMM, 0x8A: TXA IMP, 0x8B: UNK UNK,
+            0x88: DEY IMP, 0x89:*NOP IMM, 0x8A: TXA IMP, 0x8B:*XAA IMM,
             0x8C: STY ABS, 0x8D: STA ABS, 0x8E: STX ABS, 0x8F:*SAX ABS,
-            0x90: BCC REL, 0x91: STA INY, 0x92: UNK UNK, 0x93: UNK UNK,
+            0x90: BCC REL, 0x91: STA INY, 0x92:*KIL IMP, 0x93:*AXA INY,
             0x94: STY ZPX, 0x95: STA ZPX, 0x96: STX ZPY, 0x97:*SAX ZPY,
-            0x98: TYA IMP, 0x99: STA ABY, 0x9A: TXS IMP, 0x9B: UNK UNK,
-            0x9C:*SYA ABX, 0x9D: STA ABX, 0x9E:*SXA ABY, 0x9F: UNK UNK,
+            0x98: TYA IMP, 0x99: STA ABY, 0x9A: TXS IMP, 0x9B:*XAS ABY,
+            0x9C:*SYA ABX, 0x9D: STA ABX, 0x9E:*SXA ABY, 0x9F:*AXA ABY,
             0xA0: LDY IMM, 0xA1: LDA INX, 0xA2: LDX IMM, 0xA3:*LAX INX,
             0xA4: LDY ZPG, 0xA5: LDA ZPG, 0xA6: LDX ZPG, 0xA7:*LAX ZPG,
             0xA8: TAY IMP, 0xA9: LDA IMM, 0xAA: TAX IMP, 0xAB:*ATX IMM,
             0xAC: LDY ABS, 0xAD: LDA ABS, 0xAE: LDX ABS, 0xAF:*LAX ABS,
-            0xB0: BCS REL, 0xB1: LDA INY, 0xB2: UNK UNK, 0xB3:*LAX INY,
+            0xB0: BCS REL, 0xB1: LDA INY, 0xB2:*KIL IMP, 0xB3:*LAX INY,
             0xB4: LDY ZPX, 0xB5: LDA ZPX, 0xB6: LDX ZPY, 0xB7:*LAX ZPY,
             0xB8: CLV IMP, 0xB9: LDA ABY, 0xBA: TSX IMP, 0xBB: UNK UNK,
             0xBC: LDY ABX, 0xBD: LDA ABX, 0xBE: LDX ABY, 0xBF:*LAX ABY,
@@ -230,7 +647,7 @@ macro_rules! instructions {
             0xC4: CPY ZPG, 0xC5: CMP ZPG, 0xC6: DEC ZPG, 0xC7:*DCP ZPG,
             0xC8: INY IMP, 0xC9: CMP IMM, 0xCA: DEX IMP, 0xCB:*AXS IMM,
             0xCC: CPY ABS, 0xCD: CMP ABS, 0xCE: DEC ABS, 0xCF:*DCP ABS,
-            0xD0: BNE REL, 0xD1: CMP INY, 0xD2: UNK UNK, 0xD3:*DCP INY,
+            0xD0: BNE REL, 0xD1: CMP INY, 0xD2:*KIL IMP, 0xD3:*DCP INY,
             0xD4:*NOP ZPX, 0xD5: CMP ZPX, 0xD6: DEC ZPX, 0xD7:*DCP ZPX,
             0xD8: CLD IMP, 0xD9: CMP ABY, 0xDA:*NOP IMP, 0xDB:*DCP ABY,
             0xDC:*NOP ABX, 0xDD: CMP ABX, 0xDE: DEC ABX, 0xDF:*DCP ABX,
@@ -238,7 +655,7 @@ macro_rules! instructions {
             0xE4: CPX ZPG, 0xE5: SBC ZPG, 0xE6: INC ZPG, 0xE7:*ISB ZPG,
             0xE8: INX IMP, 0xE9: SBC IMM, 0xEA: NOP IMP, 0xEB:*SBC IMM,
             0xEC: CPX ABS, 0xED: SBC ABS, 0xEE: INC ABS, 0xEF:*ISB ABS,
-            0xF0: BEQ REL, 0xF1: SBC INY, 0xF2: UNK UNK, 0xF3:*ISB INY,
+            0xF0: BEQ REL, 0xF1: SBC INY, 0xF2:*KIL IMP, 0xF3:*ISB INY,
             0xF4:*NOP ZPX, 0xF5: SBC ZPX, 0xF6: INC ZPX, 0xF7:*ISB ZPX,
             0xF8: SED IMP, 0xF9: SBC ABY, 0xFA:*NOP IMP, 0xFB:*ISB ABY,
             0xFC:*NOP ABX, 0xFD: SBC ABX, 0xFE: INC ABX, 0xFF:*ISB ABX,
@@ -246,45 +663,171 @@ macro_rules! instructions {
     };
 }
 
+pub(crate) use instructions;
+
 impl Cpu {
     pub fn tick(&mut self, ctx: &mut impl Context) {
-        let stall = ctx.cpu_stall();
-        for _ in 0..stall {
-            self.tick_bus(ctx);
-        }
-
         self.world += 1;
 
-        while self.counter < self.world {
-            let nmi_cur = ctx.nmi();
-            let nmi_prev = self.nmi_prev;
-            self.nmi_prev = nmi_cur;
+        // An instruction stopped by a pause resumes right away
+        while self.counter < self.world || self.suspended.is_some() {
+            // A jammed CPU executes nothing and ignores interrupts, while the rest of
+            // the console keeps running
+            if self.jammed {
+                self.tick_bus(ctx);
+                if self.pause_at == Some(ctx.now()) {
+                    self.pause_at = None;
+                    self.pause_hit = true;
+                    break;
+                }
+                continue;
+            }
 
-            let irq_prev = ctx.irq();
-            self.i_flag_prev = self.reg.flag.i;
+            if let Some(suspended) = self.suspended.take() {
+                // Replays the cycles done before the pause, then goes on from there
+                self.counter -= suspended.cycles;
+                self.journal = Journal::resume(suspended);
+            } else {
+                // Stopping at an execution breakpoint leaves the cycles to the next tick
+                let now = ctx.now();
+                if ctx
+                    .memory_ctrl_mut()
+                    .debugger_mut()
+                    .check_exec(self.reg.pc, now)
+                {
+                    break;
+                }
+                if self.pause_at.is_some() {
+                    self.journal = Journal::Record(Suspended::default());
+                }
+            }
+            let reg = self.reg.clone();
+            let (poll, prev_poll) = (self.poll, self.prev_poll);
 
-            self.exec_one(ctx);
+            if self.profiler.is_some() {
+                self.exec_one_profiled(ctx);
+            } else {
+                self.exec_one(ctx);
+            }
 
-            if nmi_prev && !nmi_cur {
-                self.exec_interrupt(ctx, Interrupt::Nmi, false);
-                continue;
+            // Instructions service the interrupts polled before their last cycle.
+            // A latched NMI edge is serviced unless the PPU suppresses it in the meantime.
+            if !matches!(self.journal, Journal::Stopped(_)) {
+                let poll = self.prev_poll;
+                if poll.nmi && self.replayed(|| ctx.nmi_edge()) {
+                    if self.journal.live() {
+                        ctx.clear_nmi_edge();
+                    }
+                    self.exec_interrupt(ctx, Interrupt::Nmi, false);
+                } else if poll.irq {
+                    self.exec_interrupt(ctx, Interrupt::Irq, false);
+                }
             }
 
-            if !self.i_flag_prev && irq_prev {
-                self.exec_interrupt(ctx, Interrupt::Irq, false);
-                continue;
+            if let Journal::Stopped(suspended) = std::mem::take(&mut self.journal) {
+                self.reg = reg;
+                self.poll = poll;
+                self.prev_poll = prev_poll;
+                self.jammed = false;
+                self.suspended = Some(suspended);
+                self.pause_hit = true;
+                break;
             }
         }
     }
 
     fn tick_bus(&mut self, ctx: &mut impl Context) {
+        let live = match &mut self.journal {
+            Journal::Off => true,
+            Journal::Record(suspended) => {
+                suspended.cycles += 1;
+                true
+            }
+            Journal::Replay(..) => false,
+            Journal::Stopped(_) => return,
+        };
         self.counter += 1;
-        ctx.tick_bus();
+        if live {
+            ctx.tick_bus();
+        }
+
+        // The interrupt lines are polled at the end of every cycle, so a flag changed
+        // on the last cycle of an instruction takes effect after the next one
+        self.prev_poll = self.poll;
+        self.poll = InterruptPoll {
+            nmi: self.replayed(|| ctx.nmi_edge()),
+            irq: self.replayed(|| ctx.irq()) && !self.reg.flag.i,
+        };
+
+        if live && self.pause_at == Some(ctx.now()) {
+            if let Journal::Record(suspended) = &mut self.journal {
+                self.journal = Journal::Stopped(std::mem::take(suspended));
+                self.pause_at = None;
+            }
+        }
+    }
+
+    /// Gets a value from the rest of the console with `get`. While a pause is set, the
+    /// values are recorded, and a resumed instruction replays them instead.
+    fn replayed<T: Replayed>(&mut self, get: impl FnOnce() -> T) -> T {
+        if let Journal::Off = self.journal {
+            get()
+        } else {
+            self.journaled(get)
+        }
+    }
+
+    // Out of line, so that the accesses stay small while no pause is set
+    #[inline(never)]
+    fn journaled<T: Replayed>(&mut self, get: impl FnOnce() -> T) -> T {
+        match &mut self.journal {
+            Journal::Off => get(),
+            Journal::Record(suspended) => {
+                let value = get();
+                suspended.values.push(value.encode());
+                value
+            }
+            Journal::Replay(suspended, pos) => {
+                let value = T::decode(suspended.values[*pos]);
+                *pos += 1;
+                if *pos == suspended.values.len() {
+                    self.journal = Journal::Record(std::mem::take(suspended));
+                }
+                value
+            }
+            Journal::Stopped(_) => T::default(),
+        }
+    }
+
+    fn exec_one_profiled(&mut self, ctx: &mut impl Context) {
+        let pc = self.reg.pc;
+        let addr = ProfileAddr {
+            prg_bank: (pc >= 0x8000).then(|| ctx.prg_page(((pc & 0x7fff) / 0x2000) as u32)),
+            addr: pc,
+        };
+        let start = self.counter;
+        self.exec_one(ctx);
+        // A stopped instruction is counted when it completes
+        if matches!(self.journal, Journal::Stopped(_)) {
+            return;
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(addr, self.counter - start);
+        }
     }
 
     fn exec_one(&mut self, ctx: &mut impl Context) {
-        if log::log_enabled!(log::Level::Trace) {
-            self.trace(ctx);
+        // A resumed instruction has been traced before it stopped
+        if !matches!(self.journal, Journal::Replay(..)) {
+            if logging::enabled(Category::Disasm, log::Level::Trace)
+                || logging::enabled(Category::Nestest, log::Level::Trace)
+            {
+                self.trace(ctx);
+            }
+            if self.trace_hook.is_some() {
+                self.call_trace_hook(ctx);
+            }
+            self.history.record_pc(self.reg.pc);
         }
 
         let opaddr = self.reg.pc;
@@ -320,6 +863,18 @@ impl Cpu {
             (DEC) => {
                 false
             };
+            (SYA) => {
+                false
+            };
+            (SXA) => {
+                false
+            };
+            (AXA) => {
+                false
+            };
+            (XAS) => {
+                false
+            };
             ($mne:ident) => {
                 true
             };
@@ -623,14 +1178,18 @@ impl Cpu {
             (RTI) => {{
                 let _ = self.read(ctx, self.reg.s as u16 | 0x100);
                 let p = self.pop8(ctx);
+                // The flag is restored before the last cycles, so it affects interrupts
+                // right after RTI
                 self.reg.flag.set_u8(p);
-                // Flag set by RTI affects interrupts
-                self.i_flag_prev = self.reg.flag.i;
                 self.reg.pc = self.pop16(ctx);
             }};
 
             (bra, $cond:ident, $val:expr, $addr:ident) => {{
                 if self.reg.flag.$cond == $val {
+                    // A taken branch doesn't poll on its last cycle unless it crosses a page,
+                    // so interrupts arriving during the operand fetch wait one more instruction
+                    self.poll.nmi &= self.prev_poll.nmi;
+                    self.poll.irq &= self.prev_poll.irq;
                     let _ = self.read(ctx, self.reg.pc);
                     if self.reg.pc & 0xff00 != $addr & 0xff00 {
                         self.read(ctx, self.reg.pc & 0xff00 | $addr & 0xff);
@@ -705,12 +1264,20 @@ impl Cpu {
             (BRK) => {{
                 self.reg.pc = self.reg.pc.wrapping_add(1);
                 self.exec_interrupt(ctx, Interrupt::Irq, true);
-                // Interrupt after BRK did not happen
-                self.i_flag_prev = self.reg.flag.i;
             }};
 
             (NOP) => {{}};
 
+            (KIL) => {{
+                core_log!(
+                    Cpu,
+                    Warn,
+                    "CPU jammed by opcode ${opc:02X} at ${opaddr:04X}"
+                );
+                self.reg.pc = opaddr;
+                self.jammed = true;
+            }};
+
             // Undocumented
             (NOP, $addr:ident) => {{
                 let _ = self.read(ctx, $addr);
@@ -810,10 +1377,14 @@ impl Cpu {
                 self.reg.flag.v = ((self.reg.a >> 5) & 1 != 0) != self.reg.flag.c;
             }};
             (ATX, $addr:ident) => {{
-                self.reg.a = self.read(ctx, $addr);
+                self.reg.a = (self.reg.a | self.magic) & self.read(ctx, $addr);
                 self.reg.x = self.reg.a;
                 self.reg.flag.set_nz(self.reg.a);
             }};
+            (XAA, $addr:ident) => {{
+                self.reg.a = (self.reg.a | self.magic) & self.reg.x & self.read(ctx, $addr);
+                self.reg.flag.set_nz(self.reg.a);
+            }};
             (AXS, $addr:ident) => {{
                 let t =
                     ((self.reg.x & self.reg.a) as u16).wrapping_sub(self.read(ctx, $addr) as u16);
@@ -821,47 +1392,82 @@ impl Cpu {
                 self.reg.flag.set_nz(self.reg.x);
                 self.reg.flag.c = t <= 0xff;
             }};
+            // Stores the value ANDed with the high byte of the base address plus 1.
+            // When indexing crosses a page, the stored value also becomes the high byte
+            // of the address.
+            (sh, $data:expr, $index:ident, $addr:ident) => {{
+                let base = $addr.wrapping_sub(self.reg.$index as u16);
+                let data = $data & ((base >> 8) as u8).wrapping_add(1);
+                let addr = if (base ^ $addr) & 0xff00 != 0 {
+                    (data as u16) << 8 | $addr & 0xff
+                } else {
+                    $addr
+                };
+                self.write(ctx, addr, data);
+            }};
             (SYA, $addr:ident) => {{
-                let t = self.reg.y & (($addr >> 8) + 1) as u8;
-                if self.reg.x as u16 + self.read(ctx, opaddr.wrapping_add(1)) as u16 <= 0xff {
-                    self.write(ctx, $addr, t);
-                }
+                exec_op!(sh, self.reg.y, x, $addr)
             }};
             (SXA, $addr:ident) => {{
-                let t = self.reg.x & (($addr >> 8) + 1) as u8;
-                if self.reg.y as u16 + self.read(ctx, opaddr.wrapping_add(1)) as u16 <= 0xff {
-                    self.write(ctx, $addr, t);
-                }
+                exec_op!(sh, self.reg.x, y, $addr)
+            }};
+            (AXA, $addr:ident) => {{
+                exec_op!(sh, self.reg.a & self.reg.x, y, $addr)
+            }};
+            (XAS, $addr:ident) => {{
+                self.reg.s = self.reg.a & self.reg.x;
+                exec_op!(sh, self.reg.s, y, $addr)
             }};
 
             (UNK, $addr:ident) => {{
-                log::warn!("invalid opcode: ${opc:02X}");
+                core_log!(Cpu, Warn, "invalid opcode: ${opc:02X}");
             }};
         }
 
         instructions!(gen_code);
     }
 
+    fn call_trace_hook(&mut self, ctx: &impl Context) {
+        let pc = self.reg.pc;
+        let bytes: [u8; 3] =
+            std::array::from_fn(|i| ctx.read_pure(pc.wrapping_add(i as u16)).unwrap_or(0));
+        let record = TraceRecord {
+            pc,
+            bytes,
+            len: INSTR_TABLE[bytes[0] as usize].1.size(),
+            state: self.state(),
+            frame: ctx.ppu().frame(),
+            line: ctx.ppu().line(),
+            dot: ctx.ppu().dot(),
+        };
+        if let Some(hook) = &mut self.trace_hook {
+            hook(&record);
+        }
+    }
+
     fn trace(&self, ctx: &impl Context) {
-        use crate::consts::{LINES_PER_FRAME, PPU_CLOCK_PER_LINE};
+        use crate::consts::PPU_CLOCK_PER_LINE;
 
         let pc = self.reg.pc;
         let opc = ctx.read_pure(pc).unwrap_or(0);
         let opr = ctx.read_pure(pc + 1).unwrap_or(0) as u16
             | (ctx.read_pure(pc + 2).unwrap_or(0) as u16) << 8;
 
-        let ppu_cycle = self.counter * 3;
-        let line = ppu_cycle / PPU_CLOCK_PER_LINE % LINES_PER_FRAME as u64;
+        let region = ctx.region();
+        let (num, den) = region.ppu_clock_ratio();
+        let ppu_cycle = self.counter * num / den;
+        let line = ppu_cycle / PPU_CLOCK_PER_LINE % region.lines_per_frame() as u64;
         let col = ppu_cycle % PPU_CLOCK_PER_LINE;
 
-        let asm = disasm(pc, opc, opr);
+        let instr = Instruction::new(pc, opc, opr);
+        let asm = format!("{}{instr}", if instr.official { ' ' } else { '*' });
         let prg_page = if pc & 0x8000 != 0 {
             format!("{:02X}", ctx.prg_page(((pc & !0x8000) / 0x2000) as _))
         } else {
             "  ".to_string()
         };
 
-        log::trace!(target: "disasm",
+        core_log!(Disasm, Trace,
             "{prg_page}:{pc:04X}: {asm:13} | A:{a:02X} X:{x:02X} Y:{y:02X} S:{s:02X} P:{n}{v}{d}{i}{z}{c} PPU:{line:3},{col:3}",
             pc = self.reg.pc,
             a = self.reg.a,
@@ -876,7 +1482,7 @@ impl Cpu {
             c = if self.reg.flag.c { 'C' } else { '-' },
         );
 
-        let bytes = match INSTR_TABLE[opc as usize].1.len() {
+        let bytes = match INSTR_TABLE[opc as usize].1.size() {
             1 => format!("{opc:02X}"),
             2 => format!("{opc:02X} {:02X}", opr & 0xff),
             3 => format!("{opc:02X} {:02X} {:02X}", opr & 0xff, opr >> 8),
@@ -944,7 +1550,9 @@ impl Cpu {
 
         let asm = format!("{}{}", asm, ctx);
 
-        log::trace!(target: "disasnt",
+        core_log!(
+            Nestest,
+            Trace,
             "{pc:04X}  {bytes:8} {asm:32} \
             A:{a:02X} X:{x:02X} Y:{y:02X} P:{p:02X} SP:{s:02X} \
             PPU:{line:3},{col:3} CYC:{cyc}",
@@ -958,48 +1566,3 @@ impl Cpu {
         );
     }
 }
-
-macro_rules! instr_table {
-    ($($opc:literal: $a:tt $b:ident $($c:ident)?, )*) => {{
-        [$(
-            instr_entry!($a $b $($c)*),
-        )*]
-    }};
-}
-
-macro_rules! instr_entry {
-    (*$mne:ident $mode:ident) => {{
-        (stringify!($mne), AddrMode::$mode, false)
-    }};
-    ($mne:ident $mode:ident) => {{
-        (stringify!($mne), AddrMode::$mode, true)
-    }};
-}
-
-const INSTR_TABLE: [(&str, AddrMode, bool); 256] = instructions!(instr_table);
-
-fn disasm(pc: u16, opc: u8, opr: u16) -> String {
-    let opc = opc as usize;
-    let (mne, addr_mode, official) = &INSTR_TABLE[opc];
-    let u = if *official { ' ' } else { '*' };
-
-    match addr_mode {
-        AddrMode::IMP => format!("{u}{mne}"),
-        AddrMode::IMM => format!("{u}{mne} #${:02X}", opr & 0xff),
-        AddrMode::ACC => format!("{u}{mne} A"),
-        AddrMode::ABS => format!("{u}{mne} ${opr:04X}"),
-        AddrMode::ABX => format!("{u}{mne} ${opr:04X},X"),
-        AddrMode::ABY => format!("{u}{mne} ${opr:04X},Y"),
-        AddrMode::IND => format!("{u}{mne} (${opr:04X})"),
-        AddrMode::ZPG => format!("{u}{mne} ${:02X}", opr & 0xff),
-        AddrMode::ZPX => format!("{u}{mne} ${:02X},X", opr & 0xff),
-        AddrMode::ZPY => format!("{u}{mne} ${:02X},Y", opr & 0xff),
-        AddrMode::INX => format!("{u}{mne} (${:02X},X)", opr & 0xff),
-        AddrMode::INY => format!("{u}{mne} (${:02X}),Y", opr & 0xff),
-        AddrMode::REL => {
-            let addr = pc.wrapping_add((opr & 0xff) as i8 as u16).wrapping_add(2);
-            format!("{u}{mne} ${:04X}", addr)
-        }
-        AddrMode::UNK => format!("{u}{mne} ???"),
-    }
-}
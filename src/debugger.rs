@@ -0,0 +1,137 @@
+//! Breakpoints and watchpoints which stop `exec_frame`
+
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+
+/// Access which triggers a breakpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BreakpointKind {
+    /// The CPU is about to execute an instruction in the range
+    Exec,
+    /// The CPU bus is read, including DMA reads
+    CpuRead,
+    /// The CPU bus is written
+    CpuWrite,
+    /// PPU memory is read through PPUDATA ($2007)
+    PpuRead,
+    /// PPU memory is written through PPUDATA ($2007)
+    PpuWrite,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Breakpoint {
+    pub kind: BreakpointKind,
+    pub addr: RangeInclusive<u16>,
+}
+
+impl Breakpoint {
+    pub fn new(kind: BreakpointKind, addr: RangeInclusive<u16>) -> Self {
+        Self { kind, addr }
+    }
+
+    pub fn exec(addr: u16) -> Self {
+        Self::new(BreakpointKind::Exec, addr..=addr)
+    }
+}
+
+/// Breakpoint which stopped emulation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct BreakpointHit {
+    /// ID returned by `Nes::add_breakpoint`
+    pub id: usize,
+    pub kind: BreakpointKind,
+    /// Address accessed, which is the PC for execution breakpoints
+    pub addr: u16,
+    /// Value read or written, or `None` for execution breakpoints
+    pub data: Option<u8>,
+    /// Address of the instruction which made the access
+    pub pc: u16,
+    pub cycle: u64,
+}
+
+/// Breakpoints set by the host, checked on each instruction and memory access.
+/// Checking costs nothing while no breakpoints are set.
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<(usize, Breakpoint)>,
+    next_id: usize,
+    hit: Option<BreakpointHit>,
+    // Instruction being executed
+    pc: u16,
+    // Instruction stopped at by an execution breakpoint, which runs when resumed
+    resume_pc: Option<u16>,
+}
+
+impl Debugger {
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push((id, breakpoint));
+        id
+    }
+
+    /// Returns false if no breakpoint has the ID
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|(i, _)| *i != id);
+        self.breakpoints.len() != len
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.breakpoints.iter().map(|(id, bp)| (*id, bp))
+    }
+
+    pub fn take_hit(&mut self) -> Option<BreakpointHit> {
+        self.hit.take()
+    }
+
+    /// Called before the CPU executes the instruction at `pc`.
+    /// Returns true if an execution breakpoint stops the CPU before it.
+    pub fn check_exec(&mut self, pc: u16, cycle: u64) -> bool {
+        if self.breakpoints.is_empty() {
+            return false;
+        }
+        self.pc = pc;
+        if self.resume_pc.take() == Some(pc) || self.hit.is_some() {
+            return false;
+        }
+        self.hit = self.find(BreakpointKind::Exec, pc, None, cycle);
+        if self.hit.is_some() {
+            self.resume_pc = Some(pc);
+        }
+        self.hit.is_some()
+    }
+
+    /// Called on a memory access. A hit stops emulation after the current instruction.
+    pub fn check_access(&mut self, kind: BreakpointKind, addr: u16, data: u8, cycle: u64) {
+        if self.breakpoints.is_empty() || self.hit.is_some() {
+            return;
+        }
+        self.hit = self.find(kind, addr, Some(data), cycle);
+    }
+
+    fn find(
+        &self,
+        kind: BreakpointKind,
+        addr: u16,
+        data: Option<u8>,
+        cycle: u64,
+    ) -> Option<BreakpointHit> {
+        let (id, _) = self
+            .breakpoints
+            .iter()
+            .find(|(_, bp)| bp.kind == kind && bp.addr.contains(&addr))?;
+        Some(BreakpointHit {
+            id: *id,
+            kind,
+            addr,
+            data,
+            pc: self.pc,
+            cycle,
+        })
+    }
+}
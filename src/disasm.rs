@@ -0,0 +1,198 @@
+//! 6502 disassembler for debuggers
+
+use serde::Serialize;
+use std::fmt;
+
+use crate::{context, cpu::instructions};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum AddrMode {
+    IMP, // Implicit
+    ACC, // Accumulator
+    IMM, // Immediate: #v
+    ZPG, // Zero Page: d
+    ABS, // Absolute: a
+    REL, // Relative: label
+    IND, // Indirect: (d)
+    ZPX, // Zero Page indexed: d,X
+    ZPY, // Zero Page indexed: d,Y
+    ABX, // Absolute indexed: a,X
+    ABY, // Absolute indexed: a,Y
+    INX, // Indirect indexed: (d,X)
+    INY, // Indirect indexed: (d),Y
+    UNK,
+}
+
+impl AddrMode {
+    /// Size of instructions in this mode, including the opcode
+    pub fn size(&self) -> usize {
+        use AddrMode::*;
+        match self {
+            IMP | ACC => 1,
+            IMM | ZPG | REL | ZPX | ZPY | INX | INY => 2,
+            ABS | IND | ABX | ABY => 3,
+            UNK => 1,
+        }
+    }
+}
+
+macro_rules! instr_table {
+    ($($opc:literal: $a:tt $b:ident $($c:ident)?, )*) => {{
+        [$(
+            instr_entry!($a $b $($c)*),
+        )*]
+    }};
+}
+
+macro_rules! instr_entry {
+    (*$mne:ident $mode:ident) => {{
+        (stringify!($mne), AddrMode::$mode, false)
+    }};
+    ($mne:ident $mode:ident) => {{
+        (stringify!($mne), AddrMode::$mode, true)
+    }};
+}
+
+/// Mnemonic, addressing mode and whether the opcode is official
+pub(crate) const INSTR_TABLE: [(&str, AddrMode, bool); 256] = instructions!(instr_table);
+
+/// Decoded instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Instruction {
+    pub addr: u16,
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub mode: AddrMode,
+    /// Operand bytes in little endian, 0 for the bytes the instruction doesn't have
+    pub operand: u16,
+    pub size: usize,
+    /// False for unofficial opcodes
+    pub official: bool,
+}
+
+impl Instruction {
+    pub fn new(addr: u16, opcode: u8, operand: u16) -> Self {
+        let (mnemonic, mode, official) = INSTR_TABLE[opcode as usize];
+        let operand = match mode.size() {
+            1 => 0,
+            2 => operand & 0xff,
+            _ => operand,
+        };
+        Self {
+            addr,
+            opcode,
+            mnemonic,
+            mode,
+            operand,
+            size: mode.size(),
+            official,
+        }
+    }
+
+    /// Decodes the instruction at the start of `bytes`, or returns `None` if it is truncated
+    pub fn decode(addr: u16, bytes: &[u8]) -> Option<Self> {
+        let opcode = *bytes.first()?;
+        let size = INSTR_TABLE[opcode as usize].1.size();
+        let operand = bytes.get(1..size)?;
+        let operand = operand.iter().rev().fold(0, |acc, &b| acc << 8 | b as u16);
+        Some(Self::new(addr, opcode, operand))
+    }
+
+    /// Destination of a branch
+    pub fn branch_target(&self) -> Option<u16> {
+        (self.mode == AddrMode::REL).then(|| {
+            self.addr
+                .wrapping_add(self.operand as u8 as i8 as u16)
+                .wrapping_add(2)
+        })
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mne = self.mnemonic;
+        let opr = self.operand;
+        match self.mode {
+            AddrMode::IMP => write!(f, "{mne}"),
+            AddrMode::IMM => write!(f, "{mne} #${opr:02X}"),
+            AddrMode::ACC => write!(f, "{mne} A"),
+            AddrMode::ABS => write!(f, "{mne} ${opr:04X}"),
+            AddrMode::ABX => write!(f, "{mne} ${opr:04X},X"),
+            AddrMode::ABY => write!(f, "{mne} ${opr:04X},Y"),
+            AddrMode::IND => write!(f, "{mne} (${opr:04X})"),
+            AddrMode::ZPG => write!(f, "{mne} ${opr:02X}"),
+            AddrMode::ZPX => write!(f, "{mne} ${opr:02X},X"),
+            AddrMode::ZPY => write!(f, "{mne} ${opr:02X},Y"),
+            AddrMode::INX => write!(f, "{mne} (${opr:02X},X)"),
+            AddrMode::INY => write!(f, "{mne} (${opr:02X}),Y"),
+            AddrMode::REL => write!(f, "{mne} ${:04X}", self.branch_target().unwrap()),
+            AddrMode::UNK => write!(f, "{mne} ???"),
+        }
+    }
+}
+
+/// Decodes instructions in `bytes` placed at `addr`, up to the last complete one
+pub fn disassemble(addr: u16, bytes: &[u8]) -> Vec<Instruction> {
+    let mut ret = vec![];
+    let mut ofs = 0;
+    while let Some(instr) = Instruction::decode(addr.wrapping_add(ofs as u16), &bytes[ofs..]) {
+        ofs += instr.size;
+        ret.push(instr);
+    }
+    ret
+}
+
+/// Decodes up to `count` instructions from `addr` in CPU memory without side effects.
+/// It stops at addresses which can't be read that way, such as I/O registers.
+pub fn disassemble_memory(ctx: &impl context::Bus, addr: u16, count: usize) -> Vec<Instruction> {
+    let mut ret = vec![];
+    let mut addr = addr;
+    for _ in 0..count {
+        let Some(opcode) = ctx.read_pure(addr) else {
+            break;
+        };
+        let size = INSTR_TABLE[opcode as usize].1.size();
+        let operand = (1..size).map(|i| ctx.read_pure(addr.wrapping_add(i as u16)));
+        let Some(operand) = operand
+            .rev()
+            .try_fold(0, |acc, b| Some(acc << 8 | b? as u16))
+        else {
+            break;
+        };
+        let instr = Instruction::new(addr, opcode, operand);
+        addr = addr.wrapping_add(instr.size as u16);
+        ret.push(instr);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_bytes() {
+        #[rustfmt::skip]
+        let bytes = [
+            0xA9, 0x01,       // LDA #$01
+            0x9D, 0x00, 0x02, // STA $0200,X
+            0xD0, 0xF9,       // BNE $C000
+            0x04, 0x10,       // *NOP $10
+            0x4C, 0x00,       // JMP (truncated)
+        ];
+        let instrs = disassemble(0xC000, &bytes);
+        let text = instrs.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(text, ["LDA #$01", "STA $0200,X", "BNE $C000", "NOP $10"]);
+
+        assert_eq!(instrs[1].addr, 0xC002);
+        assert_eq!(
+            (instrs[1].mode, instrs[1].operand, instrs[1].size),
+            (AddrMode::ABX, 0x0200, 3)
+        );
+        assert_eq!(instrs[2].branch_target(), Some(0xC000));
+        assert_eq!(instrs[0].branch_target(), None);
+        assert!(instrs[0].official && !instrs[3].official);
+        assert_eq!(Instruction::decode(0, &[0x4C, 0x00]), None);
+    }
+}
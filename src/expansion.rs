@@ -0,0 +1,16 @@
+//! Devices on the Famicom expansion port
+//!
+//! The expansion port sees the OUT0-OUT2 lines written to $4016, and drives D1 of
+//! $4016 and D1-D4 of $4017. Paddles, keyboards, barcode readers and such are
+//! implemented on top of `ExpansionDevice` and attached with `Nes::set_expansion_device`.
+
+/// Device plugged into the expansion port
+pub trait ExpansionDevice: Send {
+    /// Called on each write to $4016 with OUT0-OUT2 in bits 0-2
+    fn strobe(&mut self, out: u8);
+
+    /// Called on each read of $4016 (port 0) or $4017 (port 1).
+    /// Returns the data bits which the device drives, where bits the port doesn't
+    /// connect are ignored.
+    fn read(&mut self, port: usize) -> u8;
+}
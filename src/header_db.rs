@@ -0,0 +1,121 @@
+//! Database of correct NES 2.0 headers, which overrides the headers of bad dumps
+//!
+//! The database is the XML file maintained by the NESdev community (`nes20db.xml`),
+//! whose games are identified by the CRC32 of PRG ROM and CHR ROM together.
+
+use std::collections::HashMap;
+
+use crate::rom::{ConsoleType, Mirroring, RomError, TimingMode};
+
+/// Header fields of a game in the database
+#[derive(Clone, Debug)]
+pub struct HeaderEntry {
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mapper_id: u16,
+    pub submapper_id: u8,
+    /// `None` if the database doesn't tell
+    pub mirroring: Option<Mirroring>,
+    pub has_battery: bool,
+    pub console_type: ConsoleType,
+    pub timing_mode: TimingMode,
+    pub expansion_device: u8,
+}
+
+#[derive(Default)]
+pub struct HeaderDatabase {
+    entries: HashMap<u32, HeaderEntry>,
+}
+
+impl HeaderDatabase {
+    /// Parses the XML database
+    pub fn from_xml(xml: &str) -> Result<Self, RomError> {
+        let doc = roxmltree::Document::parse(xml)
+            .map_err(|err| RomError::InvalidHeaderDatabase(err.to_string()))?;
+
+        let mut entries = HashMap::new();
+        for game in doc
+            .root_element()
+            .children()
+            .filter(|n| n.has_tag_name("game"))
+        {
+            let attr = |tag: &str, name: &str| {
+                game.children()
+                    .find(|n| n.has_tag_name(tag))
+                    .and_then(|n| n.attribute(name))
+            };
+            let num = |tag: &str, name: &str| {
+                attr(tag, name).map_or(Ok(0), |v| {
+                    v.parse::<usize>().map_err(|_| {
+                        RomError::InvalidHeaderDatabase(format!("invalid {tag} {name}: {v}"))
+                    })
+                })
+            };
+
+            let Some(crc) = attr("rom", "crc32") else {
+                continue;
+            };
+            let crc = u32::from_str_radix(crc, 16)
+                .map_err(|_| RomError::InvalidHeaderDatabase(format!("invalid crc32: {crc}")))?;
+
+            let mirroring = match attr("pcb", "mirroring") {
+                Some("H") => Some(Mirroring::Horizontal),
+                Some("V") => Some(Mirroring::Vertical),
+                Some("4") => Some(Mirroring::FourScreen),
+                _ => None,
+            };
+
+            let console_type = match num("console", "type")? {
+                0 => ConsoleType::Nes,
+                1 => ConsoleType::VsSystem {
+                    ppu_type: num("vs", "ppu")? as u8,
+                    hardware_type: num("vs", "hardware")? as u8,
+                },
+                2 => ConsoleType::Playchoice10,
+                t => ConsoleType::ExtendConsoleType {
+                    console_type: t as u8,
+                },
+            };
+
+            let timing_mode = match num("console", "region")? {
+                0 => TimingMode::Ntsc,
+                1 => TimingMode::Pal,
+                2 => TimingMode::MultipleRegion,
+                _ => TimingMode::Dendy,
+            };
+
+            let entry = HeaderEntry {
+                prg_rom_size: num("prgrom", "size")?,
+                chr_rom_size: num("chrrom", "size")?,
+                prg_ram_size: num("prgram", "size")?,
+                prg_nvram_size: num("prgnvram", "size")?,
+                chr_ram_size: num("chrram", "size")?,
+                chr_nvram_size: num("chrnvram", "size")?,
+                mapper_id: num("pcb", "mapper")? as u16,
+                submapper_id: num("pcb", "submapper")? as u8,
+                mirroring,
+                has_battery: num("pcb", "battery")? != 0,
+                console_type,
+                timing_mode,
+                expansion_device: num("expansion", "type")? as u8,
+            };
+            entries.insert(crc, entry);
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Returns the entry of the game with the CRC32 of PRG ROM and CHR ROM
+    pub fn get(&self, prg_chr_crc32: u32) -> Option<&HeaderEntry> {
+        self.entries.get(&prg_chr_crc32)
+    }
+
+    /// Number of games in the database
+    pub fn size(&self) -> usize {
+        self.entries.len()
+    }
+}
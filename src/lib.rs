@@ -1,14 +1,32 @@
 pub mod apu;
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod blip;
 pub mod consts;
 pub mod context;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod expansion;
+pub mod header_db;
+pub mod logging;
 pub mod mapper;
 pub mod memory;
+pub mod movie;
 pub mod nes;
+pub mod nsf;
+pub mod ntsc;
 pub mod palette;
+pub mod patch;
 pub mod ppu;
+pub mod profiler;
+pub mod quirks;
+pub mod rewind;
 pub mod rom;
+pub mod savestate;
 pub mod util;
+pub mod watch;
 
 pub use nes::{Config, Nes};
 pub use rom::Rom;
+pub use watch::RomWatcher;
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{logging::core_log, rom::Mirroring};
+
+#[derive(Serialize, Deserialize)]
+pub struct Action53 {
+    reg_select: u8,
+    chr_bank: u8,
+    inner_bank: u8,
+    mode: u8,
+    outer_bank: u8,
+}
+
+impl Action53 {
+    pub fn new(ctx: &mut impl super::Context) -> Self {
+        let mut ret = Self {
+            reg_select: 0,
+            chr_bank: 0,
+            inner_bank: 0,
+            mode: 0,
+            // Power on with the last bank so that the reset vector is valid
+            outer_bank: 0xff,
+        };
+        ret.update(ctx);
+        ret
+    }
+
+    fn update(&mut self, ctx: &mut impl super::Context) {
+        for i in 0..8 {
+            ctx.map_chr(i, (self.chr_bank as u32 & 3) * 8 + i);
+        }
+
+        ctx.memory_ctrl_mut().set_mirroring(match self.mode & 3 {
+            0 => Mirroring::OneScreenLow,
+            1 => Mirroring::OneScreenHigh,
+            2 => Mirroring::Vertical,
+            3 => Mirroring::Horizontal,
+            _ => unreachable!(),
+        });
+
+        // Bank numbers are in 16KB unit
+        let outer = self.outer_bank as u32 * 2;
+        let inner = self.inner_bank as u32 & 0x0f;
+        let mask = (2 << ((self.mode >> 4) & 3)) - 1;
+
+        let (lo, hi) = match (self.mode >> 2) & 3 {
+            0 | 1 => {
+                let bank = (outer & !mask) | (inner << 1 & mask);
+                (bank, bank | 1)
+            }
+            2 => (outer, (outer & !mask) | (inner & mask)),
+            3 => ((outer & !mask) | (inner & mask), outer | 1),
+            _ => unreachable!(),
+        };
+
+        ctx.map_prg(0, lo * 2);
+        ctx.map_prg(1, lo * 2 + 1);
+        ctx.map_prg(2, hi * 2);
+        ctx.map_prg(3, hi * 2 + 1);
+    }
+}
+
+impl super::MapperTrait for Action53 {
+    fn write_prg(&mut self, ctx: &mut impl super::Context, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5fff => {
+                self.reg_select = data & 0x81;
+            }
+            0x8000..=0xffff => {
+                core_log!(
+                    Mapper,
+                    Trace,
+                    "Action 53: reg[${:02X}] <- ${data:02X}",
+                    self.reg_select
+                );
+
+                match self.reg_select {
+                    0x00 => self.chr_bank = data,
+                    0x01 => self.inner_bank = data,
+                    0x80 => self.mode = data,
+                    0x81 => self.outer_bank = data,
+                    _ => unreachable!(),
+                }
+
+                // Bit 4 of CHR and inner bank register selects the page of one-screen mirroring
+                if self.reg_select & 0x80 == 0 && self.mode & 2 == 0 {
+                    self.mode = (self.mode & !1) | (data >> 4) & 1;
+                }
+
+                self.update(ctx);
+            }
+            _ => ctx.write_prg(addr, data),
+        }
+    }
+}
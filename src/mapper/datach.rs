@@ -0,0 +1,398 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{context::IrqSource, logging::core_log, rom::Mirroring};
+
+/// CPU cycles for which the barcode reader outputs each bar
+const BARCODE_BIT_CYCLES: u64 = 1000;
+
+/// Offset of the 24C01 in PRG RAM, which holds the EEPROMs so that they are backed up.
+/// The 24C02 is at the start.
+const EEPROM_24C01_OFFSET: usize = 0x100;
+
+/// Bandai Datach Joint ROM System (mapper 157).
+/// The LZ93D50 of the base unit with a 24C02 EEPROM and a barcode reader,
+/// and a 24C01 EEPROM on some sub-ROM cartridges. Dumps contain the PRG ROM
+/// of the sub-ROM cartridge, and the unit has 8KB of CHR RAM.
+#[derive(Serialize, Deserialize)]
+pub struct Datach {
+    prg_bank: u8,
+    irq_enable: bool,
+    irq_counter: u16,
+    irq_latch: u16,
+    eeprom_24c02: Eeprom,
+    eeprom_24c01: Eeprom,
+    // SCL of the 24C01 and SDA of both EEPROMs
+    scl_24c01: bool,
+    sda: bool,
+    barcode: Barcode,
+}
+
+impl Datach {
+    pub fn new(ctx: &mut impl super::Context) -> Self {
+        let mut ret = Self {
+            prg_bank: 0,
+            irq_enable: false,
+            irq_counter: 0,
+            irq_latch: 0,
+            eeprom_24c02: Eeprom::new(EepromKind::X24C02),
+            eeprom_24c01: Eeprom::new(EepromKind::X24C01),
+            scl_24c01: false,
+            sda: false,
+            barcode: Barcode::default(),
+        };
+        ret.update(ctx);
+        ret
+    }
+
+    fn update(&mut self, ctx: &mut impl super::Context) {
+        let prg_pages = ctx.memory_ctrl().prg_pages();
+        let bank = (self.prg_bank & 0x0F) as u32 * 2;
+        ctx.map_prg(0, bank);
+        ctx.map_prg(1, bank + 1);
+        ctx.map_prg(2, prg_pages - 2);
+        ctx.map_prg(3, prg_pages - 1);
+    }
+
+    /// Starts reading a barcode of 13 or 8 digits (EAN-13 or EAN-8). The check digit can
+    /// be omitted, and a wrong one is corrected. Returns false if the code is invalid.
+    pub fn scan_barcode(&mut self, code: &str) -> bool {
+        let Some(bars) = encode_ean(code) else {
+            return false;
+        };
+        self.barcode = Barcode {
+            bars,
+            pos: 0,
+            counter: 0,
+            output: false,
+        };
+        true
+    }
+
+    fn update_eeprom(&mut self, ctx: &mut impl super::Context, scl_24c02: bool) {
+        let prg_ram = ctx.memory_ctrl_mut().prg_ram_mut();
+        let split = EEPROM_24C01_OFFSET.min(prg_ram.len());
+        let (ram_24c02, ram_24c01) = prg_ram.split_at_mut(split);
+        self.eeprom_24c02.update(scl_24c02, self.sda, ram_24c02);
+        self.eeprom_24c01
+            .update(self.scl_24c01, self.sda, ram_24c01);
+    }
+}
+
+impl super::MapperTrait for Datach {
+    fn read_prg(&self, ctx: &impl super::Context, addr: u16) -> u8 {
+        if !(0x6000..0x8000).contains(&addr) {
+            return ctx.read_prg(addr);
+        }
+
+        // The EEPROMs pull the shared SDA line low to output 0
+        let sda = self.eeprom_24c02.output && self.eeprom_24c01.output;
+        (sda as u8) << 4 | (self.barcode.output as u8) << 3
+    }
+
+    fn write_prg(&mut self, ctx: &mut impl super::Context, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            core_log!(Mapper, Info, "Datach: write ${addr:04X} <- ${data:02X}");
+            return;
+        }
+
+        match addr & 0x0F {
+            // CHR bank registers of the LZ93D50, where the unit only connects SCL of the 24C01
+            0x0 => {
+                self.scl_24c01 = data & 0x08 != 0;
+                let scl_24c02 = self.eeprom_24c02.scl;
+                self.update_eeprom(ctx, scl_24c02);
+            }
+            0x1..=0x7 => (),
+            0x8 => {
+                self.prg_bank = data;
+                self.update(ctx);
+            }
+            0x9 => {
+                ctx.memory_ctrl_mut().set_mirroring(match data & 3 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::OneScreenLow,
+                    _ => Mirroring::OneScreenHigh,
+                });
+            }
+            0xA => {
+                self.irq_enable = data & 1 != 0;
+                self.irq_counter = self.irq_latch;
+                ctx.set_irq_source(IrqSource::Mapper, false);
+            }
+            0xB => self.irq_latch = self.irq_latch & 0xFF00 | data as u16,
+            0xC => self.irq_latch = self.irq_latch & 0x00FF | (data as u16) << 8,
+            0xD => {
+                self.sda = data & 0x40 != 0;
+                self.update_eeprom(ctx, data & 0x20 != 0);
+            }
+            _ => core_log!(Mapper, Info, "Datach: write ${addr:04X} <- ${data:02X}"),
+        }
+    }
+
+    fn tick_cpu(&mut self, ctx: &mut impl super::Context) {
+        if self.irq_enable {
+            // The IRQ fires on a counter of 0 both before and after the decrement
+            if self.irq_counter == 0 {
+                ctx.set_irq_source(IrqSource::Mapper, true);
+            }
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0 {
+                ctx.set_irq_source(IrqSource::Mapper, true);
+            }
+        }
+
+        self.barcode.clock();
+    }
+}
+
+/// Bars being read by the barcode reader, which outputs 1 for spaces
+#[derive(Default, Serialize, Deserialize)]
+struct Barcode {
+    // True for bars
+    bars: Vec<bool>,
+    pos: usize,
+    counter: u64,
+    output: bool,
+}
+
+impl Barcode {
+    fn clock(&mut self) {
+        self.counter += 1;
+        if self.counter < BARCODE_BIT_CYCLES {
+            return;
+        }
+        self.counter = 0;
+        match self.bars.get(self.pos) {
+            Some(&bar) => {
+                self.output = !bar;
+                self.pos += 1;
+            }
+            None => self.output = false,
+        }
+    }
+}
+
+/// L-codes of the EAN digits. R-codes are their complements, and G-codes are the
+/// R-codes reversed.
+const EAN_L_CODES: [u8; 10] = [0x0D, 0x19, 0x13, 0x3D, 0x23, 0x31, 0x2F, 0x3B, 0x37, 0x0B];
+
+/// Digits of the left half encoded with G-codes for each first digit of an EAN-13
+const EAN_13_PARITY: [u8; 10] = [0x00, 0x0B, 0x0D, 0x0E, 0x13, 0x19, 0x1C, 0x15, 0x16, 0x1A];
+
+/// Converts an EAN-13 or EAN-8 code to bars, with quiet zones on both sides
+fn encode_ean(code: &str) -> Option<Vec<bool>> {
+    let digits = code
+        .chars()
+        .map(|c| c.to_digit(10).map(|d| d as u8))
+        .collect::<Option<Vec<_>>>()?;
+    let mut digits = match digits.len() {
+        12 | 13 => digits[..12].to_vec(),
+        7 | 8 => digits[..7].to_vec(),
+        _ => None?,
+    };
+
+    // Weights are 3 and 1 alternately from the digit next to the check digit
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| d as u32 * if i % 2 == 0 { 3 } else { 1 })
+        .sum();
+    digits.push(((10 - sum % 10) % 10) as u8);
+
+    let mut bars = vec![];
+    let mut push = |pattern: u8, len: usize| {
+        bars.extend((0..len).rev().map(|i| pattern >> i & 1 != 0));
+    };
+    let l_code = |d: u8| EAN_L_CODES[d as usize];
+    let r_code = |d: u8| !l_code(d) & 0x7F;
+    let g_code = |d: u8| r_code(d).reverse_bits() >> 1;
+
+    // The first digit of an EAN-13 is encoded in the L/G pattern of the left half
+    let (parity, digits) = if digits.len() == 13 {
+        (EAN_13_PARITY[digits[0] as usize], &digits[1..])
+    } else {
+        (0, &digits[..])
+    };
+    let (left, right) = digits.split_at(digits.len() / 2);
+
+    push(0b101, 3);
+    for (i, &d) in left.iter().enumerate() {
+        let g = parity >> (left.len() - 1 - i) & 1 != 0;
+        push(if g { g_code(d) } else { l_code(d) }, 7);
+    }
+    push(0b01010, 5);
+    for &d in right {
+        push(r_code(d), 7);
+    }
+    push(0b101, 3);
+
+    let quiet_zone = [false; 32];
+    Some([&quiet_zone[..], &bars, &quiet_zone].concat())
+}
+
+#[derive(Serialize, Deserialize)]
+enum EepromKind {
+    /// Standard I2C EEPROM of 256 bytes, addressed by a device byte
+    X24C02,
+    /// EEPROM of 128 bytes, addressed right after the start condition, LSB first
+    X24C01,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+enum EepromMode {
+    Idle,
+    Device,
+    Address,
+    Read,
+    Write,
+    SendAck,
+    WaitAck,
+}
+
+/// Serial EEPROM on SCL and SDA lines
+#[derive(Serialize, Deserialize)]
+struct Eeprom {
+    kind: EepromKind,
+    mode: EepromMode,
+    next_mode: EepromMode,
+    scl: bool,
+    sda: bool,
+    // Bits shifted in or out of the current byte
+    count: u8,
+    shift: u8,
+    addr: u8,
+    // False while pulling SDA low
+    output: bool,
+}
+
+impl Eeprom {
+    fn new(kind: EepromKind) -> Self {
+        Self {
+            kind,
+            mode: EepromMode::Idle,
+            next_mode: EepromMode::Idle,
+            scl: false,
+            sda: false,
+            count: 0,
+            shift: 0,
+            addr: 0,
+            output: true,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self.kind {
+            EepromKind::X24C02 => 0x100,
+            EepromKind::X24C01 => 0x80,
+        }
+    }
+
+    // Bit of the byte sent `count`-th
+    fn bit_mask(&self) -> u8 {
+        match self.kind {
+            EepromKind::X24C02 => 0x80 >> self.count,
+            EepromKind::X24C01 => 1 << self.count,
+        }
+    }
+
+    /// Updates the lines. `mem` holds the contents, and is empty if the EEPROM is absent.
+    fn update(&mut self, scl: bool, sda: bool, mem: &mut [u8]) {
+        use EepromMode::*;
+
+        let size = self.size().min(mem.len());
+        let addr = self.addr as usize % self.size();
+
+        if self.scl && scl && self.sda && !sda {
+            // Start condition
+            self.mode = match self.kind {
+                EepromKind::X24C02 => Device,
+                EepromKind::X24C01 => Address,
+            };
+            self.count = 0;
+            self.output = true;
+        } else if self.scl && scl && !self.sda && sda {
+            // Stop condition
+            self.mode = Idle;
+            self.output = true;
+        } else if !self.scl && scl {
+            // Rising edge, where data is sampled
+            match self.mode {
+                Device | Address | Write if self.count < 8 => {
+                    if sda {
+                        self.shift |= self.bit_mask();
+                    } else {
+                        self.shift &= !self.bit_mask();
+                    }
+                    self.count += 1;
+                }
+                Read if self.count < 8 => {
+                    self.output = self.shift & self.bit_mask() != 0;
+                    self.count += 1;
+                }
+                SendAck => self.output = false,
+                // The reader sends 0 to continue reading
+                WaitAck => {
+                    self.next_mode = if sda { Idle } else { Read };
+                    self.shift = if addr < size { mem[addr] } else { 0xFF };
+                }
+                _ => (),
+            }
+        } else if self.scl && !scl {
+            // Falling edge, after which data changes
+            match self.mode {
+                Device if self.count == 8 => {
+                    if self.shift & 0xF0 == 0xA0 {
+                        self.mode = SendAck;
+                        self.next_mode = if self.shift & 1 != 0 { Read } else { Address };
+                        self.shift = if addr < size { mem[addr] } else { 0xFF };
+                    } else {
+                        self.mode = Idle;
+                    }
+                    self.output = true;
+                }
+                // The 24C01 takes a 7-bit address and the R/W bit
+                Address if self.count == 8 => {
+                    self.mode = SendAck;
+                    match self.kind {
+                        EepromKind::X24C02 => {
+                            self.addr = self.shift;
+                            self.next_mode = Write;
+                        }
+                        EepromKind::X24C01 => {
+                            self.addr = self.shift & 0x7F;
+                            let addr = self.addr as usize;
+                            self.next_mode = if self.shift & 0x80 != 0 { Read } else { Write };
+                            self.shift = if addr < size { mem[addr] } else { 0xFF };
+                        }
+                    }
+                    self.output = true;
+                }
+                Read if self.count == 8 => {
+                    self.mode = WaitAck;
+                    self.addr = ((addr + 1) % self.size()) as u8;
+                    self.output = true;
+                }
+                Write if self.count == 8 => {
+                    if addr < size {
+                        mem[addr] = self.shift;
+                    }
+                    self.addr = ((addr + 1) % self.size()) as u8;
+                    self.mode = SendAck;
+                    self.next_mode = Write;
+                    self.output = true;
+                }
+                SendAck | WaitAck => {
+                    self.mode = self.next_mode;
+                    self.count = 0;
+                    self.output = true;
+                }
+                _ => (),
+            }
+        }
+
+        self.scl = scl;
+        self.sda = sda;
+    }
+}
@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Mutex};
+
+use super::Context;
+use crate::apu::ExpansionChip;
+
+/// Mapper implemented outside of this crate.
+///
+/// Unlike `MapperTrait`, this trait is object safe so that implementations can be
+/// registered at runtime with [`register_mapper`].
+pub trait ExternalMapper: Send {
+    fn read_prg(&self, ctx: &dyn Context, addr: u16) -> u8 {
+        ctx.read_prg(addr)
+    }
+
+    fn write_prg(&mut self, ctx: &mut dyn Context, addr: u16, data: u8) {
+        ctx.write_prg(addr, data);
+    }
+
+    fn read_chr(&mut self, ctx: &mut dyn Context, addr: u16) -> u8 {
+        ctx.read_chr(addr)
+    }
+
+    fn write_chr(&mut self, ctx: &mut dyn Context, addr: u16, data: u8) {
+        ctx.write_chr(addr, data);
+    }
+
+    /// Called on every PPU dot
+    fn tick(&mut self, _ctx: &mut dyn Context) {}
+
+    /// Called on every CPU cycle
+    fn tick_cpu(&mut self, _ctx: &mut dyn Context) {}
+
+    /// Current output of the sound chip on the cartridge, in the same scale as
+    /// the 2A03 mixer output
+    fn audio_output(&self) -> Option<(ExpansionChip, f32)> {
+        None
+    }
+
+    /// Serializes the mapper state for save states
+    fn save_state(&self) -> Vec<u8>;
+
+    /// Restores the mapper state from the data made by `save_state`
+    fn load_state(&mut self, data: &[u8]);
+}
+
+pub type MapperConstructor = fn(&mut dyn Context) -> Box<dyn ExternalMapper>;
+
+static REGISTRY: Mutex<BTreeMap<u16, MapperConstructor>> = Mutex::new(BTreeMap::new());
+
+/// Registers a mapper implementation for a mapper id.
+/// Registered mappers take precedence over built-in ones.
+pub fn register_mapper(mapper_id: u16, constructor: MapperConstructor) {
+    REGISTRY.lock().unwrap().insert(mapper_id, constructor);
+}
+
+pub fn unregister_mapper(mapper_id: u16) {
+    REGISTRY.lock().unwrap().remove(&mapper_id);
+}
+
+pub(super) fn lookup(mapper_id: u16) -> Option<MapperConstructor> {
+    REGISTRY.lock().unwrap().get(&mapper_id).copied()
+}
+
+pub struct External {
+    mapper: Option<Box<dyn ExternalMapper>>,
+    // State loaded from a save state, waiting for the mapper instance to be restored
+    pending_state: Vec<u8>,
+}
+
+impl External {
+    pub fn new(mapper: Box<dyn ExternalMapper>) -> Self {
+        Self {
+            mapper: Some(mapper),
+            pending_state: vec![],
+        }
+    }
+
+    /// Takes over the mapper instance from `from` and restores the deserialized state to it
+    pub fn restore(&mut self, from: &mut External) {
+        if self.mapper.is_some() {
+            return;
+        }
+        if let Some(mut mapper) = from.mapper.take() {
+            mapper.load_state(&self.pending_state);
+            self.mapper = Some(mapper);
+            self.pending_state.clear();
+        }
+    }
+
+    fn mapper(&self) -> &dyn ExternalMapper {
+        self.mapper
+            .as_deref()
+            .expect("external mapper is not restored")
+    }
+
+    fn mapper_mut(&mut self) -> &mut dyn ExternalMapper {
+        self.mapper
+            .as_deref_mut()
+            .expect("external mapper is not restored")
+    }
+}
+
+impl Serialize for External {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.mapper {
+            Some(mapper) => mapper.save_state().serialize(serializer),
+            None => self.pending_state.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for External {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self {
+            mapper: None,
+            pending_state: Vec::deserialize(deserializer)?,
+        })
+    }
+}
+
+impl super::MapperTrait for External {
+    fn read_prg(&self, ctx: &impl Context, addr: u16) -> u8 {
+        self.mapper().read_prg(ctx, addr)
+    }
+
+    fn write_prg(&mut self, ctx: &mut impl Context, addr: u16, data: u8) {
+        self.mapper_mut().write_prg(ctx, addr, data);
+    }
+
+    fn read_chr(&mut self, ctx: &mut impl Context, addr: u16) -> u8 {
+        self.mapper_mut().read_chr(ctx, addr)
+    }
+
+    fn write_chr(&mut self, ctx: &mut impl Context, addr: u16, data: u8) {
+        self.mapper_mut().write_chr(ctx, addr, data);
+    }
+
+    fn tick(&mut self, ctx: &mut impl Context) {
+        self.mapper_mut().tick(ctx);
+    }
+
+    fn tick_cpu(&mut self, ctx: &mut impl Context) {
+        self.mapper_mut().tick_cpu(ctx);
+    }
+
+    fn audio_output(&self) -> Option<(ExpansionChip, f32)> {
+        self.mapper().audio_output()
+    }
+}
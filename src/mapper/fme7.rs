@@ -0,0 +1,294 @@
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+
+use crate::{apu::ExpansionChip, context::IrqSource, logging::core_log, rom::Mirroring};
+
+use bitvec::prelude::*;
+
+/// Output of a channel at full volume, about 1.5 times a 2A03 pulse channel
+const FULL_AMPLITUDE: f32 = 0.17;
+
+/// Channel amplitudes for the 32 envelope levels, 1.5 dB apart
+static LEVELS: LazyLock<[f32; 32]> = LazyLock::new(|| {
+    std::array::from_fn(|i| {
+        if i == 0 {
+            0.0
+        } else {
+            FULL_AMPLITUDE * 10.0_f32.powf((i as f32 - 31.0) * 1.5 / 20.0)
+        }
+    })
+});
+
+/// Sunsoft FME-7, and the 5B which adds a 3-channel PSG to it
+#[derive(Serialize, Deserialize)]
+pub struct Fme7 {
+    cmd: u8,
+    chr_bank: [u8; 8],
+    prg_bank: [u8; 4],
+    irq_enable: bool,
+    irq_counter_enable: bool,
+    irq_counter: u16,
+    psg: Psg,
+}
+
+impl Fme7 {
+    pub fn new(ctx: &mut impl super::Context) -> Self {
+        let mut ret = Self {
+            cmd: 0,
+            chr_bank: [0; 8],
+            prg_bank: [0; 4],
+            irq_enable: false,
+            irq_counter_enable: false,
+            irq_counter: 0,
+            psg: Psg {
+                noise_lfsr: 1,
+                ..Default::default()
+            },
+        };
+        ret.update(ctx);
+        ret
+    }
+
+    fn update(&mut self, ctx: &mut impl super::Context) {
+        let prg_pages = ctx.memory_ctrl().prg_pages();
+        for i in 0..3 {
+            ctx.map_prg(i, (self.prg_bank[i as usize + 1] & 0x3F) as _);
+        }
+        ctx.map_prg(3, prg_pages - 1);
+
+        for i in 0..8 {
+            ctx.map_chr(i, self.chr_bank[i as usize] as _);
+        }
+    }
+}
+
+impl super::MapperTrait for Fme7 {
+    fn read_prg(&self, ctx: &impl super::Context, addr: u16) -> u8 {
+        if !(0x6000..0x8000).contains(&addr) {
+            return ctx.read_prg(addr);
+        }
+
+        // $6000-$7FFF maps PRG ROM, PRG RAM or nothing
+        let v = self.prg_bank[0].view_bits::<Lsb0>();
+        match (v[6], v[7]) {
+            (false, _) => {
+                let prg_rom = &ctx.rom().prg_rom;
+                let bank = v[0..6].load::<usize>() % (prg_rom.len() / 0x2000);
+                prg_rom[bank * 0x2000 + (addr & 0x1FFF) as usize]
+            }
+            (true, true) => ctx.read_prg(addr),
+            (true, false) => 0,
+        }
+    }
+
+    fn write_prg(&mut self, ctx: &mut impl super::Context, addr: u16, data: u8) {
+        match addr & 0xE000 {
+            0x6000 => {
+                if self.prg_bank[0] & 0xC0 == 0xC0 {
+                    ctx.write_prg(addr, data);
+                }
+            }
+            0x8000 => self.cmd = data & 0x0F,
+            0xA000 => match self.cmd {
+                0..=7 => {
+                    self.chr_bank[self.cmd as usize] = data;
+                    self.update(ctx);
+                }
+                8..=0xB => {
+                    self.prg_bank[self.cmd as usize - 8] = data;
+                    self.update(ctx);
+                }
+                0xC => {
+                    ctx.memory_ctrl_mut().set_mirroring(match data & 3 {
+                        0 => Mirroring::Vertical,
+                        1 => Mirroring::Horizontal,
+                        2 => Mirroring::OneScreenLow,
+                        _ => Mirroring::OneScreenHigh,
+                    });
+                }
+                0xD => {
+                    self.irq_enable = data & 0x01 != 0;
+                    self.irq_counter_enable = data & 0x80 != 0;
+                    ctx.set_irq_source(IrqSource::Mapper, false);
+                }
+                0xE => self.irq_counter = self.irq_counter & 0xFF00 | data as u16,
+                0xF => self.irq_counter = self.irq_counter & 0x00FF | (data as u16) << 8,
+                _ => unreachable!(),
+            },
+            0xC000 => self.psg.select(data),
+            0xE000 => self.psg.write(data),
+            _ => core_log!(Mapper, Info, "FME-7: write ${addr:04X} <- ${data:02X}"),
+        }
+    }
+
+    fn tick_cpu(&mut self, ctx: &mut impl super::Context) {
+        if self.irq_counter_enable {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_enable {
+                ctx.set_irq_source(IrqSource::Mapper, true);
+            }
+        }
+
+        self.psg.clock();
+    }
+
+    fn audio_output(&self) -> Option<(ExpansionChip, f32)> {
+        Some((ExpansionChip::Sunsoft5b, self.psg.output()))
+    }
+}
+
+/// PSG of the 5B, compatible with the YM2149F
+#[derive(Default, Serialize, Deserialize)]
+struct Psg {
+    addr: u8,
+    reg: [u8; 16],
+
+    // The PSG runs at half the CPU clock, and its counters at 1/8 of that
+    prescaler: u8,
+    tone: [Tone; 3],
+    noise_counter: u16,
+    noise_toggle: bool,
+    noise_lfsr: u32,
+    envelope_counter: u16,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Tone {
+    counter: u16,
+    output: bool,
+}
+
+impl Psg {
+    fn select(&mut self, data: u8) {
+        self.addr = data;
+    }
+
+    fn write(&mut self, data: u8) {
+        // The upper bits of the address must be 0
+        if self.addr >= 0x10 {
+            return;
+        }
+        self.reg[self.addr as usize] = data;
+        if self.addr == 0x0D {
+            self.envelope_counter = 0;
+            self.envelope_step = 0;
+            self.envelope_attack = data & 0x04 != 0;
+            self.envelope_holding = false;
+        }
+    }
+
+    fn tone_period(&self, ch: usize) -> u16 {
+        (self.reg[ch * 2] as u16 | (self.reg[ch * 2 + 1] as u16 & 0x0F) << 8).max(1)
+    }
+
+    fn clock(&mut self) {
+        // The envelope runs twice as fast as the tone counters to make 32 steps
+        self.prescaler = (self.prescaler + 1) % 16;
+        if self.prescaler.is_multiple_of(8) {
+            self.clock_envelope();
+        }
+        if self.prescaler != 0 {
+            return;
+        }
+
+        for ch in 0..3 {
+            let period = self.tone_period(ch);
+            let tone = &mut self.tone[ch];
+            tone.counter += 1;
+            if tone.counter >= period {
+                tone.counter = 0;
+                tone.output = !tone.output;
+            }
+        }
+
+        // The noise generator shifts at half the tone clock
+        let period = (self.reg[6] as u16 & 0x1F).max(1);
+        self.noise_counter += 1;
+        if self.noise_counter >= period {
+            self.noise_counter = 0;
+            self.noise_toggle = !self.noise_toggle;
+            if self.noise_toggle {
+                let fb = (self.noise_lfsr ^ self.noise_lfsr >> 3) & 1;
+                self.noise_lfsr = self.noise_lfsr >> 1 | fb << 16;
+            }
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        let period = (self.reg[0x0B] as u16 | (self.reg[0x0C] as u16) << 8).max(1);
+        self.envelope_counter += 1;
+        if self.envelope_counter < period {
+            return;
+        }
+        self.envelope_counter = 0;
+
+        if self.envelope_holding {
+            return;
+        }
+        self.envelope_step += 1;
+        if self.envelope_step < 32 {
+            return;
+        }
+
+        let shape = self.reg[0x0D].view_bits::<Lsb0>();
+        let (hold, alternate, cont) = (shape[0], shape[1], shape[3]);
+        if !cont || hold {
+            self.envelope_holding = true;
+            self.envelope_step = 31;
+        } else {
+            self.envelope_step = 0;
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+        }
+    }
+
+    /// Envelope level from 0 to 31
+    fn envelope_level(&self) -> u8 {
+        let shape = self.reg[0x0D].view_bits::<Lsb0>();
+        let (hold, alternate, attack, cont) = (shape[0], shape[1], shape[2], shape[3]);
+
+        let rising = if self.envelope_holding {
+            // Shapes without continue drop to 0 after the first cycle
+            if !cont {
+                return 0;
+            }
+            attack != (alternate && hold)
+        } else {
+            self.envelope_attack
+        };
+        if rising {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let mixer = self.reg[7];
+        let noise = self.noise_lfsr & 1 != 0;
+
+        (0..3)
+            .map(|ch| {
+                let tone_off = mixer >> ch & 1 != 0;
+                let noise_off = mixer >> (ch + 3) & 1 != 0;
+                if !((self.tone[ch].output || tone_off) && (noise || noise_off)) {
+                    return 0.0;
+                }
+
+                let volume = self.reg[8 + ch];
+                let level = if volume & 0x10 != 0 {
+                    self.envelope_level()
+                } else if volume & 0x0F == 0 {
+                    0
+                } else {
+                    (volume & 0x0F) * 2 + 1
+                };
+                LEVELS[level as usize]
+            })
+            .sum()
+    }
+}
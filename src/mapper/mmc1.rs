@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::rom::Mirroring;
+use crate::{logging::core_log, rom::Mirroring};
 
 #[derive(Serialize, Deserialize)]
 pub struct Mmc1 {
@@ -47,10 +47,10 @@ impl super::MapperTrait for Mmc1 {
             return;
         }
 
-        log::trace!("MMC1: {addr:04X} <- {data:02X}");
+        core_log!(Mapper, Trace, "MMC1: {addr:04X} <- {data:02X}");
 
         if data & 0x80 != 0 {
-            log::trace!("MMC1: Reset");
+            core_log!(Mapper, Trace, "MMC1: Reset");
             self.buf = 0;
             self.cnt = 0;
             return;
@@ -69,7 +69,11 @@ impl super::MapperTrait for Mmc1 {
 
         let reg_num = (addr >> 13) & 3;
 
-        log::trace!("MMC1: reg[{reg_num}] <- ${cmd:02X} (b{cmd:05b})");
+        core_log!(
+            Mapper,
+            Trace,
+            "MMC1: reg[{reg_num}] <- ${cmd:02X} (b{cmd:05b})"
+        );
 
         match reg_num {
             0 => {
@@ -110,7 +114,7 @@ impl super::MapperTrait for Mmc1 {
             },
             2 => match self.chr_rom_bank_mode {
                 ChrRomBankMode::Switch8K => {
-                    log::info!("MMC1: High CHR page set on 8K CHR mode");
+                    core_log!(Mapper, Info, "MMC1: High CHR page set on 8K CHR mode");
                 }
                 ChrRomBankMode::Switch4K => {
                     let page = cmd as u32;
@@ -1,8 +1,9 @@
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    consts::{LINES_PER_FRAME, PPU_CLOCK_PER_LINE, PRE_RENDER_LINE, SCREEN_RANGE},
+    consts::{PPU_CLOCK_PER_LINE, SCREEN_RANGE},
     context::IrqSource,
+    logging::core_log,
     rom::Mirroring,
 };
 
@@ -20,6 +21,10 @@ pub struct Mmc3 {
     irq_counter: u8,
     irq_reload: bool,
     irq_enable: bool,
+    // MMC3A (submapper 4) only raises IRQ when the counter becomes 0 by a decrement or
+    // a reload requested by $C001, not when it is reloaded with 0 on every clock
+    #[serde(default)]
+    irq_rev_a: bool,
     ppu_cycle: u64,
     ppu_line: u64,
     ppu_frame: u64,
@@ -41,6 +46,7 @@ impl Mmc3 {
             irq_counter: 0,
             irq_reload: false,
             irq_enable: false,
+            irq_rev_a: ctx.rom().submapper_id == 4,
             ppu_cycle: 0,
             ppu_line: 0,
             ppu_frame: 0,
@@ -126,11 +132,19 @@ impl super::MapperTrait for Mmc3 {
             }
             0xA001 => {
                 let v = data.view_bits::<Lsb0>();
-                log::info!("PRG RAM protect: enable: {}, write protect: {}", v[7], v[6]);
+                core_log!(
+                    Mapper,
+                    Info,
+                    "PRG RAM protect: enable: {}, write protect: {}",
+                    v[7],
+                    v[6]
+                );
             }
 
             0xC000 => {
-                log::trace!(
+                core_log!(
+                    Mapper,
+                    Trace,
                     "MMC3 IRQ latch  : {data:3}, PPU frame={}, line={}, pixel={}",
                     self.ppu_frame,
                     self.ppu_line,
@@ -139,7 +153,9 @@ impl super::MapperTrait for Mmc3 {
                 self.irq_latch = data
             }
             0xC001 => {
-                log::trace!(
+                core_log!(
+                    Mapper,
+                    Trace,
                     "MMC3 IRQ reload :      PPU frame={}, line={}, pixel={}",
                     self.ppu_frame,
                     self.ppu_line,
@@ -150,7 +166,9 @@ impl super::MapperTrait for Mmc3 {
             }
 
             0xE000 => {
-                log::trace!(
+                core_log!(
+                    Mapper,
+                    Trace,
                     "MMC3 IRQ disable:      PPU frame={}, line={}, pixel={}",
                     self.ppu_frame,
                     self.ppu_line,
@@ -160,7 +178,9 @@ impl super::MapperTrait for Mmc3 {
                 ctx.set_irq_source(IrqSource::Mapper, false);
             }
             0xE001 => {
-                log::trace!(
+                core_log!(
+                    Mapper,
+                    Trace,
                     "MMC3 IRQ enable :      PPU frame={}, line={}, pixel={}",
                     self.ppu_frame,
                     self.ppu_line,
@@ -184,18 +204,23 @@ impl super::MapperTrait for Mmc3 {
     }
 
     fn tick(&mut self, ctx: &mut impl super::Context) {
-        if (self.ppu_line < SCREEN_RANGE.end as u64 || self.ppu_line == PRE_RENDER_LINE as u64)
-            && self.ppu_cycle == 260
+        let region = ctx.region();
+        // Checked right after the first sprite pattern fetch of the line at dot 261
+        if (self.ppu_line < SCREEN_RANGE.end as u64
+            || self.ppu_line == region.pre_render_line() as u64)
+            && self.ppu_cycle == 261
         {
             if self.ppu_a12_edge {
                 let tmp = self.irq_counter;
+                let reload = self.irq_reload;
                 if self.irq_counter == 0 || self.irq_reload {
                     self.irq_counter = self.irq_latch;
                     self.irq_reload = false;
                 } else {
                     self.irq_counter -= 1;
                 }
-                if (tmp > 0 || self.irq_reload) && self.irq_counter == 0 && self.irq_enable {
+                let fire = !self.irq_rev_a || tmp > 0 || reload;
+                if fire && self.irq_counter == 0 && self.irq_enable {
                     ctx.set_irq_source(IrqSource::Mapper, true);
                 }
             }
@@ -206,7 +231,7 @@ impl super::MapperTrait for Mmc3 {
         if self.ppu_cycle == PPU_CLOCK_PER_LINE {
             self.ppu_cycle = 0;
             self.ppu_line += 1;
-            if self.ppu_line == LINES_PER_FRAME as u64 {
+            if self.ppu_line == region.lines_per_frame() as u64 {
                 self.ppu_line = 0;
                 self.ppu_frame += 1;
             }
@@ -1,22 +1,40 @@
+mod action53;
 mod cnrom;
+mod datach;
+mod external;
+mod fme7;
 mod mmc1;
 mod mmc3;
+mod namco163;
+mod nsf;
 mod null;
+mod racermate;
 mod unrom;
+mod unrom512;
+mod vrc6;
 
 use ambassador::{delegatable_trait, Delegate};
 use serde::{Deserialize, Serialize};
 
-use crate::{context, nes::Error, util::trait_alias};
+use crate::{apu::ExpansionChip, context, nes::Error, util::trait_alias};
 
-trait_alias!(pub trait Context = context::MemoryController + context::Rom + context::Interrupt);
+pub use external::{register_mapper, unregister_mapper, ExternalMapper, MapperConstructor};
+
+trait_alias!(pub trait Context = context::MemoryController + context::Rom + context::Interrupt + context::Timing);
 
 #[delegatable_trait]
 pub trait MapperTrait {
+    /// Reads PRG space without side effects, which the debugger also uses
     fn read_prg(&self, ctx: &impl Context, addr: u16) -> u8 {
         ctx.read_prg(addr)
     }
 
+    /// Reads PRG space from the CPU. Mappers with registers which change when read
+    /// override this, leaving them unchanged in `read_prg`.
+    fn read_prg_mut(&mut self, ctx: &mut impl Context, addr: u16) -> u8 {
+        self.read_prg(ctx, addr)
+    }
+
     fn write_prg(&mut self, ctx: &mut impl Context, addr: u16, data: u8) {
         ctx.write_prg(addr, data);
     }
@@ -29,21 +47,35 @@ pub trait MapperTrait {
         ctx.write_chr(addr, data);
     }
 
+    /// Called on every PPU dot
     fn tick(&mut self, _ctx: &mut impl Context) {}
+
+    /// Called on every CPU cycle, for chips which run on the CPU clock
+    fn tick_cpu(&mut self, _ctx: &mut impl Context) {}
+
+    /// Current output of the sound chip on the cartridge, in the same scale as
+    /// the 2A03 mixer output. The APU mixes it in on every CPU cycle.
+    fn audio_output(&self) -> Option<(ExpansionChip, f32)> {
+        None
+    }
 }
 
 macro_rules! def_mapper {
-    ($($id:expr => $constr:ident($ty:ty),)*) => {
+    ($($id:expr => $constr:ident($ty:ty, $board:literal),)*) => {
         #[derive(Delegate, Serialize, Deserialize)]
         #[delegate(MapperTrait)]
         pub enum Mapper {
             $(
                 $constr($ty),
             )*
+            External(external::External),
         }
 
         pub fn create_mapper(ctx: &mut impl Context) -> Result<Mapper, Error> {
             let mapper_id = ctx.rom().mapper_id;
+            if let Some(constructor) = external::lookup(mapper_id) {
+                return Ok(Mapper::External(external::External::new(constructor(ctx))));
+            }
             Ok(match mapper_id {
                 $(
                     $id => Mapper::$constr(<$ty>::new(ctx)),
@@ -51,13 +83,41 @@ macro_rules! def_mapper {
                 _ => Err(Error::UnsupportedMapper(mapper_id))?,
             })
         }
+
+        /// Name of the board of the built-in mapper, `None` if there is no built-in mapper
+        pub fn board_name(mapper_id: u16) -> Option<&'static str> {
+            Some(match mapper_id {
+                $(
+                    $id => $board,
+                )*
+                _ => None?,
+            })
+        }
+    }
+}
+
+impl Mapper {
+    /// Restores the external mapper instance after deserialization
+    pub fn restore_external(&mut self, from: &mut Mapper) {
+        if let (Mapper::External(to), Mapper::External(from)) = (self, from) {
+            to.restore(from);
+        }
     }
 }
 
 def_mapper! {
-    0 => NullMapper(null::NullMapper),
-    1 => Mmc1(mmc1::Mmc1),
-    2 => Unrom(unrom::Unrom),
-    3 => Cnrom(cnrom::Cnrom),
-    4 => Mmc3(mmc3::Mmc3),
+    0 => NullMapper(null::NullMapper, "NROM"),
+    1 => Mmc1(mmc1::Mmc1, "MMC1"),
+    2 => Unrom(unrom::Unrom, "UxROM"),
+    3 => Cnrom(cnrom::Cnrom, "CNROM"),
+    4 => Mmc3(mmc3::Mmc3, "MMC3"),
+    19 => Namco163(namco163::Namco163, "Namco 163"),
+    24 => Vrc6a(vrc6::Vrc6, "VRC6a"),
+    26 => Vrc6b(vrc6::Vrc6, "VRC6b"),
+    28 => Action53(action53::Action53, "Action 53"),
+    30 => Unrom512(unrom512::Unrom512, "UNROM 512"),
+    31 => Nsf(nsf::Nsf, "NSF"),
+    69 => Fme7(fme7::Fme7, "FME-7"),
+    157 => Datach(datach::Datach, "Datach Joint ROM System"),
+    168 => RacerMate(racermate::RacerMate, "RacerMate"),
 }
@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{apu::ExpansionChip, context::IrqSource, logging::core_log, memory::NametableSource};
+
+use bitvec::prelude::*;
+
+/// Output of one step of a channel sample times its volume.
+/// A channel at full volume is about twice as loud as a 2A03 pulse channel.
+const AMPLITUDE_STEP: f32 = 0.001;
+
+/// CPU cycles taken to update one sound channel
+const CHANNEL_CYCLES: u8 = 15;
+
+#[derive(Serialize, Deserialize)]
+pub struct Namco163 {
+    prg_bank: [u8; 3],
+    chr_bank: [u8; 8],
+    nametable: [u8; 4],
+    irq_counter: u16,
+    irq_enable: bool,
+    sound_disable: bool,
+    // Sound RAM address and auto-increment flag
+    sound_addr: u8,
+    sound_ram: Vec<u8>,
+    // Channel being updated and the cycles spent on it
+    sound_channel: usize,
+    sound_cycle: u8,
+    channel_output: [i16; 8],
+}
+
+impl Namco163 {
+    pub fn new(ctx: &mut impl super::Context) -> Self {
+        let mut ret = Self {
+            prg_bank: [0, 1, 2],
+            chr_bank: [0; 8],
+            nametable: [0xE0, 0xE1, 0xE0, 0xE1],
+            irq_counter: 0,
+            irq_enable: false,
+            sound_disable: false,
+            sound_addr: 0,
+            sound_ram: vec![0; 0x80],
+            sound_channel: 7,
+            sound_cycle: 0,
+            channel_output: [0; 8],
+        };
+        ret.update(ctx);
+        ret
+    }
+
+    fn update(&mut self, ctx: &mut impl super::Context) {
+        let prg_pages = ctx.memory_ctrl().prg_pages();
+        for i in 0..3 {
+            ctx.map_prg(i, self.prg_bank[i as usize] as _);
+        }
+        ctx.map_prg(3, prg_pages - 1);
+
+        for i in 0..8 {
+            ctx.map_chr(i, self.chr_bank[i as usize] as _);
+        }
+
+        // Banks $E0 and above select the console VRAM instead of CHR ROM
+        for (i, &bank) in self.nametable.iter().enumerate() {
+            let source = if bank >= 0xE0 {
+                NametableSource::Vram(bank as usize & 1)
+            } else {
+                NametableSource::Chr(bank as usize)
+            };
+            ctx.memory_ctrl_mut().set_nametable_source(i, source);
+        }
+    }
+
+    /// Returns the sound RAM address of the data port access, and advances it if enabled
+    fn next_sound_addr(&mut self) -> usize {
+        let v = self.sound_addr;
+        if v & 0x80 != 0 {
+            self.sound_addr = 0x80 | (v.wrapping_add(1) & 0x7F);
+        }
+        (v & 0x7F) as usize
+    }
+
+    fn enabled_channels(&self) -> usize {
+        (self.sound_ram[0x7F] as usize >> 4 & 7) + 1
+    }
+
+    fn clock_sound(&mut self) {
+        self.sound_cycle += 1;
+        if self.sound_cycle < CHANNEL_CYCLES {
+            return;
+        }
+        self.sound_cycle = 0;
+
+        let ch = self.sound_channel;
+        let base = 0x40 + ch * 8;
+        let reg = &mut self.sound_ram[base..base + 8];
+
+        let freq = reg[0] as u32 | (reg[2] as u32) << 8 | (reg[4] as u32 & 3) << 16;
+        let length = 256 - (reg[4] as u32 & 0xFC);
+        let mut phase = reg[1] as u32 | (reg[3] as u32) << 8 | (reg[5] as u32) << 16;
+        phase = (phase + freq) % (length << 16);
+        reg[1] = phase as u8;
+        reg[3] = (phase >> 8) as u8;
+        reg[5] = (phase >> 16) as u8;
+
+        let addr = ((phase >> 16) + reg[6] as u32) as u8;
+        let volume = (reg[7] & 0x0F) as i16;
+        let byte = self.sound_ram[(addr >> 1) as usize & 0x7F];
+        let sample = if addr & 1 == 0 {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        };
+        self.channel_output[ch] = (sample as i16 - 8) * volume;
+
+        // Channels are updated from 7 down to the last enabled one
+        self.sound_channel = if ch <= 8 - self.enabled_channels() {
+            7
+        } else {
+            ch - 1
+        };
+    }
+}
+
+impl super::MapperTrait for Namco163 {
+    fn read_prg(&self, ctx: &impl super::Context, addr: u16) -> u8 {
+        match addr & 0xF800 {
+            0x4800 => self.sound_ram[self.sound_addr as usize & 0x7F],
+            0x5000 => self.irq_counter as u8,
+            0x5800 => (self.irq_counter >> 8) as u8 | (self.irq_enable as u8) << 7,
+            _ => ctx.read_prg(addr),
+        }
+    }
+
+    fn read_prg_mut(&mut self, ctx: &mut impl super::Context, addr: u16) -> u8 {
+        match addr & 0xF800 {
+            // Reads of the data port also advance the address
+            0x4800 => {
+                let addr = self.next_sound_addr();
+                self.sound_ram[addr]
+            }
+            _ => self.read_prg(ctx, addr),
+        }
+    }
+
+    fn write_prg(&mut self, ctx: &mut impl super::Context, addr: u16, data: u8) {
+        match addr & 0xF800 {
+            0x4800 => {
+                let addr = self.next_sound_addr();
+                self.sound_ram[addr] = data;
+            }
+            0x5000 => {
+                self.irq_counter = self.irq_counter & 0x7F00 | data as u16;
+                ctx.set_irq_source(IrqSource::Mapper, false);
+            }
+            0x5800 => {
+                self.irq_counter = self.irq_counter & 0xFF | (data as u16 & 0x7F) << 8;
+                self.irq_enable = data & 0x80 != 0;
+                ctx.set_irq_source(IrqSource::Mapper, false);
+            }
+            0x8000..=0xB800 => {
+                self.chr_bank[(addr as usize - 0x8000) >> 11] = data;
+                if data >= 0xE0 {
+                    core_log!(
+                        Mapper,
+                        Info,
+                        "N163: CHR bank ${data:02X} in VRAM is not supported"
+                    );
+                }
+                self.update(ctx);
+            }
+            0xC000..=0xD800 => {
+                self.nametable[(addr as usize - 0xC000) >> 11] = data;
+                self.update(ctx);
+            }
+            0xE000 => {
+                let v = data.view_bits::<Lsb0>();
+                self.prg_bank[0] = v[0..6].load();
+                self.sound_disable = v[6];
+                self.update(ctx);
+            }
+            0xE800 => {
+                self.prg_bank[1] = data & 0x3F;
+                self.update(ctx);
+            }
+            0xF000 => {
+                self.prg_bank[2] = data & 0x3F;
+                self.update(ctx);
+            }
+            0xF800 => self.sound_addr = data,
+            _ => ctx.write_prg(addr, data),
+        }
+    }
+
+    fn tick_cpu(&mut self, ctx: &mut impl super::Context) {
+        if self.irq_enable && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7FFF {
+                ctx.set_irq_source(IrqSource::Mapper, true);
+            }
+        }
+
+        self.clock_sound();
+    }
+
+    fn audio_output(&self) -> Option<(ExpansionChip, f32)> {
+        if self.sound_disable {
+            return Some((ExpansionChip::Namco163, 0.0));
+        }
+
+        // The chip outputs one channel at a time. Averaging them gives the level heard
+        // after the cartridge's filtering, without the whine of the switching.
+        let n = self.enabled_channels();
+        let sum: i16 = self.channel_output[8 - n..].iter().sum();
+        Some((
+            ExpansionChip::Namco163,
+            sum as f32 / n as f32 * AMPLITUDE_STEP,
+        ))
+    }
+}
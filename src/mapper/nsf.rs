@@ -0,0 +1,311 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{consts::Region, context::IrqSource, logging::core_log, nsf::NsfInfo};
+
+/// Player program for NSF files at $4100-$41FF, which no console or NSF hardware uses
+const SHIM_BASE: u16 = 0x4100;
+const SHIM_RESET: u16 = 0x4100;
+const SHIM_IRQ: u16 = 0x4150;
+const SHIM_NMI: u16 = 0x4170;
+/// Writes start the play timer
+const SHIM_START_TIMER: u16 = 0x41F0;
+/// Reads return $80 if the play timer IRQ is pending, and writes acknowledge it
+const SHIM_TIMER_IRQ: u16 = 0x41F1;
+/// Reads return the IRQ vector of the tune
+const SHIM_TUNE_IRQ_VECTOR: u16 = 0x41F2;
+/// IRQ handler of tunes which don't set their own
+const SHIM_RTI: u16 = 0x4161;
+
+/// Play calls within this difference from the frame rate are synchronized to NMI
+const FRAME_SYNC_TOLERANCE_US: u64 = 300;
+
+#[rustfmt::skip]
+const SHIM: &[(u16, &[u8])] = &[
+    (SHIM_RESET, &[
+        0x78,             // SEI
+        0xD8,             // CLD
+        0xA2, 0xFF,       // LDX #$FF
+        0x9A,             // TXS
+        0xA9, 0x00,       // LDA #$00
+        0x8D, 0x00, 0x20, // STA $2000
+        0x8D, 0x01, 0x20, // STA $2001
+        0x8D, 0x15, 0x40, // STA $4015
+        0xA2, 0x13,       // LDX #$13
+        0x9D, 0x00, 0x40, // STA $4000,X
+        0xCA,             // DEX
+        0x10, 0xFA,       // BPL -6
+        0xA9, 0x0F,       // LDA #$0F
+        0x8D, 0x15, 0x40, // STA $4015
+        0xA9, 0x40,       // LDA #$40
+        0x8D, 0x17, 0x40, // STA $4017
+        0xA9, 0x00,       // LDA #song
+        0xA2, 0x00,       // LDX #region
+        0x20, 0x00, 0x00, // JSR init
+        0xA9, 0x00,       // LDA #nmi_enable
+        0xF0, 0x0A,       // BEQ +10
+        // The PPU ignores $2000 until its warm-up ends, so wait for the second vblank
+        0x2C, 0x02, 0x20, // BIT $2002
+        0x10, 0xFB,       // BPL -5
+        0x2C, 0x02, 0x20, // BIT $2002
+        0x10, 0xFB,       // BPL -5
+        0x8D, 0x00, 0x20, // STA $2000
+        0x8D, 0xF0, 0x41, // STA SHIM_START_TIMER
+        0x58,             // CLI
+        0x4C, 0x3E, 0x41, // JMP *
+    ]),
+    (SHIM_IRQ, &[
+        0x48,             // PHA
+        0xAD, 0xF1, 0x41, // LDA SHIM_TIMER_IRQ
+        0x10, 0x07,       // BPL +7 (other IRQ sources)
+        0x8D, 0xF1, 0x41, // STA SHIM_TIMER_IRQ
+        0x68,             // PLA
+        0x4C, 0x70, 0x41, // JMP SHIM_NMI
+        0x68,             // PLA
+        0x6C, 0xF2, 0x41, // JMP (SHIM_TUNE_IRQ_VECTOR)
+        0x40,             // RTI
+    ]),
+    (SHIM_NMI, &[
+        0x48,             // PHA
+        0x8A,             // TXA
+        0x48,             // PHA
+        0x98,             // TYA
+        0x48,             // PHA
+        0x20, 0x00, 0x00, // JSR play
+        0x68,             // PLA
+        0xA8,             // TAY
+        0x68,             // PLA
+        0xAA,             // TAX
+        0x68,             // PLA
+        0x40,             // RTI
+    ]),
+];
+
+// Offsets of the operands filled in by the player
+const SHIM_SONG: usize = 0x23;
+const SHIM_REGION: usize = 0x25;
+const SHIM_INIT: usize = 0x27;
+const SHIM_NMI_ENABLE: usize = 0x2A;
+const SHIM_PLAY: usize = 0x76;
+
+/// Mapper 31, with 4KB PRG banks switched at $5000-$5FFF like NSF files.
+/// For NSF files, it also runs the player program which calls the init and play routines.
+#[derive(Serialize, Deserialize)]
+pub struct Nsf {
+    prg_bank: [u8; 8],
+    player: Option<Player>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Player {
+    song: u8,
+    initial_banks: [u8; 8],
+    shim: Vec<u8>,
+    timer_enable: bool,
+    // Microseconds times the CPU clock rate until the next play call
+    timer_counter: u64,
+    timer_irq: bool,
+    irq: Nsf2Irq,
+}
+
+/// IRQ timer of NSF2, which counts down CPU cycles from the reload value
+#[derive(Serialize, Deserialize)]
+struct Nsf2Irq {
+    reload: u16,
+    counter: u16,
+    enable: bool,
+    pending: bool,
+    // Written to $FFFE-$FFFF, which still read the vector of the player
+    vector: u16,
+}
+
+impl Default for Nsf2Irq {
+    fn default() -> Self {
+        Self {
+            reload: 0,
+            counter: 0,
+            enable: false,
+            pending: false,
+            vector: SHIM_RTI,
+        }
+    }
+}
+
+impl Nsf {
+    pub fn new(ctx: &mut impl super::Context) -> Self {
+        let Some(info) = &ctx.rom().nsf else {
+            let mut prg_bank = [0; 8];
+            prg_bank[7] = 0xFF;
+            return Self {
+                prg_bank,
+                player: None,
+            };
+        };
+
+        if info.expansion_chips != 0 {
+            core_log!(
+                Mapper,
+                Info,
+                "NSF: expansion chips ${:02X} are not supported",
+                info.expansion_chips
+            );
+        }
+
+        let mut shim = vec![0; 0x100];
+        for (addr, code) in SHIM {
+            let ofs = (addr - SHIM_BASE) as usize;
+            shim[ofs..ofs + code.len()].copy_from_slice(code);
+        }
+        shim[SHIM_INIT..SHIM_INIT + 2].copy_from_slice(&info.init_addr.to_le_bytes());
+        shim[SHIM_PLAY..SHIM_PLAY + 2].copy_from_slice(&info.play_addr.to_le_bytes());
+
+        Self {
+            prg_bank: info.initial_banks(),
+            player: Some(Player {
+                song: info.starting_song,
+                initial_banks: info.initial_banks(),
+                shim,
+                timer_enable: false,
+                timer_counter: 0,
+                timer_irq: false,
+                irq: Nsf2Irq::default(),
+            }),
+        }
+    }
+
+    /// Returns the track selected for the player, or `None` if the ROM is not an NSF file
+    pub fn song(&self) -> Option<u8> {
+        self.player.as_ref().map(|p| p.song)
+    }
+
+    /// Selects the track which the player starts at the next reset.
+    /// The banks are restored and the timers stop until the track is initialized.
+    pub fn set_song(&mut self, song: u8) {
+        if let Some(player) = &mut self.player {
+            self.prg_bank = player.initial_banks;
+            player.song = song;
+            player.timer_enable = false;
+            player.timer_irq = false;
+            player.irq = Nsf2Irq::default();
+        }
+    }
+
+    fn frame_synced(info: &NsfInfo, ctx: &impl super::Context) -> bool {
+        let region = ctx.region();
+        let frame_us = 1_000_000 / region.frame_rate();
+        (info.speed(region) as u64).abs_diff(frame_us) <= FRAME_SYNC_TOLERANCE_US
+    }
+
+    fn read_shim(&self, ctx: &impl super::Context, player: &Player, addr: u16) -> u8 {
+        let info = ctx.rom().nsf.as_ref().unwrap();
+        match addr {
+            SHIM_TIMER_IRQ => (player.timer_irq as u8) << 7,
+            SHIM_TUNE_IRQ_VECTOR => player.irq.vector as u8,
+            0x41F3 => (player.irq.vector >> 8) as u8,
+            _ => match (addr - SHIM_BASE) as usize {
+                SHIM_SONG => player.song,
+                SHIM_REGION => (ctx.region() == Region::Pal) as u8,
+                SHIM_NMI_ENABLE => (Self::frame_synced(info, ctx) as u8) << 7,
+                ofs => player.shim[ofs],
+            },
+        }
+    }
+
+    fn update_irq(ctx: &mut impl super::Context, player: &Player) {
+        ctx.set_irq_source(IrqSource::Mapper, player.timer_irq || player.irq.pending);
+    }
+}
+
+impl super::MapperTrait for Nsf {
+    fn read_prg(&self, ctx: &impl super::Context, addr: u16) -> u8 {
+        match (addr, &self.player) {
+            (0x4100..=0x41FF, Some(player)) => self.read_shim(ctx, player, addr),
+            // The vectors enter the player
+            (0xFFFA..=0xFFFF, Some(_)) => {
+                let vector = match addr & !1 {
+                    0xFFFA => SHIM_NMI,
+                    0xFFFC => SHIM_RESET,
+                    _ => SHIM_IRQ,
+                };
+                vector.to_le_bytes()[addr as usize & 1]
+            }
+            (0x8000..=0xFFFF, _) => {
+                let prg_rom = &ctx.rom().prg_rom;
+                let bank = self.prg_bank[(addr as usize >> 12) & 7] as usize;
+                let bank = bank % (prg_rom.len() / 0x1000);
+                prg_rom[bank * 0x1000 + (addr & 0x0FFF) as usize]
+            }
+            _ => ctx.read_prg(addr),
+        }
+    }
+
+    fn write_prg(&mut self, ctx: &mut impl super::Context, addr: u16, data: u8) {
+        let irq_support = ctx.rom().nsf.as_ref().is_some_and(|info| info.irq_support);
+
+        match (addr, &mut self.player) {
+            (SHIM_START_TIMER, Some(player)) => {
+                player.timer_enable = true;
+                player.timer_counter = 0;
+            }
+            (SHIM_TIMER_IRQ, Some(player)) => {
+                player.timer_irq = false;
+                Self::update_irq(ctx, player);
+            }
+            (0x4018, Some(player)) if irq_support => {
+                player.irq.reload = player.irq.reload & 0xFF00 | data as u16;
+            }
+            (0x4019, Some(player)) if irq_support => {
+                player.irq.reload = player.irq.reload & 0x00FF | (data as u16) << 8;
+            }
+            (0x401A, Some(player)) if irq_support => {
+                player.irq.enable = data & 1 != 0;
+                player.irq.counter = player.irq.reload;
+                player.irq.pending = false;
+                Self::update_irq(ctx, player);
+            }
+            (0xFFFE, Some(player)) if irq_support => {
+                player.irq.vector = player.irq.vector & 0xFF00 | data as u16;
+            }
+            (0xFFFF, Some(player)) if irq_support => {
+                player.irq.vector = player.irq.vector & 0x00FF | (data as u16) << 8;
+            }
+            // NSF files switch banks at $5FF8-$5FFF, mapper 31 in all of $5000-$5FFF
+            (0x5FF8..=0x5FFF, Some(_)) | (0x5000..=0x5FFF, None) => {
+                self.prg_bank[addr as usize & 7] = data;
+            }
+            (0x6000..=0x7FFF, _) => ctx.write_prg(addr, data),
+            _ => core_log!(Mapper, Info, "NSF: write ${addr:04X} <- ${data:02X}"),
+        }
+    }
+
+    fn tick_cpu(&mut self, ctx: &mut impl super::Context) {
+        let Some(player) = &mut self.player else {
+            return;
+        };
+
+        let irq = &mut player.irq;
+        if irq.enable {
+            if irq.counter == 0 {
+                irq.counter = irq.reload;
+                irq.pending = true;
+                Self::update_irq(ctx, player);
+            } else {
+                irq.counter -= 1;
+            }
+        }
+
+        let info = ctx.rom().nsf.as_ref().unwrap();
+        if !player.timer_enable || Self::frame_synced(info, ctx) {
+            return;
+        }
+
+        // Counted in microseconds times the CPU clock rate to avoid drift
+        let region = ctx.region();
+        let period = info.speed(region).max(1) as u64 * region.cpu_clock_rate();
+        player.timer_counter += 1_000_000;
+        if player.timer_counter >= period {
+            player.timer_counter -= period;
+            player.timer_irq = true;
+            Self::update_irq(ctx, player);
+        }
+    }
+}
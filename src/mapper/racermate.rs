@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+use crate::logging::core_log;
+
+#[derive(Serialize, Deserialize)]
+pub struct RacerMate;
+
+impl RacerMate {
+    pub fn new(ctx: &mut impl super::Context) -> Self {
+        let prg_pages = ctx.memory_ctrl().prg_pages();
+        ctx.map_prg(0, 0);
+        ctx.map_prg(1, 1);
+        ctx.map_prg(2, prg_pages - 2);
+        ctx.map_prg(3, prg_pages - 1);
+
+        for i in 0..8 {
+            ctx.map_chr(i, i);
+        }
+
+        Self
+    }
+}
+
+impl super::MapperTrait for RacerMate {
+    fn write_prg(&mut self, ctx: &mut impl super::Context, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0xbfff => {
+                core_log!(Mapper, Trace, "RacerMate: bank <- ${data:02X}");
+
+                // $0000-$0FFF is fixed to the first 4KB of CHR RAM
+                let chr_bank = (data & 0x0f) as u32;
+                for i in 0..4 {
+                    ctx.map_chr(i + 4, chr_bank * 4 + i);
+                }
+
+                let prg_bank = (data >> 6) as u32;
+                ctx.map_prg(0, prg_bank * 2);
+                ctx.map_prg(1, prg_bank * 2 + 1);
+            }
+            0xc000..=0xffff => {
+                // IRQ acknowledge on the real board, which is not used by the game
+            }
+            _ => ctx.write_prg(addr, data),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{logging::core_log, rom::Mirroring};
+
+#[derive(Serialize, Deserialize)]
+pub struct Unrom512 {
+    flashable: bool,
+    one_screen: bool,
+    flash_state: FlashState,
+    software_id: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+enum FlashState {
+    Ready,
+    Unlock1,
+    Unlock2,
+    Program,
+    Erase,
+    EraseUnlock1,
+    EraseUnlock2,
+}
+
+const FLASH_SECTOR_SIZE: usize = 0x1000;
+
+// SST39SF040
+const FLASH_MANUFACTURER_ID: u8 = 0xBF;
+const FLASH_DEVICE_ID: u8 = 0xB7;
+
+impl Unrom512 {
+    pub fn new(ctx: &mut impl super::Context) -> Self {
+        let prg_pages = ctx.memory_ctrl().prg_pages();
+        ctx.map_prg(0, 0);
+        ctx.map_prg(1, 1);
+        ctx.map_prg(2, prg_pages - 2);
+        ctx.map_prg(3, prg_pages - 1);
+
+        let one_screen = ctx.rom().mirroring == Mirroring::OneScreenLow;
+
+        Self {
+            flashable: ctx.rom().has_prg_flash(),
+            one_screen,
+            flash_state: FlashState::Ready,
+            software_id: false,
+        }
+    }
+
+    fn write_bank(&mut self, ctx: &mut impl super::Context, data: u8) {
+        let prg_bank = (data & 0x1f) as u32;
+        ctx.map_prg(0, prg_bank * 2);
+        ctx.map_prg(1, prg_bank * 2 + 1);
+
+        let chr_bank = ((data >> 5) & 3) as u32;
+        for i in 0..8 {
+            ctx.map_chr(i, chr_bank * 8 + i);
+        }
+
+        if self.one_screen {
+            ctx.memory_ctrl_mut().set_mirroring(if data & 0x80 == 0 {
+                Mirroring::OneScreenLow
+            } else {
+                Mirroring::OneScreenHigh
+            });
+        }
+    }
+
+    fn write_flash(&mut self, ctx: &mut impl super::Context, addr: u16, data: u8) {
+        let page = ((addr & 0x7fff) / 0x2000) as u32;
+        let offset = ctx.prg_page(page) as usize * 0x2000 + (addr & 0x1fff) as usize;
+
+        // Command addresses are decoded with the lower 15 bits of the flash address
+        let cmd_addr = offset & 0x7fff;
+
+        core_log!(
+            Mapper,
+            Trace,
+            "UNROM 512: flash[${offset:05X}] <- ${data:02X}"
+        );
+
+        if data == 0xF0 {
+            self.software_id = false;
+            self.flash_state = FlashState::Ready;
+            return;
+        }
+
+        self.flash_state = match (&self.flash_state, cmd_addr, data) {
+            (FlashState::Ready, 0x5555, 0xAA) => FlashState::Unlock1,
+            (FlashState::Unlock1, 0x2AAA, 0x55) => FlashState::Unlock2,
+            (FlashState::Unlock2, 0x5555, 0xA0) => FlashState::Program,
+            (FlashState::Unlock2, 0x5555, 0x80) => FlashState::Erase,
+            (FlashState::Unlock2, 0x5555, 0x90) => {
+                self.software_id = true;
+                FlashState::Ready
+            }
+            (FlashState::Program, _, _) => {
+                ctx.memory_ctrl_mut().program_prg_flash(offset, data);
+                FlashState::Ready
+            }
+            (FlashState::Erase, 0x5555, 0xAA) => FlashState::EraseUnlock1,
+            (FlashState::EraseUnlock1, 0x2AAA, 0x55) => FlashState::EraseUnlock2,
+            (FlashState::EraseUnlock2, 0x5555, 0x10) => {
+                core_log!(Mapper, Info, "UNROM 512: chip erase");
+                let size = ctx.rom().prg_rom.len();
+                ctx.memory_ctrl_mut().erase_prg_flash(0..size);
+                FlashState::Ready
+            }
+            (FlashState::EraseUnlock2, _, 0x30) => {
+                core_log!(Mapper, Info, "UNROM 512: sector erase: ${offset:05X}");
+                let start = offset & !(FLASH_SECTOR_SIZE - 1);
+                ctx.memory_ctrl_mut()
+                    .erase_prg_flash(start..start + FLASH_SECTOR_SIZE);
+                FlashState::Ready
+            }
+            _ => {
+                core_log!(
+                    Mapper,
+                    Info,
+                    "UNROM 512: unexpected flash command: ${offset:05X} <- ${data:02X}"
+                );
+                FlashState::Ready
+            }
+        };
+    }
+}
+
+impl super::MapperTrait for Unrom512 {
+    fn read_prg(&self, ctx: &impl super::Context, addr: u16) -> u8 {
+        if self.software_id && (0x8000..=0xbfff).contains(&addr) {
+            return match addr & 1 {
+                0 => FLASH_MANUFACTURER_ID,
+                _ => FLASH_DEVICE_ID,
+            };
+        }
+        ctx.read_prg(addr)
+    }
+
+    fn write_prg(&mut self, ctx: &mut impl super::Context, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0xbfff if self.flashable => self.write_flash(ctx, addr, data),
+            0x8000..=0xffff => {
+                // Non flashable boards have bus conflicts
+                let data = if self.flashable {
+                    data
+                } else {
+                    data & ctx.read_prg(addr)
+                };
+                self.write_bank(ctx, data);
+            }
+            _ => ctx.write_prg(addr, data),
+        }
+    }
+}
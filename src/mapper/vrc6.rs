@@ -0,0 +1,342 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    apu::ExpansionChip, consts::PPU_CLOCK_PER_LINE, context::IrqSource, logging::core_log,
+    rom::Mirroring,
+};
+
+use bitvec::prelude::*;
+
+/// Output of one VRC6 volume step, matching a step of the 2A03 pulse channels
+const VOLUME_STEP: f32 = 0.00752;
+
+#[derive(Serialize, Deserialize)]
+pub struct Vrc6 {
+    // VRC6b (mapper 26) has the A0 and A1 lines swapped
+    swap_a0_a1: bool,
+    prg_bank_16k: u8,
+    prg_bank_8k: u8,
+    chr_bank: [u8; 8],
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+}
+
+impl Vrc6 {
+    pub fn new(ctx: &mut impl super::Context) -> Self {
+        let mut ret = Self {
+            swap_a0_a1: ctx.rom().mapper_id == 26,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_bank: [0; 8],
+            irq: VrcIrq::default(),
+            audio: Vrc6Audio::default(),
+        };
+        ret.update(ctx);
+        ret
+    }
+
+    fn update(&mut self, ctx: &mut impl super::Context) {
+        let prg_pages = ctx.memory_ctrl().prg_pages();
+        ctx.map_prg(0, self.prg_bank_16k as u32 * 2);
+        ctx.map_prg(1, self.prg_bank_16k as u32 * 2 + 1);
+        ctx.map_prg(2, self.prg_bank_8k as _);
+        ctx.map_prg(3, prg_pages - 1);
+
+        for i in 0..8 {
+            ctx.map_chr(i, self.chr_bank[i as usize] as _);
+        }
+    }
+}
+
+impl super::MapperTrait for Vrc6 {
+    fn write_prg(&mut self, ctx: &mut impl super::Context, addr: u16, data: u8) {
+        if addr & 0x8000 == 0 {
+            ctx.write_prg(addr, data);
+            return;
+        }
+
+        let addr = if self.swap_a0_a1 {
+            addr & 0xF000 | (addr & 1) << 1 | (addr & 2) >> 1
+        } else {
+            addr & 0xF003
+        };
+
+        match addr {
+            0x8000..=0x8003 => {
+                self.prg_bank_16k = data & 0x0F;
+                self.update(ctx);
+            }
+            0x9000..=0xB002 => self.audio.write(addr, data),
+            0xB003 => {
+                let mirroring = match (data >> 2) & 3 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::OneScreenLow,
+                    _ => Mirroring::OneScreenHigh,
+                };
+                ctx.memory_ctrl_mut().set_mirroring(mirroring);
+                if data & 0x13 != 0 {
+                    core_log!(
+                        Mapper,
+                        Info,
+                        "VRC6: unsupported PPU banking mode ${data:02X}"
+                    );
+                }
+            }
+            0xC000..=0xC003 => {
+                self.prg_bank_8k = data & 0x1F;
+                self.update(ctx);
+            }
+            0xD000..=0xE003 => {
+                let ix = ((addr - 0xD000) >> 12) * 4 + (addr & 3);
+                self.chr_bank[ix as usize] = data;
+                self.update(ctx);
+            }
+            0xF000 => self.irq.latch = data,
+            0xF001 => {
+                self.irq.write_control(data);
+                ctx.set_irq_source(IrqSource::Mapper, false);
+            }
+            0xF002 => {
+                self.irq.acknowledge();
+                ctx.set_irq_source(IrqSource::Mapper, false);
+            }
+            _ => core_log!(Mapper, Info, "VRC6: write ${addr:04X} <- ${data:02X}"),
+        }
+    }
+
+    fn tick_cpu(&mut self, ctx: &mut impl super::Context) {
+        if self.irq.clock() {
+            ctx.set_irq_source(IrqSource::Mapper, true);
+        }
+        self.audio.clock();
+    }
+
+    fn audio_output(&self) -> Option<(ExpansionChip, f32)> {
+        Some((
+            ExpansionChip::Vrc6,
+            self.audio.output() as f32 * VOLUME_STEP,
+        ))
+    }
+}
+
+/// IRQ counter shared by the Konami VRC boards, which counts scanlines or CPU cycles
+#[derive(Default, Serialize, Deserialize)]
+struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i32,
+    enable: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+}
+
+impl VrcIrq {
+    fn write_control(&mut self, data: u8) {
+        let v = data.view_bits::<Lsb0>();
+        self.enable_after_ack = v[0];
+        self.enable = v[1];
+        self.cycle_mode = v[2];
+        if self.enable {
+            self.counter = self.latch;
+            self.prescaler = PPU_CLOCK_PER_LINE as i32;
+        }
+    }
+
+    fn acknowledge(&mut self) {
+        self.enable = self.enable_after_ack;
+    }
+
+    /// Runs a CPU cycle and returns whether the IRQ is raised
+    fn clock(&mut self) -> bool {
+        if !self.enable {
+            return false;
+        }
+
+        // In scanline mode, the prescaler counts 341 PPU clocks, 3 per CPU cycle
+        if !self.cycle_mode {
+            self.prescaler -= 3;
+            if self.prescaler > 0 {
+                return false;
+            }
+            self.prescaler += PPU_CLOCK_PER_LINE as i32;
+        }
+
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            true
+        } else {
+            self.counter += 1;
+            false
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Vrc6Audio {
+    pulse: [Vrc6Pulse; 2],
+    saw: Vrc6Saw,
+    halt: bool,
+    // Right shift of the channel periods from the frequency control register
+    period_shift: u8,
+}
+
+impl Vrc6Audio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9000..=0x9002 => self.pulse[0].write(addr & 3, data),
+            0xA000..=0xA002 => self.pulse[1].write(addr & 3, data),
+            0xB000..=0xB002 => self.saw.write(addr & 3, data),
+            0x9003 => {
+                let v = data.view_bits::<Lsb0>();
+                self.halt = v[0];
+                self.period_shift = if v[2] {
+                    8
+                } else if v[1] {
+                    4
+                } else {
+                    0
+                };
+            }
+            _ => core_log!(Mapper, Info, "VRC6: write ${addr:04X} <- ${data:02X}"),
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        for pulse in &mut self.pulse {
+            pulse.clock(self.period_shift);
+        }
+        self.saw.clock(self.period_shift);
+    }
+
+    /// Sum of the channel outputs, from 0 to 61
+    fn output(&self) -> u8 {
+        self.pulse[0].output() + self.pulse[1].output() + self.saw.output()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Vrc6Pulse {
+    // Ignore the duty and output the volume constantly
+    digitized: bool,
+    duty: u8,
+    volume: u8,
+    period: u16,
+    enable: bool,
+
+    divider: u16,
+    step: u8,
+}
+
+impl Default for Vrc6Pulse {
+    fn default() -> Self {
+        Self {
+            digitized: false,
+            duty: 0,
+            volume: 0,
+            period: 0,
+            enable: false,
+            divider: 0,
+            step: 15,
+        }
+    }
+}
+
+impl Vrc6Pulse {
+    fn write(&mut self, reg: u16, data: u8) {
+        let v = data.view_bits::<Lsb0>();
+        match reg {
+            0 => {
+                self.digitized = v[7];
+                self.duty = v[4..7].load();
+                self.volume = v[0..4].load();
+            }
+            1 => self.period = self.period & 0xF00 | data as u16,
+            2 => {
+                self.period = self.period & 0xFF | (v[0..4].load::<u16>() << 8);
+                self.enable = v[7];
+                if !self.enable {
+                    self.step = 15;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn clock(&mut self, period_shift: u8) {
+        if !self.enable {
+            return;
+        }
+        if self.divider == 0 {
+            self.divider = self.period >> period_shift;
+            self.step = self.step.wrapping_sub(1) & 15;
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enable && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Vrc6Saw {
+    rate: u8,
+    period: u16,
+    enable: bool,
+
+    divider: u16,
+    // The accumulator takes the rate on every other of 14 steps
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn write(&mut self, reg: u16, data: u8) {
+        let v = data.view_bits::<Lsb0>();
+        match reg {
+            0 => self.rate = v[0..6].load(),
+            1 => self.period = self.period & 0xF00 | data as u16,
+            2 => {
+                self.period = self.period & 0xFF | (v[0..4].load::<u16>() << 8);
+                self.enable = v[7];
+                if !self.enable {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn clock(&mut self, period_shift: u8) {
+        if !self.enable {
+            return;
+        }
+        if self.divider != 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.period >> period_shift;
+
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
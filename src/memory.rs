@@ -1,37 +1,58 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, ops::Range};
 
 use crate::{
     context,
+    debugger::{BreakpointKind, Debugger},
+    logging::core_log,
+    mapper::MapperTrait,
     nes::Error,
     rom::{Mirroring, Rom},
     util::trait_alias,
 };
 
-trait_alias!(pub trait Context = context::Mapper + context::Ppu + context::Apu + context::Interrupt + context::Timing);
+trait_alias!(pub trait Context = context::Mapper + context::MemoryController + context::Ppu + context::Apu + context::Interrupt + context::Timing);
 
 #[derive(Serialize, Deserialize)]
 pub struct MemoryMap {
     ram: Vec<u8>,
-    cpu_stall: u64,
+    // Page written to $4014, which the CPU hasn't copied yet
+    oam_dma: Option<u8>,
+    // Fraction of PPU clocks carried over to the next CPU clock
+    ppu_clock_frac: u64,
+    // Last value on the CPU data bus, which unmapped bits read back
+    open_bus: u8,
 }
 
 impl Default for MemoryMap {
     fn default() -> Self {
         Self {
             ram: vec![0x00; 2 * 1024],
-            cpu_stall: 0,
+            oam_dma: None,
+            ppu_clock_frac: 0,
+            open_bus: 0,
         }
     }
 }
 
 impl MemoryMap {
-    pub fn read(&self, ctx: &mut impl Context, addr: u16) -> u8 {
-        match addr {
+    pub fn read(&mut self, ctx: &mut impl Context, addr: u16) -> u8 {
+        let ret = match addr {
             0x0000..=0x1fff => self.ram[(addr & 0x7ff) as usize],
             0x2000..=0x3fff => ctx.read_ppu(addr & 7),
-            0x4000..=0x4017 => ctx.read_apu(addr),
+            0x4000..=0x4017 => ctx.read_apu(addr, self.open_bus),
             0x4018..=0xffff => ctx.read_prg_mapper(addr),
+        };
+        // $4015 is read inside the CPU chip and doesn't drive the external bus
+        if addr != 0x4015 {
+            self.open_bus = ret;
         }
+        let now = ctx.now();
+        ctx.memory_ctrl_mut()
+            .debugger_mut()
+            .check_access(BreakpointKind::CpuRead, addr, ret, now);
+        ret
     }
 
     pub fn read_pure(&self, ctx: &impl Context, addr: u16) -> Option<u8> {
@@ -39,88 +60,264 @@ impl MemoryMap {
             0x0000..=0x1fff => self.ram[(addr & 0x7ff) as usize],
             0x2000..=0x3fff => None?,
             0x4000..=0x4017 => None?,
-            0x4018..=0xffff => ctx.read_prg_mapper(addr),
+            0x4018..=0xffff => ctx.peek_prg_mapper(addr),
         })
     }
 
     pub fn write(&mut self, ctx: &mut impl Context, addr: u16, data: u8) {
+        self.open_bus = data;
+        let now = ctx.now();
+        ctx.memory_ctrl_mut().debugger_mut().check_access(
+            BreakpointKind::CpuWrite,
+            addr,
+            data,
+            now,
+        );
         match addr {
             0x0000..=0x1fff => self.ram[(addr & 0x7ff) as usize] = data,
             0x2000..=0x3fff => ctx.write_ppu(addr & 7, data),
             0x4000..=0x4013 | 0x4015..=0x4017 => ctx.write_apu(addr, data),
-            0x4018..=0xffff => ctx.write_prg_mapper(addr, data),
-
-            0x4014 => {
-                // OAM DMA
-                let hi = (data as u16) << 8;
-
-                for lo in 0..0x100 {
-                    let data = self.read(ctx, hi | lo);
-                    self.write(ctx, 0x2004, data);
+            0x4018..=0xffff => {
+                // $6000-$7FFF is PRG RAM on most boards, which would flood the log
+                if !(0x6000..0x8000).contains(&addr) {
+                    let write = MapperWrite {
+                        addr,
+                        data,
+                        frame: ctx.ppu().frame(),
+                        line: ctx.ppu().line(),
+                        dot: ctx.ppu().dot(),
+                        cycle: ctx.now(),
+                    };
+                    ctx.memory_ctrl_mut().mapper_write_log_mut().record(write);
                 }
+                ctx.write_prg_mapper(addr, data);
+            }
 
-                // FIXME: odd frame stall one more cycle
-                self.cpu_stall += 513
+            0x4014 => {
+                // OAM DMA, which the CPU performs cycle by cycle
+                self.oam_dma = Some(data);
             }
         }
     }
 
     pub fn tick(&mut self, ctx: &mut impl Context) {
-        for _ in 0..3 {
+        ctx.tick_mapper_cpu();
+
+        let (num, den) = ctx.region().ppu_clock_ratio();
+        self.ppu_clock_frac += num;
+        while self.ppu_clock_frac >= den {
+            self.ppu_clock_frac -= den;
             ctx.tick_ppu();
             ctx.tick_mapper();
         }
+        if let Some((chip, output)) = ctx.mapper().audio_output() {
+            ctx.apu_mut().set_expansion_input(chip, output);
+        }
         ctx.tick_apu();
+        ctx.elapse(1);
     }
 
-    pub fn cpu_stall(&mut self) -> u64 {
-        let ret = self.cpu_stall;
-        self.cpu_stall = 0;
-        ret
+    pub fn dmc_dma_pending(&self, ctx: &impl Context) -> bool {
+        ctx.apu().dmc_dma_addr().is_some()
+    }
+
+    /// The CPU halted by DMC DMA keeps driving its read address. Registers with read
+    /// side effects see this as one more read before the real one.
+    pub fn dmc_dma_halt(&mut self, ctx: &mut impl Context, addr: u16) {
+        if ctx.apu().dmc_dma_conflicts() {
+            self.read(ctx, addr);
+        }
+    }
+
+    pub fn dmc_dma_fetch(&mut self, ctx: &mut impl Context) {
+        if let Some(addr) = ctx.apu().dmc_dma_addr() {
+            let data = self.read(ctx, addr);
+            ctx.complete_dmc_dma(data);
+        }
+    }
+
+    pub fn take_oam_dma(&mut self) -> Option<u8> {
+        self.oam_dma.take()
     }
 }
 
+/// Backing storage of a 1KB nametable page
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NametableSource {
+    /// 1KB bank of the console VRAM (or the extra VRAM on four-screen boards)
+    Vram(usize),
+    /// 1KB bank of the RAM allocated by the mapper
+    MapperRam(usize),
+    /// 1KB bank of CHR ROM (or CHR RAM)
+    Chr(usize),
+    /// Every tile reads `tile` and every attribute reads `attr` (2 bits)
+    Fill { tile: u8, attr: u8 },
+}
+
+/// Contents of palette RAM at power-on, which is not initialized by hardware
+#[derive(Default, Clone, PartialEq, Eq, Debug, JsonSchema, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerOnPalette {
+    /// Pattern of the console which power_up_palette.nes was made with
+    #[default]
+    Blargg,
+    /// All entries are $0F
+    Black,
+    /// All entries are $30
+    White,
+    /// Pattern dumped from another console ($3F00-$3F1F, missing entries are $0F)
+    Custom(Vec<u8>),
+}
+
+impl PowerOnPalette {
+    pub fn palette(&self) -> [u8; 0x20] {
+        match self {
+            #[rustfmt::skip]
+            PowerOnPalette::Blargg => [
+                0x09, 0x01, 0x00, 0x01, 0x00, 0x02, 0x02, 0x0D,
+                0x08, 0x10, 0x08, 0x24, 0x00, 0x00, 0x04, 0x2C,
+                0x09, 0x01, 0x34, 0x03, 0x00, 0x04, 0x00, 0x14,
+                0x08, 0x3A, 0x00, 0x02, 0x00, 0x20, 0x2C, 0x08,
+            ],
+            PowerOnPalette::Black => [0x0f; 0x20],
+            PowerOnPalette::White => [0x30; 0x20],
+            PowerOnPalette::Custom(dat) => {
+                let mut ret = [0x0f; 0x20];
+                for (r, d) in ret.iter_mut().zip(dat) {
+                    *r = d & 0x3f;
+                }
+                ret
+            }
+        }
+    }
+}
+
+/// Write to a mapper register, recorded by `MapperWriteLog`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct MapperWrite {
+    pub addr: u16,
+    pub data: u8,
+    pub frame: u64,
+    pub line: usize,
+    pub dot: usize,
+    pub cycle: u64,
+}
+
+/// Ring buffer of the most recent mapper register writes.
+/// Recording is disabled while the capacity is 0.
+#[derive(Default)]
+pub struct MapperWriteLog {
+    capacity: usize,
+    entries: VecDeque<MapperWrite>,
+}
+
+impl MapperWriteLog {
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn record(&mut self, write: MapperWrite) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(write);
+    }
+
+    /// Returns recorded writes from oldest to newest
+    pub fn entries(&self) -> impl Iterator<Item = &MapperWrite> {
+        self.entries.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Callback invoked with (address, old value, new value) when a CHR RAM or nametable byte changes
+pub type ChrWriteHook = Box<dyn FnMut(u16, u8, u8) + Send>;
+
 #[derive(Serialize, Deserialize)]
 pub struct MemoryController {
     prg_ram: Vec<u8>,
     chr_ram: Vec<u8>,
+    chr_nvram: bool,
+    prg_flash: Vec<u8>,
 
     nametable: Vec<u8>,
+    mapper_ram: Vec<u8>,
     palette: [u8; 0x20],
+    power_on_palette: PowerOnPalette,
 
     rom_page: [usize; 4],
     chr_page: [usize; 8],
-    nametable_page: [usize; 4],
+    nametable_page: [NametableSource; 4],
 
     prg_pages: u32,
     chr_pages: u32,
+
+    #[serde(skip)]
+    chr_write_hook: Option<ChrWriteHook>,
+    #[serde(skip)]
+    mapper_write_log: MapperWriteLog,
+    #[serde(skip)]
+    debugger: Debugger,
 }
 
 impl MemoryController {
     pub fn new(rom: &Rom, backup: Option<Vec<u8>>) -> Result<Self, Error> {
-        assert!(rom.chr_ram_size == 0 || rom.chr_rom.is_empty());
+        let prg_ram_size = rom.prg_ram_size + rom.prg_nvram_size;
+        let chr_ram_size = rom.chr_ram_size + rom.chr_nvram_size;
+        let chr_nvram = rom.chr_nvram_size > 0;
+
+        assert!(chr_ram_size == 0 || rom.chr_rom.is_empty());
 
         let mirroring = rom.mirroring;
 
-        let prg_ram = if let Some(backup) = backup {
-            if backup.len() != rom.prg_ram_size {
-                Err(Error::BackupSizeMismatch(backup.len(), rom.prg_ram_size))?
-            }
-            backup
+        let mut prg_ram = vec![0x00; prg_ram_size];
+        let mut chr_ram = vec![0x00; chr_ram_size];
+        let mut prg_flash = if rom.has_prg_flash() {
+            rom.prg_rom.clone()
         } else {
-            vec![0x00; rom.prg_ram_size]
+            vec![]
         };
-        let chr_ram = vec![0x00; rom.chr_ram_size];
 
-        let nametable = vec![0x00; 2 * 1024];
+        if let Some(backup) = backup {
+            let expected = rom.backup_size();
+            if backup.len() != expected {
+                Err(Error::BackupSizeMismatch(backup.len(), expected))?
+            }
+
+            if rom.has_prg_flash() {
+                prg_flash = backup;
+            } else {
+                let (prg, chr) = backup.split_at(prg_ram_size);
+                prg_ram.copy_from_slice(prg);
+                chr_ram[..chr.len()].copy_from_slice(chr);
+            }
+        }
+
+        // Trainers are loaded at $7000-$71FF, over the backup which they patch
+        if let Some(trainer) = &rom.trainer {
+            match prg_ram.get_mut(0x1000..0x1000 + trainer.len()) {
+                Some(ram) => ram.copy_from_slice(trainer),
+                None => core_log!(Rom, Warn, "No PRG RAM to load the trainer into"),
+            }
+        }
+
+        let nametable = vec![0x00; 4 * 1024];
 
-        #[rustfmt::skip]
-        let palette = [
-            0x09, 0x01, 0x00, 0x01, 0x00, 0x02, 0x02, 0x0D,
-            0x08, 0x10, 0x08, 0x24, 0x00, 0x00, 0x04, 0x2C,
-            0x09, 0x01, 0x34, 0x03, 0x00, 0x04, 0x00, 0x14,
-            0x08, 0x3A, 0x00, 0x02, 0x00, 0x20, 0x2C, 0x08,
-        ];
+        let power_on_palette = PowerOnPalette::default();
+        let palette = power_on_palette.palette();
 
         let prg_pages = (rom.prg_rom.len() / 0x2000) as u32;
         let chr_pages = (rom.chr_rom.len() / 0x0400) as u32;
@@ -128,13 +325,20 @@ impl MemoryController {
         let mut ret = Self {
             prg_ram,
             chr_ram,
+            chr_nvram,
+            prg_flash,
             nametable,
+            mapper_ram: vec![],
             palette,
+            power_on_palette,
             rom_page: [0; 4],
             chr_page: [0; 8],
-            nametable_page: [0; 4],
+            nametable_page: [NametableSource::Vram(0); 4],
             prg_pages,
             chr_pages,
+            chr_write_hook: None,
+            mapper_write_log: MapperWriteLog::default(),
+            debugger: Debugger::default(),
         };
 
         for i in 0..4 {
@@ -150,10 +354,102 @@ impl MemoryController {
         Ok(ret)
     }
 
+    /// PRG RAM ($6000-$7FFF window), including the battery backed part
     pub fn prg_ram(&self) -> &[u8] {
         &self.prg_ram
     }
 
+    /// Mutable PRG RAM. The size is fixed by the ROM header.
+    pub fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    /// CHR RAM, including the battery backed part. Empty for CHR ROM cartridges.
+    pub fn chr_ram(&self) -> &[u8] {
+        &self.chr_ram
+    }
+
+    /// Mutable CHR RAM. Changes made through this do not invoke the CHR write hook.
+    pub fn chr_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.chr_ram
+    }
+
+    /// Returns the data to be persisted for battery backed cartridges
+    pub fn backup(&self) -> Vec<u8> {
+        if !self.prg_flash.is_empty() {
+            self.prg_flash.clone()
+        } else if self.chr_nvram {
+            [self.prg_ram.as_slice(), self.chr_ram.as_slice()].concat()
+        } else {
+            self.prg_ram.clone()
+        }
+    }
+
+    /// Returns the pattern palette RAM was initialized with
+    pub fn power_on_palette(&self) -> &PowerOnPalette {
+        &self.power_on_palette
+    }
+
+    /// Initializes palette RAM with `pattern`. This should be called only at power-on.
+    pub fn set_power_on_palette(&mut self, pattern: &PowerOnPalette) {
+        self.palette = pattern.palette();
+        self.power_on_palette = pattern.clone();
+    }
+
+    pub fn set_chr_write_hook(&mut self, hook: Option<ChrWriteHook>) {
+        self.chr_write_hook = hook;
+    }
+
+    pub fn take_chr_write_hook(&mut self) -> Option<ChrWriteHook> {
+        self.chr_write_hook.take()
+    }
+
+    pub fn mapper_write_log(&self) -> &MapperWriteLog {
+        &self.mapper_write_log
+    }
+
+    pub fn mapper_write_log_mut(&mut self) -> &mut MapperWriteLog {
+        &mut self.mapper_write_log
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    fn notify_chr_write(&mut self, addr: u16, old: u8, new: u8) {
+        if old != new {
+            if let Some(hook) = &mut self.chr_write_hook {
+                hook(addr, old, new);
+            }
+        }
+    }
+
+    /// Programs a byte of flash PRG. Programming can only clear bits.
+    pub fn program_prg_flash(&mut self, offset: usize, data: u8) {
+        let len = self.prg_flash.len();
+        self.prg_flash[offset % len] &= data;
+    }
+
+    /// Erases a range of flash PRG to $FF
+    pub fn erase_prg_flash(&mut self, range: Range<usize>) {
+        self.prg_flash[range].fill(0xff);
+    }
+
+    pub fn debug_dump(&self) -> serde_json::Value {
+        let banks =
+            |pages: &[usize], size: usize| pages.iter().map(|p| p / size).collect::<Vec<_>>();
+
+        serde_json::json!({
+            "prg_banks_8k": banks(&self.rom_page, 0x2000),
+            "chr_banks_1k": banks(&self.chr_page, 0x0400),
+            "nametable_sources": self.nametable_page,
+        })
+    }
+
     /// Maps a PRG ROM page to a given 8KB bank
     pub fn map_prg(&mut self, rom: &Rom, page: u32, bank8k: u32) {
         self.rom_page[page as usize] = (bank8k * 0x2000) as usize % rom.prg_rom.len();
@@ -172,7 +468,7 @@ impl MemoryController {
         if !rom.chr_rom.is_empty() {
             self.chr_page[page as usize] = (bank1k * 0x0400) as usize % rom.chr_rom.len();
         } else {
-            self.chr_page[page as usize] = (bank1k * 0x0400) as usize % rom.chr_ram_size;
+            self.chr_page[page as usize] = (bank1k * 0x0400) as usize % self.chr_ram.len();
         }
     }
 
@@ -180,8 +476,77 @@ impl MemoryController {
         self.chr_pages
     }
 
+    /// Maps a nametable page to a given 1KB bank of VRAM
     pub fn map_nametable(&mut self, page: usize, bank: usize) {
-        self.nametable_page[page] = bank * 0x0400;
+        self.set_nametable_source(page, NametableSource::Vram(bank));
+    }
+
+    pub fn set_nametable_source(&mut self, page: usize, source: NametableSource) {
+        self.nametable_page[page] = source;
+    }
+
+    pub fn nametable_source(&self, page: usize) -> NametableSource {
+        self.nametable_page[page]
+    }
+
+    /// Allocates RAM owned by the mapper (e.g. MMC5 ExRAM),
+    /// which can be used as a nametable with `NametableSource::MapperRam`
+    pub fn alloc_mapper_ram(&mut self, size: usize) {
+        self.mapper_ram = vec![0x00; size];
+    }
+
+    pub fn mapper_ram(&self) -> &[u8] {
+        &self.mapper_ram
+    }
+
+    pub fn mapper_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.mapper_ram
+    }
+
+    fn read_nametable(&self, rom: &Rom, page: usize, ofs: usize) -> u8 {
+        match self.nametable_page[page] {
+            NametableSource::Vram(bank) => {
+                self.nametable[(bank * 0x0400 + ofs) % self.nametable.len()]
+            }
+            NametableSource::MapperRam(bank) => {
+                let ix = bank * 0x0400 + ofs;
+                self.mapper_ram.get(ix).copied().unwrap_or(0)
+            }
+            NametableSource::Chr(bank) => {
+                let ix = bank * 0x0400 + ofs;
+                if !rom.chr_rom.is_empty() {
+                    rom.chr_rom[ix % rom.chr_rom.len()]
+                } else {
+                    self.chr_ram[ix % self.chr_ram.len()]
+                }
+            }
+            NametableSource::Fill { tile, attr } => {
+                if ofs < 0x3c0 {
+                    tile
+                } else {
+                    (attr & 3) * 0x55
+                }
+            }
+        }
+    }
+
+    fn write_nametable(&mut self, rom: &Rom, page: usize, ofs: usize, data: u8) -> Option<u8> {
+        let r = match self.nametable_page[page] {
+            NametableSource::Vram(bank) => {
+                let len = self.nametable.len();
+                &mut self.nametable[(bank * 0x0400 + ofs) % len]
+            }
+            NametableSource::MapperRam(bank) => self.mapper_ram.get_mut(bank * 0x0400 + ofs)?,
+            NametableSource::Chr(bank) => {
+                if !rom.chr_rom.is_empty() {
+                    return None;
+                }
+                let len = self.chr_ram.len();
+                &mut self.chr_ram[(bank * 0x0400 + ofs) % len]
+            }
+            NametableSource::Fill { .. } => return None,
+        };
+        Some(std::mem::replace(r, data))
     }
 
     pub fn set_mirroring(&mut self, mirroring: Mirroring) {
@@ -211,7 +576,10 @@ impl MemoryController {
                 self.map_nametable(3, 1);
             }
             Mirroring::FourScreen => {
-                todo!()
+                self.map_nametable(0, 0);
+                self.map_nametable(1, 1);
+                self.map_nametable(2, 2);
+                self.map_nametable(3, 3);
             }
         }
     }
@@ -220,12 +588,17 @@ impl MemoryController {
         match addr {
             0x6000..=0x7fff => {
                 let addr = addr & 0x1fff;
-                self.prg_ram[addr as usize]
+                // Boards without PRG RAM leave this area unmapped
+                self.prg_ram.get(addr as usize).copied().unwrap_or(0)
             }
             0x8000..=0xffff => {
                 let page = (addr & 0x7fff) / 0x2000;
                 let ix = self.rom_page[page as usize] + (addr & 0x1fff) as usize;
-                rom.prg_rom[ix]
+                if !self.prg_flash.is_empty() {
+                    self.prg_flash[ix]
+                } else {
+                    rom.prg_rom[ix]
+                }
             }
             _ => 0,
         }
@@ -235,17 +608,19 @@ impl MemoryController {
         match addr {
             0x6000..=0x7fff => {
                 let addr = addr & 0x1fff;
-                self.prg_ram[addr as usize] = data;
+                if let Some(r) = self.prg_ram.get_mut(addr as usize) {
+                    *r = data;
+                }
             }
             0x8000..=0xffff => {
-                log::warn!("Write to PRG ROM: {addr:04x} = {data:02x}");
+                core_log!(PrgMem, Warn, "Write to PRG ROM: {addr:04x} = {data:02x}");
             }
             _ => (),
         }
     }
 
     pub fn read_chr(&self, rom: &Rom, addr: u16) -> u8 {
-        log::trace!("Read CHR MEM: ${addr:04X}");
+        core_log!(ChrMem, Trace, "Read CHR MEM: ${addr:04X}");
 
         match addr {
             0x0000..=0x1fff => {
@@ -261,8 +636,7 @@ impl MemoryController {
             0x2000..=0x3eff => {
                 let page = (addr as usize & 0x0fff) / 0x400;
                 let ofs = addr as usize & 0x03ff;
-                let ix = self.nametable_page[page] + ofs;
-                self.nametable[ix]
+                self.read_nametable(rom, page, ofs)
             }
             0x3f00..=0x3fff => {
                 let addr = addr & if addr & 3 == 0 { 0x0f } else { 0x1f };
@@ -273,7 +647,7 @@ impl MemoryController {
     }
 
     pub fn write_chr(&mut self, rom: &Rom, addr: u16, data: u8) {
-        log::trace!("Write CHR MEM: (${addr:04X}) = ${data:02X}");
+        core_log!(ChrMem, Trace, "Write CHR MEM: (${addr:04X}) = ${data:02X}");
 
         match addr {
             0x0000..=0x1fff => {
@@ -281,16 +655,23 @@ impl MemoryController {
                 let ix = self.chr_page[page] + (addr & 0x03ff) as usize;
 
                 if !rom.chr_rom.is_empty() {
-                    log::warn!("Write to CHR ROM: (${addr:04X}) = ${data:02X}");
+                    core_log!(
+                        ChrMem,
+                        Warn,
+                        "Write to CHR ROM: (${addr:04X}) = ${data:02X}"
+                    );
                 } else {
+                    let old = self.chr_ram[ix];
                     self.chr_ram[ix] = data;
+                    self.notify_chr_write(addr, old, data);
                 }
             }
             0x2000..=0x3eff => {
                 let page = (addr as usize & 0x0fff) / 0x400;
                 let ofs = addr as usize & 0x03ff;
-                let ix = self.nametable_page[page] + ofs;
-                self.nametable[ix] = data;
+                if let Some(old) = self.write_nametable(rom, page, ofs, data) {
+                    self.notify_chr_write(addr, old, data);
+                }
             }
             0x3f00..=0x3fff => {
                 let addr = addr & if addr & 3 == 0 { 0x0f } else { 0x1f };
@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+
+use crate::util::Input;
+
+/// Event on the console itself rather than on the controllers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConsoleEvent {
+    /// Pressing the reset button
+    SoftReset,
+    /// Turning the power off and on. Battery backed memory is kept.
+    PowerCycle,
+}
+
+/// Input of a single frame. `event` is applied before the frame is executed.
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct MovieFrame {
+    pub input: Input,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<ConsoleEvent>,
+}
+
+/// Recorded input which starts from power-on
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct Movie {
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a frame with the given input and console event
+    pub fn record(&mut self, input: &Input, event: Option<ConsoleEvent>) {
+        self.frames.push(MovieFrame {
+            input: input.clone(),
+            event,
+        });
+    }
+
+    /// Returns frames which have a console event, with their frame numbers
+    pub fn events(&self) -> impl Iterator<Item = (usize, ConsoleEvent)> + '_ {
+        self.frames
+            .iter()
+            .enumerate()
+            .filter_map(|(i, f)| Some((i, f.event?)))
+    }
+}
@@ -1,21 +1,1101 @@
+use std::sync::Arc;
+
 use bytesize::ByteSize;
 use meru_interface::{CoreInfo, EmulatorCore, KeyConfig};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    consts,
+    apu::{
+        Channel, ChannelPanning, ChannelStreams, ConsoleModel, ExpansionMixLevels,
+        FourPlayerAdapter, Mixer,
+    },
+    consts::{Region, SCREEN_WIDTH},
     context::{self, MemoryController},
-    rom::{self, RomError, RomFormat},
+    cpu::{CpuState, CpuWrite, TraceRecord},
+    debugger::{Breakpoint, BreakpointHit},
+    disasm::{self, Instruction},
+    expansion::ExpansionDevice,
+    header_db::HeaderDatabase,
+    logging::core_log,
+    mapper,
+    memory::{MapperWrite, PowerOnPalette},
+    movie::{ConsoleEvent, MovieFrame},
+    nsf::{NsfInfo, NsfTrack},
+    ntsc::NtscFilter,
+    ppu::{ScrollRect, Sprite0Hit, SpriteInfo},
+    profiler::{ProfileEntry, Profiler},
+    quirks::{self, QuirksMap},
+    rewind::{RewindBuffer, RewindConfig},
+    rom::{self, InputDevice, RomError, RomFormat},
+    savestate::{self, SaveStateFile, SaveStateMetadata, Thumbnail},
     util::{Input, Pad},
 };
 
 pub struct Nes {
     pub ctx: context::Context,
+    config: Config,
+    hard_pause_at: Option<u64>,
+    hard_paused: bool,
+    breakpoint_hit: Option<BreakpointHit>,
+    ntsc_frame: meru_interface::FrameBuffer,
+    rgba_frame: Vec<u8>,
+    rewind: Option<RewindBuffer>,
+}
+
+// Frontends run the emulator on its own thread, so the hooks it holds must be Send
+fn _assert_send()
+where
+    Nes: Send,
+{
+}
+
+#[derive(Default, Clone, JsonSchema, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Trade-off between emulation accuracy and speed
+    pub accuracy: AccuracyProfile,
+    /// Database of correct headers, which override the headers of bad dumps.
+    /// Takes effect when a ROM is loaded.
+    #[serde(skip)]
+    pub header_database: Option<Arc<HeaderDatabase>>,
+    /// Use ROM headers as they are, even for games in `header_database`
+    pub disable_header_database: bool,
+    /// Game-specific overrides of header fields, applied over the header database.
+    /// Takes effect when a ROM is loaded.
+    #[serde(skip)]
+    pub quirks: QuirksMap,
+    /// Console hardware to emulate. Chosen from the input device and the timing mode
+    /// in the ROM header, or in the header database for games in it, when not specified.
+    pub console_model: Option<ConsoleModel>,
+    /// Adapter which connects controllers 3 and 4.
+    /// Chosen from the expansion device of NES 2.0 headers when not specified.
+    pub four_player_adapter: Option<FourPlayerAdapter>,
+    /// Video timing (NTSC or PAL). Chosen from the ROM header when not specified.
+    /// Takes effect at the next power-on or reset.
+    pub region: Option<Region>,
+    /// Mixer of the APU channels. Chosen by `accuracy` when not specified.
+    pub mixer: Option<Mixer>,
+    /// Output audio without the high-pass and low-pass filters of the console
+    pub disable_audio_filters: bool,
+    /// Silence the triangle channel when games park it at an ultrasonic frequency,
+    /// instead of playing it through the low-pass filtering like the hardware
+    pub mute_ultrasonic_triangle: bool,
+    /// Mixing levels of cartridge expansion audio
+    pub expansion_audio: ExpansionMixLevels,
+    /// Stereo position of each sound channel for a pseudo-stereo mix
+    pub panning: ChannelPanning,
+    /// Master volume of the audio output in dB
+    pub volume: f32,
+    /// Compress loud peaks, such as loud expansion audio, instead of clipping them
+    pub soft_clipping: bool,
+    /// Also record the output of each sound channel for `channel_streams`
+    pub channel_streams: bool,
+    /// DIP switches of Vs. System games (bit 0 is switch 1)
+    pub vs_dip_switches: u8,
+    /// Palette RAM contents at power-on. Takes effect at the next power-on or reset.
+    pub power_on_palette: PowerOnPalette,
+    /// Lines and columns cropped from the edges by `cropped_frame_buffer`
+    pub overscan: Overscan,
+    /// Post-process frames with the NTSC composite video filter, which makes them wider
+    pub ntsc_filter: Option<NtscFilter>,
+    /// Draw all sprites on each line instead of the first 8 like the hardware, which reduces flicker
+    pub disable_sprite_limit: bool,
+    /// Also store frames as packed RGBA8888 bytes for `frame_buffer_rgba`
+    pub rgba_output: bool,
+    /// Value which the unstable opcodes XAA (ANE, $8B) and ATX (LXA, $AB) OR into A,
+    /// which varies between CPUs. $FF when not specified.
+    pub unstable_opcode_magic: Option<u8>,
+    /// Frames which turbo buttons stay pressed and then released.
+    /// 2 when not specified, which fires 15 times a second on NTSC.
+    pub turbo_period: Option<u32>,
+    /// Capture states periodically for `Nes::rewind_frames`. Disabled when not specified.
+    pub rewind: Option<RewindConfig>,
+}
+
+/// Number of pixels hidden at each edge of the screen.
+/// TVs typically hide about 8 lines at the top and bottom.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug, JsonSchema, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
 }
 
-#[derive(Default, JsonSchema, Serialize, Deserialize)]
-pub struct Config {}
+impl Overscan {
+    /// Returns a copy of `fb` without the overscan area.
+    /// Columns are scaled for frames wider than the screen, such as NTSC filtered ones.
+    pub fn crop(&self, fb: &meru_interface::FrameBuffer) -> meru_interface::FrameBuffer {
+        let scale = (fb.width / SCREEN_WIDTH).max(1);
+        let left = (self.left * scale).min(fb.width);
+        let top = self.top.min(fb.height);
+        let width = fb.width - left - (self.right * scale).min(fb.width - left);
+        let height = fb.height - top - self.bottom.min(fb.height - top);
+
+        let mut ret = meru_interface::FrameBuffer::new(width, height);
+        for y in 0..height {
+            let src = (top + y) * fb.width + left;
+            ret.buffer[y * width..(y + 1) * width].clone_from_slice(&fb.buffer[src..src + width]);
+        }
+        ret
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug, JsonSchema, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccuracyProfile {
+    Fast,
+    #[default]
+    Accurate,
+    /// Accurate, plus internal consistency checks which panic on failure
+    Debug,
+}
+
+/// Emulation features which are enabled or disabled by `AccuracyProfile`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub struct AccuracyFeatures {
+    /// Render the background with per-dot fetches instead of per-line
+    pub dot_renderer: bool,
+    /// Mix APU channels with the non-linear DAC formula instead of the linear approximation
+    pub nonlinear_mixer: bool,
+    /// Emulate bus conflicts caused by DMC DMA
+    pub dmc_dma_conflicts: bool,
+    /// Return open bus values for unmapped reads
+    pub open_bus: bool,
+    /// Check internal invariants of the emulator
+    pub sanity_checks: bool,
+}
+
+impl AccuracyProfile {
+    pub fn features(&self) -> AccuracyFeatures {
+        let accurate = *self != AccuracyProfile::Fast;
+        AccuracyFeatures {
+            dot_renderer: accurate,
+            nonlinear_mixer: accurate,
+            dmc_dma_conflicts: accurate,
+            open_bus: accurate,
+            sanity_checks: *self == AccuracyProfile::Debug,
+        }
+    }
+}
+
+impl Nes {
+    /// Returns the console model in use
+    pub fn console_model(&self) -> ConsoleModel {
+        use context::Rom;
+
+        let rom = self.ctx.rom();
+        self.config.console_model.unwrap_or_else(|| {
+            match (rom.input_device(), rom.timing_mode) {
+                // Games for devices on the Famicom expansion port
+                (
+                    InputDevice::FamicomFourPlayers
+                    | InputDevice::FamilyTrainer
+                    | InputDevice::ArkanoidFamicom
+                    | InputDevice::FamilyBasicKeyboard,
+                    _,
+                ) => ConsoleModel::Famicom,
+                // Dendy clones are wired like a Famicom
+                (_, rom::TimingMode::Dendy) => ConsoleModel::Famicom,
+                _ => ConsoleModel::default(),
+            }
+        })
+    }
+
+    /// Returns the four player adapter in use
+    pub fn four_player_adapter(&self) -> FourPlayerAdapter {
+        use context::Rom;
+
+        self.config
+            .four_player_adapter
+            .unwrap_or_else(|| match self.ctx.rom().input_device() {
+                InputDevice::FourScore => FourPlayerAdapter::FourScore,
+                InputDevice::FamicomFourPlayers => FourPlayerAdapter::Famicom,
+                _ => FourPlayerAdapter::None,
+            })
+    }
+
+    /// Returns the video timing in use
+    pub fn region(&self) -> Region {
+        use context::Timing;
+        self.ctx.region()
+    }
+
+    /// Returns the emulation features enabled by the current accuracy profile
+    pub fn accuracy_features(&self) -> AccuracyFeatures {
+        self.config.accuracy.features()
+    }
+
+    /// Dumps the current emulation state as a JSON string for bug reports
+    pub fn debug_dump(&self) -> String {
+        use context::{Cpu, Interrupt, IrqSource, Mapper, Ppu, Timing};
+
+        let dump = serde_json::json!({
+            "cpu": self.ctx.cpu().debug_dump(),
+            "ppu": self.ctx.ppu().debug_dump(),
+            "mapper": self.ctx.mapper(),
+            "memory": self.ctx.memory_ctrl().debug_dump(),
+            "mapper_writes": self.mapper_write_log(),
+            "interrupt": {
+                "nmi": self.ctx.nmi(),
+                "irq_apu_frame": self.irq_asserted(IrqSource::ApuFrame),
+                "irq_apu_dmc": self.irq_asserted(IrqSource::ApuDmc),
+                "irq_mapper": self.irq_asserted(IrqSource::Mapper),
+            },
+            "cpu_cycle": self.ctx.now(),
+        });
+
+        serde_json::to_string_pretty(&dump).unwrap()
+    }
+
+    /// Returns whether the CPU has stopped at a KIL opcode. Games which crash this way
+    /// stay frozen until reset like on the hardware.
+    pub fn cpu_jammed(&self) -> bool {
+        use context::Cpu;
+        self.ctx.cpu().jammed()
+    }
+
+    /// Returns the CPU registers between instructions
+    pub fn cpu_state(&self) -> CpuState {
+        use context::Cpu;
+        self.ctx.cpu().state()
+    }
+
+    /// Patches the CPU registers, which the next instruction runs with.
+    /// `cycle` of `state` is ignored.
+    pub fn set_cpu_state(&mut self, state: &CpuState) {
+        use context::Cpu;
+        self.ctx.cpu_mut().set_state(state);
+    }
+
+    /// Returns whether the IRQ line of `source` is currently asserted
+    pub fn irq_asserted(&self, source: context::IrqSource) -> bool {
+        use context::Interrupt;
+        self.ctx.irq_source(source)
+    }
+
+    /// Acknowledges a pending IRQ without running game code.
+    /// APU sources are cleared like $4015 does (frame IRQ on read, DMC IRQ on write)
+    /// without the other side effects of accessing the register.
+    /// Mapper IRQs are released, but the mapper may assert them again on its next event.
+    pub fn acknowledge_irq(&mut self, source: context::IrqSource) {
+        use context::{Apu, Interrupt};
+        if source == context::IrqSource::ApuFrame {
+            self.ctx.acknowledge_frame_irq();
+        } else {
+            self.ctx.set_irq_source(source, false);
+        }
+    }
+
+    /// Returns the console the ROM is made for.
+    /// Frontends can use this to warn that arcade hardware is only partially emulated.
+    pub fn console_type(&self) -> rom::ConsoleType {
+        use context::Rom;
+        self.ctx.rom().console_type.clone()
+    }
+
+    /// Returns the header of the loaded NSF file, or `None` for cartridge ROMs
+    pub fn nsf_info(&self) -> Option<&NsfInfo> {
+        use context::Rom;
+        self.ctx.rom().nsf.as_ref()
+    }
+
+    /// Returns the NSF track being played, counted from 0
+    pub fn nsf_track(&self) -> Option<u8> {
+        use context::Mapper;
+        match self.ctx.mapper() {
+            mapper::Mapper::Nsf(nsf) => nsf.song(),
+            _ => None,
+        }
+    }
+
+    /// Returns the metadata of the NSF track being played, such as its name and length.
+    /// Players can move to the next track after its length and fade-out.
+    pub fn nsf_track_info(&self) -> Option<&NsfTrack> {
+        let track = self.nsf_track()?;
+        self.nsf_info()?.tracks.get(track as usize)
+    }
+
+    /// Starts playing an NSF track from the beginning. Tracks out of range wrap around.
+    /// RAM is cleared and the player runs the init routine of the track again.
+    pub fn select_nsf_track(&mut self, track: u8) {
+        use context::{Bus, Interrupt, Mapper};
+
+        let Some(song_count) = self.nsf_info().map(|info| info.song_count) else {
+            return;
+        };
+        if let mapper::Mapper::Nsf(nsf) = self.ctx.mapper_mut() {
+            nsf.set_song(track % song_count);
+        }
+        self.ctx.set_irq_source(context::IrqSource::Mapper, false);
+
+        for addr in 0..0x800 {
+            self.ctx.write(addr, 0);
+        }
+        self.prg_ram_mut().fill(0);
+        self.soft_reset();
+    }
+
+    /// Scans a barcode with the barcode reader of the Datach Joint ROM System.
+    /// Returns false if the cartridge has no barcode reader or the code is not
+    /// an EAN-13 or EAN-8 code.
+    pub fn scan_barcode(&mut self, code: &str) -> bool {
+        use context::Mapper;
+        match self.ctx.mapper_mut() {
+            mapper::Mapper::Datach(datach) => datach.scan_barcode(code),
+            _ => false,
+        }
+    }
+
+    /// Goes back `frames` frames with the states captured for rewinding, or as far as
+    /// possible. Lands on the newest captured state at or before the frame.
+    /// Returns the number of frames actually rewound.
+    pub fn rewind_frames(&mut self, frames: u64) -> Result<u64, Error> {
+        use context::Ppu;
+
+        let current = self.ctx.ppu().frame();
+        let Some((frame, state)) = self
+            .rewind
+            .as_mut()
+            .and_then(|rewind| rewind.rewind_to(current.saturating_sub(frames)))
+        else {
+            return Ok(0);
+        };
+        let ctx = bincode::deserialize(state)?;
+        self.restore_context(ctx);
+        Ok(current.saturating_sub(frame))
+    }
+
+    /// Saves the state with metadata and a half size thumbnail of the current frame
+    pub fn save_state_file(&self) -> SaveStateFile {
+        use context::{Ppu, Rom};
+
+        SaveStateFile {
+            metadata: SaveStateMetadata {
+                core_version: env!("CARGO_PKG_VERSION").to_string(),
+                rom_crc32: self.ctx.rom().prg_chr_crc32(),
+                timestamp: chrono::Utc::now().timestamp(),
+                frame: self.ctx.ppu().frame(),
+                thumbnail: Thumbnail::from_frame(self.ctx.ppu().frame_buffer(), 2),
+            },
+            state: self.save_state(),
+        }
+    }
+
+    /// Checks that the save state was made with the loaded ROM
+    pub fn validate_state(&self, metadata: &SaveStateMetadata) -> Result<(), Error> {
+        use context::Rom;
+
+        let crc = self.ctx.rom().prg_chr_crc32();
+        if metadata.rom_crc32 != crc {
+            Err(Error::SaveStateRomMismatch(metadata.rom_crc32, crc))?
+        }
+        Ok(())
+    }
+
+    /// Loads the save state after checking that it was made with the loaded ROM
+    pub fn load_state_file(&mut self, file: &SaveStateFile) -> Result<(), Error> {
+        self.validate_state(&file.metadata)?;
+        self.load_state(&file.state)
+    }
+
+    /// Plays the next NSF track, wrapping around after the last one
+    pub fn next_nsf_track(&mut self) {
+        if let (Some(track), Some(info)) = (self.nsf_track(), self.nsf_info()) {
+            self.select_nsf_track((track + 1) % info.song_count);
+        }
+    }
+
+    /// Plays the previous NSF track, wrapping around before the first one
+    pub fn prev_nsf_track(&mut self) {
+        if let (Some(track), Some(info)) = (self.nsf_track(), self.nsf_info()) {
+            let count = info.song_count;
+            self.select_nsf_track(track.checked_sub(1).unwrap_or(count - 1));
+        }
+    }
+
+    /// Hands off the last completed frame, if a new one is available since the last call.
+    /// Useful for frontends which present frames on another thread.
+    /// The frame is not processed by the NTSC filter.
+    pub fn take_completed_frame(&mut self) -> Option<meru_interface::FrameBuffer> {
+        use context::Ppu;
+        self.ctx.ppu_mut().take_completed_frame()
+    }
+
+    /// Returns the current frame without the overscan area configured in `Config`
+    pub fn cropped_frame_buffer(&self) -> meru_interface::FrameBuffer {
+        self.config.overscan.crop(self.frame_buffer())
+    }
+
+    /// Returns the last completed frame as raw 256x240 pixels before palette lookup and filtering.
+    /// Each pixel is `emphasis << 6 | palette index`, where `emphasis` is bits 5-7 of PPUMASK.
+    pub fn index_buffer(&self) -> &[u16] {
+        use context::Ppu;
+        self.ctx.ppu().index_buffer()
+    }
+
+    /// Returns the pixels of `frame_buffer()` as packed RGBA8888 bytes with opaque alpha.
+    /// Empty unless `Config::rgba_output` is set.
+    pub fn frame_buffer_rgba(&self) -> &[u8] {
+        &self.rgba_frame
+    }
+
+    /// Renders the four nametables into a 512x480 frame for debuggers
+    pub fn render_nametables(&self) -> meru_interface::FrameBuffer {
+        use context::Ppu;
+        let mut fb = meru_interface::FrameBuffer::default();
+        self.ctx.ppu().render_nametables(&self.ctx, &mut fb);
+        fb
+    }
+
+    /// Decodes all 64 sprites in OAM for debuggers
+    pub fn sprites(&self) -> Vec<SpriteInfo> {
+        use context::Ppu;
+        self.ctx.ppu().sprites(&self.ctx)
+    }
+
+    /// Enables or mutes an audio channel. Only the mix is affected, so emulation stays the same.
+    pub fn set_channel_enabled(&mut self, channel: Channel, enable: bool) {
+        use context::Apu;
+        self.ctx.apu_mut().set_channel_enabled(channel, enable);
+    }
+
+    pub fn channel_enabled(&self, channel: Channel) -> bool {
+        use context::Apu;
+        self.ctx.apu().channel_enabled(channel)
+    }
+
+    /// Mutes all audio channels except `channel`, or enables all of them with `None`
+    pub fn solo_channel(&mut self, channel: Option<Channel>) {
+        for ch in Channel::ALL {
+            self.set_channel_enabled(ch, channel.is_none_or(|c| c == ch));
+        }
+    }
+
+    /// Returns the samples of each channel for the current frame, which are recorded
+    /// along with `audio_buffer` when `Config::channel_streams` is set
+    pub fn channel_streams(&self) -> Option<&ChannelStreams> {
+        use context::Apu;
+        self.ctx.apu().channel_streams()
+    }
+
+    /// Returns the area of `render_nametables` shown on the screen
+    pub fn scroll_rect(&self) -> ScrollRect {
+        use context::Ppu;
+        self.ctx.ppu().scroll_rect()
+    }
+
+    /// Returns where sprite 0 hit occurred in the last completed frame
+    pub fn sprite0_hit(&self) -> Option<Sprite0Hit> {
+        use context::Ppu;
+        self.ctx.ppu().prev_sprite0_hit_position()
+    }
+
+    /// Runs a frame like `exec_frame`, but converts a panic caused by an emulation bug
+    /// into `Error::EmulationPanic` instead of unwinding into the host application.
+    /// After an error, the state is inconsistent and should be discarded by `reset` or `load_state`.
+    pub fn try_exec_frame(&mut self, render_graphics: bool) -> Result<(), Error> {
+        use context::{Cpu, Ppu, Rom};
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            self.exec_frame(render_graphics)
+        }));
+
+        result.map_err(|payload| {
+            let message = if let Some(s) = payload.downcast_ref::<&str>() {
+                s.to_string()
+            } else if let Some(s) = payload.downcast_ref::<String>() {
+                s.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            Error::EmulationPanic {
+                message,
+                pc: self.ctx.cpu().pc(),
+                frame: self.ctx.ppu().frame(),
+                mapper_id: self.ctx.rom().mapper_id,
+            }
+        })
+    }
+
+    /// Returns the number of CPU cycles since power-on
+    pub fn cpu_cycle(&self) -> u64 {
+        use context::Timing;
+        self.ctx.now()
+    }
+
+    /// Stops `exec_frame` when the CPU cycle counter reaches `cycle`, leaving the frame
+    /// unfinished. The next `exec_frame` resumes from there and completes the frame.
+    /// A cycle which has already passed stops the next `exec_frame` right away.
+    ///
+    /// The CPU can stop in the middle of an instruction. Its registers then read as they
+    /// were before the instruction, and the stopped instruction is kept in save states.
+    pub fn set_hard_pause(&mut self, cycle: Option<u64>) {
+        self.hard_pause_at = cycle;
+    }
+
+    /// Returns true if the last `exec_frame` was stopped by a hard pause
+    pub fn is_hard_paused(&self) -> bool {
+        self.hard_paused
+    }
+
+    /// Runs a single instruction, including the interrupt entry which follows it.
+    /// A breakpoint can stop it before the instruction, and a hard pause in the middle.
+    /// An instruction stopped by a hard pause is completed instead.
+    pub fn step_instruction(&mut self) {
+        use context::Cpu;
+
+        self.ctx.cpu_mut().sync_clock();
+        let mut ticked = false;
+        self.run(|_| std::mem::replace(&mut ticked, true));
+    }
+
+    /// Runs until the PPU enters `line`, where the pre-render line is the last one.
+    /// If the PPU is already on the line, it runs until the line of the next frame.
+    /// The CPU executes an instruction at once, so it stops at the end of the instruction
+    /// running when the line starts.
+    pub fn run_to_scanline(&mut self, line: usize) {
+        use context::{Ppu, Timing};
+
+        assert!(
+            line < self.ctx.region().lines_per_frame(),
+            "scanline {line} is out of range"
+        );
+
+        let mut prev = self.ctx.ppu().line();
+        self.run(|nes| {
+            let cur = nes.ctx.ppu().line();
+            let entered = cur == line && prev != line;
+            prev = cur;
+            entered
+        });
+    }
+
+    /// Runs for `cycles` CPU cycles, stopping at the end of the last instruction
+    pub fn run_cpu_cycles(&mut self, cycles: u64) {
+        let end = self.cpu_cycle() + cycles;
+        self.run(|nes| nes.cpu_cycle() >= end);
+    }
+
+    /// Presses the reset button. Unlike `reset`, RAM and most of the hardware state is kept.
+    pub fn soft_reset(&mut self) {
+        use context::{Apu, Cpu, Ppu};
+
+        // Reset silences all channels like writing 0 to $4015
+        self.ctx.write_apu(0x4015, 0);
+        self.ctx.ppu_mut().soft_reset();
+        self.ctx.reset_cpu();
+    }
+
+    /// Applies a console event. Movie playback goes through this too.
+    pub fn apply_event(&mut self, event: ConsoleEvent) {
+        match event {
+            ConsoleEvent::SoftReset => self.soft_reset(),
+            ConsoleEvent::PowerCycle => self.reset(),
+        }
+    }
+
+    /// Returns the controller input used for the current frame
+    pub fn input(&self) -> &Input {
+        use context::Apu;
+        self.ctx.apu().input()
+    }
+
+    /// Applies the event and input of a movie frame, then runs the frame
+    pub fn play_movie_frame(&mut self, frame: &MovieFrame, render_graphics: bool) {
+        use context::Apu;
+
+        if let Some(event) = frame.event {
+            self.apply_event(event);
+        }
+        self.ctx.apu_mut().set_input(&frame.input);
+        self.exec_frame(render_graphics);
+    }
+
+    /// Replaces the running ROM with new ROM data and restarts emulation.
+    /// If `preserve_prg_ram` is set, the current PRG RAM contents are carried over
+    /// when the new ROM has the same PRG RAM layout.
+    pub fn reload_rom(&mut self, data: &[u8], preserve_prg_ram: bool) -> Result<(), Error> {
+        let rom = load_rom(data, &self.config)?;
+
+        let backup = preserve_prg_ram
+            .then(|| self.ctx.memory_ctrl().backup())
+            .filter(|backup| {
+                let (actual, expected) = (backup.len(), rom.backup_size());
+                if actual != expected {
+                    core_log!(
+                        Rom,
+                        Warn,
+                        "PRG RAM not preserved: size changed from {actual} to {expected}"
+                    );
+                }
+                actual == expected
+            });
+        let mut ctx = context::Context::new(rom, backup)?;
+
+        self.inherit_host_state(&mut ctx);
+        self.ctx = ctx;
+        self.apply_config();
+        self.power_on();
+        Ok(())
+    }
+
+    /// Returns PRG RAM (SRAM) for inspection by save editors
+    pub fn prg_ram(&self) -> &[u8] {
+        self.ctx.memory_ctrl().prg_ram()
+    }
+
+    /// Returns PRG RAM for live modification (e.g. cheats).
+    /// Battery backed contents are persisted through `backup()` as usual.
+    pub fn prg_ram_mut(&mut self) -> &mut [u8] {
+        self.ctx.memory_ctrl_mut().prg_ram_mut()
+    }
+
+    pub fn chr_ram(&self) -> &[u8] {
+        self.ctx.memory_ctrl().chr_ram()
+    }
+
+    pub fn chr_ram_mut(&mut self) -> &mut [u8] {
+        self.ctx.memory_ctrl_mut().chr_ram_mut()
+    }
+
+    /// Sets a callback invoked with (address, old value, new value)
+    /// whenever a CHR RAM or nametable byte changes
+    pub fn set_chr_write_hook(&mut self, hook: impl FnMut(u16, u8, u8) + Send + 'static) {
+        self.ctx
+            .memory_ctrl_mut()
+            .set_chr_write_hook(Some(Box::new(hook)));
+    }
+
+    pub fn clear_chr_write_hook(&mut self) {
+        self.ctx.memory_ctrl_mut().set_chr_write_hook(None);
+    }
+
+    /// Sets a callback invoked with the PPU on every line, before the PPU processes `dot` (0 to 340).
+    /// Dot 0 is the start of the line.
+    pub fn set_scanline_hook(
+        &mut self,
+        dot: usize,
+        hook: impl FnMut(&crate::ppu::Ppu) + Send + 'static,
+    ) {
+        use context::Ppu;
+        self.ctx
+            .ppu_mut()
+            .set_scanline_hook(Some((dot, Box::new(hook))));
+    }
+
+    pub fn clear_scanline_hook(&mut self) {
+        use context::Ppu;
+        self.ctx.ppu_mut().set_scanline_hook(None);
+    }
+
+    /// Sets a callback invoked with the instruction, registers and PPU position
+    /// before the CPU executes each instruction
+    pub fn set_trace_hook(&mut self, hook: impl FnMut(&TraceRecord) + Send + 'static) {
+        use context::Cpu;
+        self.ctx.cpu_mut().set_trace_hook(Some(Box::new(hook)));
+    }
+
+    pub fn clear_trace_hook(&mut self) {
+        use context::Cpu;
+        self.ctx.cpu_mut().set_trace_hook(None);
+    }
+
+    /// Sets a callback queried for the input each time the game strobes the
+    /// controllers, instead of the input given by `set_input` once a frame
+    pub fn set_input_provider(&mut self, provider: impl FnMut(u64) -> Input + Send + 'static) {
+        use context::Apu;
+        self.ctx
+            .apu_mut()
+            .set_input_provider(Some(Box::new(provider)));
+    }
+
+    pub fn clear_input_provider(&mut self) {
+        use context::Apu;
+        self.ctx.apu_mut().set_input_provider(None);
+    }
+
+    /// Plugs a device into the expansion port, replacing the current one
+    pub fn set_expansion_device(&mut self, device: impl ExpansionDevice + 'static) {
+        use context::Apu;
+        self.ctx
+            .apu_mut()
+            .set_expansion_device(Some(Box::new(device)));
+    }
+
+    /// Unplugs the expansion port device and returns it
+    pub fn take_expansion_device(&mut self) -> Option<Box<dyn ExpansionDevice>> {
+        use context::Apu;
+        self.ctx.apu_mut().take_expansion_device()
+    }
+
+    /// Starts or stops counting the CPU cycles spent on each instruction.
+    /// Stopping discards the counts.
+    pub fn set_profiling(&mut self, enable: bool) {
+        use context::Cpu;
+        if enable != self.ctx.cpu().profiler().is_some() {
+            let profiler = enable.then(Profiler::default);
+            self.ctx.cpu_mut().set_profiler(profiler);
+        }
+    }
+
+    /// Returns the cycles spent on each instruction since profiling started,
+    /// the most expensive first
+    pub fn profile_report(&self) -> Vec<ProfileEntry> {
+        use context::Cpu;
+        self.ctx
+            .cpu()
+            .profiler()
+            .map(|p| p.report())
+            .unwrap_or_default()
+    }
+
+    pub fn clear_profile(&mut self) {
+        use context::Cpu;
+        if let Some(profiler) = self.ctx.cpu_mut().profiler_mut() {
+            profiler.clear();
+        }
+    }
+
+    /// Starts recording the addresses of the last `capacity` executed instructions
+    /// (0 stops recording)
+    pub fn set_pc_history_capacity(&mut self, capacity: usize) {
+        use context::Cpu;
+        self.ctx.cpu_mut().history_mut().set_pc_capacity(capacity);
+    }
+
+    /// Returns the addresses of recently executed instructions from oldest to newest
+    pub fn pc_history(&self) -> Vec<u16> {
+        use context::Cpu;
+        self.ctx.cpu().history().pcs().collect()
+    }
+
+    /// Starts recording the last `capacity` writes made by the CPU (0 stops recording).
+    /// DMA writes are not recorded.
+    pub fn set_write_history_capacity(&mut self, capacity: usize) {
+        use context::Cpu;
+        self.ctx
+            .cpu_mut()
+            .history_mut()
+            .set_write_capacity(capacity);
+    }
+
+    /// Returns recent CPU writes from oldest to newest
+    pub fn write_history(&self) -> Vec<CpuWrite> {
+        use context::Cpu;
+        self.ctx.cpu().history().writes().copied().collect()
+    }
+
+    pub fn clear_history(&mut self) {
+        use context::Cpu;
+        self.ctx.cpu_mut().history_mut().clear();
+    }
+
+    /// Starts recording the last `capacity` mapper register writes (0 stops recording).
+    /// Writes to $6000-$7FFF are not recorded.
+    pub fn set_mapper_write_log_capacity(&mut self, capacity: usize) {
+        self.ctx
+            .memory_ctrl_mut()
+            .mapper_write_log_mut()
+            .set_capacity(capacity);
+    }
+
+    /// Returns recorded mapper register writes from oldest to newest
+    pub fn mapper_write_log(&self) -> Vec<MapperWrite> {
+        self.ctx
+            .memory_ctrl()
+            .mapper_write_log()
+            .entries()
+            .copied()
+            .collect()
+    }
+
+    pub fn clear_mapper_write_log(&mut self) {
+        self.ctx.memory_ctrl_mut().mapper_write_log_mut().clear();
+    }
+
+    /// Decodes up to `count` instructions from `addr` in CPU memory.
+    /// It stops at I/O registers, which can't be read without side effects.
+    pub fn disassemble(&self, addr: u16, count: usize) -> Vec<Instruction> {
+        disasm::disassemble_memory(&self.ctx, addr, count)
+    }
+
+    /// Sets a breakpoint, which stops `exec_frame` in the middle of a frame.
+    /// Execution breakpoints stop before the instruction and the others after the instruction
+    /// which made the access. The next `exec_frame` resumes from there.
+    /// Returns the ID to remove it with.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.ctx
+            .memory_ctrl_mut()
+            .debugger_mut()
+            .add_breakpoint(breakpoint)
+    }
+
+    /// Returns false if no breakpoint has the ID
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        self.ctx
+            .memory_ctrl_mut()
+            .debugger_mut()
+            .remove_breakpoint(id)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.ctx
+            .memory_ctrl_mut()
+            .debugger_mut()
+            .clear_breakpoints();
+    }
+
+    /// Returns the breakpoints with their IDs
+    pub fn breakpoints(&self) -> Vec<(usize, Breakpoint)> {
+        self.ctx
+            .memory_ctrl()
+            .debugger()
+            .breakpoints()
+            .map(|(id, bp)| (id, bp.clone()))
+            .collect()
+    }
+
+    /// Returns the breakpoint which stopped the last `exec_frame`, if any
+    pub fn breakpoint_hit(&self) -> Option<BreakpointHit> {
+        self.breakpoint_hit
+    }
+
+    /// Holds the coin switch of a Vs. System coin slot (0 or 1) for a few frames
+    pub fn insert_coin(&mut self, slot: usize) {
+        use context::Apu;
+        if let Some(vs) = self.ctx.apu_mut().vs_switches_mut() {
+            vs.insert_coin(slot);
+        }
+    }
+
+    pub fn set_service_button(&mut self, pressed: bool) {
+        use context::Apu;
+        if let Some(vs) = self.ctx.apu_mut().vs_switches_mut() {
+            vs.service = pressed;
+        }
+    }
+
+    /// Sets the Vs. System DIP switches. The setting is kept across resets.
+    pub fn set_dip_switches(&mut self, dip_switches: u8) {
+        self.config.vs_dip_switches = dip_switches;
+        self.apply_config();
+    }
+
+    /// Returns the pattern palette RAM was initialized with at the last power-on
+    pub fn power_on_palette(&self) -> &PowerOnPalette {
+        self.ctx.memory_ctrl().power_on_palette()
+    }
+
+    /// Initializes the state which is set up at power-on rather than by the context
+    fn power_on(&mut self) {
+        use context::Cpu;
+
+        if let Some(region) = self.config.region {
+            self.ctx.set_region(region);
+        }
+        self.ctx
+            .memory_ctrl_mut()
+            .set_power_on_palette(&self.config.power_on_palette);
+        self.ctx.reset_cpu();
+        self.hard_paused = false;
+        self.breakpoint_hit = None;
+    }
+
+    /// Ticks the CPU until `done` returns true, or a hard pause or a breakpoint stops it.
+    /// Frames completed on the way are output.
+    fn run(&mut self, mut done: impl FnMut(&Self) -> bool) {
+        use context::{Cpu, Ppu};
+
+        self.hard_paused = false;
+        self.breakpoint_hit = None;
+
+        // Accesses made by the host between runs don't stop emulation
+        let _ = self.ctx.memory_ctrl_mut().debugger_mut().take_hit();
+        self.ctx.cpu_mut().set_pause(self.hard_pause_at);
+
+        while !done(self) {
+            if matches!(self.hard_pause_at, Some(cycle) if self.cpu_cycle() >= cycle) {
+                self.hard_pause_at = None;
+                self.hard_paused = true;
+                return;
+            }
+
+            let frame = self.ctx.ppu().frame();
+            self.ctx.tick_cpu();
+            if frame != self.ctx.ppu().frame() {
+                self.end_frame();
+            }
+
+            if self.ctx.cpu_mut().take_pause_hit() {
+                self.hard_pause_at = None;
+                self.hard_paused = true;
+                return;
+            }
+
+            if let Some(hit) = self.ctx.memory_ctrl_mut().debugger_mut().take_hit() {
+                self.breakpoint_hit = Some(hit);
+                return;
+            }
+        }
+    }
+
+    fn end_frame(&mut self) {
+        use context::{Apu, Ppu};
+
+        self.ctx.apu_mut().end_frame();
+        if let Some(vs) = self.ctx.apu_mut().vs_switches_mut() {
+            vs.end_frame();
+        }
+
+        let render_graphics = self.ctx.ppu().render_graphics();
+        if let Some(filter) = &self.config.ntsc_filter {
+            if render_graphics {
+                let ppu = self.ctx.ppu();
+                filter.apply(ppu.index_buffer(), ppu.frame(), &mut self.ntsc_frame);
+            }
+        }
+
+        if !self.config.rgba_output {
+            self.rgba_frame = vec![];
+        } else if render_graphics {
+            let mut rgba = std::mem::take(&mut self.rgba_frame);
+            rgba.clear();
+            for c in &self.frame_buffer().buffer {
+                rgba.extend_from_slice(&[c.r, c.g, c.b, 0xff]);
+            }
+            self.rgba_frame = rgba;
+        }
+
+        let frame = self.ctx.ppu().frame();
+        if matches!(&self.rewind, Some(rewind) if rewind.should_capture(frame)) {
+            // Captured every frame, so with bincode which is much faster than the
+            // versioned format. These states never outlive the process.
+            let state = bincode::serialize(&self.ctx).unwrap();
+            self.rewind.as_mut().unwrap().push(frame, state);
+        }
+    }
+
+    /// Replaces the emulation state with a decoded one, keeping the ROM and the host state
+    fn restore_context(&mut self, mut ctx: context::Context) {
+        use context::Rom;
+        std::mem::swap(ctx.rom_mut(), self.ctx.rom_mut());
+        self.inherit_host_state(&mut ctx);
+        self.ctx = ctx;
+        self.apply_config();
+        self.hard_paused = false;
+        self.breakpoint_hit = None;
+    }
+
+    fn apply_config(&mut self) {
+        use context::{Apu, Cpu, Ppu};
+
+        let console_model = self.console_model();
+        self.ctx.apu_mut().set_console_model(console_model);
+        let four_player_adapter = self.four_player_adapter();
+        self.ctx
+            .apu_mut()
+            .set_four_player_adapter(four_player_adapter);
+        let turbo_period = self.config.turbo_period.unwrap_or(2);
+        self.ctx.apu_mut().set_turbo_period(turbo_period);
+
+        let features = self.accuracy_features();
+        let mixer = self.config.mixer.unwrap_or(if features.nonlinear_mixer {
+            Mixer::Nonlinear
+        } else {
+            Mixer::Linear
+        });
+        self.ctx.apu_mut().set_mixer(mixer);
+        self.ctx
+            .apu_mut()
+            .set_filters_enabled(!self.config.disable_audio_filters);
+        self.ctx
+            .apu_mut()
+            .set_mute_ultrasonic_triangle(self.config.mute_ultrasonic_triangle);
+        self.ctx
+            .apu_mut()
+            .set_dmc_dma_conflicts(features.dmc_dma_conflicts);
+        self.ctx.apu_mut().set_open_bus_enabled(features.open_bus);
+        self.ctx.ppu_mut().set_sanity_checks(features.sanity_checks);
+        self.ctx.ppu_mut().set_dot_renderer(features.dot_renderer);
+        self.ctx
+            .ppu_mut()
+            .set_unlimited_sprites(self.config.disable_sprite_limit);
+
+        self.ctx
+            .apu_mut()
+            .set_expansion_levels(&self.config.expansion_audio);
+        self.ctx.apu_mut().set_panning(&self.config.panning);
+        self.ctx.apu_mut().set_volume(self.config.volume);
+        self.ctx
+            .apu_mut()
+            .set_soft_clipping(self.config.soft_clipping);
+        self.ctx
+            .apu_mut()
+            .set_channel_streams_enabled(self.config.channel_streams);
+
+        if let Some(vs) = self.ctx.apu_mut().vs_switches_mut() {
+            vs.dip_switches = self.config.vs_dip_switches;
+        }
+
+        let magic = self.config.unstable_opcode_magic.unwrap_or(0xFF);
+        self.ctx.cpu_mut().set_unstable_opcode_magic(magic);
+
+        match &self.config.rewind {
+            None => self.rewind = None,
+            Some(config) if self.rewind.as_ref().map(|r| r.config()) != Some(config) => {
+                self.rewind = Some(RewindBuffer::new(config.clone()));
+            }
+            Some(_) => (),
+        }
+    }
+
+    /// Moves host side resources which are not a part of emulation state to a new context
+    fn inherit_host_state(&mut self, ctx: &mut context::Context) {
+        use context::{Apu, Cpu, Mapper, Ppu};
+
+        let hook = self.ctx.cpu_mut().take_trace_hook();
+        ctx.cpu_mut().set_trace_hook(hook);
+
+        let profiler = self.ctx.cpu_mut().take_profiler();
+        ctx.cpu_mut().set_profiler(profiler);
+
+        std::mem::swap(
+            self.ctx.cpu_mut().history_mut(),
+            ctx.cpu_mut().history_mut(),
+        );
+
+        let hook = self.ctx.memory_ctrl_mut().take_chr_write_hook();
+        ctx.memory_ctrl_mut().set_chr_write_hook(hook);
+
+        let hook = self.ctx.ppu_mut().take_scanline_hook();
+        ctx.ppu_mut().set_scanline_hook(hook);
+
+        std::mem::swap(
+            self.ctx.memory_ctrl_mut().mapper_write_log_mut(),
+            ctx.memory_ctrl_mut().mapper_write_log_mut(),
+        );
+        std::mem::swap(
+            self.ctx.memory_ctrl_mut().debugger_mut(),
+            ctx.memory_ctrl_mut().debugger_mut(),
+        );
+
+        let provider = self.ctx.apu_mut().take_input_provider();
+        ctx.apu_mut().set_input_provider(provider);
+
+        let device = self.ctx.apu_mut().take_expansion_device();
+        ctx.apu_mut().set_expansion_device(device);
+
+        ctx.mapper_mut().restore_external(self.ctx.mapper_mut());
+
+        for channel in Channel::ALL {
+            let enabled = self.ctx.apu().channel_enabled(channel);
+            ctx.apu_mut().set_channel_enabled(channel, enabled);
+        }
+    }
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -25,14 +1105,39 @@ pub enum Error {
     UnsupportedMapper(u16),
     #[error("{0}")]
     DeserializeFailed(#[from] bincode::Error),
+    #[error("{0}")]
+    StateDecodeFailed(#[from] rmp_serde::decode::Error),
+    #[error("state migration failed: {0}")]
+    StateMigrationFailed(String),
+    #[error("unsupported state version: {0}")]
+    UnsupportedStateVersion(u32),
     #[error("backup ram size mismatch: actual: {0}, expected: {1}")]
     BackupSizeMismatch(usize, usize),
+    #[error("invalid save state: {0}")]
+    InvalidSaveState(&'static str),
+    #[error("save state is for another ROM: state: {0:08X}, loaded: {1:08X}")]
+    SaveStateRomMismatch(u32, u32),
+    #[error("emulation panicked at PC=${pc:04X}, frame={frame}, mapper={mapper_id}: {message}")]
+    EmulationPanic {
+        message: String,
+        pc: u16,
+        frame: u64,
+        mapper_id: u16,
+    },
 }
 
 const CORE_INFO: CoreInfo = CoreInfo {
     system_name: "NES (Sabicom)",
     abbrev: "nes",
-    file_extensions: &["nes"],
+    file_extensions: &[
+        "nes",
+        "nsf",
+        "nsfe",
+        #[cfg(feature = "archive")]
+        "zip",
+        #[cfg(feature = "archive")]
+        "gz",
+    ],
 };
 
 fn default_key_config() -> KeyConfig {
@@ -48,6 +1153,8 @@ fn default_key_config() -> KeyConfig {
         ("B", any!(keycode!(Z), pad_button!(0, South))),
         ("Start", any!(keycode!(Return), pad_button!(0, Start))),
         ("Select", any!(keycode!(RShift), pad_button!(0, Select))),
+        ("Turbo A", any!(keycode!(S), pad_button!(0, North))),
+        ("Turbo B", any!(keycode!(A), pad_button!(0, West))),
     ];
 
     let empty = vec![
@@ -59,16 +1166,41 @@ fn default_key_config() -> KeyConfig {
         ("B", KeyAssign::default()),
         ("Start", KeyAssign::default()),
         ("Select", KeyAssign::default()),
+        ("Turbo A", KeyAssign::default()),
+        ("Turbo B", KeyAssign::default()),
     ];
 
+    let mut empty_mic = empty.clone();
+    empty_mic.push(("Microphone", KeyAssign::default()));
+
     KeyConfig {
-        controllers: [keys, empty]
+        controllers: [keys, empty_mic, empty.clone(), empty]
             .into_iter()
             .map(|v| v.into_iter().map(|(k, a)| (k.to_string(), a)).collect())
             .collect(),
     }
 }
 
+/// Parses a ROM file, and fixes its header with the header database and quirks as configured
+fn load_rom(data: &[u8], config: &Config) -> Result<rom::Rom, Error> {
+    let mut rom = rom::Rom::from_bytes(data)?;
+    if let Some(db) = &config.header_database {
+        if !config.disable_header_database {
+            rom.apply_header_database(db);
+        }
+    }
+    quirks::apply(&mut rom, &config.quirks);
+    match rom.input_device() {
+        InputDevice::Unspecified
+        | InputDevice::StandardControllers
+        | InputDevice::FourScore
+        | InputDevice::FamicomFourPlayers
+        | InputDevice::VsSystem => {}
+        device => core_log!(Rom, Warn, "Input device is not supported: {device:?}"),
+    }
+    Ok(rom)
+}
+
 impl EmulatorCore for Nes {
     type Config = Config;
     type Error = Error;
@@ -80,97 +1212,116 @@ impl EmulatorCore for Nes {
     fn try_from_file(
         data: &[u8],
         backup: Option<&[u8]>,
-        _config: &Self::Config,
+        config: &Self::Config,
     ) -> Result<Self, Self::Error>
     where
         Self: Sized,
     {
-        use context::Cpu;
-        let rom = rom::Rom::from_bytes(data)?;
-        let mut ctx = context::Context::new(rom, backup.map(|r| r.to_vec()))?;
-        ctx.reset_cpu();
-        Ok(Self { ctx })
+        let rom = load_rom(data, config)?;
+        let ctx = context::Context::new(rom, backup.map(|r| r.to_vec()))?;
+
+        let mut ret = Self {
+            ctx,
+            config: config.clone(),
+            hard_pause_at: None,
+            hard_paused: false,
+            breakpoint_hit: None,
+            ntsc_frame: Default::default(),
+            rgba_frame: vec![],
+            rewind: None,
+        };
+        ret.apply_config();
+        ret.power_on();
+        Ok(ret)
     }
 
     fn game_info(&self) -> Vec<(String, String)> {
         use context::Rom;
-        let rom = self.ctx.rom();
+        let info = self.ctx.rom().info(self.config.header_database.as_deref());
 
         let to_si = |x| ByteSize(x as _).to_string_as(true);
         let yn = |b| if b { "Yes" } else { "No" };
 
-        let prg_chr_crc32 = {
-            let mut hasher = crc32fast::Hasher::new();
-            hasher.update(&rom.prg_rom);
-            hasher.update(&rom.chr_rom);
-            hasher.finalize()
-        };
-        let prg_rom_crc32 = crc32fast::hash(&rom.prg_rom);
-        let chr_rom_crc32 = crc32fast::hash(&rom.chr_rom);
-
         let ret = vec![
             (
                 "ROM Format",
-                match &rom.format {
+                match info.format {
                     RomFormat::INes => "iNES",
                     RomFormat::Nes20 => "NES 2.0",
+                    RomFormat::Nsf => "NSF",
                 }
                 .to_string(),
             ),
             (
                 "Mapper ID",
-                format!("{} ({})", rom.mapper_id, rom.submapper_id),
+                format!("{} ({})", info.mapper_id, info.submapper_id),
             ),
-            ("Mirroring", format!("{:?}", rom.mirroring)),
-            ("Console Type", format!("{:?}", rom.console_type)),
-            ("Timing Mode", format!("{:?}", rom.timing_mode)),
-            ("Battery", yn(rom.has_battery).to_string()),
-            ("Trainer", yn(rom.trainer.is_some()).to_string()),
-            ("PRG ROM Size", to_si(rom.prg_rom.len())),
-            ("CHR ROM Size", to_si(rom.chr_rom.len())),
-            ("PRG RAM Size", to_si(rom.prg_ram_size)),
-            ("PRG NVRAM Size", to_si(rom.prg_nvram_size)),
-            ("CHR RAM Size", to_si(rom.chr_ram_size)),
-            ("CHR NVRAM Size", to_si(rom.chr_nvram_size)),
-            ("PRG+CHR CRC32", format!("{prg_chr_crc32:08X}")),
-            ("PRG ROM CRC32", format!("{prg_rom_crc32:08X}")),
-            ("CHR ROM CRC32", format!("{chr_rom_crc32:08X}")),
+            ("Board", info.board_name.unwrap_or("Unknown").to_string()),
+            ("Mirroring", format!("{:?}", info.mirroring)),
+            ("Console Type", format!("{:?}", info.console_type)),
+            ("Timing Mode", format!("{:?}", info.timing_mode)),
+            ("Battery", yn(info.has_battery).to_string()),
+            ("Trainer", yn(info.has_trainer).to_string()),
+            ("PlayChoice-10 Data", yn(info.has_playchoice).to_string()),
+            ("Input Device", format!("{:?}", info.input_device)),
+            ("PRG ROM Size", to_si(info.prg_rom_size)),
+            ("CHR ROM Size", to_si(info.chr_rom_size)),
+            ("PRG RAM Size", to_si(info.prg_ram_size)),
+            ("PRG NVRAM Size", to_si(info.prg_nvram_size)),
+            ("CHR RAM Size", to_si(info.chr_ram_size)),
+            ("CHR NVRAM Size", to_si(info.chr_nvram_size)),
+            (
+                "Misc ROMs",
+                format!("{} ({})", info.misc_rom_count, to_si(info.misc_rom_size)),
+            ),
+            ("PRG+CHR CRC32", format!("{:08X}", info.prg_chr_crc32)),
+            ("PRG ROM CRC32", format!("{:08X}", info.prg_rom_crc32)),
+            ("CHR ROM CRC32", format!("{:08X}", info.chr_rom_crc32)),
+            ("Header Database", yn(info.in_header_database).to_string()),
         ];
 
         ret.into_iter().map(|(k, v)| (k.to_string(), v)).collect()
     }
 
-    fn set_config(&mut self, _config: &Self::Config) {}
+    fn set_config(&mut self, config: &Self::Config) {
+        self.config = config.clone();
+        self.apply_config();
+    }
 
     fn exec_frame(&mut self, render_graphics: bool) {
-        use context::{Apu, Cpu, Ppu};
+        use context::{Apu, Ppu};
 
-        self.ctx.apu_mut().audio_buffer_mut().samples.clear();
-        self.ctx
-            .ppu_mut()
-            .frame_buffer_mut()
-            .resize(consts::SCREEN_WIDTH, consts::SCREEN_HEIGHT);
+        // Samples generated before a hard pause or a breakpoint belong to the resumed frame
+        if !self.hard_paused && self.breakpoint_hit.is_none() {
+            self.ctx.apu_mut().audio_buffer_mut().samples.clear();
+            if let Some(streams) = self.ctx.apu_mut().channel_streams_mut() {
+                streams.clear();
+            }
+        }
         self.ctx.ppu_mut().set_render_graphics(render_graphics);
 
         let frame = self.ctx.ppu().frame();
-        while frame == self.ctx.ppu().frame() {
-            self.ctx.tick_cpu();
-        }
+        self.run(|nes| nes.ctx.ppu().frame() != frame);
     }
 
     fn reset(&mut self) {
-        use context::{Cpu, Rom};
+        use context::Rom;
 
         let backup = self.backup();
         let mut rom = rom::Rom::default();
         std::mem::swap(&mut rom, self.ctx.rom_mut());
-        self.ctx = context::Context::new(rom, backup).unwrap();
-
-        self.ctx.reset_cpu();
+        let mut ctx = context::Context::new(rom, backup).unwrap();
+        self.inherit_host_state(&mut ctx);
+        self.ctx = ctx;
+        self.apply_config();
+        self.power_on();
     }
 
     fn frame_buffer(&self) -> &meru_interface::FrameBuffer {
         use context::Ppu;
+        if self.config.ntsc_filter.is_some() {
+            return &self.ntsc_frame;
+        }
         self.ctx.ppu().frame_buffer()
     }
 
@@ -184,9 +1335,10 @@ impl EmulatorCore for Nes {
     }
 
     fn set_input(&mut self, input: &meru_interface::InputData) {
-        let mut pad: [Pad; 2] = Default::default();
+        let mut pad: [Pad; 4] = Default::default();
+        let mut microphone = false;
 
-        for i in 0..2 {
+        for i in 0..input.controllers.len().min(4) {
             let mut pad = &mut pad[i];
             for (key, value) in &input.controllers[i] {
                 match key.as_str() {
@@ -198,41 +1350,34 @@ impl EmulatorCore for Nes {
                     "B" => pad.b = *value,
                     "Start" => pad.start = *value,
                     "Select" => pad.select = *value,
+                    "Turbo A" => pad.turbo_a = *value,
+                    "Turbo B" => pad.turbo_b = *value,
+                    "Microphone" => microphone |= *value,
                     _ => (),
                 }
             }
         }
 
         use context::Apu;
-        self.ctx.apu_mut().set_input(&Input { pad });
+        self.ctx.apu_mut().set_input(&Input { pad, microphone });
     }
 
     fn backup(&self) -> Option<Vec<u8>> {
         use context::Rom;
         if self.ctx.rom().has_battery {
-            Some(self.ctx.memory_ctrl().prg_ram().to_vec())
+            Some(self.ctx.memory_ctrl().backup())
         } else {
             None
         }
     }
 
     fn save_state(&self) -> Vec<u8> {
-        bincode::serialize(&self.ctx).unwrap()
+        savestate::encode_state(&self.ctx)
     }
 
     fn load_state(&mut self, data: &[u8]) -> Result<(), Self::Error> {
-        use context::{Apu, Ppu, Rom};
-        let mut ctx: context::Context = bincode::deserialize(data)?;
-        std::mem::swap(ctx.rom_mut(), self.ctx.rom_mut());
-        std::mem::swap(
-            ctx.ppu_mut().frame_buffer_mut(),
-            self.ctx.ppu_mut().frame_buffer_mut(),
-        );
-        std::mem::swap(
-            ctx.apu_mut().audio_buffer_mut(),
-            self.ctx.apu_mut().audio_buffer_mut(),
-        );
-        self.ctx = ctx;
+        let ctx = savestate::decode_state(data)?;
+        self.restore_context(ctx);
         Ok(())
     }
 }
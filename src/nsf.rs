@@ -0,0 +1,336 @@
+//! NSF (NES Sound Format) loader
+//!
+//! An NSF file holds the music code and data of a game without its graphics.
+//! It is loaded as a mapper 31 ROM, whose 4KB banks match the NSF bank switching,
+//! and the mapper runs the tracks through a small player program.
+//!
+//! NSFe files and the metadata of NSF2 files are made of chunks, each of which is
+//! a 32-bit length, a 4 character ID and the data. Chunks with an upper case ID
+//! are required to play the file, and others are optional.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consts::Region,
+    rom::{ConsoleType, Mirroring, Rom, RomError, RomFormat, TimingMode},
+};
+
+const HEADER_SIZE: usize = 0x80;
+const BANK_SIZE: usize = 0x1000;
+
+const NSF_MAGIC: &[u8] = b"NESM\x1a";
+const NSFE_MAGIC: &[u8] = b"NSFE";
+
+/// Mapper which runs NSF files
+pub const NSF_MAPPER_ID: u16 = 31;
+
+/// Header and metadata of an NSF file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NsfInfo {
+    pub song_count: u8,
+    /// Track played first, counted from 0
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    /// Who ripped the music from the game (NSFe only)
+    pub ripper: String,
+    /// Interval of play calls in microseconds on NTSC consoles
+    pub ntsc_speed: u16,
+    /// Interval of play calls in microseconds on PAL consoles
+    pub pal_speed: u16,
+    /// Initial 4KB banks of $8000-$FFFF, if the tune uses bank switching
+    pub bank_init: Option<[u8; 8]>,
+    /// Expansion sound chips used by the tune (bit 0: VRC6, 1: VRC7, 2: FDS, 3: MMC5, 4: N163, 5: 5B)
+    pub expansion_chips: u8,
+    /// The tune uses the IRQ timer of NSF2 at $4018-$401A
+    pub irq_support: bool,
+    /// Metadata of each track, `song_count` entries
+    pub tracks: Vec<NsfTrack>,
+    /// Mixing levels of the sound chips, if the file specifies them
+    pub mixing_levels: Vec<NsfMixingLevel>,
+}
+
+/// Metadata of a track, which NSFe and NSF2 files can have
+#[derive(Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NsfTrack {
+    pub name: Option<String>,
+    /// Length of the track in milliseconds, before fading out
+    pub length_ms: Option<u32>,
+    /// Duration of the fade-out after the track length in milliseconds
+    pub fade_ms: Option<u32>,
+}
+
+/// Volume of a sound chip relative to its nominal level
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NsfMixingLevel {
+    /// 0: APU pulses, 1: APU triangle, noise and DMC, then the expansion chips
+    /// in the order of `expansion_chips` (2: VRC6, 3: VRC7, ...)
+    pub device: u8,
+    /// Level in millibels (1/100 dB)
+    pub millibels: i16,
+}
+
+impl NsfInfo {
+    /// Interval of play calls in microseconds
+    pub fn speed(&self, region: Region) -> u16 {
+        match region {
+            Region::Ntsc => self.ntsc_speed,
+            Region::Pal => self.pal_speed,
+        }
+    }
+
+    /// Initial 4KB banks of $8000-$FFFF
+    pub fn initial_banks(&self) -> [u8; 8] {
+        self.bank_init
+            .unwrap_or_else(|| std::array::from_fn(|i| i as u8))
+    }
+}
+
+pub fn is_nsf(dat: &[u8]) -> bool {
+    dat.starts_with(NSF_MAGIC) || dat.starts_with(NSFE_MAGIC)
+}
+
+pub fn load(dat: &[u8]) -> Result<Rom, RomError> {
+    if dat.starts_with(NSFE_MAGIC) {
+        return load_nsfe(&dat[NSFE_MAGIC.len()..]);
+    }
+
+    if dat.len() < HEADER_SIZE {
+        Err(RomError::InvalidNsf("file is shorter than the header"))?;
+    }
+    let header = &dat[..HEADER_SIZE];
+    let mut data = &dat[HEADER_SIZE..];
+
+    let word = |ix: usize| u16::from_le_bytes([header[ix], header[ix + 1]]);
+    let text = |ix: usize| c_strings(&header[ix..ix + 32]).next().unwrap_or_default();
+
+    let bank_init: [u8; 8] = header[0x70..0x78].try_into().unwrap();
+    let bank_init = bank_init.iter().any(|&b| b != 0).then_some(bank_init);
+
+    let mut info = NsfInfo {
+        song_count: header[0x06],
+        starting_song: header[0x07].saturating_sub(1),
+        load_addr: word(0x08),
+        init_addr: word(0x0A),
+        play_addr: word(0x0C),
+        title: text(0x0E),
+        artist: text(0x2E),
+        copyright: text(0x4E),
+        ripper: String::new(),
+        ntsc_speed: word(0x6E),
+        pal_speed: word(0x78),
+        bank_init,
+        expansion_chips: header[0x7B],
+        irq_support: false,
+        tracks: vec![NsfTrack::default(); header[0x06] as usize],
+        mixing_levels: vec![],
+    };
+
+    // NSF2 can have NSFe metadata chunks after the program data
+    if header[0x05] >= 2 {
+        info.irq_support = header[0x7C] & 0x10 != 0;
+        let data_len = u32::from_le_bytes([header[0x7D], header[0x7E], header[0x7F], 0]) as usize;
+        if data_len != 0 {
+            if data_len > data.len() {
+                Err(RomError::InvalidNsf("program data exceeds the file"))?;
+            }
+            let metadata;
+            (data, metadata) = data.split_at(data_len);
+            for (id, chunk) in chunks(metadata)? {
+                info.apply_chunk(id, chunk)?;
+            }
+        }
+    }
+
+    build_rom(info, data, header[0x7A])
+}
+
+fn load_nsfe(dat: &[u8]) -> Result<Rom, RomError> {
+    let chunks = chunks(dat)?;
+    let find = |name: &[u8; 4]| chunks.iter().find(|(id, _)| id == name).map(|(_, c)| *c);
+
+    let Some(chunk) = find(b"INFO").filter(|c| c.len() >= 8) else {
+        Err(RomError::InvalidNsf("no INFO chunk"))?
+    };
+    let Some(data) = find(b"DATA") else {
+        Err(RomError::InvalidNsf("no DATA chunk"))?
+    };
+
+    let word = |ix: usize| u16::from_le_bytes([chunk[ix], chunk[ix + 1]]);
+    let song_count = chunk.get(8).copied().unwrap_or(1);
+    let mut info = NsfInfo {
+        song_count,
+        starting_song: chunk.get(9).copied().unwrap_or(0),
+        load_addr: word(0),
+        init_addr: word(2),
+        play_addr: word(4),
+        title: String::new(),
+        artist: String::new(),
+        copyright: String::new(),
+        ripper: String::new(),
+        // Play calls are synchronized to the frame rate unless a RATE chunk says otherwise
+        ntsc_speed: 16639,
+        pal_speed: 19997,
+        bank_init: None,
+        expansion_chips: chunk[7],
+        irq_support: false,
+        tracks: vec![NsfTrack::default(); song_count as usize],
+        mixing_levels: vec![],
+    };
+
+    for &(id, chunk) in &chunks {
+        if !matches!(&id, b"INFO" | b"DATA") {
+            info.apply_chunk(id, chunk)?;
+        }
+    }
+
+    build_rom(info, data, chunk[6])
+}
+
+/// ID and data of a chunk
+type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+/// Splits chunks up to the NEND chunk or the end of data
+fn chunks(mut dat: &[u8]) -> Result<Vec<Chunk<'_>>, RomError> {
+    let mut ret = vec![];
+    while dat.len() >= 8 {
+        let len = u32::from_le_bytes(dat[0..4].try_into().unwrap()) as usize;
+        let id: [u8; 4] = dat[4..8].try_into().unwrap();
+        dat = &dat[8..];
+        if &id == b"NEND" {
+            break;
+        }
+        if len > dat.len() {
+            Err(RomError::InvalidNsf("chunk exceeds the file"))?;
+        }
+        ret.push((id, &dat[..len]));
+        dat = &dat[len..];
+    }
+    Ok(ret)
+}
+
+/// Splits null-terminated strings
+fn c_strings(dat: &[u8]) -> impl Iterator<Item = String> + '_ {
+    let dat = dat.strip_suffix(&[0]).unwrap_or(dat);
+    dat.split(|&c| c == 0)
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+}
+
+/// Milliseconds of a time or fade entry, where negative values mean the default
+fn milliseconds(dat: &[u8]) -> Option<u32> {
+    u32::try_from(i32::from_le_bytes(dat.try_into().unwrap())).ok()
+}
+
+impl NsfInfo {
+    fn apply_chunk(&mut self, id: [u8; 4], chunk: &[u8]) -> Result<(), RomError> {
+        match &id {
+            b"BANK" => {
+                let mut banks = [0; 8];
+                let len = chunk.len().min(8);
+                banks[..len].copy_from_slice(&chunk[..len]);
+                self.bank_init = Some(banks);
+            }
+            b"RATE" => {
+                let word = |ix: usize| {
+                    chunk
+                        .get(ix..ix + 2)
+                        .map(|w| u16::from_le_bytes([w[0], w[1]]))
+                };
+                self.ntsc_speed = word(0).unwrap_or(self.ntsc_speed);
+                self.pal_speed = word(2).unwrap_or(self.pal_speed);
+            }
+            b"auth" => {
+                let mut strings = c_strings(chunk);
+                for s in [
+                    &mut self.title,
+                    &mut self.artist,
+                    &mut self.copyright,
+                    &mut self.ripper,
+                ] {
+                    *s = strings.next().unwrap_or_default();
+                }
+            }
+            b"tlbl" => {
+                for (track, name) in self.tracks.iter_mut().zip(c_strings(chunk)) {
+                    track.name = Some(name);
+                }
+            }
+            b"time" => {
+                for (track, ms) in self.tracks.iter_mut().zip(chunk.chunks_exact(4)) {
+                    track.length_ms = milliseconds(ms);
+                }
+            }
+            b"fade" => {
+                for (track, ms) in self.tracks.iter_mut().zip(chunk.chunks_exact(4)) {
+                    track.fade_ms = milliseconds(ms);
+                }
+            }
+            b"mixe" => {
+                self.mixing_levels = chunk
+                    .chunks_exact(3)
+                    .map(|e| NsfMixingLevel {
+                        device: e[0],
+                        millibels: i16::from_le_bytes([e[1], e[2]]),
+                    })
+                    .collect();
+            }
+            // The player can't play the file without understanding the required chunks
+            [b'A'..=b'Z', ..] => Err(RomError::InvalidNsf("unsupported required chunk"))?,
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+fn build_rom(info: NsfInfo, data: &[u8], region_flags: u8) -> Result<Rom, RomError> {
+    if info.song_count == 0 {
+        Err(RomError::InvalidNsf("no songs"))?;
+    }
+
+    // Without bank switching, the data is loaded at the load address as it is.
+    // With it, the data is split into 4KB banks from the load address within a bank.
+    let padding = if info.bank_init.is_some() {
+        info.load_addr as usize % BANK_SIZE
+    } else if info.load_addr >= 0x8000 {
+        info.load_addr as usize - 0x8000
+    } else {
+        Err(RomError::InvalidNsf("load address is below $8000"))?
+    };
+
+    let mut prg_rom = vec![0; padding];
+    prg_rom.extend_from_slice(data);
+    let size = prg_rom.len().div_ceil(BANK_SIZE) * BANK_SIZE;
+    prg_rom.resize(size.max(0x8000), 0);
+
+    let timing_mode = match region_flags & 3 {
+        0 => TimingMode::Ntsc,
+        1 => TimingMode::Pal,
+        _ => TimingMode::MultipleRegion,
+    };
+
+    Ok(Rom {
+        format: RomFormat::Nsf,
+        mapper_id: NSF_MAPPER_ID,
+        submapper_id: 0,
+        prg_rom,
+        chr_rom: vec![],
+        trainer: None,
+        prg_ram_size: 8 * 1024,
+        prg_nvram_size: 0,
+        chr_ram_size: 8 * 1024,
+        chr_nvram_size: 0,
+        mirroring: Mirroring::Vertical,
+        console_type: ConsoleType::Nes,
+        timing_mode,
+        has_battery: false,
+        expansion_device: 0,
+        playchoice: None,
+        misc_rom_count: 0,
+        misc_rom: vec![],
+        nsf: Some(info),
+    })
+}
@@ -0,0 +1,182 @@
+//! NTSC composite video filter
+//!
+//! Generates the composite signal of the PPU from raw pixels and decodes it back to RGB,
+//! reproducing color artifacts such as dot crawl and fringing that games rely on for
+//! dithering and transparency effects.
+//! See <https://www.nesdev.org/wiki/NTSC_video> for the signal model.
+
+use meru_interface::{Color, FrameBuffer};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::consts::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Signal samples per PPU dot (8 half master clocks)
+const SAMPLES_PER_DOT: usize = 8;
+/// Signal samples per color subcarrier cycle
+const SAMPLES_PER_CYCLE: usize = 12;
+/// Output pixels per PPU dot
+const PIXELS_PER_DOT: usize = 2;
+/// Black samples around each line, enough for the widest decoding window
+const PADDING: usize = SAMPLES_PER_CYCLE * 2;
+
+/// Subcarrier phase offset which aligns the decoded hue with the standard palette
+const HUE_OFFSET: f32 = 4.0;
+
+/// Output levels of the PPU for luma 0 to 3, normalized as black = 0.0 and white = 1.0
+const LEVEL_LOW: [f32; 4] = [-0.107, 0.0, 0.304, 0.721];
+const LEVEL_HIGH: [f32; 4] = [0.376, 0.670, 1.0, 1.0];
+/// Attenuation of the signal by color emphasis
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// Width of the frame produced by the filter
+pub const NTSC_WIDTH: usize = SCREEN_WIDTH * PIXELS_PER_DOT;
+
+/// Settings of the NTSC filter
+#[derive(Clone, Copy, PartialEq, Debug, JsonSchema, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NtscFilter {
+    /// Color resolution from 0.0 (colors bleed over about two subcarrier cycles) to 1.0
+    pub sharpness: f32,
+    /// Amount of the color subcarrier left in luma from 0.0 (none) to 1.0,
+    /// which appears as colored fringes on edges and dot crawl
+    pub fringing: f32,
+}
+
+impl Default for NtscFilter {
+    fn default() -> Self {
+        Self {
+            sharpness: 0.5,
+            fringing: 0.5,
+        }
+    }
+}
+
+impl NtscFilter {
+    /// Filters raw pixels returned by `Ppu::index_buffer` into a `NTSC_WIDTH` pixels wide frame.
+    /// `frame` selects the subcarrier phase, which alternates on each frame.
+    pub fn apply(&self, indices: &[u16], frame: u64, fb: &mut FrameBuffer) {
+        assert_eq!(indices.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        fb.resize(NTSC_WIDTH, SCREEN_HEIGHT);
+
+        let sharpness = self.sharpness.clamp(0.0, 1.0);
+        let fringing = self.fringing.clamp(0.0, 1.0);
+
+        let (cos, sin): (Vec<f32>, Vec<f32>) = (0..SAMPLES_PER_CYCLE)
+            .map(|p| {
+                let t = std::f32::consts::PI * (p as f32 + HUE_OFFSET) / 6.0;
+                (t.cos(), t.sin())
+            })
+            .unzip();
+
+        let mut signal = vec![0.0; PADDING * 2 + SCREEN_WIDTH * SAMPLES_PER_DOT];
+
+        for y in 0..SCREEN_HEIGHT {
+            // A line is 341 dots, which shifts the phase by 4 samples.
+            // A frame with the skipped dot shifts it by 4 more.
+            let phase = (y * 4 + (frame as usize & 1) * 4) % SAMPLES_PER_CYCLE;
+            // Phase of signal[i]
+            let phase_of =
+                |i: usize| (phase + SAMPLES_PER_CYCLE * 2 + i - PADDING) % SAMPLES_PER_CYCLE;
+
+            let line = &indices[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH];
+            for (x, &pixel) in line.iter().enumerate() {
+                for s in 0..SAMPLES_PER_DOT {
+                    let i = PADDING + x * SAMPLES_PER_DOT + s;
+                    signal[i] = sample(pixel, phase_of(i));
+                }
+            }
+
+            // Averages of the signal, and the signal demodulated by the subcarrier
+            let window = |center: usize, width: usize| {
+                let range = center - width / 2..center + width / 2;
+                let n = width as f32;
+                let mut y = 0.0;
+                let mut i = 0.0;
+                let mut q = 0.0;
+                for k in range {
+                    let p = phase_of(k);
+                    y += signal[k];
+                    i += signal[k] * cos[p];
+                    q += signal[k] * sin[p];
+                }
+                (y / n, i / n, q / n)
+            };
+
+            for x in 0..NTSC_WIDTH {
+                let center = PADDING + x * SAMPLES_PER_DOT / PIXELS_PER_DOT + 2;
+
+                // A full cycle cancels the subcarrier, a third of it leaves the subcarrier in
+                let (y_wide, i_sharp, q_sharp) = window(center, SAMPLES_PER_CYCLE);
+                let (y_narrow, _, _) = window(center, SAMPLES_PER_CYCLE / 3);
+                let (_, i_blur, q_blur) = window(center, SAMPLES_PER_CYCLE * 2);
+
+                let luma = y_wide + (y_narrow - y_wide) * fringing;
+                let i = i_blur + (i_sharp - i_blur) * sharpness;
+                let q = q_blur + (q_sharp - q_blur) * sharpness;
+
+                let [r, g, b] = yiq_to_rgb(luma, i * 2.0, q * 2.0);
+                let to_u8 = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+                *fb.pixel_mut(x, y) = Color::new(to_u8(r), to_u8(g), to_u8(b));
+            }
+        }
+    }
+}
+
+/// Decodes a screen filled with `pixel` to YIQ, with the subcarrier phase rotated by `hue` degrees
+pub(crate) fn decode_color(pixel: u16, hue: f32) -> (f32, f32, f32) {
+    let mut y = 0.0;
+    let mut i = 0.0;
+    let mut q = 0.0;
+    for p in 0..SAMPLES_PER_CYCLE {
+        let signal = sample(pixel, p);
+        let t = std::f32::consts::PI * (p as f32 + HUE_OFFSET + hue / 30.0) / 6.0;
+        y += signal;
+        i += signal * t.cos();
+        q += signal * t.sin();
+    }
+    let n = SAMPLES_PER_CYCLE as f32;
+    (y / n, i / n * 2.0, q / n * 2.0)
+}
+
+/// Composite signal level of `pixel` at subcarrier `phase`
+fn sample(pixel: u16, phase: usize) -> f32 {
+    let in_phase = |color: usize| (color + phase) % SAMPLES_PER_CYCLE < 6;
+
+    let color = (pixel & 0x0f) as usize;
+    let level = if color >= 0x0e {
+        1
+    } else {
+        (pixel >> 4 & 3) as usize
+    };
+    let emphasis = pixel >> 6;
+
+    let low = LEVEL_LOW[level];
+    let high = LEVEL_HIGH[level];
+    let (low, high) = match color {
+        0x00 => (high, high),
+        0x0d..=0x0f => (low, low),
+        _ => (low, high),
+    };
+
+    let mut signal = if in_phase(color) { high } else { low };
+
+    // Emphasis bits attenuate the signal while the subcarrier is in the phase of red, green and blue
+    if color < 0x0e
+        && ((emphasis & 1 != 0 && in_phase(0x0c))
+            || (emphasis & 2 != 0 && in_phase(0x04))
+            || (emphasis & 4 != 0 && in_phase(0x08)))
+    {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+
+    signal
+}
+
+pub(crate) fn yiq_to_rgb(y: f32, i: f32, q: f32) -> [f32; 3] {
+    [
+        y + 0.956 * i + 0.621 * q,
+        y - 0.272 * i - 0.647 * q,
+        y - 1.106 * i + 1.703 * q,
+    ]
+}
@@ -1,4 +1,8 @@
 use meru_interface::Color;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::ntsc;
 
 macro_rules! colors {
     ($({ $r:expr, $g:expr, $b:expr },) *) => {
@@ -29,3 +33,101 @@ pub const NES_PALETTE: [Color; 0x40] = colors! {
     {0xFF,0xE7,0xA3}, {0xE3,0xFF,0xA3}, {0xAB,0xF3,0xBF}, {0xB3,0xFF,0xCF},
     {0x9F,0xFF,0xF3}, {0xDD,0xDD,0xDD}, {0x11,0x11,0x11}, {0x11,0x11,0x11},
 };
+
+/// Palette of the RGB PPUs (RP2C03, RC2C05) used in Vs. System and PlayChoice-10
+pub const RGB_PALETTE: [Color; 0x40] = colors! {
+    {0x6D,0x6D,0x6D}, {0x00,0x24,0x91}, {0x00,0x00,0xDA}, {0x6D,0x48,0xDA},
+    {0x91,0x00,0x6D}, {0xB6,0x00,0x6D}, {0xB6,0x24,0x00}, {0x91,0x48,0x00},
+    {0x6D,0x48,0x00}, {0x24,0x48,0x00}, {0x00,0x6D,0x24}, {0x00,0x91,0x00},
+    {0x00,0x48,0x48}, {0x00,0x00,0x00}, {0x00,0x00,0x00}, {0x00,0x00,0x00},
+
+    {0xB6,0xB6,0xB6}, {0x00,0x6D,0xDA}, {0x00,0x48,0xFF}, {0x91,0x00,0xFF},
+    {0xB6,0x00,0xFF}, {0xFF,0x00,0x91}, {0xFF,0x00,0x00}, {0xDA,0x6D,0x00},
+    {0x91,0x6D,0x00}, {0x24,0x91,0x00}, {0x00,0x91,0x00}, {0x00,0xB6,0x6D},
+    {0x00,0x91,0x91}, {0x00,0x00,0x00}, {0x00,0x00,0x00}, {0x00,0x00,0x00},
+
+    {0xFF,0xFF,0xFF}, {0x6D,0xB6,0xFF}, {0x91,0x91,0xFF}, {0xDA,0x6D,0xFF},
+    {0xFF,0x00,0xFF}, {0xFF,0x6D,0xFF}, {0xFF,0x91,0x00}, {0xFF,0xB6,0x00},
+    {0xDA,0xDA,0x00}, {0x6D,0xDA,0x00}, {0x00,0xFF,0x00}, {0x48,0xFF,0xDA},
+    {0x00,0xFF,0xFF}, {0x00,0x00,0x00}, {0x00,0x00,0x00}, {0x00,0x00,0x00},
+
+    {0xFF,0xFF,0xFF}, {0xB6,0xDA,0xFF}, {0xDA,0xB6,0xFF}, {0xFF,0xB6,0xFF},
+    {0xFF,0x91,0xFF}, {0xFF,0xB6,0xB6}, {0xFF,0xDA,0x91}, {0xFF,0xFF,0x48},
+    {0xFF,0xFF,0x6D}, {0xB6,0xFF,0x48}, {0x91,0xFF,0x6D}, {0x48,0xFF,0xDA},
+    {0x91,0xDA,0xFF}, {0x00,0x00,0x00}, {0x00,0x00,0x00}, {0x00,0x00,0x00},
+};
+
+/// Color index remapping of the RP2C04-0001 to RP2C04-0004 PPUs into `RGB_PALETTE`
+#[rustfmt::skip]
+pub const RP2C04_LUT: [[u8; 0x40]; 4] = [
+    [
+        0x35, 0x23, 0x16, 0x22, 0x1C, 0x09, 0x1D, 0x15, 0x20, 0x00, 0x27, 0x05, 0x04, 0x28, 0x08, 0x20,
+        0x21, 0x3E, 0x1F, 0x29, 0x3C, 0x32, 0x36, 0x12, 0x3F, 0x2B, 0x2E, 0x1E, 0x3D, 0x2D, 0x24, 0x01,
+        0x0E, 0x31, 0x33, 0x2A, 0x2C, 0x0C, 0x1B, 0x14, 0x2E, 0x07, 0x34, 0x06, 0x13, 0x02, 0x26, 0x2E,
+        0x2E, 0x19, 0x10, 0x0A, 0x39, 0x03, 0x37, 0x17, 0x0F, 0x11, 0x0B, 0x0D, 0x38, 0x25, 0x18, 0x3A,
+    ],
+    [
+        0x2E, 0x27, 0x18, 0x39, 0x3A, 0x25, 0x1C, 0x31, 0x16, 0x13, 0x38, 0x34, 0x20, 0x23, 0x3C, 0x0B,
+        0x0F, 0x21, 0x06, 0x3D, 0x1B, 0x29, 0x1E, 0x22, 0x1D, 0x24, 0x0E, 0x2B, 0x32, 0x08, 0x2E, 0x03,
+        0x04, 0x36, 0x26, 0x33, 0x11, 0x1F, 0x10, 0x02, 0x14, 0x3F, 0x00, 0x09, 0x12, 0x2E, 0x28, 0x20,
+        0x3E, 0x0D, 0x2A, 0x17, 0x0C, 0x01, 0x15, 0x19, 0x2E, 0x2C, 0x07, 0x37, 0x35, 0x05, 0x0A, 0x2D,
+    ],
+    [
+        0x14, 0x25, 0x3A, 0x10, 0x0B, 0x20, 0x31, 0x09, 0x01, 0x2E, 0x36, 0x08, 0x15, 0x3D, 0x3E, 0x3C,
+        0x22, 0x1C, 0x05, 0x12, 0x19, 0x18, 0x17, 0x1B, 0x00, 0x03, 0x2E, 0x02, 0x16, 0x06, 0x34, 0x35,
+        0x23, 0x0F, 0x0E, 0x37, 0x0D, 0x27, 0x26, 0x20, 0x29, 0x04, 0x21, 0x24, 0x11, 0x2D, 0x2E, 0x1F,
+        0x2C, 0x1E, 0x39, 0x33, 0x07, 0x2A, 0x28, 0x1D, 0x0A, 0x2E, 0x32, 0x38, 0x13, 0x2B, 0x3F, 0x0C,
+    ],
+    [
+        0x18, 0x03, 0x1C, 0x28, 0x2E, 0x35, 0x01, 0x17, 0x10, 0x1F, 0x2A, 0x0E, 0x36, 0x37, 0x0B, 0x39,
+        0x25, 0x1E, 0x12, 0x34, 0x2E, 0x1D, 0x06, 0x26, 0x3E, 0x1B, 0x22, 0x19, 0x04, 0x2E, 0x3A, 0x21,
+        0x05, 0x0A, 0x07, 0x02, 0x13, 0x14, 0x00, 0x15, 0x0C, 0x3D, 0x11, 0x0F, 0x0D, 0x38, 0x2D, 0x24,
+        0x33, 0x20, 0x08, 0x16, 0x3F, 0x2B, 0x20, 0x3C, 0x2E, 0x27, 0x23, 0x31, 0x29, 0x32, 0x2C, 0x09,
+    ],
+];
+
+/// Parameters of `generate_palette`
+#[derive(Clone, Copy, PartialEq, Debug, JsonSchema, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PaletteParams {
+    /// Hue rotation in degrees
+    pub hue: f32,
+    /// Chroma gain (1.0 is unchanged)
+    pub saturation: f32,
+    /// Luma offset (0.0 is unchanged, 1.0 is the difference between black and white)
+    pub brightness: f32,
+    /// Luma gain (1.0 is unchanged)
+    pub contrast: f32,
+    /// Gamma correction applied to RGB (1.0 is unchanged)
+    pub gamma: f32,
+}
+
+impl Default for PaletteParams {
+    fn default() -> Self {
+        Self {
+            hue: 0.0,
+            saturation: 1.0,
+            brightness: 0.0,
+            contrast: 1.0,
+            gamma: 1.0,
+        }
+    }
+}
+
+/// Generates a palette by decoding the NTSC signal of each color.
+/// Returns 0x200 entries indexed by `emphasis << 6 | palette index`, where `emphasis` is bits 5-7 of PPUMASK,
+/// so the first 0x40 entries are the colors without emphasis.
+pub fn generate_palette(params: &PaletteParams) -> Vec<Color> {
+    (0..0x200)
+        .map(|pixel| {
+            let (y, i, q) = ntsc::decode_color(pixel, params.hue);
+            let y = y * params.contrast + params.brightness;
+            let rgb = ntsc::yiq_to_rgb(y, i * params.saturation, q * params.saturation);
+            let [r, g, b] = rgb.map(|v| {
+                let v = v.clamp(0.0, 1.0).powf(1.0 / params.gamma);
+                (v * 255.0).round() as u8
+            });
+            Color::new(r, g, b)
+        })
+        .collect()
+}
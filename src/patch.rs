@@ -0,0 +1,306 @@
+//! IPS and BPS patches, which are applied to ROM files before loading them
+//!
+//! Frontends can soft-patch ROMs with [`read_patched`], which applies the patch
+//! next to the ROM file with the same name (e.g. `game.ips` for `game.nes`).
+
+use std::{io, path::Path};
+
+use crate::logging::core_log;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+/// Extensions of patch files looked for by `read_patched`, in the order of preference
+pub const PATCH_EXTENSIONS: &[&str] = &["bps", "ips"];
+
+#[derive(thiserror::Error, Debug)]
+pub enum PatchError {
+    #[error("unknown patch format")]
+    UnknownFormat,
+    #[error("invalid IPS patch: {0}")]
+    InvalidIps(&'static str),
+    #[error("invalid BPS patch: {0}")]
+    InvalidBps(&'static str),
+    #[error("{0} CRC32 mismatch: actual: {1:08X}, expected: {2:08X}")]
+    ChecksumMismatch(&'static str, u32, u32),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+/// Applies an IPS or BPS patch, chosen by the header of the patch
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+/// Reads a ROM file, and applies the patch next to it if there is one
+pub fn read_patched(path: impl AsRef<Path>) -> Result<Vec<u8>, PatchError> {
+    let path = path.as_ref();
+    let rom = std::fs::read(path)?;
+    for ext in PATCH_EXTENSIONS {
+        let patch_path = path.with_extension(ext);
+        if patch_path.is_file() {
+            core_log!(Rom, Info, "Applying patch: {}", patch_path.display());
+            return apply_patch(&rom, &std::fs::read(patch_path)?);
+        }
+    }
+    Ok(rom)
+}
+
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let Some(mut patch) = patch.strip_prefix(IPS_MAGIC) else {
+        Err(PatchError::InvalidIps("no header"))?
+    };
+    let mut take = |len: usize| -> Result<&[u8], PatchError> {
+        if patch.len() < len {
+            Err(PatchError::InvalidIps("unexpected end of patch"))?
+        }
+        let ret;
+        (ret, patch) = patch.split_at(len);
+        Ok(ret)
+    };
+    let be = |bytes: &[u8]| bytes.iter().fold(0, |acc, &b| acc << 8 | b as usize);
+
+    let mut ret = rom.to_vec();
+    loop {
+        let offset = take(3)?;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = be(offset);
+        let size = be(take(2)?);
+
+        // Size 0 marks a run of the same byte
+        let data = if size == 0 {
+            let count = be(take(2)?);
+            vec![take(1)?[0]; count]
+        } else {
+            take(size)?.to_vec()
+        };
+
+        if ret.len() < offset + data.len() {
+            ret.resize(offset + data.len(), 0);
+        }
+        ret[offset..offset + data.len()].copy_from_slice(&data);
+    }
+
+    // An extension after the end marker truncates the file
+    if let Ok(size) = take(3) {
+        ret.truncate(be(size));
+    }
+
+    Ok(ret)
+}
+
+pub fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if !patch.starts_with(BPS_MAGIC) || patch.len() < BPS_MAGIC.len() + 12 {
+        Err(PatchError::InvalidBps("no header"))?
+    }
+
+    let footer = |ix: usize| {
+        let ofs = patch.len() - 12 + ix * 4;
+        u32::from_le_bytes(patch[ofs..ofs + 4].try_into().unwrap())
+    };
+    let check = |name, actual, expected| {
+        if actual == expected {
+            Ok(())
+        } else {
+            Err(PatchError::ChecksumMismatch(name, actual, expected))
+        }
+    };
+    check(
+        "patch",
+        crc32fast::hash(&patch[..patch.len() - 4]),
+        footer(2),
+    )?;
+    check("source", crc32fast::hash(rom), footer(0))?;
+
+    let mut reader = BpsReader {
+        dat: &patch[BPS_MAGIC.len()..patch.len() - 12],
+    };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        Err(PatchError::InvalidBps("source size mismatch"))?
+    }
+
+    let mut ret = Vec::with_capacity(target_size);
+    let mut source_rel = 0;
+    let mut target_rel = 0;
+    let relative = |reader: &mut BpsReader, pos: &mut usize| {
+        let d = reader.number()?;
+        let ofs = d >> 1;
+        *pos = if d & 1 != 0 {
+            pos.checked_sub(ofs)
+        } else {
+            pos.checked_add(ofs)
+        }
+        .ok_or(PatchError::InvalidBps("copy offset out of range"))?;
+        Ok::<_, PatchError>(())
+    };
+
+    while !reader.dat.is_empty() {
+        let data = reader.number()?;
+        let len = (data >> 2) + 1;
+        match data & 3 {
+            // SourceRead
+            0 => {
+                let pos = ret.len();
+                let Some(src) = rom.get(pos..pos + len) else {
+                    Err(PatchError::InvalidBps("source read out of range"))?
+                };
+                ret.extend_from_slice(src);
+            }
+            // TargetRead
+            1 => ret.extend_from_slice(reader.bytes(len)?),
+            // SourceCopy
+            2 => {
+                relative(&mut reader, &mut source_rel)?;
+                let Some(src) = rom.get(source_rel..source_rel + len) else {
+                    Err(PatchError::InvalidBps("source copy out of range"))?
+                };
+                ret.extend_from_slice(src);
+                source_rel += len;
+            }
+            // TargetCopy, which can overlap the bytes being written
+            _ => {
+                relative(&mut reader, &mut target_rel)?;
+                for _ in 0..len {
+                    let Some(&b) = ret.get(target_rel) else {
+                        Err(PatchError::InvalidBps("target copy out of range"))?
+                    };
+                    ret.push(b);
+                    target_rel += 1;
+                }
+            }
+        }
+        if ret.len() > target_size {
+            Err(PatchError::InvalidBps("target size mismatch"))?
+        }
+    }
+
+    if ret.len() != target_size {
+        Err(PatchError::InvalidBps("target size mismatch"))?
+    }
+    check("target", crc32fast::hash(&ret), footer(1))?;
+    Ok(ret)
+}
+
+struct BpsReader<'a> {
+    dat: &'a [u8],
+}
+
+impl<'a> BpsReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        if self.dat.len() < len {
+            Err(PatchError::InvalidBps("unexpected end of patch"))?
+        }
+        let ret;
+        (ret, self.dat) = self.dat.split_at(len);
+        Ok(ret)
+    }
+
+    /// Reads a variable length number, 7 bits per byte with the last byte marked by bit 7
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let mut ret = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let b = self.bytes(1)?[0];
+            let add = (b as usize & 0x7F).checked_mul(shift);
+            ret = add
+                .and_then(|add| ret.checked_add(add))
+                .ok_or(PatchError::InvalidBps("number overflow"))?;
+            if b & 0x80 != 0 {
+                return Ok(ret);
+            }
+            shift = shift
+                .checked_shl(7)
+                .filter(|&s| s != 0)
+                .ok_or(PatchError::InvalidBps("number overflow"))?;
+            ret = ret
+                .checked_add(shift)
+                .ok_or(PatchError::InvalidBps("number overflow"))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ips() {
+        #[rustfmt::skip]
+        let patch = [
+            b"PATCH".as_slice(),
+            &[0x00, 0x00, 0x01, 0x00, 0x02, b'A', b'B'], // $000001: "AB"
+            &[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x03, b'Z'], // $000006: "ZZZ"
+            b"EOF",
+        ]
+        .concat();
+        assert_eq!(apply_patch(b"hello", &patch).unwrap(), b"hABlo\0ZZZ");
+
+        let truncate = [patch.as_slice(), &[0x00, 0x00, 0x03]].concat();
+        assert_eq!(apply_patch(b"hello", &truncate).unwrap(), b"hAB");
+        assert!(apply_ips(b"hello", &patch[..patch.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn bps() {
+        let source = b"hello world";
+        let target = b"hello there world!!";
+
+        let number = |mut n: usize| {
+            let mut ret = vec![];
+            loop {
+                let x = (n & 0x7F) as u8;
+                n >>= 7;
+                if n == 0 {
+                    ret.push(x | 0x80);
+                    return ret;
+                }
+                ret.push(x);
+                n -= 1;
+            }
+        };
+        let action = |cmd: usize, len: usize| number((len - 1) << 2 | cmd);
+
+        let patch = [
+            b"BPS1".as_slice(),
+            &number(source.len()),
+            &number(target.len()),
+            &number(0),
+            &action(0, 6), // SourceRead "hello "
+            &action(1, 6), // TargetRead "there "
+            b"there ",
+            &action(2, 5), // SourceCopy "world"
+            &number(6 << 1),
+            &action(1, 1), // TargetRead "!"
+            b"!",
+            &action(3, 1), // TargetCopy "!"
+            &number(17 << 1),
+            &crc32fast::hash(source).to_le_bytes(),
+            &crc32fast::hash(target).to_le_bytes(),
+        ]
+        .concat();
+        let patch = [patch.as_slice(), &crc32fast::hash(&patch).to_le_bytes()].concat();
+
+        assert_eq!(apply_patch(source, &patch).unwrap(), target);
+        assert!(matches!(
+            apply_bps(b"hello World", &patch),
+            Err(PatchError::ChecksumMismatch("source", _, _))
+        ));
+        assert!(matches!(
+            apply_patch(source, b"garbage"),
+            Err(PatchError::UnknownFormat)
+        ));
+    }
+}
@@ -1,10 +1,145 @@
 use bitvec::prelude::*;
-use meru_interface::FrameBuffer;
+use meru_interface::{Color, FrameBuffer};
 use serde::{Deserialize, Serialize};
 
-use crate::{consts::*, context, palette::NES_PALETTE, util::trait_alias};
+use crate::{
+    consts::*,
+    context,
+    debugger::BreakpointKind,
+    logging::core_log,
+    palette::{NES_PALETTE, RGB_PALETTE, RP2C04_LUT},
+    util::trait_alias,
+};
+
+trait_alias!(pub trait Context = context::Mapper + context::MemoryController + context::Interrupt + context::Timing);
+
+/// PPU chip variant, which determines the output palette
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PpuModel {
+    /// Standard NES PPU
+    #[default]
+    Rp2c02,
+    /// PAL NES PPU, which has red and green emphasis bits swapped
+    Rp2c07,
+    /// RGB PPU
+    Rp2c03,
+    /// RGB PPU with scrambled palette (0 to 3 for RP2C04-0001 to RP2C04-0004)
+    Rp2c04(u8),
+    /// RGB PPU with swapped $2000/$2001, returning `status_id` in the low bits of $2002
+    Rc2c05 { status_id: u8 },
+}
+
+impl PpuModel {
+    /// Converts the Vs. System PPU type in the NES 2.0 header
+    pub fn from_vs_ppu_type(ppu_type: u8) -> Self {
+        match ppu_type {
+            2..=5 => PpuModel::Rp2c04(ppu_type - 2),
+            8 => PpuModel::Rc2c05 { status_id: 0x1b },
+            9 => PpuModel::Rc2c05 { status_id: 0x3d },
+            10 => PpuModel::Rc2c05 { status_id: 0x1c },
+            11 => PpuModel::Rc2c05 { status_id: 0x1b },
+            12 => PpuModel::Rc2c05 { status_id: 0x00 },
+            _ => PpuModel::Rp2c03,
+        }
+    }
+
+    /// Converts a palette index to RGB, applying the emphasis bits of PPUMASK
+    /// (bit 0: red, bit 1: green, bit 2: blue as written on NTSC)
+    fn color(&self, index: u8, emphasis: u8) -> Color {
+        let index = index as usize & 0x3f;
+        let color = match self {
+            PpuModel::Rp2c02 | PpuModel::Rp2c07 => &NES_PALETTE[index],
+            PpuModel::Rp2c03 | PpuModel::Rc2c05 { .. } => &RGB_PALETTE[index],
+            PpuModel::Rp2c04(n) => &RGB_PALETTE[RP2C04_LUT[*n as usize][index] as usize],
+        };
 
-trait_alias!(pub trait Context = context::Mapper + context::Interrupt);
+        if emphasis == 0 {
+            return color.clone();
+        }
+
+        match self {
+            PpuModel::Rp2c02 | PpuModel::Rp2c07 => {
+                // Columns $xE and $xF are forced black and not affected
+                if index & 0x0e == 0x0e {
+                    return color.clone();
+                }
+                let emphasis = if *self == PpuModel::Rp2c07 {
+                    emphasis & 4 | (emphasis & 1) << 1 | (emphasis >> 1) & 1
+                } else {
+                    emphasis
+                };
+                // Each emphasized channel attenuates the other two by about 0.816
+                let attenuate = |v: u8, channel: u8| {
+                    let n = (emphasis & !(1 << channel)).count_ones();
+                    (0..n).fold(v as u32, |v, _| v * 209 / 256) as u8
+                };
+                Color::new(
+                    attenuate(color.r, 0),
+                    attenuate(color.g, 1),
+                    attenuate(color.b, 2),
+                )
+            }
+            // RGB PPUs drive the emphasized channels at full intensity
+            _ => {
+                let full = |v: u8, channel: u8| {
+                    if emphasis & (1 << channel) != 0 {
+                        0xff
+                    } else {
+                        v
+                    }
+                };
+                Color::new(full(color.r, 0), full(color.g, 1), full(color.b, 2))
+            }
+        }
+    }
+}
+
+fn new_index_buffer() -> Vec<u16> {
+    vec![0; SCREEN_WIDTH * SCREEN_HEIGHT]
+}
+
+/// Callback invoked with the PPU at a dot of each line
+pub type ScanlineHook = Box<dyn FnMut(&Ppu) + Send>;
+
+/// Area of the nametables shown on the screen, in the coordinates of `Ppu::render_nametables`.
+/// It wraps around at the right and bottom edges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrollRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Sprite in OAM decoded for debuggers
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SpriteInfo {
+    /// Index in OAM (0 to 63)
+    pub index: usize,
+    pub x: u8,
+    /// Y coordinate in OAM. The sprite appears from the next line.
+    pub y: u8,
+    pub tile: u8,
+    /// Raw attribute byte
+    pub attr: u8,
+    /// Sprite palette (0 to 3)
+    pub palette: u8,
+    pub behind_bg: bool,
+    pub flip_h: bool,
+    pub flip_v: bool,
+    /// 8 or 16
+    pub height: usize,
+    /// 8 x `height` pixel values (0 to 3, 0 is transparent) with flips applied
+    pub pattern: Vec<u8>,
+}
+
+/// Position where the sprite 0 hit flag was set
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sprite0Hit {
+    pub frame: u64,
+    pub line: usize,
+    pub dot: usize,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Ppu {
@@ -14,11 +149,115 @@ pub struct Ppu {
     line: usize,
     frame: u64,
     line_buf: Vec<u8>,
+    spr_buf: Vec<u8>,
     sprite0_hit: Vec<bool>,
-
-    #[serde(skip)]
+    bg_fetch: BgFetch,
+    sec_oam: [u8; 32],
+    spr_eval: SpriteEval,
+    spr_units: [SpriteUnit; 8],
+    sprite0_hit_pos: Option<Sprite0Hit>,
+    prev_sprite0_hit_pos: Option<Sprite0Hit>,
+
+    // Buffer being rendered
+    #[serde(with = "crate::util::frame_buffer_serde")]
+    back_buffer: FrameBuffer,
+    // Last completed frame
+    #[serde(with = "crate::util::frame_buffer_serde")]
     frame_buffer: FrameBuffer,
+    // Raw pixels (emphasis << 6 | palette index) of `back_buffer` and `frame_buffer`
+    #[serde(skip, default = "new_index_buffer")]
+    back_indices: Vec<u16>,
+    #[serde(skip, default = "new_index_buffer")]
+    frame_indices: Vec<u16>,
+    frame_ready: bool,
     render_graphics: bool,
+    // Scroll registers were written in the middle of a visible line in this frame
+    mid_line_scroll: bool,
+    // $2002 was read just before vblank starts
+    suppress_vblank: bool,
+    // Writes to $2000, $2001, $2005 and $2006 are ignored until the end of the first vblank
+    // after power-on or reset
+    warming_up: bool,
+    // Remaining frames in which the per-line renderer falls back to the per-dot one
+    // because of recent mid-line scroll writes
+    dot_frames: u32,
+    model: PpuModel,
+    #[serde(skip)]
+    scanline_hook: Option<(usize, ScanlineHook)>,
+    #[serde(skip)]
+    sanity_checks: bool,
+    #[serde(skip)]
+    dot_renderer: bool,
+    #[serde(skip)]
+    unlimited_sprites: bool,
+}
+
+/// State of the sprite evaluation, which copies sprites on the next line to secondary OAM
+#[derive(Default, Serialize, Deserialize)]
+struct SpriteEval {
+    // OAMADDR at the start of evaluation, which OAM is scanned from
+    start: usize,
+    // Sprite index and byte index in OAM
+    n: usize,
+    m: usize,
+    sec_index: usize,
+    latch: u8,
+    sprite0: bool,
+    done: bool,
+}
+
+/// Sprite fetched for the current line
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+struct SpriteUnit {
+    // Pattern bits with horizontal flip applied, leftmost pixel in bit 7
+    pat_lo: u8,
+    pat_hi: u8,
+    attr: u8,
+    x: u8,
+    sprite0: bool,
+}
+
+/// Latches and shift registers of the background fetch pipeline
+#[derive(Default, Serialize, Deserialize)]
+struct BgFetch {
+    nametable: u8,
+    attr: u8,
+    pat_lo: u8,
+    pat_hi: u8,
+
+    shift_pat_lo: u16,
+    shift_pat_hi: u16,
+    shift_attr_lo: u16,
+    shift_attr_hi: u16,
+}
+
+impl BgFetch {
+    fn shift(&mut self) {
+        self.shift_pat_lo <<= 1;
+        self.shift_pat_hi <<= 1;
+        self.shift_attr_lo <<= 1;
+        self.shift_attr_hi <<= 1;
+    }
+
+    fn load(&mut self) {
+        let fill = |b: bool| if b { 0xff } else { 0x00 };
+        self.shift_pat_lo = (self.shift_pat_lo & 0xff00) | self.pat_lo as u16;
+        self.shift_pat_hi = (self.shift_pat_hi & 0xff00) | self.pat_hi as u16;
+        self.shift_attr_lo = (self.shift_attr_lo & 0xff00) | fill(self.attr & 1 != 0);
+        self.shift_attr_hi = (self.shift_attr_hi & 0xff00) | fill(self.attr & 2 != 0);
+    }
+
+    /// Returns the palette index (attribute << 2 | pixel) of the current pixel
+    fn pixel(&self, fine_x: u8) -> u8 {
+        let bit = 15 - fine_x as u16;
+        let b = |r: u16| (r >> bit) as u8 & 1;
+        let pixel = b(self.shift_pat_lo) | b(self.shift_pat_hi) << 1;
+        if pixel == 0 {
+            0
+        } else {
+            (b(self.shift_attr_lo) | b(self.shift_attr_hi) << 1) << 2 | pixel
+        }
+    }
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -42,16 +281,93 @@ struct Register {
 
     oam_addr: u8,
 
-    toggle: bool,
-    scroll_x: u8,
-    tmp_addr: u16,
-    cur_addr: u16,
+    scroll: Loopy,
 
     vblank: bool,
     sprite0_hit: bool,
     sprite_over: bool,
 }
 
+/// Internal scroll registers of the PPU, named after the loopy document
+///
+/// `v` and `t` are laid out as `yyy NN YYYYY XXXXX`
+/// (fine Y, nametable, coarse Y, coarse X).
+#[derive(Default, Clone, Copy, Debug, Serialize, Deserialize)]
+struct Loopy {
+    /// Current VRAM address
+    v: u16,
+    /// Temporary VRAM address, which is the scroll position of the top left of the screen
+    t: u16,
+    /// Fine X scroll
+    x: u8,
+    /// First or second write toggle of $2005 and $2006
+    w: bool,
+}
+
+impl Loopy {
+    /// $2000 write: nametable select
+    fn write_ctrl(&mut self, data: u8) {
+        self.t = (self.t & !0x0c00) | (data as u16 & 3) << 10;
+    }
+
+    /// $2005 write
+    fn write_scroll(&mut self, data: u8) {
+        let data = data as u16;
+        if !self.w {
+            self.t = (self.t & !0x001f) | data >> 3;
+            self.x = data as u8 & 7;
+        } else {
+            self.t = (self.t & !0x73e0) | (data & 0xf8) << 2 | (data & 7) << 12;
+        }
+        self.w = !self.w;
+    }
+
+    /// $2006 write
+    fn write_addr(&mut self, data: u8) {
+        let data = data as u16;
+        if !self.w {
+            self.t = (self.t & 0x00ff) | (data & 0x3f) << 8;
+        } else {
+            self.t = (self.t & 0x7f00) | data;
+            self.v = self.t;
+        }
+        self.w = !self.w;
+    }
+
+    fn increment_x(&mut self) {
+        if self.v & 0x1f == 0x1f {
+            self.v = (self.v & !0x1f) ^ 0x400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if (self.v >> 12) & 7 == 7 {
+            self.v &= !0x7000;
+            if ((self.v >> 5) & 0x1f) == 29 {
+                self.v = (self.v & !0x03e0) ^ 0x800;
+            } else if (self.v >> 5) & 0x1f == 0x1f {
+                self.v &= !0x03e0;
+            } else {
+                self.v += 0x20;
+            }
+        } else {
+            self.v += 0x1000;
+        }
+    }
+
+    /// Copies coarse X and the horizontal nametable bit from `t` to `v`
+    fn copy_horizontal(&mut self) {
+        self.v = (self.v & !0x041f) | (self.t & 0x041f);
+    }
+
+    /// Copies fine Y, coarse Y and the vertical nametable bit from `t` to `v`
+    fn copy_vertical(&mut self) {
+        self.v = (self.v & !0x7be0) | (self.t & 0x7be0);
+    }
+}
+
 impl Register {
     fn new() -> Self {
         Self {
@@ -69,104 +385,390 @@ impl Default for Ppu {
             line: 0,
             frame: 0,
             line_buf: vec![0x00; SCREEN_WIDTH],
+            spr_buf: vec![0x00; SCREEN_WIDTH],
             sprite0_hit: vec![false; SCREEN_WIDTH],
+            bg_fetch: BgFetch::default(),
+            sec_oam: [0xff; 32],
+            spr_eval: SpriteEval::default(),
+            spr_units: Default::default(),
+            sprite0_hit_pos: None,
+            prev_sprite0_hit_pos: None,
+            back_buffer: FrameBuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT),
             frame_buffer: FrameBuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+            back_indices: new_index_buffer(),
+            frame_indices: new_index_buffer(),
+            frame_ready: false,
             render_graphics: true,
+            mid_line_scroll: false,
+            suppress_vblank: false,
+            warming_up: true,
+            dot_frames: 0,
+            model: PpuModel::default(),
+            scanline_hook: None,
+            sanity_checks: false,
+            dot_renderer: false,
+            unlimited_sprites: false,
         }
     }
 }
 
 impl Ppu {
+    /// Returns the last completed frame
     pub fn frame_buffer(&self) -> &FrameBuffer {
         &self.frame_buffer
     }
 
-    pub fn frame_buffer_mut(&mut self) -> &mut FrameBuffer {
-        &mut self.frame_buffer
+    /// Returns the last completed frame as raw pixels before palette lookup.
+    /// Each pixel is `emphasis << 6 | palette index`, where `emphasis` is bits 5-7 of PPUMASK.
+    pub fn index_buffer(&self) -> &[u16] {
+        &self.frame_indices
+    }
+
+    /// Takes the last completed frame if a new frame was completed since the last call.
+    /// `frame_buffer()` returns an empty buffer until the next frame is completed.
+    pub fn take_completed_frame(&mut self) -> Option<FrameBuffer> {
+        if !self.frame_ready {
+            return None;
+        }
+        self.frame_ready = false;
+        Some(std::mem::take(&mut self.frame_buffer))
+    }
+
+    fn swap_buffers(&mut self) {
+        std::mem::swap(&mut self.back_buffer, &mut self.frame_buffer);
+        std::mem::swap(&mut self.back_indices, &mut self.frame_indices);
+        self.back_buffer.resize(SCREEN_WIDTH, SCREEN_HEIGHT);
+        self.frame_ready = true;
+    }
+
+    /// Clears the registers which the reset signal clears
+    pub fn soft_reset(&mut self) {
+        let reg = &mut self.reg;
+        reg.nmi_enable = false;
+        reg.ppu_master = false;
+        reg.sprite_size = false;
+        reg.bg_pat_addr = false;
+        reg.sprite_pat_addr = false;
+        reg.ppu_addr_incr = false;
+        reg.bg_color = 0;
+        reg.sprite_visible = false;
+        reg.bg_visible = false;
+        reg.sprite_clip = true;
+        reg.bg_clip = true;
+        reg.color_display = false;
+        reg.scroll.w = false;
+        reg.scroll.x = 0;
+        reg.scroll.t = 0;
+        reg.vram_read_buf = 0;
+        self.warming_up = true;
     }
 
     pub fn frame(&self) -> u64 {
         self.frame
     }
 
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn dot(&self) -> usize {
+        self.counter
+    }
+
+    pub fn render_graphics(&self) -> bool {
+        self.render_graphics
+    }
+
     pub fn set_render_graphics(&mut self, render: bool) {
         self.render_graphics = render;
     }
 
+    /// Selects the per-dot background renderer instead of the per-line one.
+    /// Without this, frames are still rendered per dot for a while after a frame which
+    /// changes scrolling in the middle of a line, so that raster splits take effect at the right pixel.
+    pub fn set_dot_renderer(&mut self, enable: bool) {
+        self.dot_renderer = enable;
+    }
+
+    fn use_dot_renderer(&self) -> bool {
+        self.dot_renderer || self.dot_frames > 0
+    }
+
+    /// Draws all sprites on a line instead of the first 8, which reduces flicker.
+    /// The overflow flag is still set as the hardware does.
+    pub fn set_unlimited_sprites(&mut self, enable: bool) {
+        self.unlimited_sprites = enable;
+    }
+
+    /// Sets a callback invoked on every line before the PPU processes `dot` (0 to 340)
+    pub fn set_scanline_hook(&mut self, hook: Option<(usize, ScanlineHook)>) {
+        self.scanline_hook = hook;
+    }
+
+    pub fn take_scanline_hook(&mut self) -> Option<(usize, ScanlineHook)> {
+        self.scanline_hook.take()
+    }
+
+    /// Enables assertions of internal invariants
+    pub fn set_sanity_checks(&mut self, enable: bool) {
+        self.sanity_checks = enable;
+    }
+
+    pub fn model(&self) -> PpuModel {
+        self.model
+    }
+
+    pub fn set_model(&mut self, model: PpuModel) {
+        self.model = model;
+    }
+
+    /// Returns where sprite 0 hit occurred in the current frame so far
+    pub fn sprite0_hit_position(&self) -> Option<Sprite0Hit> {
+        self.sprite0_hit_pos
+    }
+
+    /// Returns where sprite 0 hit occurred in the last completed frame
+    pub fn prev_sprite0_hit_position(&self) -> Option<Sprite0Hit> {
+        self.prev_sprite0_hit_pos
+    }
+
+    /// Renders the four nametables into a 512x480 frame for debuggers,
+    /// using the current mirroring, pattern table, attributes and palette.
+    /// Reads memory without side effects on the mapper.
+    pub fn render_nametables(&self, ctx: &impl context::MemoryController, fb: &mut FrameBuffer) {
+        fb.resize(SCREEN_WIDTH * 2, SCREEN_HEIGHT * 2);
+
+        let pat_addr = if self.reg.bg_pat_addr { 0x1000 } else { 0x0000 };
+        let backdrop = ctx.read_chr(0x3f00) & 0x3f;
+
+        for nt in 0..4 {
+            let base = 0x2000 + nt as u16 * 0x400;
+            let ox = (nt & 1) * SCREEN_WIDTH;
+            let oy = (nt >> 1) * SCREEN_HEIGHT;
+
+            for ty in 0..30 {
+                for tx in 0..32 {
+                    let tile = ctx.read_chr(base + ty * 32 + tx) as u16 * 16;
+                    let attr = ctx.read_chr(base + 0x3c0 + ty / 4 * 8 + tx / 4);
+                    let attr = (attr >> ((tx & 2) + (ty & 2) * 2)) & 3;
+
+                    for y in 0..8 {
+                        let b0 = ctx.read_chr(pat_addr + tile + y);
+                        let b1 = ctx.read_chr(pat_addr + tile + 8 + y);
+                        for x in 0..8 {
+                            let b = (b0 >> (7 - x)) & 1 | ((b1 >> (7 - x)) & 1) << 1;
+                            let color = if b == 0 {
+                                backdrop
+                            } else {
+                                ctx.read_chr(0x3f00 + (attr << 2 | b) as u16) & 0x3f
+                            };
+                            *fb.pixel_mut(
+                                ox + tx as usize * 8 + x,
+                                oy + ty as usize * 8 + y as usize,
+                            ) = self.model.color(color, self.reg.bg_color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the raw contents of OAM
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    /// Decodes all 64 sprites in OAM with their patterns.
+    /// Reads memory without side effects on the mapper.
+    pub fn sprites(&self, ctx: &impl context::MemoryController) -> Vec<SpriteInfo> {
+        let height = self.sprite_height();
+        let pat_addr = if self.reg.sprite_pat_addr { 0x1000 } else { 0 };
+
+        (0..64)
+            .map(|index| {
+                let r = &self.oam[index * 4..index * 4 + 4];
+                let (y, tile, attr, x) = (r[0], r[1], r[2], r[3]);
+                let flip_h = attr & 0x40 != 0;
+                let flip_v = attr & 0x80 != 0;
+
+                let mut pattern = vec![0; 8 * height];
+                for row in 0..height {
+                    let src = if flip_v { height - 1 - row } else { row } as u16;
+                    let tile = tile as u16;
+                    let addr = if height == 16 {
+                        (tile & 1) * 0x1000 + (tile & !1) * 16 + (src & 8) * 2 + (src & 7)
+                    } else {
+                        pat_addr + tile * 16 + src
+                    };
+                    let b0 = ctx.read_chr(addr);
+                    let b1 = ctx.read_chr(addr + 8);
+                    for col in 0..8 {
+                        let bit = if flip_h { col } else { 7 - col };
+                        pattern[row * 8 + col] = (b0 >> bit) & 1 | ((b1 >> bit) & 1) << 1;
+                    }
+                }
+
+                SpriteInfo {
+                    index,
+                    x,
+                    y,
+                    tile,
+                    attr,
+                    palette: attr & 3,
+                    behind_bg: attr & 0x20 != 0,
+                    flip_h,
+                    flip_v,
+                    height,
+                    pattern,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the area of the nametables which the next frame starts rendering from
+    pub fn scroll_rect(&self) -> ScrollRect {
+        let t = self.reg.scroll.t as usize;
+        let coarse_x = t & 0x1f;
+        let coarse_y = (t >> 5) & 0x1f;
+        let nt = (t >> 10) & 3;
+        let fine_y = (t >> 12) & 7;
+        ScrollRect {
+            x: (nt & 1) * SCREEN_WIDTH + coarse_x * 8 + self.reg.scroll.x as usize,
+            y: (nt >> 1) * SCREEN_HEIGHT + coarse_y * 8 + fine_y,
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+        }
+    }
+
+    pub fn debug_dump(&self) -> serde_json::Value {
+        serde_json::json!({
+            "frame": self.frame,
+            "line": self.line,
+            "dot": self.counter,
+            "reg": &self.reg,
+            "sprite0_hit": self.sprite0_hit_pos,
+            "prev_sprite0_hit": self.prev_sprite0_hit_pos,
+        })
+    }
+
     pub fn tick(&mut self, ctx: &mut impl Context) {
         // 1 PPU cycle for 1 pixel
 
-        let screen_visible = self.reg.bg_visible || self.reg.sprite_visible;
+        if matches!(&self.scanline_hook, Some((dot, _)) if *dot == self.counter) {
+            let (dot, mut hook) = self.scanline_hook.take().unwrap();
+            hook(self);
+            self.scanline_hook = Some((dot, hook));
+        }
 
-        if self.counter == 0 {
-            log::info!("line {} starts", self.line);
+        let screen_visible = self.reg.bg_visible || self.reg.sprite_visible;
+        let region = ctx.region();
+        let pre_render_line = region.pre_render_line();
 
-            if self.line == SCREEN_RANGE.start && screen_visible {
-                self.reg.cur_addr = self.reg.tmp_addr;
+        if (self.line, self.counter) == (pre_render_line, 0) {
+            if std::mem::take(&mut self.mid_line_scroll) {
+                self.dot_frames = DOT_RENDERER_FALLBACK_FRAMES;
+            } else {
+                self.dot_frames = self.dot_frames.saturating_sub(1);
             }
+        }
 
-            if SCREEN_RANGE.contains(&self.line) && screen_visible {
-                self.reg.cur_addr = (self.reg.cur_addr & 0xfbe0) | (self.reg.tmp_addr & 0x041f);
-            }
+        if self.use_dot_renderer() {
+            self.tick_dot(ctx);
+        } else if self.counter == 0 {
+            core_log!(PpuTiming, Info, "line {} starts", self.line);
 
             if SCREEN_RANGE.contains(&self.line) {
                 self.render_line(ctx);
+            }
+        }
 
-                if screen_visible {
-                    if (self.reg.cur_addr >> 12) & 7 == 7 {
-                        self.reg.cur_addr &= !0x7000;
-                        if ((self.reg.cur_addr >> 5) & 0x1f) == 29 {
-                            self.reg.cur_addr = (self.reg.cur_addr & !0x03e0) ^ 0x800;
-                        } else if (self.reg.cur_addr >> 5) & 0x1f == 0x1f {
-                            self.reg.cur_addr &= !0x03e0;
-                        } else {
-                            self.reg.cur_addr += 0x20;
-                        }
-                    } else {
-                        self.reg.cur_addr += 0x1000;
-                    }
+        // The per-line renderer does not fetch per dot, but updates `v` at the same timings
+        if !self.use_dot_renderer()
+            && screen_visible
+            && (SCREEN_RANGE.contains(&self.line) || self.line == pre_render_line)
+        {
+            self.update_scroll(self.counter, self.line == pre_render_line);
+        }
+
+        if screen_visible && (SCREEN_RANGE.contains(&self.line) || self.line == pre_render_line) {
+            match self.counter {
+                // OAMADDR is cleared while sprite patterns are fetched
+                257..=320 => self.reg.oam_addr = 0,
+                // 2C02 bug: starting rendering with OAMADDR >= 8 copies
+                // the 8 bytes at OAMADDR & $F8 to the beginning of OAM
+                1 if self.line == pre_render_line && self.reg.oam_addr >= 8 => {
+                    let src = (self.reg.oam_addr & 0xf8) as usize;
+                    self.oam.copy_within(src..src + 8, 0);
                 }
+                _ => (),
             }
         }
 
         if (self.line, self.counter) == (POST_RENDER_LINE + 1, 1) {
-            log::info!("enter vblank");
-            self.reg.vblank = true;
+            core_log!(PpuTiming, Info, "enter vblank");
+            if !std::mem::take(&mut self.suppress_vblank) {
+                self.reg.vblank = true;
+            }
         }
 
-        if (self.line, self.counter) == (PRE_RENDER_LINE, 1) {
-            log::info!("leave vblank");
+        if (self.line, self.counter) == (pre_render_line, 1) {
+            core_log!(PpuTiming, Info, "leave vblank");
+            self.warming_up = false;
             self.reg.vblank = false;
             self.reg.sprite0_hit = false;
+            self.reg.sprite_over = false;
         }
 
-        if screen_visible
-            && (self.line < SCREEN_RANGE.end || self.line == PRE_RENDER_LINE)
+        if !self.use_dot_renderer()
+            && screen_visible
+            && (self.line < SCREEN_RANGE.end || self.line == pre_render_line)
             && self.counter == 256
         {
             let bg_pat_addr = if self.reg.bg_pat_addr { 0x1000 } else { 0 };
             let spr_pat_addr = if self.reg.sprite_pat_addr { 0x1000 } else { 0 };
-            // FIXME: Dummy read for mapper that use CHR Address value
+            // The per-line renderer does not fetch per dot, so mappers which watch
+            // the CHR address see the switch from background to sprite fetches here
             let _ = read_pattern(ctx, bg_pat_addr);
             let _ = read_pattern(ctx, spr_pat_addr);
         }
 
-        if screen_visible
+        // The per-line renderer finds hits at dot 0, and sets the flag when the pixel is output
+        // at the dot after its X coordinate, as the per-dot renderer does.
+        // Rendering and clipping changes made since dot 0 are honored.
+        if !self.use_dot_renderer()
             && SCREEN_RANGE.contains(&self.line)
-            && self.counter < SCREEN_WIDTH
-            && self.sprite0_hit[self.counter as usize]
+            && (1..=SCREEN_WIDTH).contains(&self.counter)
         {
-            self.reg.sprite0_hit = true;
+            let x = self.counter - 1;
+            let clipped = x < 8 && (self.reg.bg_clip || self.reg.sprite_clip);
+            if self.sprite0_hit[x] && self.reg.bg_visible && self.reg.sprite_visible && !clipped {
+                self.set_sprite0_hit();
+            }
         }
 
         self.counter += 1;
 
+        // The idle dot at the end of the pre-render line is skipped on odd frames
+        // while rendering is enabled (only on NTSC)
+        if region == Region::Ntsc
+            && self.line == pre_render_line
+            && self.counter == PPU_CLOCK_PER_LINE as usize - 1
+            && self.frame % 2 == 1
+            && (self.reg.bg_visible || self.reg.sprite_visible)
+        {
+            self.counter += 1;
+            // Keep mappers which count PPU dots in sync
+            ctx.tick_mapper();
+        }
+
         if self.counter == PPU_CLOCK_PER_LINE as usize {
             self.counter = 0;
             self.line += 1;
-            if self.line == LINES_PER_FRAME {
+            if self.line == region.lines_per_frame() {
                 self.line = 0;
                 self.frame += 1;
+                self.swap_buffers();
+                self.prev_sprite0_hit_pos = self.sprite0_hit_pos.take();
             }
         }
 
@@ -174,29 +776,424 @@ impl Ppu {
         ctx.set_nmi(nmi);
     }
 
+    fn set_sprite0_hit(&mut self) {
+        if !self.reg.sprite0_hit {
+            self.sprite0_hit_pos = Some(Sprite0Hit {
+                frame: self.frame,
+                line: self.line,
+                dot: self.counter,
+            });
+        }
+        self.reg.sprite0_hit = true;
+    }
+
+    fn tick_dot(&mut self, ctx: &mut impl Context) {
+        let rendering = self.reg.bg_visible || self.reg.sprite_visible;
+        let visible_line = SCREEN_RANGE.contains(&self.line);
+        let pre_render_line = self.line == ctx.region().pre_render_line();
+        let dot = self.counter;
+
+        if visible_line && dot == 0 {
+            self.build_sprite_line();
+            if rendering && self.unlimited_sprites {
+                self.render_extra_sprites(ctx, self.spr_eval.start, 0);
+            }
+        }
+
+        if rendering && visible_line {
+            match dot {
+                1..=64 if dot.is_multiple_of(2) => self.sec_oam[dot / 2 - 1] = 0xff,
+                65..=256 => self.evaluate_sprites(dot),
+                _ => (),
+            }
+        }
+
+        if (visible_line || pre_render_line) && (257..=320).contains(&dot) {
+            if dot == 257 {
+                self.spr_units = Default::default();
+            }
+            if rendering {
+                self.fetch_sprites(ctx, dot, visible_line);
+            }
+        }
+
+        if rendering && (visible_line || pre_render_line) {
+            if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+                self.bg_fetch.shift();
+            }
+
+            if (1..=256).contains(&dot) || (321..=336).contains(&dot) {
+                self.fetch_bg(ctx, (dot - 1) % 8);
+            }
+
+            match dot {
+                257 => self.bg_fetch.load(),
+                // Unused nametable fetches
+                337 | 339 => {
+                    let _ = read_nametable(ctx, self.reg.scroll.v & 0x0fff);
+                }
+                _ => (),
+            }
+
+            self.update_scroll(dot, pre_render_line);
+        }
+
+        if visible_line && (1..=256).contains(&dot) {
+            self.output_pixel(ctx, dot - 1);
+        }
+    }
+
+    fn fetch_bg(&mut self, ctx: &mut impl Context, step: usize) {
+        let v = self.reg.scroll.v;
+        let pat_addr = if self.reg.bg_pat_addr { 0x1000 } else { 0x0000 };
+        let fine_y = (v >> 12) & 7;
+
+        match step {
+            0 => {
+                self.bg_fetch.load();
+                self.bg_fetch.nametable = read_nametable(ctx, v & 0x0fff);
+            }
+            2 => {
+                let addr = 0x03c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                let shift = ((v >> 4) & 4) | (v & 2);
+                self.bg_fetch.attr = (read_nametable(ctx, addr) >> shift) & 3;
+            }
+            4 => {
+                let tile = self.bg_fetch.nametable as u16 * 16;
+                self.bg_fetch.pat_lo = read_pattern(ctx, pat_addr + tile + fine_y);
+            }
+            6 => {
+                let tile = self.bg_fetch.nametable as u16 * 16;
+                self.bg_fetch.pat_hi = read_pattern(ctx, pat_addr + tile + 8 + fine_y);
+            }
+            _ => (),
+        }
+    }
+
+    fn is_rendering(&self, region: Region) -> bool {
+        (self.reg.bg_visible || self.reg.sprite_visible)
+            && (SCREEN_RANGE.contains(&self.line) || self.line == region.pre_render_line())
+    }
+
+    /// Value on the OAM data bus seen by $2004 reads during rendering,
+    /// or `None` if $2004 reads OAM at OAMADDR
+    fn oam_bus(&self, region: Region) -> Option<u8> {
+        if !self.use_dot_renderer() || !self.is_rendering(region) {
+            return None;
+        }
+        let visible_line = SCREEN_RANGE.contains(&self.line);
+        Some(match self.counter {
+            // Secondary OAM clear reads are forced to $FF
+            1..=64 if visible_line => 0xff,
+            65..=256 if visible_line => self.spr_eval.latch,
+            257..=320 => {
+                let step = (self.counter - 257) % 8;
+                self.sec_oam[(self.counter - 257) / 8 * 4 + step.min(3)]
+            }
+            _ => self.sec_oam[0],
+        })
+    }
+
+    fn sprite_height(&self) -> usize {
+        if self.reg.sprite_size {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn evaluate_sprites(&mut self, dot: usize) {
+        if dot == 65 {
+            self.spr_eval = SpriteEval {
+                start: self.reg.oam_addr as usize,
+                ..Default::default()
+            };
+        }
+
+        let height = self.sprite_height();
+        let line = self.line;
+        let in_range = |y: u8| line.wrapping_sub(y as usize) < height;
+        let e = &mut self.spr_eval;
+
+        if e.done {
+            return;
+        }
+
+        // OAM is read on odd cycles and secondary OAM is written on even cycles
+        if dot % 2 == 1 {
+            e.latch = self.oam[(e.start + e.n * 4 + e.m) & 0xff];
+            return;
+        }
+
+        if e.sec_index < self.sec_oam.len() {
+            self.sec_oam[e.sec_index] = e.latch;
+            if e.m == 0 {
+                if in_range(e.latch) {
+                    e.sprite0 |= e.n == 0;
+                    e.sec_index += 1;
+                    e.m = 1;
+                } else {
+                    e.n += 1;
+                }
+            } else {
+                e.sec_index += 1;
+                e.m += 1;
+                if e.m == 4 {
+                    e.m = 0;
+                    e.n += 1;
+                }
+            }
+        } else if in_range(e.latch) {
+            self.reg.sprite_over = true;
+            e.done = true;
+        } else {
+            // Hardware bug: the byte index is incremented along with the sprite index,
+            // so the overflow check reads a diagonal of OAM
+            e.n += 1;
+            e.m = (e.m + 1) % 4;
+        }
+
+        if e.n == 64 {
+            e.done = true;
+        }
+    }
+
+    fn fetch_sprites(&mut self, ctx: &mut impl Context, dot: usize, evaluated: bool) {
+        let i = (dot - 257) / 8;
+        let step = (dot - 257) % 8;
+
+        // Slots without sprites fetch tile $FF
+        let count = self.spr_eval.sec_index.div_ceil(4);
+        let empty = !evaluated || i >= count;
+        let r = &self.sec_oam[i * 4..i * 4 + 4];
+        let (y, tile, attr, x) = if empty {
+            (0, 0xff, 0, 0xff)
+        } else {
+            (r[0], r[1], r[2], r[3])
+        };
+
+        let height = self.sprite_height();
+        let row = if empty {
+            0
+        } else {
+            let row = self.line.wrapping_sub(y as usize) % height;
+            if attr & 0x80 != 0 {
+                height - 1 - row
+            } else {
+                row
+            }
+        } as u16;
+
+        let tile = tile as u16;
+        let addr = if height == 16 {
+            (tile & 1) * 0x1000 + (tile & !1) * 16 + (row & 8) * 2 + (row & 7)
+        } else {
+            let pat_addr = if self.reg.sprite_pat_addr { 0x1000 } else { 0 };
+            pat_addr + tile * 16 + row
+        };
+
+        let flip = |b: u8| {
+            if attr & 0x40 != 0 {
+                b.reverse_bits()
+            } else {
+                b
+            }
+        };
+        let v = self.reg.scroll.v;
+        let unit = &mut self.spr_units[i];
+
+        match step {
+            0 => {
+                // Garbage nametable fetches, which mappers still see on the bus
+                let _ = read_nametable(ctx, v & 0x0fff);
+                unit.attr = attr & 0xe3;
+                unit.x = x;
+                unit.sprite0 = i == 0 && self.spr_eval.sprite0 && !empty;
+            }
+            2 => {
+                let _ = read_nametable(ctx, v & 0x0fff);
+            }
+            4 => {
+                let b = flip(read_pattern(ctx, addr));
+                unit.pat_lo = if empty { 0 } else { b };
+            }
+            6 => {
+                let b = flip(read_pattern(ctx, addr + 8));
+                unit.pat_hi = if empty { 0 } else { b };
+            }
+            _ => (),
+        }
+    }
+
+    /// Renders fetched sprite units into `spr_buf`
+    fn build_sprite_line(&mut self) {
+        self.spr_buf.fill(0);
+
+        for unit in &self.spr_units {
+            for lx in 0..8 {
+                let x = unit.x as usize + lx;
+                if x >= SCREEN_WIDTH {
+                    break;
+                }
+                let bit = 7 - lx;
+                let pixel = (unit.pat_lo >> bit) & 1 | ((unit.pat_hi >> bit) & 1) << 1;
+                if pixel == 0 || self.spr_buf[x] & SPR_OPAQUE != 0 {
+                    continue;
+                }
+
+                let mut spr = SPR_OPAQUE | 0x10 | (unit.attr & 3) << 2 | pixel;
+                if unit.sprite0 {
+                    spr |= SPR_ZERO;
+                }
+                if unit.attr & 0x20 != 0 {
+                    spr |= SPR_BEHIND;
+                }
+                self.spr_buf[x] = spr;
+            }
+        }
+    }
+
+    /// Draws sprites on the current line after the first 8 into `spr_buf`, behind the others.
+    /// OAM is scanned from byte `start`, and patterns are read without notifying the mapper
+    /// because the hardware never fetches them.
+    fn render_extra_sprites(&mut self, ctx: &impl Context, start: usize, leftmost: usize) {
+        let height = self.sprite_height();
+        let line = self.line;
+        let in_range = |y: u8| line.wrapping_sub(y as usize + 1) < height;
+
+        let mut found = 0;
+        for n in 0..64 {
+            let r: [u8; 4] = std::array::from_fn(|m| self.oam[(start + n * 4 + m) & 0xff]);
+            let [y, tile, attr, x] = r;
+            if !in_range(y) {
+                continue;
+            }
+            found += 1;
+            if found <= 8 {
+                continue;
+            }
+
+            let row = line - (y as usize + 1);
+            let row = if attr & 0x80 != 0 {
+                height - 1 - row
+            } else {
+                row
+            } as u16;
+            let tile = tile as u16;
+            let addr = if height == 16 {
+                (tile & 1) * 0x1000 + (tile & !1) * 16 + (row & 8) * 2 + (row & 7)
+            } else {
+                let pat_addr = if self.reg.sprite_pat_addr { 0x1000 } else { 0 };
+                pat_addr + tile * 16 + row
+            };
+            let lo = ctx.read_chr(addr);
+            let hi = ctx.read_chr(addr + 8);
+
+            for lx in 0..8 {
+                let px = x as usize + lx;
+                if px >= SCREEN_WIDTH {
+                    break;
+                }
+                let bit = if attr & 0x40 != 0 { lx } else { 7 - lx };
+                let pixel = (lo >> bit) & 1 | ((hi >> bit) & 1) << 1;
+                if px < leftmost || pixel == 0 || self.spr_buf[px] & SPR_OPAQUE != 0 {
+                    continue;
+                }
+                let mut spr = SPR_OPAQUE | 0x10 | (attr & 3) << 2 | pixel;
+                if attr & 0x20 != 0 {
+                    spr |= SPR_BEHIND;
+                }
+                self.spr_buf[px] = spr;
+            }
+        }
+    }
+
+    /// Advances `v` after a $2007 access
+    fn increment_vram_addr(&mut self, region: Region) {
+        if self.is_rendering(region) {
+            // While rendering, the access glitches into the coarse X and Y increments of rendering
+            self.reg.scroll.increment_x();
+            self.reg.scroll.increment_y();
+        } else {
+            let inc_addr = if self.reg.ppu_addr_incr { 32 } else { 1 };
+            self.reg.scroll.v = self.reg.scroll.v.wrapping_add(inc_addr);
+        }
+    }
+
+    /// Updates `v` at a dot of a visible or the pre-render line while rendering
+    fn update_scroll(&mut self, dot: usize, pre_render_line: bool) {
+        let scroll = &mut self.reg.scroll;
+
+        // Coarse X is incremented after each tile fetch, including the two prefetched tiles
+        if ((1..=256).contains(&dot) || (321..=336).contains(&dot)) && dot.is_multiple_of(8) {
+            scroll.increment_x();
+        }
+
+        match dot {
+            256 => scroll.increment_y(),
+            257 => scroll.copy_horizontal(),
+            280..=304 if pre_render_line => scroll.copy_vertical(),
+            _ => (),
+        }
+    }
+
+    fn output_pixel(&mut self, ctx: &mut impl Context, x: usize) {
+        let bg = if self.reg.bg_visible && !(self.reg.bg_clip && x < 8) {
+            self.bg_fetch.pixel(self.reg.scroll.x)
+        } else {
+            0
+        };
+
+        let spr = if self.reg.sprite_visible && !(self.reg.sprite_clip && x < 8) {
+            self.spr_buf[x]
+        } else {
+            0
+        };
+        if spr & SPR_ZERO != 0 && bg != 0 && x < 255 {
+            self.set_sprite0_hit();
+        }
+
+        let color = read_palette(ctx, mux_pixel(bg, spr)) & 0x3f;
+        self.put_pixel(x, color);
+    }
+
     pub fn render_line(&mut self, ctx: &mut impl Context) {
-        let bg = read_palette(ctx, 0) & 0x3f;
-        self.line_buf.fill(bg);
+        self.line_buf.fill(0);
         self.sprite0_hit.fill(false);
 
         self.render_bg(ctx);
         self.render_spr(ctx);
 
-        if self.reg.bg_clip || self.reg.sprite_clip {
+        for x in 0..SCREEN_WIDTH {
+            let bg = self.line_buf[x];
+            let spr = self.spr_buf[x];
+            if spr & SPR_ZERO != 0 && bg != 0 && x < 255 {
+                self.sprite0_hit[x] = true;
+            }
+            self.line_buf[x] = mux_pixel(bg, spr);
+        }
+
+        if self.sanity_checks && (self.reg.bg_clip || self.reg.sprite_clip) {
             for i in 0..8 {
                 assert!(!self.sprite0_hit[i]);
             }
         }
 
         for x in 0..SCREEN_WIDTH {
-            *self.frame_buffer.pixel_mut(x, self.line) =
-                NES_PALETTE[self.line_buf[x] as usize & 0x3f].clone();
+            let color = read_palette(ctx, self.line_buf[x]) & 0x3f;
+            self.put_pixel(x, color);
         }
     }
 
+    fn put_pixel(&mut self, x: usize, color: u8) {
+        let emphasis = self.reg.bg_color;
+        *self.back_buffer.pixel_mut(x, self.line) = self.model.color(color, emphasis);
+        self.back_indices[self.line * SCREEN_WIDTH + x] = (emphasis as u16) << 6 | color as u16;
+    }
+
     pub fn render_bg(&mut self, ctx: &mut impl Context) {
-        let x_ofs = self.reg.scroll_x as usize;
-        let y_ofs = (self.reg.cur_addr >> 12) & 7;
+        let x_ofs = self.reg.scroll.x as usize;
+        let y_ofs = (self.reg.scroll.v >> 12) & 7;
         let pat_addr = if self.reg.bg_pat_addr { 0x1000 } else { 0x0000 };
         let leftmost = if self.reg.bg_clip { 8 } else { 0 };
 
@@ -206,7 +1203,15 @@ impl Ppu {
             return;
         }
 
-        let mut name_addr = self.reg.cur_addr & 0xfff;
+        // `v` has been advanced by the two tiles prefetched at the end of the previous line
+        let mut name_addr = self.reg.scroll.v & 0xfff;
+        for _ in 0..2 {
+            name_addr = if name_addr & 0x1f == 0 {
+                (name_addr | 0x1f) ^ 0x400
+            } else {
+                name_addr - 1
+            };
+        }
 
         for i in 0..33 {
             let tile = read_nametable(ctx, name_addr) as u16 * 16;
@@ -235,7 +1240,7 @@ impl Ppu {
 
                 let b = (b0 >> (7 - lx)) & 1 | ((b1 >> (7 - lx)) & 1) << 1;
                 if b != 0 {
-                    self.line_buf[x - 8] = 0x40 + read_palette(ctx, attr << 2 | b);
+                    self.line_buf[x - 8] = attr << 2 | b;
                 }
             }
 
@@ -247,31 +1252,69 @@ impl Ppu {
         }
     }
 
+    /// Finds the first 8 sprites on the current line and sets the overflow flag,
+    /// scanning OAM the same way as `evaluate_sprites` does
+    fn scan_sprites(&mut self) -> ([usize; 8], usize) {
+        let height = self.sprite_height();
+        let line = self.line;
+        let in_range = |y: u8| line.wrapping_sub(y as usize + 1) < height;
+
+        let mut sprites = [0; 8];
+        let mut count = 0;
+        let mut n = 0;
+        while n < 64 && count < 8 {
+            if in_range(self.oam[n * 4]) {
+                sprites[count] = n;
+                count += 1;
+            }
+            n += 1;
+        }
+
+        // Hardware bug: the byte index is incremented along with the sprite index,
+        // so the overflow check reads a diagonal of OAM
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam[n * 4 + m]) {
+                self.reg.sprite_over = true;
+                break;
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
+
+        (sprites, count)
+    }
+
+    /// Renders sprites of the current line into `spr_buf`
     pub fn render_spr(&mut self, ctx: &mut impl Context) {
+        self.spr_buf.fill(0);
+
+        if !(self.reg.bg_visible || self.reg.sprite_visible) {
+            return;
+        }
+
+        let (sprites, count) = self.scan_sprites();
+
         if !self.reg.sprite_visible {
             return;
         }
 
-        let spr_height = if self.reg.sprite_size { 16 } else { 8 };
+        let spr_height = self.sprite_height();
         let pat_addr = if self.reg.sprite_pat_addr { 0x1000 } else { 0 };
         let leftmost = if self.reg.sprite_clip { 8 } else { 0 };
 
-        for i in 0..64 {
+        for &i in &sprites[..count] {
             let r = &self.oam[i * 4..(i + 1) * 4];
             let spr_y = r[0] as usize + 1;
 
-            if i == 0 {
-                log::trace!("sprite {i}, y = {spr_y}, cur_line: {}", self.line);
-            }
-
-            if !(spr_y..spr_y + spr_height).contains(&self.line) {
-                continue;
-            }
-
             let tile_index = r[1] as u16;
             let spr_x = r[3] as usize;
 
-            log::trace!("sprite {i}, x = {spr_x}, y = {spr_y}, tile = {tile_index}");
+            core_log!(
+                Sprite,
+                Trace,
+                "sprite {i}, x = {spr_x}, y = {spr_y}, tile = {tile_index}"
+            );
 
             let attr = r[2].view_bits::<Lsb0>();
             let upper = attr[0..2].load::<u8>() << 2;
@@ -304,17 +1347,22 @@ impl Ppu {
                 }
 
                 let lo = (b0 >> lx) & 1 | ((b1 >> lx) & 1) << 1;
-                if lo != 0 && self.line_buf[x] & 0x80 == 0 {
-                    if i == 0 && x < 255 && self.line_buf[x] & 0x40 != 0 {
-                        self.sprite0_hit[x] = true;
+                if lo != 0 && self.spr_buf[x] & SPR_OPAQUE == 0 {
+                    let mut spr = SPR_OPAQUE | 0x10 | upper | lo;
+                    if i == 0 {
+                        spr |= SPR_ZERO;
                     }
-                    if !is_bg || self.line_buf[x] & 0x40 == 0 {
-                        self.line_buf[x] = read_palette(ctx, 0x10 | upper | lo);
+                    if is_bg {
+                        spr |= SPR_BEHIND;
                     }
-                    self.line_buf[x] |= 0x80;
+                    self.spr_buf[x] = spr;
                 }
             }
         }
+
+        if self.unlimited_sprites {
+            self.render_extra_sprites(ctx, 0, leftmost);
+        }
     }
 
     pub fn read(&mut self, ctx: &mut impl Context, addr: u16) -> u8 {
@@ -322,36 +1370,57 @@ impl Ppu {
             2 => {
                 // Status
                 let ret = bits![mut u8, Lsb0; 0; 8];
-                ret[0..5].store(self.reg.buf & 0x1f);
+                match self.model {
+                    PpuModel::Rc2c05 { status_id } if status_id != 0 => {
+                        ret[0..5].store(status_id & 0x1f)
+                    }
+                    _ => ret[0..5].store(self.reg.buf & 0x1f),
+                }
                 ret.set(5, self.reg.sprite_over);
                 ret.set(6, self.reg.sprite0_hit);
                 ret.set(7, self.reg.vblank);
 
+                // Race with the start of vblank at dot 1: reading one dot before it
+                // suppresses the flag, and reading on the dot or one dot after it
+                // returns the flag but suppresses NMI
+                if self.line == POST_RENDER_LINE + 1 {
+                    match self.counter {
+                        1 => self.suppress_vblank = true,
+                        2 | 3 => ctx.clear_nmi_edge(),
+                        _ => (),
+                    }
+                }
+
                 self.reg.vblank = false;
-                self.reg.toggle = false;
+                self.reg.scroll.w = false;
 
-                log::info!(target: "ppureg", "[PPUSTATUS] -> ${ret:02X}");
+                core_log!(PpuReg, Info, "[PPUSTATUS] -> ${ret:02X}");
 
                 ret.load()
             }
 
             4 => {
                 // OAM Data
-                let ret = self.oam[self.reg.oam_addr as usize];
-                let ret = if self.reg.oam_addr & 3 == 2 {
-                    ret & 0xe3
-                } else {
-                    ret
+                let ret = match self.oam_bus(ctx.region()) {
+                    Some(ret) => ret,
+                    None => {
+                        let ret = self.oam[self.reg.oam_addr as usize];
+                        if self.reg.oam_addr & 3 == 2 {
+                            ret & 0xe3
+                        } else {
+                            ret
+                        }
+                    }
                 };
 
-                log::info!(target: "ppureg", "[OAMDATA] -> ${ret:02X}",);
+                core_log!(PpuReg, Info, "[OAMDATA] -> ${ret:02X}",);
 
                 ret
             }
 
             7 => {
                 // Data
-                let addr = self.reg.cur_addr & 0x3fff;
+                let addr = self.reg.scroll.v & 0x3fff;
 
                 let ret = if addr & 0x3f00 == 0x3f00 {
                     self.reg.vram_read_buf = ctx.read_chr_mapper(addr & !0x1000);
@@ -362,16 +1431,23 @@ impl Ppu {
                     ret
                 };
 
-                let inc_addr = if self.reg.ppu_addr_incr { 32 } else { 1 };
-                self.reg.cur_addr = self.reg.cur_addr.wrapping_add(inc_addr);
+                self.increment_vram_addr(ctx.region());
+
+                core_log!(PpuReg, Info, "[PPUDATA], CHR[${addr:04X}] -> ${ret:02X}");
 
-                log::info!(target: "ppureg", "[PPUDATA], CHR[${addr:04X}] -> ${ret:02X}");
+                let now = ctx.now();
+                ctx.memory_ctrl_mut().debugger_mut().check_access(
+                    BreakpointKind::PpuRead,
+                    addr,
+                    ret,
+                    now,
+                );
 
                 ret
             }
 
             _ => {
-                log::info!("Read from invalid PPU register: [{addr}]");
+                core_log!(PpuReg, Info, "Read from invalid PPU register: [{addr}]");
                 self.reg.buf
             }
         };
@@ -383,14 +1459,44 @@ impl Ppu {
     pub fn write(&mut self, ctx: &mut impl Context, addr: u16, data: u8) {
         self.reg.buf = data;
 
+        let addr = match (self.model, addr) {
+            (PpuModel::Rc2c05 { .. }, 0 | 1) => addr ^ 1,
+            _ => addr,
+        };
+
+        if self.warming_up && matches!(addr, 0 | 1 | 5 | 6) {
+            core_log!(
+                PpuReg,
+                Trace,
+                "ignored write during warm-up: ${:04X} = ${data:02X}",
+                0x2000 + addr
+            );
+            return;
+        }
+
+        // $2000 writes which keep the nametable select are not counted as scroll changes
+        let scroll_write = match addr {
+            0 => (data as u16 & 3) << 10 != self.reg.scroll.t & 0x0c00,
+            5 | 6 => true,
+            _ => false,
+        };
+        if scroll_write
+            && self.is_rendering(ctx.region())
+            && SCREEN_RANGE.contains(&self.line)
+            && (1..=SCREEN_WIDTH).contains(&self.counter)
+        {
+            self.mid_line_scroll = true;
+        }
+
         match addr {
             0 => {
                 // Controller
                 let data = data.view_bits::<Lsb0>();
 
-                log::info!(
-                    target: "ppureg::PPUCTRL",
-                    "= b{data:08b}: nmi={nmi}, ppu={ppu}, spr={sprite_size}, bgpat=${bg_pat_addr:04X}, sprpat=${sprite_pat_addr:04X}, addrinc={ppu_addr_incr}, nt_addr=${base_nametable_addr:04X}",
+                core_log!(
+                    PpuReg,
+                    Info,
+                    "[PPUCTRL] = b{data:08b}: nmi={nmi}, ppu={ppu}, spr={sprite_size}, bgpat=${bg_pat_addr:04X}, sprpat=${sprite_pat_addr:04X}, addrinc={ppu_addr_incr}, nt_addr=${base_nametable_addr:04X}",
                     nmi = if data[7] { "t" } else { "f" },
                     ppu = if data[6] { "t" } else { "f" },
                     sprite_size = if data[5] { "8x16" } else { "8x8" },
@@ -407,14 +1513,14 @@ impl Ppu {
                 self.reg.sprite_pat_addr = data[3];
                 self.reg.ppu_addr_incr = data[2];
 
-                self.reg.tmp_addr.view_bits_mut::<Lsb0>()[10..12].store(data[0..2].load::<u16>());
+                self.reg.scroll.write_ctrl(data.load());
             }
 
             1 => {
                 // Mask
                 let data = data.view_bits::<Lsb0>();
 
-                log::info!(target: "ppureg::PPUMASK", "= b{data:08b}: bgcol={r}{g}{b}, spr_vis={sprite_visible}, bg_vis={bg_visible}, spr_clip={sprite_clip}, bg_clip={bg_clip}, greyscale={greyscale}",
+                core_log!(PpuReg, Info, "[PPUMASK] = b{data:08b}: bgcol={r}{g}{b}, spr_vis={sprite_visible}, bg_vis={bg_visible}, spr_clip={sprite_clip}, bg_clip={bg_clip}, greyscale={greyscale}",
                     r = if data[5] { "R" } else { "-" },
                     g = if data[6] { "G" } else { "-" },
                     b = if data[7] { "B" } else { "-" },
@@ -434,69 +1540,79 @@ impl Ppu {
             }
             2 => {
                 // Status
-                log::warn!("Write to $2002 = {data:02X}");
+                core_log!(PpuReg, Warn, "Write to $2002 = {data:02X}");
             }
             3 => {
                 // OAM address
-                log::info!(target: "ppureg::OAMADDR", "= ${data:02X}");
+                core_log!(PpuReg, Info, "[OAMADDR] = ${data:02X}");
 
                 self.reg.oam_addr = data;
             }
             4 => {
                 // OAM data
-                log::info!(target: "ppureg::OAMDATA", "= ${data:02X}: OAM[${oam_addr:02X}] = ${data:02X}",
-                    oam_addr = self.reg.oam_addr);
+                core_log!(
+                    PpuReg,
+                    Info,
+                    "[OAMDATA] = ${data:02X}: OAM[${oam_addr:02X}] = ${data:02X}",
+                    oam_addr = self.reg.oam_addr
+                );
 
-                self.oam[self.reg.oam_addr as usize] = data;
-                self.reg.oam_addr = self.reg.oam_addr.wrapping_add(1);
+                if self.is_rendering(ctx.region()) {
+                    // Writes are ignored during rendering, but OAMADDR gets
+                    // a glitchy increment of its upper 6 bits
+                    self.reg.oam_addr = self.reg.oam_addr.wrapping_add(4);
+                } else {
+                    self.oam[self.reg.oam_addr as usize] = data;
+                    self.reg.oam_addr = self.reg.oam_addr.wrapping_add(1);
+                }
             }
             5 => {
                 // Scroll
-                log::info!(target: "ppureg::PPUSCROLL", "= ${data:02X}");
+                core_log!(PpuReg, Info, "[PPUSCROLL] = ${data:02X}");
 
-                let data = data.view_bits::<Lsb0>();
-
-                if !self.reg.toggle {
-                    self.reg.tmp_addr = (self.reg.tmp_addr & 0x7fe0) | data[3..8].load_le::<u16>();
-                    self.reg.scroll_x = data[0..3].load_le();
-                } else {
-                    self.reg.tmp_addr = (self.reg.tmp_addr & 0x0c1f)
-                        | data[3..8].load_le::<u16>() << 5
-                        | data[0..3].load_le::<u16>() << 12;
-                }
-                self.reg.toggle = !self.reg.toggle;
+                self.reg.scroll.write_scroll(data);
             }
             6 => {
                 // Address
-                log::info!(target: "ppureg::PPUADDR", "= ${data:02X}");
-
-                let data = data.view_bits::<Lsb0>();
+                core_log!(PpuReg, Info, "[PPUADDR] = ${data:02X}");
 
-                if !self.reg.toggle {
-                    self.reg.tmp_addr =
-                        (self.reg.tmp_addr & 0x00ff) | data[0..6].load_be::<u16>() << 8;
-                } else {
-                    self.reg.tmp_addr = (self.reg.tmp_addr & 0x7f00) | data.load_be::<u16>();
-                    self.reg.cur_addr = self.reg.tmp_addr;
-                }
-                self.reg.toggle = !self.reg.toggle;
+                self.reg.scroll.write_addr(data);
             }
             7 => {
                 // Data
-                let addr = self.reg.cur_addr & 0x3fff;
+                let addr = self.reg.scroll.v & 0x3fff;
 
-                log::info!(target: "ppureg::PPUDATA", "= ${data:02X}, CHR[${addr:04X}] <- ${data:02X}");
+                core_log!(
+                    PpuReg,
+                    Info,
+                    "[PPUDATA] = ${data:02X}, CHR[${addr:04X}] <- ${data:02X}"
+                );
 
                 ctx.write_chr_mapper(addr, data);
 
-                let inc_addr = if self.reg.ppu_addr_incr { 32 } else { 1 };
-                self.reg.cur_addr = self.reg.cur_addr.wrapping_add(inc_addr);
+                let now = ctx.now();
+                ctx.memory_ctrl_mut().debugger_mut().check_access(
+                    BreakpointKind::PpuWrite,
+                    addr,
+                    data,
+                    now,
+                );
+
+                self.increment_vram_addr(ctx.region());
             }
             _ => unreachable!(),
         }
     }
 }
 
+/// Frames rendered per dot after a mid-line scroll write when the per-line renderer is selected
+const DOT_RENDERER_FALLBACK_FRAMES: u32 = 60;
+
+// Flags of `Ppu::spr_buf`, which holds the sprite palette index in the lower 5 bits
+const SPR_ZERO: u8 = 0x20;
+const SPR_BEHIND: u8 = 0x40;
+const SPR_OPAQUE: u8 = 0x80;
+
 fn read_nametable(ctx: &mut impl Context, addr: u16) -> u8 {
     ctx.read_chr_mapper(0x2000 + addr)
 }
@@ -505,6 +1621,20 @@ fn read_pattern(ctx: &mut impl Context, addr: u16) -> u8 {
     ctx.read_chr_mapper(addr)
 }
 
-fn read_palette(ctx: &mut impl Context, index: u8) -> u8 {
-    ctx.read_chr_mapper(0x3f00 + index as u16)
+/// Selects the palette index of a pixel from the background pixel (0 if transparent)
+/// and the sprite pixel in `spr_buf`.
+/// `spr_buf` holds the first opaque sprite in OAM order regardless of its priority,
+/// so a sprite behind the background also hides the sprites after it,
+/// as the hardware does (Super Mario Bros. 3 hides items in blocks with this).
+fn mux_pixel(bg: u8, spr: u8) -> u8 {
+    if spr & SPR_OPAQUE != 0 && (spr & SPR_BEHIND == 0 || bg == 0) {
+        spr & 0x1f
+    } else {
+        bg
+    }
+}
+
+/// Palette RAM is inside the PPU, so lookups do not appear on the bus
+fn read_palette(ctx: &impl Context, index: u8) -> u8 {
+    ctx.read_chr(0x3f00 + index as u16)
 }
@@ -0,0 +1,52 @@
+//! Profiler which counts CPU cycles spent on each instruction
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Location of an instruction. The same address in different PRG banks is different code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct ProfileAddr {
+    /// 8KB PRG ROM bank mapped at `addr`, or `None` below $8000
+    pub prg_bank: Option<u32>,
+    pub addr: u16,
+}
+
+/// Cycles spent on an instruction, including the DMA cycles which stalled it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct ProfileEntry {
+    pub addr: ProfileAddr,
+    pub cycles: u64,
+    pub executions: u64,
+}
+
+#[derive(Default)]
+pub struct Profiler {
+    entries: HashMap<ProfileAddr, (u64, u64)>,
+}
+
+impl Profiler {
+    pub fn record(&mut self, addr: ProfileAddr, cycles: u64) {
+        let (total, count) = self.entries.entry(addr).or_default();
+        *total += cycles;
+        *count += 1;
+    }
+
+    /// Returns the profiled instructions, the most expensive first
+    pub fn report(&self) -> Vec<ProfileEntry> {
+        let mut ret = self
+            .entries
+            .iter()
+            .map(|(&addr, &(cycles, executions))| ProfileEntry {
+                addr,
+                cycles,
+                executions,
+            })
+            .collect::<Vec<_>>();
+        ret.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.addr.cmp(&b.addr)));
+        ret
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,134 @@
+//! Game-specific overrides of ROM header fields, for games which need them even
+//! with correct headers
+//!
+//! Games are identified by the CRC32 of PRG ROM and CHR ROM together, like the
+//! header database. Entries in `Config::quirks` take precedence over built-in ones.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    logging::core_log,
+    rom::{Mirroring, Rom, RomFormat, TimingMode},
+};
+
+/// Overrides of a game. Fields left `None` keep the value in the header.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    pub mapper_id: Option<u16>,
+    /// Also selects chip revisions, such as the IRQ behavior of MMC3A (submapper 4)
+    pub submapper_id: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub prg_ram_size: Option<usize>,
+    pub prg_nvram_size: Option<usize>,
+    pub chr_ram_size: Option<usize>,
+    pub has_battery: Option<bool>,
+    pub timing_mode: Option<TimingMode>,
+}
+
+impl Quirks {
+    /// Quirks which override nothing, for `..Quirks::NONE` in table entries
+    pub const NONE: Quirks = Quirks {
+        mapper_id: None,
+        submapper_id: None,
+        mirroring: None,
+        prg_ram_size: None,
+        prg_nvram_size: None,
+        chr_ram_size: None,
+        has_battery: None,
+        timing_mode: None,
+    };
+}
+
+/// Quirks of games, keyed by the CRC32 of PRG ROM and CHR ROM
+pub type QuirksMap = BTreeMap<u32, Quirks>;
+
+/// Built-in quirks, keyed by the CRC32 of PRG ROM and CHR ROM. Each entry notes the
+/// game and where its CRC32 comes from.
+const BUILTIN_QUIRKS: &[(u32, Quirks)] = &[];
+
+/// Returns the quirks of the game, ones in `quirks` first and then built-in ones
+pub fn lookup(quirks: &QuirksMap, prg_chr_crc32: u32) -> Option<Quirks> {
+    lookup_in(BUILTIN_QUIRKS, quirks, prg_chr_crc32)
+}
+
+fn lookup_in(builtin: &[(u32, Quirks)], quirks: &QuirksMap, prg_chr_crc32: u32) -> Option<Quirks> {
+    if let Some(quirks) = quirks.get(&prg_chr_crc32) {
+        return Some(quirks.clone());
+    }
+    builtin
+        .iter()
+        .find(|(crc, _)| *crc == prg_chr_crc32)
+        .map(|(_, quirks)| quirks.clone())
+}
+
+/// Applies the quirks of the game to the ROM. Returns true if the game has quirks.
+pub(crate) fn apply(rom: &mut Rom, quirks: &QuirksMap) -> bool {
+    if matches!(rom.format, RomFormat::Nsf) {
+        return false;
+    }
+    let crc = rom.prg_chr_crc32();
+    let Some(quirks) = lookup(quirks, crc) else {
+        return false;
+    };
+    core_log!(Rom, Info, "Applying quirks for {crc:08X}: {quirks:?}");
+
+    let Quirks {
+        mapper_id,
+        submapper_id,
+        mirroring,
+        prg_ram_size,
+        prg_nvram_size,
+        chr_ram_size,
+        has_battery,
+        timing_mode,
+    } = quirks;
+
+    rom.mapper_id = mapper_id.unwrap_or(rom.mapper_id);
+    rom.submapper_id = submapper_id.unwrap_or(rom.submapper_id);
+    rom.mirroring = mirroring.unwrap_or(rom.mirroring);
+    rom.prg_ram_size = prg_ram_size.unwrap_or(rom.prg_ram_size);
+    rom.prg_nvram_size = prg_nvram_size.unwrap_or(rom.prg_nvram_size);
+    rom.chr_ram_size = chr_ram_size.unwrap_or(rom.chr_ram_size);
+    rom.has_battery = has_battery.unwrap_or(rom.has_battery);
+    rom.timing_mode = timing_mode.unwrap_or(rom.timing_mode);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_quirks_take_precedence() {
+        let builtin = [
+            (
+                1,
+                Quirks {
+                    mapper_id: Some(1),
+                    ..Quirks::NONE
+                },
+            ),
+            (
+                2,
+                Quirks {
+                    mapper_id: Some(2),
+                    ..Quirks::NONE
+                },
+            ),
+        ];
+        let mut quirks = QuirksMap::new();
+        quirks.insert(
+            2,
+            Quirks {
+                mirroring: Some(Mirroring::Vertical),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(lookup_in(&builtin, &quirks, 1).unwrap().mapper_id, Some(1));
+        let overridden = lookup_in(&builtin, &quirks, 2).unwrap();
+        assert_eq!(overridden.mapper_id, None);
+        assert_eq!(overridden.mirroring, Some(Mirroring::Vertical));
+        assert_eq!(lookup_in(&builtin, &quirks, 3), None);
+    }
+}
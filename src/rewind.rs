@@ -0,0 +1,236 @@
+//! Rewinding with periodically captured save states
+//!
+//! Only the newest state is kept as is. Older states are stored as the difference
+//! from the next newer one, run-length encoded, since consecutive states are mostly
+//! the same bytes.
+
+use std::collections::VecDeque;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, PartialEq, Eq, Debug, JsonSchema, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RewindConfig {
+    /// Frames between captured states
+    pub interval: u32,
+    /// Maximum bytes used by captured states. The oldest ones are dropped when exceeded.
+    pub memory_budget: usize,
+}
+
+impl Default for RewindConfig {
+    fn default() -> Self {
+        Self {
+            interval: 1,
+            memory_budget: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Ring buffer of save states with their frame numbers
+pub struct RewindBuffer {
+    config: RewindConfig,
+    newest: Option<(u64, Vec<u8>)>,
+    /// Frame numbers and deltas which restore older states, oldest first
+    deltas: VecDeque<(u64, Vec<u8>)>,
+    size: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(config: RewindConfig) -> Self {
+        Self {
+            config,
+            newest: None,
+            deltas: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    pub fn config(&self) -> &RewindConfig {
+        &self.config
+    }
+
+    /// Number of states in the buffer
+    pub fn states(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    /// Bytes used by the states in the buffer
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.size = 0;
+    }
+
+    /// Returns true if a state should be captured at the frame
+    pub fn should_capture(&self, frame: u64) -> bool {
+        frame.is_multiple_of(self.config.interval.max(1) as u64)
+    }
+
+    /// Adds the state of the frame as the newest one
+    pub fn push(&mut self, frame: u64, state: Vec<u8>) {
+        // The timeline went back, by a reset or loading a state
+        if matches!(&self.newest, Some((newest, _)) if *newest >= frame) {
+            self.clear();
+        }
+
+        self.size += state.len();
+        if let Some((newest_frame, newest)) = self.newest.replace((frame, state)) {
+            let delta = encode_delta(&self.newest.as_ref().unwrap().1, &newest);
+            self.size += delta.len();
+            self.size -= newest.len();
+            self.deltas.push_back((newest_frame, delta));
+        }
+
+        while self.size > self.config.memory_budget {
+            let Some((_, delta)) = self.deltas.pop_front() else {
+                break;
+            };
+            self.size -= delta.len();
+        }
+    }
+
+    /// Drops the states newer than `frame`, except the oldest one, and returns the
+    /// newest remaining state with its frame number. The returned state stays in the buffer.
+    pub fn rewind_to(&mut self, frame: u64) -> Option<(u64, &[u8])> {
+        while !self.deltas.is_empty()
+            && matches!(&self.newest, Some((newest, _)) if *newest > frame)
+        {
+            let (_, newest) = self.newest.take().unwrap();
+            self.size -= newest.len();
+            let (older_frame, delta) = self.deltas.pop_back().unwrap();
+            let older = decode_delta(&newest, &delta);
+            self.size += older.len();
+            self.size -= delta.len();
+            self.newest = Some((older_frame, older));
+        }
+        let (frame, state) = self.newest.as_ref()?;
+        Some((*frame, state))
+    }
+}
+
+/// Encodes the XOR of `base` and `target` as runs of zero bytes followed by literal bytes,
+/// preceded by the length of `target`
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut ret = vec![];
+    write_number(&mut ret, target.len());
+
+    let xor = |i: usize| target[i] ^ base.get(i).copied().unwrap_or(0);
+    let mut i = 0;
+    while i < target.len() {
+        let zeros = (i..target.len()).take_while(|&j| xor(j) == 0).count();
+        i += zeros;
+        let literals = (i..target.len()).take_while(|&j| xor(j) != 0).count();
+        write_number(&mut ret, zeros);
+        write_number(&mut ret, literals);
+        ret.extend((i..i + literals).map(xor));
+        i += literals;
+    }
+    ret
+}
+
+fn decode_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut delta = delta;
+    let len = read_number(&mut delta);
+    let mut ret = base.to_vec();
+    ret.resize(len, 0);
+
+    let mut i = 0;
+    while !delta.is_empty() {
+        i += read_number(&mut delta);
+        let literals = read_number(&mut delta);
+        for (r, d) in ret[i..i + literals].iter_mut().zip(&delta[..literals]) {
+            *r ^= d;
+        }
+        delta = &delta[literals..];
+        i += literals;
+    }
+    ret
+}
+
+fn write_number(buf: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn read_number(buf: &mut &[u8]) -> usize {
+    let mut ret = 0;
+    let mut shift = 0;
+    loop {
+        let b = buf[0];
+        *buf = &buf[1..];
+        ret |= (b as usize & 0x7F) << shift;
+        if b & 0x80 == 0 {
+            return ret;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trip() {
+        let base = (0..1000).map(|i| (i * 13) as u8).collect::<Vec<_>>();
+        let mut target = base.clone();
+        target[10] ^= 1;
+        target[500..520].fill(0xAA);
+        target.extend([1, 2, 3]);
+
+        let delta = encode_delta(&base, &target);
+        assert!(delta.len() < 40);
+        assert_eq!(decode_delta(&base, &delta), target);
+
+        let shorter = &base[..300];
+        assert_eq!(decode_delta(&base, &encode_delta(&base, shorter)), shorter);
+    }
+
+    #[test]
+    fn rewind_buffer() {
+        let state = |frame: u64| {
+            let mut ret = vec![0; 100];
+            ret[0] = frame as u8;
+            ret
+        };
+
+        let mut buf = RewindBuffer::new(RewindConfig {
+            interval: 1,
+            memory_budget: 300,
+        });
+        for frame in 0..10 {
+            buf.push(frame, state(frame));
+        }
+        assert_eq!(buf.states(), 10);
+        assert!(buf.size() < 200);
+
+        assert_eq!(buf.rewind_to(6), Some((6, state(6).as_slice())));
+        assert_eq!(buf.rewind_to(6), Some((6, state(6).as_slice())));
+        assert_eq!(buf.rewind_to(2), Some((2, state(2).as_slice())));
+        assert_eq!(buf.states(), 3);
+
+        // Pushing an older frame starts over
+        buf.push(1, state(1));
+        assert_eq!(buf.states(), 1);
+        assert_eq!(buf.rewind_to(0), Some((1, state(1).as_slice())));
+
+        // States which differ entirely use up the budget quickly
+        for frame in 0..10 {
+            buf.push(frame, vec![frame as u8; 100]);
+        }
+        assert!(buf.size() <= 300);
+        assert_eq!(buf.states(), 2);
+        assert_eq!(buf.rewind_to(0), Some((8, [8; 100].as_slice())));
+
+        buf.clear();
+        assert_eq!(buf.rewind_to(0), None);
+    }
+}
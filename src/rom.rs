@@ -1,5 +1,12 @@
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    header_db::HeaderDatabase,
+    logging::core_log,
+    mapper,
+    nsf::{self, NsfInfo},
+};
+
 pub struct Rom {
     pub format: RomFormat,
     pub mapper_id: u16,
@@ -15,6 +22,28 @@ pub struct Rom {
     pub console_type: ConsoleType,
     pub timing_mode: TimingMode,
     pub has_battery: bool,
+    /// Default expansion device of NES 2.0 headers (byte 15), 0 if unspecified
+    pub expansion_device: u8,
+    pub playchoice: Option<PlayChoiceRom>,
+    /// Number of miscellaneous ROMs of NES 2.0 headers (byte 14)
+    pub misc_rom_count: u8,
+    /// Miscellaneous ROM area after CHR ROM, which holds all of the miscellaneous ROMs
+    pub misc_rom: Vec<u8>,
+    /// Header of the NSF file the ROM is built from
+    pub nsf: Option<NsfInfo>,
+}
+
+/// Data for the PlayChoice-10 BIOS side of the board, appended after CHR ROM
+pub struct PlayChoiceRom {
+    /// 8KB instruction screen ROM
+    pub inst_rom: Vec<u8>,
+    /// 16 bytes PROM data followed by 16 bytes PROM CounterOut
+    pub prom: Vec<u8>,
+}
+
+impl PlayChoiceRom {
+    const INST_ROM_SIZE: usize = 8 * 1024;
+    const PROM_SIZE: usize = 32;
 }
 
 impl Default for Rom {
@@ -34,13 +63,20 @@ impl Default for Rom {
             console_type: ConsoleType::Nes,
             timing_mode: TimingMode::Ntsc,
             has_battery: false,
+            expansion_device: 0,
+            playchoice: None,
+            misc_rom_count: 0,
+            misc_rom: vec![],
+            nsf: None,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum RomFormat {
     INes,
     Nes20,
+    Nsf,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -52,7 +88,7 @@ pub enum Mirroring {
     FourScreen,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub enum ConsoleType {
     Nes,
     VsSystem { ppu_type: u8, hardware_type: u8 },
@@ -60,7 +96,7 @@ pub enum ConsoleType {
     ExtendConsoleType { console_type: u8 },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum TimingMode {
     Ntsc,
     Pal,
@@ -68,6 +104,84 @@ pub enum TimingMode {
     Dendy,
 }
 
+/// Structured information about a ROM, for tools analyzing ROM files
+#[derive(Debug, Clone, Serialize)]
+pub struct RomInfo {
+    pub format: RomFormat,
+    pub mapper_id: u16,
+    pub submapper_id: u8,
+    /// Name of the board, `None` if there is no built-in mapper for it
+    pub board_name: Option<&'static str>,
+    pub mirroring: Mirroring,
+    pub console_type: ConsoleType,
+    pub timing_mode: TimingMode,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+    pub has_playchoice: bool,
+    pub input_device: InputDevice,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub misc_rom_count: u8,
+    pub misc_rom_size: usize,
+    pub prg_chr_crc32: u32,
+    pub prg_rom_crc32: u32,
+    pub chr_rom_crc32: u32,
+    /// CRC32 of each 16KB bank of PRG ROM
+    pub prg_bank_crc32: Vec<u32>,
+    /// CRC32 of each 8KB bank of CHR ROM
+    pub chr_bank_crc32: Vec<u32>,
+    /// True if the header database given to `Rom::info` has an entry for the game
+    pub in_header_database: bool,
+}
+
+/// Input device which a game expects, told by the default expansion device of
+/// NES 2.0 headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum InputDevice {
+    Unspecified,
+    StandardControllers,
+    /// NES Four Score or Satellite
+    FourScore,
+    /// Famicom four players adapter
+    FamicomFourPlayers,
+    VsSystem,
+    Zapper,
+    TwoZappers,
+    PowerPad,
+    FamilyTrainer,
+    /// Arkanoid Vaus paddle for NES
+    ArkanoidNes,
+    /// Arkanoid Vaus paddle for Famicom
+    ArkanoidFamicom,
+    FamilyBasicKeyboard,
+    /// Other devices, with the expansion device number
+    Other(u8),
+}
+
+impl InputDevice {
+    pub fn from_expansion_device(id: u8) -> Self {
+        match id {
+            0x00 => InputDevice::Unspecified,
+            0x01 => InputDevice::StandardControllers,
+            0x02 => InputDevice::FourScore,
+            0x03 => InputDevice::FamicomFourPlayers,
+            0x04 | 0x05 => InputDevice::VsSystem,
+            0x07 | 0x08 => InputDevice::Zapper,
+            0x09 => InputDevice::TwoZappers,
+            0x0B | 0x0C => InputDevice::PowerPad,
+            0x0D | 0x0E => InputDevice::FamilyTrainer,
+            0x0F => InputDevice::ArkanoidNes,
+            0x10 | 0x11 => InputDevice::ArkanoidFamicom,
+            0x23 => InputDevice::FamilyBasicKeyboard,
+            id => InputDevice::Other(id),
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum RomError {
     #[error("invalid ROM magic: {0:?}, expected: 'NES\x1a'")]
@@ -76,11 +190,129 @@ pub enum RomError {
     InvalidMirroring(u8),
     #[error("ROM data has invalid extra bytes")]
     InvalidExtraBytes,
+    #[error("invalid NSF: {0}")]
+    InvalidNsf(&'static str),
+    #[error("invalid archive: {0}")]
+    InvalidArchive(String),
+    #[error("invalid header database: {0}")]
+    InvalidHeaderDatabase(String),
 }
 
 impl Rom {
+    /// Returns true if the PRG ROM is a flash memory writable by the game itself
+    pub fn has_prg_flash(&self) -> bool {
+        self.mapper_id == 30 && self.has_battery
+    }
+
+    /// Size of the backup data, which is the flash PRG for self-flashable boards,
+    /// otherwise PRG RAM followed by CHR RAM if it is battery backed
+    pub fn backup_size(&self) -> usize {
+        if self.has_prg_flash() {
+            self.prg_rom.len()
+        } else if self.chr_nvram_size > 0 {
+            self.prg_ram_size + self.prg_nvram_size + self.chr_ram_size + self.chr_nvram_size
+        } else {
+            self.prg_ram_size + self.prg_nvram_size
+        }
+    }
+
+    /// CRC32 of PRG ROM and CHR ROM, which identifies the game
+    pub fn prg_chr_crc32(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.prg_rom);
+        hasher.update(&self.chr_rom);
+        hasher.finalize()
+    }
+
+    /// Input device told by the header
+    pub fn input_device(&self) -> InputDevice {
+        InputDevice::from_expansion_device(self.expansion_device)
+    }
+
+    /// Returns the header fields, checksums and the detected board of the ROM,
+    /// and whether the game is in the header database
+    pub fn info(&self, db: Option<&HeaderDatabase>) -> RomInfo {
+        let prg_chr_crc32 = self.prg_chr_crc32();
+        let bank_crc32 = |dat: &[u8], size| dat.chunks(size).map(crc32fast::hash).collect();
+
+        RomInfo {
+            format: self.format,
+            mapper_id: self.mapper_id,
+            submapper_id: self.submapper_id,
+            board_name: mapper::board_name(self.mapper_id),
+            mirroring: self.mirroring,
+            console_type: self.console_type.clone(),
+            timing_mode: self.timing_mode,
+            has_battery: self.has_battery,
+            has_trainer: self.trainer.is_some(),
+            has_playchoice: self.playchoice.is_some(),
+            input_device: self.input_device(),
+            prg_rom_size: self.prg_rom.len(),
+            chr_rom_size: self.chr_rom.len(),
+            prg_ram_size: self.prg_ram_size,
+            prg_nvram_size: self.prg_nvram_size,
+            chr_ram_size: self.chr_ram_size,
+            chr_nvram_size: self.chr_nvram_size,
+            misc_rom_count: self.misc_rom_count,
+            misc_rom_size: self.misc_rom.len(),
+            prg_chr_crc32,
+            prg_rom_crc32: crc32fast::hash(&self.prg_rom),
+            chr_rom_crc32: crc32fast::hash(&self.chr_rom),
+            prg_bank_crc32: bank_crc32(&self.prg_rom, 16 * 1024),
+            chr_bank_crc32: bank_crc32(&self.chr_rom, 8 * 1024),
+            in_header_database: db.is_some_and(|db| db.get(prg_chr_crc32).is_some()),
+        }
+    }
+
+    /// Replaces the header fields with the entry of the game in the header database,
+    /// if there is one. Returns true if the header is replaced.
+    pub fn apply_header_database(&mut self, db: &HeaderDatabase) -> bool {
+        if matches!(self.format, RomFormat::Nsf) {
+            return false;
+        }
+        let Some(entry) = db.get(self.prg_chr_crc32()).cloned() else {
+            return false;
+        };
+
+        // Headers with wrong ROM sizes split the data at a wrong place
+        if entry.prg_rom_size + entry.chr_rom_size == self.prg_rom.len() + self.chr_rom.len()
+            && entry.prg_rom_size != self.prg_rom.len()
+        {
+            let mut dat = std::mem::take(&mut self.prg_rom);
+            dat.append(&mut self.chr_rom);
+            self.chr_rom = dat.split_off(entry.prg_rom_size);
+            self.prg_rom = dat;
+        }
+
+        self.mapper_id = entry.mapper_id;
+        self.submapper_id = entry.submapper_id;
+        self.prg_ram_size = entry.prg_ram_size;
+        self.prg_nvram_size = entry.prg_nvram_size;
+        self.chr_ram_size = entry.chr_ram_size;
+        self.chr_nvram_size = entry.chr_nvram_size;
+        if let Some(mirroring) = entry.mirroring {
+            self.mirroring = mirroring;
+        }
+        self.has_battery = entry.has_battery;
+        self.console_type = entry.console_type;
+        self.timing_mode = entry.timing_mode;
+        self.expansion_device = entry.expansion_device;
+        true
+    }
+
+    /// Parses an iNES, NES 2.0 or NSF file. With the `archive` feature, the file can
+    /// also be in a zip or gzip archive.
     pub fn from_bytes(dat: &[u8]) -> Result<Self, RomError> {
-        let header = &dat[..0x10];
+        #[cfg(feature = "archive")]
+        if crate::archive::is_archive(dat) {
+            return Self::from_bytes(&crate::archive::extract(dat)?);
+        }
+
+        if nsf::is_nsf(dat) {
+            return nsf::load(dat);
+        }
+
+        let mut header: [u8; 0x10] = dat[..0x10].try_into().unwrap();
         let mut dat = &dat[0x10..];
 
         let magic = &header[0..4];
@@ -90,6 +322,18 @@ impl Rom {
 
         let is_nes2 = header[7] & 0x0C == 0x08;
 
+        // Old dumps may have garbage such as "DiskDude!" in bytes 7-15, which are
+        // only trusted when bytes 12-15 are zero, as described on the NESdev wiki.
+        if !is_nes2 && (header[7] & 0x0C != 0 || header[12..16].iter().any(|&b| b != 0)) {
+            core_log!(
+                Rom,
+                Warn,
+                "Ignoring garbage in iNES header bytes 7-15: {:?}",
+                String::from_utf8_lossy(&header[7..16])
+            );
+            header[7..16].fill(0);
+        }
+
         let prg_rom_size_in_16kib = if is_nes2 {
             header[4] as usize | (header[9] as usize & 0x0f) << 8
         } else {
@@ -106,13 +350,6 @@ impl Rom {
 
         let chr_rom_size = chr_rom_size_in_8kib * 8 * 1024;
 
-        let mirroring = match header[6] & 0x09 {
-            0 => Mirroring::Horizontal,
-            1 => Mirroring::Vertical,
-            8 => Mirroring::FourScreen,
-            _ => Err(RomError::InvalidMirroring(header[6] & 0x09))?,
-        };
-
         let has_battery = header[6] & 0x02 != 0;
         let has_trainer = header[6] & 0x04 != 0;
 
@@ -124,6 +361,15 @@ impl Rom {
 
         let submapper_id = if is_nes2 { header[8] >> 4 } else { 0 };
 
+        let mirroring = match (mapper_id, header[6] & 0x09) {
+            (_, 0) => Mirroring::Horizontal,
+            (_, 1) => Mirroring::Vertical,
+            // UNROM 512 uses bit 3 alone for mapper controlled one-screen mirroring
+            (30, 8) => Mirroring::OneScreenLow,
+            (_, 8 | 9) => Mirroring::FourScreen,
+            _ => Err(RomError::InvalidMirroring(header[6] & 0x09))?,
+        };
+
         let console_type = if is_nes2 {
             match header[7] & 3 {
                 0 => ConsoleType::Nes,
@@ -137,6 +383,13 @@ impl Rom {
                 },
                 _ => unreachable!(),
             }
+        } else if header[7] & 1 != 0 {
+            ConsoleType::VsSystem {
+                ppu_type: 0,
+                hardware_type: 0,
+            }
+        } else if header[7] & 2 != 0 {
+            ConsoleType::Playchoice10
         } else {
             ConsoleType::Nes
         };
@@ -173,7 +426,13 @@ impl Rom {
                 64 << shift_count
             }
         } else if chr_rom_size == 0 {
-            8 * 1024
+            match mapper_id {
+                // Action 53 and UNROM 512 boards have 32KB CHR RAM
+                28 | 30 => 32 * 1024,
+                // RacerMate has only battery backed CHR RAM
+                168 => 0,
+                _ => 8 * 1024,
+            }
         } else {
             0
         };
@@ -185,6 +444,8 @@ impl Rom {
             } else {
                 64 << shift_count
             }
+        } else if mapper_id == 168 {
+            64 * 1024
         } else {
             0
         };
@@ -205,19 +466,9 @@ impl Rom {
             }
         };
 
-        // TODO:
+        let expansion_device = if is_nes2 { header[15] & 0x3F } else { 0 };
 
-        //  14     Miscellaneous ROMs
-        //         D~7654 3210
-        //           ---------
-        //           .... ..RR
-        //                  ++- Number of miscellaneous ROMs present
-
-        //  15     Default Expansion Device
-        //         D~7654 3210
-        //           ---------
-        //           ..DD DDDD
-        //             ++-++++- Default Expansion Device
+        let misc_rom_count = if is_nes2 { header[14] & 3 } else { 0 };
 
         let trainer = if has_trainer {
             let v = &dat[..512];
@@ -232,6 +483,27 @@ impl Rom {
         let chr_rom = dat[..chr_rom_size].to_owned();
         dat = &dat[chr_rom_size..];
 
+        // PlayChoice-10 dumps have INST-ROM and optionally PROM after CHR ROM
+        let playchoice = if console_type == ConsoleType::Playchoice10
+            && dat.len() >= PlayChoiceRom::INST_ROM_SIZE
+        {
+            let inst_rom = dat[..PlayChoiceRom::INST_ROM_SIZE].to_owned();
+            dat = &dat[PlayChoiceRom::INST_ROM_SIZE..];
+            let prom_size = dat.len().min(PlayChoiceRom::PROM_SIZE);
+            let prom = dat[..prom_size].to_owned();
+            dat = &dat[prom_size..];
+            Some(PlayChoiceRom { inst_rom, prom })
+        } else {
+            None
+        };
+
+        // The rest is the miscellaneous ROM area, whose layout depends on the board
+        let misc_rom = if misc_rom_count > 0 {
+            std::mem::take(&mut dat).to_owned()
+        } else {
+            vec![]
+        };
+
         if !dat.is_empty() {
             Err(RomError::InvalidExtraBytes)?;
         }
@@ -253,6 +525,11 @@ impl Rom {
             console_type,
             timing_mode,
             has_battery,
+            expansion_device,
+            playchoice,
+            misc_rom_count,
+            misc_rom,
+            nsf: None,
             prg_ram_size,
             prg_nvram_size,
             chr_ram_size,
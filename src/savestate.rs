@@ -0,0 +1,256 @@
+//! Save state format, and save state files which carry metadata and a thumbnail
+//! along with the state
+//!
+//! States and save state files are MessagePack with field names, preceded by the
+//! format version, so that fields can be added and removed without breaking old ones.
+//! Fields added later need `#[serde(default)]`, or a migration when the default is
+//! not good enough. Older versions are migrated one version at a time on a MessagePack
+//! value tree.
+//!
+//! Frontends can list save slots with [`SaveStateFile::read_metadata`], which doesn't
+//! decode the state itself.
+
+use meru_interface::FrameBuffer;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::nes::Error;
+
+const FILE_MAGIC: &[u8] = b"SABICOM-STATE\x1a";
+const STATE_MAGIC: &[u8] = b"SBST";
+
+/// Version of the state format made by `save_state`
+pub const STATE_VERSION: u32 = 1;
+/// Version of the save state file format
+pub const FILE_VERSION: u32 = 1;
+
+type Migration = fn(&mut rmpv::Value);
+
+/// Migrations of states from version `n` to `n + 1`, starting from version 1
+const STATE_MIGRATIONS: &[Migration] = &[];
+/// Migrations of save state files from version `n` to `n + 1`, starting from version 1
+const FILE_MIGRATIONS: &[Migration] = &[];
+
+const _: () = assert!(STATE_MIGRATIONS.len() as u32 + 1 == STATE_VERSION);
+const _: () = assert!(FILE_MIGRATIONS.len() as u32 + 1 == FILE_VERSION);
+
+pub(crate) fn encode_state(state: &impl Serialize) -> Vec<u8> {
+    encode(STATE_VERSION, state)
+}
+
+pub(crate) fn decode_state<T: DeserializeOwned>(dat: &[u8]) -> Result<T, Error> {
+    decode(dat, STATE_VERSION, STATE_MIGRATIONS)
+}
+
+fn encode(version: u32, value: &impl Serialize) -> Vec<u8> {
+    let mut ret = STATE_MAGIC.to_vec();
+    ret.extend(version.to_le_bytes());
+    ret.extend(rmp_serde::to_vec_named(value).unwrap());
+    ret
+}
+
+fn decode<T: DeserializeOwned>(
+    dat: &[u8],
+    current: u32,
+    migrations: &[Migration],
+) -> Result<T, Error> {
+    let Some(dat) = dat.strip_prefix(STATE_MAGIC) else {
+        // Version 0, bare bincode made before the format was versioned
+        return Ok(bincode::deserialize(dat)?);
+    };
+    if dat.len() < 4 {
+        Err(Error::InvalidSaveState("no format version"))?
+    }
+    let (version, payload) = dat.split_at(4);
+    let version = u32::from_le_bytes(version.try_into().unwrap());
+
+    if version == current {
+        Ok(rmp_serde::from_slice(payload)?)
+    } else if (1..current).contains(&version) {
+        migrate(payload, &migrations[version as usize - 1..])
+    } else {
+        Err(Error::UnsupportedStateVersion(version))
+    }
+}
+
+/// Applies the migrations on the value tree, which is encoded back to MessagePack
+/// to be decoded the same way as current states
+fn migrate<T: DeserializeOwned>(payload: &[u8], migrations: &[Migration]) -> Result<T, Error> {
+    let failed = |err: &dyn std::fmt::Display| Error::StateMigrationFailed(err.to_string());
+
+    let mut value = rmpv::decode::read_value(&mut &payload[..]).map_err(|e| failed(&e))?;
+    for migration in migrations {
+        migration(&mut value);
+    }
+    let mut migrated = vec![];
+    rmpv::encode::write_value(&mut migrated, &value).map_err(|e| failed(&e))?;
+    rmp_serde::from_slice(&migrated).map_err(|e| failed(&e))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SaveStateMetadata {
+    /// Version of the crate which made the state
+    pub core_version: String,
+    /// CRC32 of PRG ROM and CHR ROM of the game
+    pub rom_crc32: u32,
+    /// Seconds since the UNIX epoch
+    pub timestamp: i64,
+    /// Frame number of the state
+    pub frame: u64,
+    pub thumbnail: Thumbnail,
+}
+
+/// Downscaled screenshot
+#[derive(Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Thumbnail {
+    pub width: usize,
+    pub height: usize,
+    /// RGB888 pixels
+    #[serde(with = "serde_bytes")]
+    pub rgb: Vec<u8>,
+}
+
+impl Thumbnail {
+    /// Shrinks the frame by `scale` in each direction, averaging each block of pixels
+    pub fn from_frame(fb: &FrameBuffer, scale: usize) -> Self {
+        let scale = scale.max(1);
+        let width = fb.width / scale;
+        let height = fb.height / scale;
+
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0; 3];
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let c = &fb.buffer[(y * scale + dy) * fb.width + x * scale + dx];
+                        sum[0] += c.r as usize;
+                        sum[1] += c.g as usize;
+                        sum[2] += c.b as usize;
+                    }
+                }
+                rgb.extend(sum.map(|s| (s / (scale * scale)) as u8));
+            }
+        }
+
+        Self { width, height, rgb }
+    }
+}
+
+/// Save state with its metadata
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SaveStateFile {
+    pub metadata: SaveStateMetadata,
+    /// State made by `EmulatorCore::save_state`
+    #[serde(with = "serde_bytes")]
+    pub state: Vec<u8>,
+}
+
+impl SaveStateFile {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = FILE_MAGIC.to_vec();
+        ret.extend(encode(FILE_VERSION, self));
+        ret
+    }
+
+    pub fn from_bytes(dat: &[u8]) -> Result<Self, Error> {
+        decode(strip_magic(dat)?, FILE_VERSION, FILE_MIGRATIONS)
+    }
+
+    /// Reads only the metadata, skipping the state
+    pub fn read_metadata(dat: &[u8]) -> Result<SaveStateMetadata, Error> {
+        #[derive(Deserialize)]
+        struct MetadataOnly {
+            metadata: SaveStateMetadata,
+        }
+        let file: MetadataOnly = decode(strip_magic(dat)?, FILE_VERSION, FILE_MIGRATIONS)?;
+        Ok(file.metadata)
+    }
+}
+
+fn strip_magic(dat: &[u8]) -> Result<&[u8], Error> {
+    dat.strip_prefix(FILE_MAGIC)
+        .ok_or(Error::InvalidSaveState("not a save state file"))
+}
+
+#[cfg(test)]
+mod tests {
+    use meru_interface::EmulatorCore;
+
+    use super::*;
+    use crate::{context::Context, nes::Nes};
+
+    #[derive(Serialize)]
+    struct Old {
+        name: String,
+        count: u8,
+        removed: Vec<u16>,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct New {
+        title: String,
+        count: u8,
+        #[serde(default)]
+        added: Option<u32>,
+    }
+
+    #[test]
+    fn migrate_state() {
+        let old = Old {
+            name: "foo".into(),
+            count: 3,
+            removed: vec![1, 2],
+        };
+        let payload = rmp_serde::to_vec_named(&old).unwrap();
+
+        let rename: Migration = |value| {
+            let rmpv::Value::Map(fields) = value else {
+                panic!("not a map");
+            };
+            for (key, _) in fields.iter_mut() {
+                if key.as_str() == Some("name") {
+                    *key = "title".into();
+                }
+            }
+        };
+        let new: New = migrate(&payload, &[rename]).unwrap();
+        assert_eq!(
+            new,
+            New {
+                title: "foo".into(),
+                count: 3,
+                added: None,
+            }
+        );
+
+        let mut future = encode_state(&old);
+        future[STATE_MAGIC.len()..][..4].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            decode_state::<New>(&future),
+            Err(Error::UnsupportedStateVersion(_))
+        ));
+    }
+
+    #[test]
+    fn migrate_real_state() {
+        let mut rom = vec![0; 0x10];
+        rom[0..4].copy_from_slice(b"NES\x1a");
+        rom[4] = 2;
+        rom[5] = 1;
+        rom.extend(vec![0xEA; 0x8000]); // NOP
+        rom.extend(vec![0; 0x2000]);
+
+        let mut nes = Nes::try_from_file(&rom, None, &Default::default()).unwrap();
+        nes.exec_frame(false);
+        let state = nes.save_state();
+
+        let payload = &state[STATE_MAGIC.len() + 4..];
+        let ctx: Context = migrate(payload, &[|_| {}]).unwrap();
+        let migrated = encode_state(&ctx);
+        assert_eq!(migrated, state);
+
+        nes.exec_frame(false);
+        nes.load_state(&migrated).unwrap();
+        assert_eq!(nes.save_state(), state);
+    }
+}
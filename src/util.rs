@@ -8,12 +8,35 @@ macro_rules! trait_alias {
 }
 pub(crate) use trait_alias;
 
+/// Callback which returns the input when the game strobes the controllers,
+/// given the CPU cycle of the strobe
+pub type InputProvider = Box<dyn FnMut(u64) -> Input + Send>;
+
+/// Input of the console. New fields may be added, so build it with `Input::new`
+/// or `Input::default` and set the fields.
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Input {
-    pub pad: [Pad; 2],
+    /// Controllers 1 to 4. Controllers 3 and 4 need a four player adapter.
+    pub pad: [Pad; 4],
+    /// Microphone on the Famicom's second controller
+    pub microphone: bool,
+}
+
+impl Input {
+    /// Input of the controllers, with the other inputs released
+    pub fn new(pad: [Pad; 4]) -> Self {
+        Self {
+            pad,
+            ..Default::default()
+        }
+    }
 }
 
+/// Buttons of a controller. New buttons may be added, so build it with `Pad::default`
+/// and set the fields.
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Pad {
     pub up: bool,
     pub down: bool,
@@ -23,4 +46,76 @@ pub struct Pad {
     pub b: bool,
     pub start: bool,
     pub select: bool,
+    /// Autofire A, pressed and released at the turbo rate
+    pub turbo_a: bool,
+    /// Autofire B, pressed and released at the turbo rate
+    pub turbo_b: bool,
+}
+
+impl Pad {
+    /// Returns the buttons in the order the controller shifts them out, from bit 0.
+    /// `turbo` tells whether the turbo buttons are in the pressed phase.
+    pub fn bits(&self, turbo: bool) -> u8 {
+        let mut ret = 0;
+        for (i, b) in [
+            self.a || self.turbo_a && turbo,
+            self.b || self.turbo_b && turbo,
+            self.select,
+            self.start,
+            self.up,
+            self.down,
+            self.left,
+            self.right,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            ret |= (b as u8) << i;
+        }
+        ret
+    }
+}
+
+/// Serializes a frame buffer as packed RGB so that a partially rendered frame
+/// survives a save state
+pub(crate) mod frame_buffer_serde {
+    use meru_interface::{Color, FrameBuffer};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(fb: &FrameBuffer, s: S) -> Result<S::Ok, S::Error> {
+        let pixels = fb
+            .buffer
+            .iter()
+            .flat_map(|c| [c.r, c.g, c.b])
+            .collect::<Vec<u8>>();
+        (fb.width, fb.height, pixels).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<FrameBuffer, D::Error> {
+        let (width, height, pixels) = <(usize, usize, Vec<u8>)>::deserialize(d)?;
+        let mut fb = FrameBuffer::new(width, height);
+        for (c, rgb) in fb.buffer.iter_mut().zip(pixels.chunks_exact(3)) {
+            *c = Color::new(rgb[0], rgb[1], rgb[2]);
+        }
+        Ok(fb)
+    }
+}
+
+/// Serializes an audio buffer including samples generated so far in the current frame
+pub(crate) mod audio_buffer_serde {
+    use meru_interface::{AudioBuffer, AudioSample};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(ab: &AudioBuffer, s: S) -> Result<S::Ok, S::Error> {
+        (ab.sample_rate, ab.channels, &ab.samples).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<AudioBuffer, D::Error> {
+        let (sample_rate, channels, samples) = <(u32, u16, Vec<AudioSample>)>::deserialize(d)?;
+        Ok(AudioBuffer {
+            sample_rate,
+            channels,
+            samples,
+        })
+    }
 }
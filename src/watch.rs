@@ -0,0 +1,53 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::logging::core_log;
+
+/// Watches a ROM file on disk for changes.
+///
+/// Intended for homebrew development: call [`RomWatcher::poll`] periodically
+/// (e.g. once per frame) and pass the returned data to [`crate::Nes::reload_rom`].
+pub struct RomWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl RomWatcher {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_owned();
+        let modified = modified_time(&path).ok();
+        Self { path, modified }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the new ROM data if the file was modified since the last poll
+    pub fn poll(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let modified = match modified_time(&self.path) {
+            Ok(modified) => modified,
+            // The file may be temporarily missing while a build tool rewrites it
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => Err(err)?,
+        };
+
+        if self.modified == Some(modified) {
+            return Ok(None);
+        }
+
+        let data = std::fs::read(&self.path)?;
+        self.modified = Some(modified);
+
+        core_log!(Rom, Info, "ROM file changed: {}", self.path.display());
+
+        Ok(Some(data))
+    }
+}
+
+fn modified_time(path: &Path) -> io::Result<SystemTime> {
+    std::fs::metadata(path)?.modified()
+}
@@ -0,0 +1,1086 @@
+use meru_interface::EmulatorCore;
+use sabicom::{context::Bus, Nes};
+
+fn make_rom(mapper_id: u16, flags6: u8, prg_16k: usize, chr_8k: usize) -> Vec<u8> {
+    let mut dat = vec![0; 0x10];
+    dat[0..4].copy_from_slice(b"NES\x1a");
+    dat[4] = prg_16k as u8;
+    dat[5] = chr_8k as u8;
+    dat[6] = flags6 | (mapper_id as u8 & 0x0f) << 4;
+    dat[7] = mapper_id as u8 & 0xf0;
+
+    for bank in 0..prg_16k {
+        dat.extend(std::iter::repeat_n(bank as u8, 0x4000));
+    }
+    dat.extend(std::iter::repeat_n(0, chr_8k * 0x2000));
+    dat
+}
+
+#[test]
+fn unrom512_flash_program_and_backup() -> anyhow::Result<()> {
+    let dat = make_rom(30, 0x02, 32, 0);
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+
+    // Select bank 3, then program $42 into flash offset $C123
+    let flash_cmd = |nes: &mut Nes, bank: u8, addr: u16, data: u8| {
+        nes.ctx.write(0xC000, bank);
+        nes.ctx.write(addr, data);
+    };
+
+    flash_cmd(&mut nes, 1, 0x9555, 0xAA);
+    flash_cmd(&mut nes, 0, 0xAAAA, 0x55);
+    flash_cmd(&mut nes, 1, 0x9555, 0xA0);
+    flash_cmd(&mut nes, 3, 0x8123, 0x42);
+
+    assert_eq!(nes.ctx.read(0x8123), 0x42 & 3);
+    assert_eq!(nes.ctx.read(0x8124), 3);
+
+    // Sector erase
+    flash_cmd(&mut nes, 1, 0x9555, 0xAA);
+    flash_cmd(&mut nes, 0, 0xAAAA, 0x55);
+    flash_cmd(&mut nes, 1, 0x9555, 0x80);
+    flash_cmd(&mut nes, 1, 0x9555, 0xAA);
+    flash_cmd(&mut nes, 0, 0xAAAA, 0x55);
+    flash_cmd(&mut nes, 3, 0x8000, 0x30);
+
+    assert_eq!(nes.ctx.read(0x8123), 0xFF);
+    assert_eq!(nes.ctx.read(0x9000), 3);
+
+    let backup = nes.backup().unwrap();
+    assert_eq!(backup.len(), 512 * 1024);
+    assert_eq!(backup[3 * 0x4000 + 0x123], 0xFF);
+
+    let nes = Nes::try_from_file(&dat, Some(&backup), &Default::default())?;
+    assert_eq!(nes.backup().unwrap(), backup);
+
+    Ok(())
+}
+
+#[test]
+fn action53_prg_banking() -> anyhow::Result<()> {
+    let dat = make_rom(28, 0, 8, 0);
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+
+    // Power on state maps the last 32KB
+    assert_eq!(nes.ctx.read(0x8000), 6);
+    assert_eq!(nes.ctx.read(0xC000), 7);
+
+    let write_reg = |nes: &mut Nes, reg: u8, data: u8| {
+        nes.ctx.write(0x5000, reg);
+        nes.ctx.write(0x8000, data);
+    };
+
+    // UNROM like mode with 256KB outer bank
+    write_reg(&mut nes, 0x80, 0x3E);
+    write_reg(&mut nes, 0x81, 0x00);
+    write_reg(&mut nes, 0x01, 0x05);
+
+    assert_eq!(nes.ctx.read(0x8000), 5);
+    assert_eq!(nes.ctx.read(0xC000), 1);
+
+    // 32KB mode with 64KB outer bank
+    write_reg(&mut nes, 0x80, 0x10);
+    write_reg(&mut nes, 0x81, 0x02);
+    write_reg(&mut nes, 0x01, 0x01);
+
+    assert_eq!(nes.ctx.read(0x8000), 6);
+    assert_eq!(nes.ctx.read(0xC000), 7);
+
+    Ok(())
+}
+
+#[test]
+fn racermate_chr_nvram_backup() -> anyhow::Result<()> {
+    use sabicom::context::Ppu;
+
+    let dat = make_rom(168, 0x02, 4, 0);
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+    // PPUADDR writes are ignored until the PPU warms up
+    nes.exec_frame(false);
+
+    // Select CHR bank 5 at PPU $1000 and write $AB to PPU $1010
+    nes.ctx.write(0x8000, 0x05);
+    nes.ctx.write_ppu(6, 0x10);
+    nes.ctx.write_ppu(6, 0x10);
+    nes.ctx.write_ppu(7, 0xAB);
+
+    let backup = nes.backup().unwrap();
+    assert_eq!(backup.len(), 8 * 1024 + 64 * 1024);
+    assert_eq!(backup[8 * 1024 + 5 * 0x1000 + 0x10], 0xAB);
+
+    let nes = Nes::try_from_file(&dat, Some(&backup), &Default::default())?;
+    assert_eq!(nes.backup().unwrap(), backup);
+
+    Ok(())
+}
+
+#[test]
+fn prg_chr_ram_access() -> anyhow::Result<()> {
+    let dat = make_rom(0, 0x02, 2, 0);
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+    assert_eq!(nes.prg_ram().len(), 8 * 1024);
+    assert_eq!(nes.chr_ram().len(), 8 * 1024);
+
+    nes.ctx.write(0x6010, 0x12);
+    assert_eq!(nes.prg_ram()[0x10], 0x12);
+
+    nes.prg_ram_mut()[0x20] = 0x34;
+    assert_eq!(nes.ctx.read(0x6020), 0x34);
+    assert_eq!(nes.backup().unwrap()[0x20], 0x34);
+
+    nes.chr_ram_mut()[0x100] = 0x56;
+    assert_eq!(nes.chr_ram()[0x100], 0x56);
+
+    Ok(())
+}
+
+#[test]
+fn trainer_loaded_at_7000() -> anyhow::Result<()> {
+    let mut dat = make_rom(0, 0x06, 2, 1);
+    let trainer = (0..0x200).map(|i| i as u8 ^ 0x5A).collect::<Vec<_>>();
+    dat.splice(0x10..0x10, trainer.iter().copied());
+
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+    for (i, &b) in trainer.iter().enumerate() {
+        assert_eq!(nes.ctx.read(0x7000 + i as u16), b);
+    }
+    assert_eq!(nes.ctx.read(0x6FFF), 0);
+    assert_eq!(nes.ctx.read(0x7200), 0);
+    assert_eq!(nes.ctx.read(0x8000), 0);
+    assert_eq!(nes.ctx.read(0xC000), 1);
+
+    Ok(())
+}
+
+#[test]
+fn dirty_ines_header() -> anyhow::Result<()> {
+    let mut dat = make_rom(2, 0x01, 4, 0);
+    dat[7..16].copy_from_slice(b"DiskDude!");
+
+    let rom = sabicom::rom::Rom::from_bytes(&dat)?;
+    assert_eq!(rom.mapper_id, 2);
+    assert_eq!(rom.console_type, sabicom::rom::ConsoleType::Nes);
+    assert_eq!(rom.prg_ram_size, 8 * 1024);
+    assert_eq!(rom.chr_ram_size, 8 * 1024);
+
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+    nes.ctx.write(0x8000, 2);
+    assert_eq!(nes.ctx.read(0x8000), 2);
+    assert_eq!(nes.ctx.read(0xC000), 3);
+
+    Ok(())
+}
+
+#[test]
+fn rom_info() -> anyhow::Result<()> {
+    let dat = make_rom(4, 0x03, 4, 2);
+    let info = sabicom::rom::Rom::from_bytes(&dat)?.info(None);
+
+    assert_eq!(info.mapper_id, 4);
+    assert_eq!(info.board_name, Some("MMC3"));
+    assert_eq!(info.mirroring, sabicom::rom::Mirroring::Vertical);
+    assert!(info.has_battery);
+    assert_eq!((info.prg_rom_size, info.chr_rom_size), (0x10000, 0x4000));
+    assert_eq!(info.prg_bank_crc32.len(), 4);
+    assert_eq!(info.prg_bank_crc32[2], crc32fast::hash(&[2; 0x4000]));
+    assert_eq!(info.chr_bank_crc32, vec![crc32fast::hash(&[0; 0x2000]); 2]);
+    assert!(!info.in_header_database);
+
+    let json = serde_json::to_value(&info)?;
+    assert_eq!(json["board_name"], "MMC3");
+    assert_eq!(sabicom::mapper::board_name(250), None);
+
+    Ok(())
+}
+
+#[test]
+fn registered_quirks() -> anyhow::Result<()> {
+    use sabicom::{
+        context::Rom,
+        quirks::Quirks,
+        rom::{Mirroring, TimingMode},
+        Config,
+    };
+
+    let dat = make_rom(2, 0x01, 3, 1);
+    let crc = sabicom::rom::Rom::from_bytes(&dat)?.prg_chr_crc32();
+    let mut config = Config::default();
+    config.quirks.insert(
+        crc,
+        Quirks {
+            mirroring: Some(Mirroring::Horizontal),
+            timing_mode: Some(TimingMode::Pal),
+            ..Default::default()
+        },
+    );
+    let nes = Nes::try_from_file(&dat, None, &config)?;
+
+    assert_eq!(nes.ctx.rom().mirroring, Mirroring::Horizontal);
+    assert_eq!(nes.ctx.rom().timing_mode, TimingMode::Pal);
+    assert_eq!(nes.ctx.rom().mapper_id, 2);
+
+    let nes = Nes::try_from_file(&dat, None, &Default::default())?;
+    assert_eq!(nes.ctx.rom().mirroring, Mirroring::Vertical);
+
+    Ok(())
+}
+
+#[test]
+fn mmc3a_irq_revision() -> anyhow::Result<()> {
+    use sabicom::context::{Interrupt, IrqSource};
+
+    // Returns whether IRQ is raised again after acknowledging one with the latch at 0
+    let irq_after_ack = |submapper_id: u8| -> anyhow::Result<bool> {
+        let mut dat = make_rom(4, 0, 4, 1);
+        dat[7] |= 0x08;
+        dat[8] = submapper_id << 4;
+        let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+        nes.exec_frame(false);
+
+        // Fetch sprite patterns from $1000 so that A12 rises once per line
+        nes.ctx.write(0x2000, 0x08);
+        nes.ctx.write(0x2001, 0x18);
+        nes.ctx.write(0xC000, 0);
+        nes.ctx.write(0xC001, 0);
+        nes.ctx.write(0xE001, 0);
+        nes.exec_frame(false);
+        assert!(nes.ctx.irq_source(IrqSource::Mapper));
+
+        nes.ctx.write(0xE000, 0);
+        nes.ctx.write(0xE001, 0);
+        nes.exec_frame(false);
+        Ok(nes.ctx.irq_source(IrqSource::Mapper))
+    };
+
+    assert!(irq_after_ack(0)?);
+    assert!(!irq_after_ack(4)?);
+
+    Ok(())
+}
+
+#[test]
+fn nes2_misc_rom() -> anyhow::Result<()> {
+    use sabicom::rom::Rom;
+
+    let mut dat = make_rom(0, 0x01, 2, 1);
+    let misc = (0..0x300).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+    dat.extend(&misc);
+    assert!(Rom::from_bytes(&dat).is_err());
+
+    // NES 2.0 header with 1 miscellaneous ROM
+    dat[7] |= 0x08;
+    dat[14] = 1;
+    let rom = Rom::from_bytes(&dat)?;
+    assert_eq!(rom.misc_rom_count, 1);
+    assert_eq!(rom.misc_rom, misc);
+    assert_eq!(rom.prg_rom.len(), 0x8000);
+    assert_eq!(rom.chr_rom.len(), 0x2000);
+
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+    assert_eq!(nes.ctx.read(0xC000), 1);
+
+    Ok(())
+}
+
+#[test]
+fn external_mapper_registration() -> anyhow::Result<()> {
+    use sabicom::mapper::{self, ExternalMapper};
+
+    struct SimpleUxrom {
+        bank: u8,
+    }
+
+    impl ExternalMapper for SimpleUxrom {
+        fn write_prg(&mut self, ctx: &mut dyn mapper::Context, _addr: u16, data: u8) {
+            self.bank = data;
+            ctx.map_prg(0, data as u32 * 2);
+            ctx.map_prg(1, data as u32 * 2 + 1);
+        }
+
+        fn save_state(&self) -> Vec<u8> {
+            vec![self.bank]
+        }
+
+        fn load_state(&mut self, data: &[u8]) {
+            self.bank = data[0];
+        }
+    }
+
+    mapper::register_mapper(255, |_| Box::new(SimpleUxrom { bank: 0 }));
+
+    let dat = make_rom(255, 0, 4, 1);
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+
+    nes.ctx.write(0x8000, 2);
+    assert_eq!(nes.ctx.read(0x8000), 2);
+
+    let state = nes.save_state();
+    nes.ctx.write(0x8000, 1);
+    assert_eq!(nes.ctx.read(0x8000), 1);
+
+    nes.load_state(&state)?;
+    assert_eq!(nes.ctx.read(0x8000), 2);
+    nes.ctx.write(0x8000, 3);
+    assert_eq!(nes.ctx.read(0x8000), 3);
+
+    mapper::unregister_mapper(255);
+    assert!(Nes::try_from_file(&dat, None, &Default::default()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn ppu_fetches_reach_mapper() -> anyhow::Result<()> {
+    use sabicom::{
+        context::Ppu,
+        mapper::{self, ExternalMapper},
+        nes::{AccuracyProfile, Config},
+    };
+    use std::sync::Mutex;
+
+    static FETCHES: Mutex<Vec<u16>> = Mutex::new(vec![]);
+
+    struct FetchLogger;
+
+    impl ExternalMapper for FetchLogger {
+        fn read_chr(&mut self, ctx: &mut dyn mapper::Context, addr: u16) -> u8 {
+            FETCHES.lock().unwrap().push(addr);
+            ctx.read_chr(addr)
+        }
+
+        fn save_state(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn load_state(&mut self, _data: &[u8]) {}
+    }
+
+    mapper::register_mapper(253, |_| Box::new(FetchLogger));
+
+    let config = Config {
+        accuracy: AccuracyProfile::Accurate,
+        ..Default::default()
+    };
+    let mut nes = Nes::try_from_file(&make_rom(253, 0, 2, 1), None, &config)?;
+    mapper::unregister_mapper(253);
+
+    nes.exec_frame(false);
+    // Background from $0000 and sprites from $1000
+    nes.ctx.write(0x2000, 0x08);
+    nes.ctx.write(0x2001, 0x18);
+    while !(nes.ctx.ppu().line() == 10 && nes.ctx.ppu().dot() == 1) {
+        nes.ctx.tick_ppu();
+    }
+    FETCHES.lock().unwrap().clear();
+    while nes.ctx.ppu().line() == 10 {
+        nes.ctx.tick_ppu();
+    }
+
+    // 34 background tiles, 8 sprites and 2 unused nametable fetches, without palette lookups
+    let fetches = FETCHES.lock().unwrap().clone();
+    assert_eq!(fetches.len(), 34 * 4 + 8 * 4 + 2);
+    assert!(fetches.iter().all(|&addr| addr < 0x3f00));
+    // Empty sprite slots fetch tile $FF after two garbage nametable fetches
+    for slot in fetches[128..160].chunks(4) {
+        assert!(slot[0] >= 0x2000 && slot[1] >= 0x2000);
+        assert_eq!(slot[2] & 0xfff0, 0x1ff0);
+        assert_eq!(slot[3], slot[2] + 8);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn expansion_audio_output() -> anyhow::Result<()> {
+    use sabicom::{
+        apu::{Channel, ExpansionChip, ExpansionMixLevels},
+        mapper::{self, ExternalMapper},
+        nes::Config,
+    };
+
+    // Square wave of a made-up sound chip
+    struct SquareChip {
+        ticks: u64,
+    }
+
+    impl ExternalMapper for SquareChip {
+        fn tick(&mut self, _ctx: &mut dyn mapper::Context) {
+            self.ticks += 1;
+        }
+
+        fn audio_output(&self) -> Option<(ExpansionChip, f32)> {
+            let high = (self.ticks / 200).is_multiple_of(2);
+            Some((ExpansionChip::Vrc6, if high { 0.2 } else { 0.0 }))
+        }
+
+        fn save_state(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn load_state(&mut self, _data: &[u8]) {}
+    }
+
+    mapper::register_mapper(252, |_| Box::new(SquareChip { ticks: 0 }));
+
+    let amplitude = |config: &Config, enable: bool| -> anyhow::Result<i32> {
+        let mut nes = Nes::try_from_file(&make_rom(252, 0, 2, 1), None, config)?;
+        nes.set_channel_enabled(Channel::Expansion, enable);
+        nes.exec_frame(false);
+        nes.exec_frame(false);
+        let samples = nes.audio_buffer().samples.iter().map(|s| s.left as i32);
+        Ok(samples.clone().max().unwrap() - samples.min().unwrap())
+    };
+
+    let full = amplitude(&Default::default(), true)?;
+    assert!(full > 1000);
+    assert!(amplitude(&Default::default(), false)? < full / 100);
+
+    // -6 dB halves the level
+    let config = Config {
+        expansion_audio: ExpansionMixLevels {
+            vrc6: -6.0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let half = amplitude(&config, true)?;
+    assert!((half * 2 - full).abs() < full / 10);
+
+    mapper::unregister_mapper(252);
+    Ok(())
+}
+
+#[test]
+fn vrc6_banking_and_audio() -> anyhow::Result<()> {
+    use sabicom::apu::Channel;
+
+    // VRC6b swaps A0 and A1, so $9002 is written at $9001
+    for (mapper_id, reg2) in [(24, 0x0002), (26, 0x0001)] {
+        let mut nes =
+            Nes::try_from_file(&make_rom(mapper_id, 0, 16, 1), None, &Default::default())?;
+
+        nes.ctx.write(0x8000, 3);
+        assert_eq!(nes.ctx.read(0x8000), 3);
+        assert_eq!(nes.ctx.read(0xA000), 3);
+        // 8KB bank 11 is the second half of 16KB bank 5
+        nes.ctx.write(0xC000, 11);
+        assert_eq!(nes.ctx.read(0xC000), 5);
+        assert_eq!(nes.ctx.read(0xE000), 15);
+
+        // Pulse 1 at 50% duty and full volume
+        nes.ctx.write(0x9000, 0x7F);
+        nes.ctx.write(0x9000 | (reg2 ^ 3), 0xFF);
+        nes.ctx.write(0x9000 | reg2, 0x80);
+
+        let amplitude = |nes: &mut Nes| {
+            nes.exec_frame(false);
+            nes.exec_frame(false);
+            let samples = nes.audio_buffer().samples.iter().map(|s| s.left as i32);
+            samples.clone().max().unwrap() - samples.min().unwrap()
+        };
+
+        assert!(amplitude(&mut nes) > 1000);
+        nes.set_channel_enabled(Channel::Expansion, false);
+        assert!(amplitude(&mut nes) < 100);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn vrc6_irq() -> anyhow::Result<()> {
+    use sabicom::context::{IrqSource, Timing};
+
+    let mut nes = Nes::try_from_file(&make_rom(24, 0, 16, 1), None, &Default::default())?;
+
+    // Cycle mode, raised after 16 CPU cycles
+    nes.ctx.write(0xF000, 0xF0);
+    nes.ctx.write(0xF001, 0x06);
+    let start = nes.ctx.now();
+    while !nes.irq_asserted(IrqSource::Mapper) {
+        nes.ctx.tick_bus();
+    }
+    let cycles = nes.ctx.now() - start;
+    assert!((16..=18).contains(&cycles));
+
+    nes.ctx.write(0xF002, 0);
+    assert!(!nes.irq_asserted(IrqSource::Mapper));
+
+    Ok(())
+}
+
+#[test]
+fn namco163_sound_and_irq() -> anyhow::Result<()> {
+    use sabicom::context::{IrqSource, Timing};
+
+    let mut nes = Nes::try_from_file(&make_rom(19, 0, 16, 1), None, &Default::default())?;
+
+    // Square wave of 32 samples, played by channel 7 alone at volume 15
+    let mut ram = [0; 0x80];
+    ram[..8].fill(0xFF);
+    ram[0x78..].copy_from_slice(&[0x35, 0, 0x1E, 0, 0xE0, 0, 0, 0x0F]);
+    nes.ctx.write(0xF800, 0x80);
+    for data in ram {
+        nes.ctx.write(0x4800, data);
+    }
+
+    // The data port auto-increments on reads too, but not on debugger reads
+    nes.ctx.write(0xF800, 0x80 | 0x78);
+    assert_eq!(nes.ctx.read_pure(0x4800), Some(0x35));
+    assert_eq!(nes.ctx.read_pure(0x4800), Some(0x35));
+    assert_eq!(nes.ctx.read(0x4800), 0x35);
+    assert_eq!(nes.ctx.read(0x4800), 0x00);
+    assert_eq!(nes.ctx.read(0x4800), 0x1E);
+
+    let amplitude = |nes: &mut Nes| {
+        nes.exec_frame(false);
+        nes.exec_frame(false);
+        let samples = nes.audio_buffer().samples.iter().map(|s| s.left as i32);
+        samples.clone().max().unwrap() - samples.min().unwrap()
+    };
+    assert!(amplitude(&mut nes) > 1000);
+    nes.ctx.write(0xE000, 0x40);
+    assert!(amplitude(&mut nes) < 100);
+
+    // The IRQ counter counts CPU cycles up to $7FFF
+    nes.ctx.write(0x5000, 0xF0);
+    nes.ctx.write(0x5800, 0xFF);
+    let start = nes.ctx.now();
+    while !nes.irq_asserted(IrqSource::Mapper) {
+        nes.ctx.tick_bus();
+    }
+    assert!((15..=17).contains(&(nes.ctx.now() - start)));
+    assert_eq!(nes.ctx.read(0x5000), 0xFF);
+    nes.ctx.write(0x5800, 0x00);
+    assert!(!nes.irq_asserted(IrqSource::Mapper));
+
+    Ok(())
+}
+
+#[test]
+fn expansion_mix_levels() -> anyhow::Result<()> {
+    use sabicom::{apu::ExpansionMixLevels, Config};
+
+    // Amplitude of a square wave played by Namco 163 channel 7 at volume 15
+    let amplitude = |levels: ExpansionMixLevels| -> anyhow::Result<i32> {
+        let config = Config {
+            expansion_audio: levels,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(19, 0, 16, 1), None, &config)?;
+        let mut ram = [0; 0x80];
+        ram[..8].fill(0xFF);
+        ram[0x78..].copy_from_slice(&[0x35, 0, 0x1E, 0, 0xE0, 0, 0, 0x0F]);
+        nes.ctx.write(0xF800, 0x80);
+        for data in ram {
+            nes.ctx.write(0x4800, data);
+        }
+
+        nes.exec_frame(false);
+        nes.exec_frame(false);
+        let samples = nes.audio_buffer().samples.iter().map(|s| s.left as i32);
+        Ok(samples.clone().max().unwrap() - samples.min().unwrap())
+    };
+
+    let default = amplitude(Default::default())?;
+    // 20dB lower is a tenth of the amplitude
+    let quiet = amplitude(ExpansionMixLevels {
+        namco163: ExpansionMixLevels::default().namco163 - 20.0,
+        ..Default::default()
+    })?;
+    assert!(default > 1000);
+    assert!((default / 12..=default / 8).contains(&quiet));
+
+    // Levels of other chips don't change it
+    let other = amplitude(ExpansionMixLevels {
+        vrc6: -20.0,
+        sunsoft5b: -20.0,
+        ..Default::default()
+    })?;
+    assert_eq!(other, default);
+
+    Ok(())
+}
+
+#[test]
+fn sunsoft5b_banking_irq_and_audio() -> anyhow::Result<()> {
+    use sabicom::context::{IrqSource, Timing};
+
+    let mut nes = Nes::try_from_file(&make_rom(69, 0, 16, 1), None, &Default::default())?;
+    let fme7 = |nes: &mut Nes, cmd: u8, data: u8| {
+        nes.ctx.write(0x8000, cmd);
+        nes.ctx.write(0xA000, data);
+    };
+    let psg = |nes: &mut Nes, reg: u8, data: u8| {
+        nes.ctx.write(0xC000, reg);
+        nes.ctx.write(0xE000, data);
+    };
+
+    // 8KB bank 7 is the second half of 16KB bank 3, also mappable at $6000
+    fme7(&mut nes, 0x9, 7);
+    assert_eq!(nes.ctx.read(0x8000), 3);
+    fme7(&mut nes, 0x8, 4);
+    assert_eq!(nes.ctx.read(0x6000), 2);
+    assert_eq!(nes.ctx.read(0xE000), 15);
+
+    // The IRQ is raised when the counter wraps below 0
+    fme7(&mut nes, 0xE, 0x10);
+    fme7(&mut nes, 0xF, 0x00);
+    fme7(&mut nes, 0xD, 0x81);
+    let start = nes.ctx.now();
+    while !nes.irq_asserted(IrqSource::Mapper) {
+        nes.ctx.tick_bus();
+    }
+    assert!((17..=19).contains(&(nes.ctx.now() - start)));
+    fme7(&mut nes, 0xD, 0x00);
+    assert!(!nes.irq_asserted(IrqSource::Mapper));
+
+    let amplitude = |nes: &mut Nes| {
+        nes.exec_frame(false);
+        nes.exec_frame(false);
+        let samples = nes.audio_buffer().samples.iter().map(|s| s.left as i32);
+        samples.clone().max().unwrap() - samples.min().unwrap()
+    };
+
+    // Tone of channel A only, at full volume
+    psg(&mut nes, 0x0, 0xFE);
+    psg(&mut nes, 0x7, 0x3E);
+    psg(&mut nes, 0x8, 0x0F);
+    assert!(amplitude(&mut nes) > 1000);
+    psg(&mut nes, 0x8, 0x00);
+    assert!(amplitude(&mut nes) < 100);
+
+    // A decaying envelope ends silent
+    psg(&mut nes, 0x8, 0x10);
+    psg(&mut nes, 0xB, 0x00);
+    psg(&mut nes, 0xC, 0x04);
+    psg(&mut nes, 0xD, 0x00);
+    assert!(amplitude(&mut nes) > 1000);
+    for _ in 0..4 {
+        amplitude(&mut nes);
+    }
+    assert!(amplitude(&mut nes) < 100);
+
+    Ok(())
+}
+
+#[test]
+fn datach_barcode_and_eeprom() -> anyhow::Result<()> {
+    use sabicom::context::{IrqSource, Timing};
+
+    let mut nes = Nes::try_from_file(&make_rom(157, 0x02, 16, 0), None, &Default::default())?;
+
+    nes.ctx.write(0x8008, 3);
+    assert_eq!(nes.ctx.read(0x8000), 3);
+    assert_eq!(nes.ctx.read(0xC000), 15);
+
+    nes.ctx.write(0x800B, 0x10);
+    nes.ctx.write(0x800C, 0x00);
+    nes.ctx.write(0x800A, 0x01);
+    let start = nes.ctx.now();
+    while !nes.irq_asserted(IrqSource::Mapper) {
+        nes.ctx.tick_bus();
+    }
+    assert!((16..=18).contains(&(nes.ctx.now() - start)));
+    nes.ctx.write(0x800A, 0x00);
+    assert!(!nes.irq_asserted(IrqSource::Mapper));
+
+    // Bars read as 0 on bit 3, one every 1000 CPU cycles
+    let scan = |nes: &mut Nes, code: &str| {
+        assert!(nes.scan_barcode(code));
+        let start = nes.ctx.now();
+        (0..32 + 95 + 32 + 1)
+            .map(|i| {
+                while nes.ctx.now() - start < i * 1000 + 1500 {
+                    nes.ctx.tick_bus();
+                }
+                nes.ctx.read(0x6000) & 0x08 == 0
+            })
+            .map(|bar| bar as u8)
+            .collect::<Vec<_>>()
+    };
+    let bars = scan(&mut nes, "4901234567894");
+    assert!(bars[..32].iter().all(|&b| b == 0));
+    // Guard bars, then 9 in an L-code as the first digit 4 selects
+    assert_eq!(bars[32..42], [1, 0, 1, 0, 0, 0, 1, 0, 1, 1]);
+    // The check digit 4 in an R-code, and the guard bars
+    assert_eq!(bars[32 + 85..32 + 95], [1, 0, 1, 1, 1, 0, 0, 1, 0, 1]);
+    assert!(bars[32 + 95..32 + 95 + 32].iter().all(|&b| b == 0));
+    // The reader outputs 0 after the code, like a bar
+    assert_eq!(bars.last(), Some(&1));
+    // The check digit is computed when omitted
+    assert_eq!(scan(&mut nes, "490123456789"), bars);
+    assert!(!nes.scan_barcode("12345"));
+
+    // Write $5A to address $10 of the 24C02, then read it back
+    let i2c = |nes: &mut Nes, scl: bool, sda: bool| {
+        nes.ctx.write(0x800D, (sda as u8) << 6 | (scl as u8) << 5);
+        nes.ctx.read(0x6000) & 0x10 != 0
+    };
+    let start = |nes: &mut Nes| {
+        for (scl, sda) in [(false, true), (true, true), (true, false), (false, false)] {
+            i2c(nes, scl, sda);
+        }
+    };
+    let stop = |nes: &mut Nes| {
+        for (scl, sda) in [(false, false), (true, false), (true, true)] {
+            i2c(nes, scl, sda);
+        }
+    };
+    // Sends a byte and returns whether it was acknowledged
+    let send = |nes: &mut Nes, data: u8| {
+        for i in (0..8).rev() {
+            let bit = data >> i & 1 != 0;
+            i2c(nes, false, bit);
+            i2c(nes, true, bit);
+            i2c(nes, false, bit);
+        }
+        i2c(nes, false, true);
+        let ack = !i2c(nes, true, true);
+        i2c(nes, false, true);
+        ack
+    };
+
+    start(&mut nes);
+    assert!(send(&mut nes, 0xA0));
+    assert!(send(&mut nes, 0x10));
+    assert!(send(&mut nes, 0x5A));
+    stop(&mut nes);
+    assert_eq!(nes.prg_ram()[0x10], 0x5A);
+
+    start(&mut nes);
+    assert!(send(&mut nes, 0xA0));
+    assert!(send(&mut nes, 0x10));
+    start(&mut nes);
+    assert!(send(&mut nes, 0xA1));
+    let mut data = 0;
+    for _ in 0..8 {
+        i2c(&mut nes, false, true);
+        data = data << 1 | i2c(&mut nes, true, true) as u8;
+    }
+    i2c(&mut nes, false, true);
+    stop(&mut nes);
+    assert_eq!(data, 0x5A);
+
+    Ok(())
+}
+
+/// Builds an NSF whose init stores A, X and the byte at $9000 to $6000, $6001 and $6003,
+/// and whose play increments $6002
+fn make_nsf(ntsc_speed: u16, bank_init: [u8; 8]) -> Vec<u8> {
+    let mut dat = vec![0; 0x80];
+    dat[0..5].copy_from_slice(b"NESM\x1a");
+    dat[5] = 1;
+    dat[6] = 3;
+    dat[7] = 2;
+    dat[0x08..0x0A].copy_from_slice(&0x8000u16.to_le_bytes());
+    dat[0x0A..0x0C].copy_from_slice(&0x8000u16.to_le_bytes());
+    dat[0x0C..0x0E].copy_from_slice(&0x8010u16.to_le_bytes());
+    dat[0x0E..0x12].copy_from_slice(b"Test");
+    dat[0x6E..0x70].copy_from_slice(&ntsc_speed.to_le_bytes());
+    dat[0x70..0x78].copy_from_slice(&bank_init);
+    dat[0x78..0x7A].copy_from_slice(&19997u16.to_le_bytes());
+
+    let mut prg = vec![0; 0x3000];
+    #[rustfmt::skip]
+    let init = [
+        0x8D, 0x00, 0x60, // STA $6000
+        0x8E, 0x01, 0x60, // STX $6001
+        0xAD, 0x00, 0x90, // LDA $9000
+        0x8D, 0x03, 0x60, // STA $6003
+        0x60,             // RTS
+    ];
+    prg[..init.len()].copy_from_slice(&init);
+    prg[0x10..0x14].copy_from_slice(&[0xEE, 0x02, 0x60, 0x60]); // INC $6002; RTS
+    prg[0x1000] = 0x11;
+    prg[0x2000] = 0x22;
+    dat.extend(prg);
+    dat
+}
+
+#[test]
+fn nsf_player_tracks() -> anyhow::Result<()> {
+    let mut nes = Nes::try_from_file(&make_nsf(16639, [0; 8]), None, &Default::default())?;
+    let info = nes.nsf_info().unwrap();
+    assert_eq!((info.title.as_str(), info.song_count), ("Test", 3));
+
+    // Play is called on every NMI after init of the starting track
+    for _ in 0..10 {
+        nes.exec_frame(false);
+    }
+    assert_eq!(nes.nsf_track(), Some(1));
+    let ram = nes.prg_ram();
+    assert_eq!((ram[0], ram[1], ram[3]), (1, 0, 0x11));
+    assert!((7..=9).contains(&ram[2]));
+
+    // Selecting a track initializes it again with cleared RAM
+    nes.next_nsf_track();
+    nes.exec_frame(false);
+    assert_eq!(nes.nsf_track(), Some(2));
+    assert_eq!(nes.prg_ram()[0], 2);
+    assert!(nes.prg_ram()[2] <= 1);
+
+    nes.next_nsf_track();
+    assert_eq!(nes.nsf_track(), Some(0));
+    nes.prev_nsf_track();
+    assert_eq!(nes.nsf_track(), Some(2));
+    Ok(())
+}
+
+#[test]
+fn nsf_player_timer_and_banks() -> anyhow::Result<()> {
+    // 120 play calls per second, and bank 2 at $9000
+    let dat = make_nsf(8333, [0, 2, 0, 0, 0, 0, 0, 0]);
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+
+    for _ in 0..10 {
+        nes.exec_frame(false);
+    }
+    let ram = nes.prg_ram();
+    assert_eq!(ram[3], 0x22);
+    assert!((18..=20).contains(&ram[2]));
+    Ok(())
+}
+
+fn nsfe_chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut ret = (data.len() as u32).to_le_bytes().to_vec();
+    ret.extend(id);
+    ret.extend(data);
+    ret
+}
+
+#[test]
+fn nsfe_metadata() -> anyhow::Result<()> {
+    assert!(Nes::core_info().file_extensions.contains(&"nsfe"));
+
+    // The same program as `make_nsf` in NSFe chunks
+    let nsf = make_nsf(16639, [0; 8]);
+    let mut info = vec![0x00, 0x80, 0x00, 0x80, 0x10, 0x80, 0x00, 0x00, 2, 1];
+    info.extend([0; 2]);
+
+    let mut time = vec![];
+    time.extend(90_000i32.to_le_bytes());
+    time.extend((-1i32).to_le_bytes());
+
+    let mut dat = b"NSFE".to_vec();
+    dat.extend(nsfe_chunk(b"INFO", &info[..10]));
+    dat.extend(nsfe_chunk(b"DATA", &nsf[0x80..]));
+    dat.extend(nsfe_chunk(b"auth", b"Game\0Composer\0\0Ripper\0"));
+    dat.extend(nsfe_chunk(b"tlbl", b"Opening\0Ending\0"));
+    dat.extend(nsfe_chunk(b"time", &time));
+    dat.extend(nsfe_chunk(b"fade", &5_000i32.to_le_bytes()));
+    dat.extend(nsfe_chunk(b"mixe", &[1, 0x2C, 0x01]));
+    dat.extend(nsfe_chunk(b"NEND", &[]));
+
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+    let info = nes.nsf_info().unwrap();
+    assert_eq!(info.song_count, 2);
+    assert_eq!(
+        (
+            info.title.as_str(),
+            info.artist.as_str(),
+            info.copyright.as_str()
+        ),
+        ("Game", "Composer", "")
+    );
+    assert_eq!(info.ripper, "Ripper");
+    assert_eq!(info.mixing_levels[0].device, 1);
+    assert_eq!(info.mixing_levels[0].millibels, 300);
+    assert_eq!(info.tracks[0].fade_ms, Some(5_000));
+    assert_eq!(info.tracks[1].length_ms, None);
+
+    let track = nes.nsf_track_info().unwrap();
+    assert_eq!(track.name.as_deref(), Some("Ending"));
+    nes.next_nsf_track();
+    let track = nes.nsf_track_info().unwrap();
+    assert_eq!(
+        (track.name.as_deref(), track.length_ms),
+        (Some("Opening"), Some(90_000))
+    );
+
+    for _ in 0..10 {
+        nes.exec_frame(false);
+    }
+    assert_eq!(nes.prg_ram()[3], 0x11);
+    assert!(nes.prg_ram()[2] >= 7);
+
+    // Unknown required chunks are rejected
+    let mut bad = dat[..dat.len() - 8].to_vec();
+    bad.extend(nsfe_chunk(b"ZZZZ", &[]));
+    assert!(Nes::try_from_file(&bad, None, &Default::default()).is_err());
+    Ok(())
+}
+
+#[test]
+fn nsf2_irq_and_metadata() -> anyhow::Result<()> {
+    let mut dat = make_nsf(16639, [0; 8]);
+    dat[0x05] = 2;
+    dat[0x0A..0x0C].copy_from_slice(&0x8020u16.to_le_bytes());
+    dat[0x7C] = 0x10;
+    let data_len = (dat.len() - 0x80) as u32;
+    dat[0x7D..0x80].copy_from_slice(&data_len.to_le_bytes()[..3]);
+
+    #[rustfmt::skip]
+    let init = [
+        0xA9, 0x40, 0x8D, 0xFE, 0xFF, // Vector $8040
+        0xA9, 0x80, 0x8D, 0xFF, 0xFF,
+        0xA9, 0x00, 0x8D, 0x18, 0x40, // Reload $2000
+        0xA9, 0x20, 0x8D, 0x19, 0x40,
+        0xA9, 0x01, 0x8D, 0x1A, 0x40, // Enable
+        0x60,
+    ];
+    #[rustfmt::skip]
+    let handler = [
+        0x48,             // PHA
+        0xA9, 0x01,       // LDA #1
+        0x8D, 0x1A, 0x40, // STA $401A
+        0xEE, 0x04, 0x60, // INC $6004
+        0x68,             // PLA
+        0x40,             // RTI
+    ];
+    dat[0xA0..0xA0 + init.len()].copy_from_slice(&init);
+    dat[0xC0..0xC0 + handler.len()].copy_from_slice(&handler);
+    dat.extend(nsfe_chunk(b"tlbl", b"One\0Two\0Three\0"));
+
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+    assert!(nes.nsf_info().unwrap().irq_support);
+    assert_eq!(nes.nsf_track_info().unwrap().name.as_deref(), Some("Two"));
+
+    for _ in 0..10 {
+        nes.exec_frame(false);
+    }
+    // The tune gets IRQs every $2001 cycles, while play is called on each frame
+    let ram = nes.prg_ram();
+    assert!((25..=37).contains(&ram[4]));
+    assert!((7..=9).contains(&ram[2]));
+    Ok(())
+}
+
+#[test]
+fn emulation_panic_is_recoverable() -> anyhow::Result<()> {
+    use sabicom::{
+        mapper::{self, ExternalMapper},
+        nes::Error,
+    };
+
+    struct BrokenMapper {
+        ticks: u64,
+    }
+
+    impl ExternalMapper for BrokenMapper {
+        fn tick(&mut self, _ctx: &mut dyn mapper::Context) {
+            self.ticks += 1;
+            assert!(self.ticks < 50_000, "broken mapper");
+        }
+
+        fn save_state(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn load_state(&mut self, _data: &[u8]) {}
+    }
+
+    mapper::register_mapper(254, |_| Box::new(BrokenMapper { ticks: 0 }));
+
+    let dat = make_rom(254, 0, 2, 1);
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+    mapper::unregister_mapper(254);
+
+    match nes.try_exec_frame(false) {
+        Err(Error::EmulationPanic {
+            message,
+            frame,
+            mapper_id,
+            ..
+        }) => {
+            assert_eq!(message, "broken mapper");
+            assert_eq!(frame, 0);
+            assert_eq!(mapper_id, 254);
+        }
+        _ => panic!("expected EmulationPanic"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn nametable_sources() -> anyhow::Result<()> {
+    use sabicom::{context::MemoryController, memory::NametableSource};
+
+    let mut dat = make_rom(0, 0, 1, 1);
+    let chr_start = 0x10 + 0x4000;
+    dat[chr_start + 0x0410] = 0x5A;
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+
+    let mem = nes.ctx.memory_ctrl_mut();
+    mem.alloc_mapper_ram(0x400);
+    mem.set_nametable_source(0, NametableSource::MapperRam(0));
+    mem.set_nametable_source(1, NametableSource::Chr(1));
+    mem.set_nametable_source(
+        2,
+        NametableSource::Fill {
+            tile: 0x12,
+            attr: 2,
+        },
+    );
+
+    nes.ctx.write_chr(0x2005, 0x77);
+    assert_eq!(nes.ctx.memory_ctrl().mapper_ram()[5], 0x77);
+    assert_eq!(nes.ctx.read_chr(0x2005), 0x77);
+
+    // CHR ROM and fill mode pages ignore writes
+    nes.ctx.write_chr(0x2410, 0x00);
+    assert_eq!(nes.ctx.read_chr(0x2410), 0x5A);
+
+    nes.ctx.write_chr(0x2800, 0x00);
+    assert_eq!(nes.ctx.read_chr(0x2800), 0x12);
+    assert_eq!(nes.ctx.read_chr(0x2BC0), 0xAA);
+
+    Ok(())
+}
+
+#[test]
+fn vs_system_switches() -> anyhow::Result<()> {
+    use sabicom::{context::Ppu, ppu::PpuModel};
+
+    let mut dat = make_rom(0, 0, 2, 1);
+    dat[7] |= 1;
+    let mut nes = Nes::try_from_file(&dat, None, &Default::default())?;
+    assert_eq!(nes.ctx.ppu().model(), PpuModel::Rp2c03);
+
+    nes.set_dip_switches(0b1010_0110);
+    nes.insert_coin(1);
+
+    assert_eq!(nes.ctx.read(0x4016) & 0x7c, 0b0100_0000 | 0b10 << 3);
+    assert_eq!(nes.ctx.read(0x4017) & 0xfc, 0b1010_0100);
+
+    for _ in 0..4 {
+        nes.exec_frame(false);
+    }
+    assert_eq!(nes.ctx.read(0x4016) & 0x60, 0);
+
+    nes.reset();
+    assert_eq!(nes.ctx.read(0x4017) & 0xfc, 0b1010_0100);
+
+    Ok(())
+}
+
+#[test]
+fn playchoice10_rom() -> anyhow::Result<()> {
+    use sabicom::{context::Ppu, ppu::PpuModel, rom::ConsoleType};
+
+    let mut dat = make_rom(0, 0, 2, 1);
+    dat[7] |= 2;
+    dat.extend(std::iter::repeat_n(0xAA, 8 * 1024 + 32));
+
+    let nes = Nes::try_from_file(&dat, None, &Default::default())?;
+    assert_eq!(nes.console_type(), ConsoleType::Playchoice10);
+    assert_eq!(nes.ctx.ppu().model(), PpuModel::Rp2c03);
+
+    let rom = sabicom::Rom::from_bytes(&dat)?;
+    let pc10 = rom.playchoice.unwrap();
+    assert_eq!(pc10.inst_rom.len(), 8 * 1024);
+    assert_eq!(pc10.prom.len(), 32);
+
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use anyhow::Result;
 use meru_interface::EmulatorCore;
-use sabicom::{context::Bus, Nes};
+use sabicom::{context::Bus, movie::ConsoleEvent, Nes};
 use std::path::Path;
 
 fn test_rom(path: impl AsRef<Path>) -> Result<()> {
@@ -28,7 +28,12 @@ fn test_rom(path: impl AsRef<Path>) -> Result<()> {
         }
 
         if !starting && stat == 0x81 {
-            todo!("need to reset");
+            // The ROM needs the reset button to be pressed after a while
+            for _ in 0..10 {
+                nes.exec_frame(false);
+            }
+            nes.apply_event(ConsoleEvent::SoftReset);
+            continue;
         }
 
         if starting {
@@ -1,5 +1,9 @@
 use meru_interface::EmulatorCore;
-use sabicom::{context::Cpu, Nes};
+use sabicom::{
+    context::Cpu,
+    logging::{self, Category},
+    Nes,
+};
 
 #[test]
 fn test_nestest() -> anyhow::Result<()> {
@@ -11,7 +15,7 @@ fn test_nestest() -> anyhow::Result<()> {
 
     impl log::Log for NestestLogger {
         fn enabled(&self, metadata: &log::Metadata) -> bool {
-            metadata.target() == "disasm-nestest" && metadata.level() <= log::Level::Trace
+            metadata.target() == Category::Nestest.target() && metadata.level() <= log::Level::Trace
         }
 
         fn log(&self, record: &log::Record) {
@@ -26,6 +30,7 @@ fn test_nestest() -> anyhow::Result<()> {
     static LOGGER: NestestLogger = NestestLogger(Mutex::new(String::new()));
 
     log::set_logger(&LOGGER).map(|()| log::set_max_level(log::LevelFilter::Trace))?;
+    logging::enable(Category::Nestest);
 
     let path = "./nes-test-roms/other/nestest.nes";
     let dat = std::fs::read(std::path::Path::new(path))?;
@@ -0,0 +1,2989 @@
+use meru_interface::EmulatorCore;
+use sabicom::{
+    context::{Cpu, Timing},
+    Nes,
+};
+
+fn make_rom() -> Vec<u8> {
+    let mut dat = vec![0; 0x10];
+    dat[0..4].copy_from_slice(b"NES\x1a");
+    dat[4] = 2;
+    dat[5] = 1;
+
+    // Wait for the PPU to warm up, enable rendering and NMI, then loop forever
+    #[rustfmt::skip]
+    let prg = [
+        0x2C, 0x02, 0x20, 0x10, 0xFB, // BIT $2002; BPL $8000
+        0x2C, 0x02, 0x20, 0x10, 0xFB, // BIT $2002; BPL $8005
+        0xA9, 0x1E, 0x8D, 0x01, 0x20, // LDA #$1E; STA $2001
+        0xA9, 0x80, 0x8D, 0x00, 0x20, // LDA #$80; STA $2000
+        0xE8, 0x4C, 0x14, 0x80,       // INX; JMP $8014
+        0x40,                         // RTI
+    ];
+    let mut prg_rom = vec![0; 0x8000];
+    prg_rom[..prg.len()].copy_from_slice(&prg);
+    prg_rom[0x7ffa..].copy_from_slice(&[0x18, 0x80, 0x00, 0x80, 0x18, 0x80]);
+
+    dat.extend(prg_rom);
+    dat.extend((0..0x2000).map(|i| i as u8));
+    dat
+}
+
+/// Runs frames until the test ROM has enabled rendering after the PPU warm-up
+fn warm_up(nes: &mut Nes) {
+    for _ in 0..3 {
+        nes.exec_frame(true);
+    }
+}
+
+#[test]
+fn mid_frame_save_state() -> anyhow::Result<()> {
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+
+    nes.exec_frame(true);
+    for _ in 0..12345 {
+        nes.ctx.tick_cpu();
+    }
+
+    let state = nes.save_state();
+    nes.exec_frame(true);
+    let frame = nes.frame_buffer().buffer.clone();
+    let audio = nes.audio_buffer().samples.clone();
+    let now = nes.ctx.now();
+
+    let mut nes2 = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes2.load_state(&state)?;
+    nes2.exec_frame(true);
+
+    assert!(nes2.frame_buffer().buffer == frame);
+    assert!(nes2.audio_buffer().samples == audio);
+    assert_eq!(nes2.ctx.now(), now);
+
+    Ok(())
+}
+
+#[test]
+fn accuracy_profiles() -> anyhow::Result<()> {
+    use sabicom::nes::{AccuracyProfile, Config};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    assert!(nes.accuracy_features().nonlinear_mixer);
+    assert!(!nes.accuracy_features().sanity_checks);
+
+    nes.set_config(&Config {
+        accuracy: AccuracyProfile::Fast,
+        ..Default::default()
+    });
+    assert!(!nes.accuracy_features().dot_renderer);
+    assert!(!nes.accuracy_features().nonlinear_mixer);
+
+    nes.set_config(&Config {
+        accuracy: AccuracyProfile::Debug,
+        ..Default::default()
+    });
+    assert!(nes.accuracy_features().sanity_checks);
+
+    let config: Config = serde_json::from_str(r#"{"accuracy": "fast"}"#)?;
+    assert_eq!(config.accuracy, AccuracyProfile::Fast);
+
+    Ok(())
+}
+
+#[test]
+fn apu_mixer() -> anyhow::Result<()> {
+    use sabicom::{
+        apu::Mixer,
+        context::Bus,
+        nes::{AccuracyProfile, Config},
+    };
+
+    // Peak-to-peak amplitude of the triangle channel with the DMC output at `dmc`
+    let amplitude = |mixer: Mixer, dmc: u8| -> anyhow::Result<i32> {
+        let config = Config {
+            accuracy: AccuracyProfile::Fast,
+            mixer: Some(mixer),
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        nes.ctx.write(0x4015, 0x04);
+        nes.ctx.write(0x4008, 0xff);
+        nes.ctx.write(0x400a, 0xfd);
+        nes.ctx.write(0x400b, 0x00);
+        nes.ctx.write(0x4011, dmc);
+        nes.exec_frame(false);
+        nes.exec_frame(false);
+        let samples = nes.audio_buffer().samples.iter().map(|s| s.left as i32);
+        Ok(samples.clone().max().unwrap() - samples.min().unwrap())
+    };
+
+    // Only the non-linear mixer lets a loud DMC attenuate the other channels
+    let linear = amplitude(Mixer::Linear, 0)?;
+    assert!(linear > 0);
+    assert!((amplitude(Mixer::Linear, 0x7f)? - linear).abs() < linear / 100);
+    let nonlinear = amplitude(Mixer::Nonlinear, 0)?;
+    assert!(nonlinear > 0);
+    assert!(amplitude(Mixer::Nonlinear, 0x7f)? < nonlinear * 9 / 10);
+
+    Ok(())
+}
+
+#[test]
+fn channel_mute_and_solo() -> anyhow::Result<()> {
+    use sabicom::{apu::Channel, context::Bus};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    let amplitude = |nes: &mut Nes| {
+        nes.ctx.write(0x4015, 0x04);
+        nes.ctx.write(0x4008, 0xff);
+        nes.ctx.write(0x400a, 0xfd);
+        nes.ctx.write(0x400b, 0x00);
+        nes.exec_frame(false);
+        nes.exec_frame(false);
+        let samples = nes.audio_buffer().samples.iter().map(|s| s.left as i32);
+        samples.clone().max().unwrap() - samples.min().unwrap()
+    };
+
+    assert!(amplitude(&mut nes) > 1000);
+
+    nes.solo_channel(Some(Channel::Pulse1));
+    assert!(!nes.channel_enabled(Channel::Triangle));
+    assert!(amplitude(&mut nes) < 100);
+    // Muting does not affect the channel itself
+    assert_eq!(nes.ctx.read(0x4015) & 0x04, 0x04);
+
+    // Kept across resets
+    nes.reset();
+    assert!(amplitude(&mut nes) < 100);
+
+    nes.solo_channel(None);
+    assert!(amplitude(&mut nes) > 1000);
+
+    Ok(())
+}
+
+#[test]
+fn channel_streams() -> anyhow::Result<()> {
+    use sabicom::{apu::Channel, context::Bus, Config};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.exec_frame(false);
+    assert!(nes.channel_streams().is_none());
+
+    let config = Config {
+        channel_streams: true,
+        ..Default::default()
+    };
+    nes.set_config(&config);
+    nes.ctx.write(0x4015, 0x04);
+    nes.ctx.write(0x4008, 0xff);
+    nes.ctx.write(0x400a, 0xfd);
+    nes.ctx.write(0x400b, 0x00);
+    // Muting in the mix doesn't affect the stream
+    nes.set_channel_enabled(Channel::Triangle, false);
+    nes.exec_frame(false);
+    nes.exec_frame(false);
+
+    let streams = nes.channel_streams().unwrap();
+    let amplitude = |channel| {
+        let samples = streams.samples(channel);
+        assert_eq!(samples.len(), nes.audio_buffer().samples.len());
+        samples.iter().max().unwrap() - samples.iter().min().unwrap()
+    };
+    assert!(amplitude(Channel::Triangle) > 1000);
+    assert_eq!(amplitude(Channel::Pulse1), 0);
+    assert_eq!(amplitude(Channel::Expansion), 0);
+
+    Ok(())
+}
+
+#[test]
+fn channel_panning() -> anyhow::Result<()> {
+    use meru_interface::AudioSample;
+    use sabicom::{apu::ChannelPanning, context::Bus, Config};
+
+    // Amplitudes of the left and right outputs with the triangle playing
+    let amplitudes = |panning: ChannelPanning| -> anyhow::Result<(i16, i16)> {
+        let config = Config {
+            panning,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        nes.ctx.write(0x4015, 0x04);
+        nes.ctx.write(0x4008, 0xff);
+        nes.ctx.write(0x400a, 0xfd);
+        nes.ctx.write(0x400b, 0x00);
+        nes.exec_frame(false);
+        nes.exec_frame(false);
+
+        let samples = &nes.audio_buffer().samples;
+        let amplitude = |side: fn(&AudioSample) -> i16| {
+            samples.iter().map(side).max().unwrap() - samples.iter().map(side).min().unwrap()
+        };
+        Ok((amplitude(|s| s.left), amplitude(|s| s.right)))
+    };
+
+    // Centered channels output the same mono mix on both sides
+    let (left, right) = amplitudes(Default::default())?;
+    assert!(left > 1000);
+    assert_eq!(left, right);
+
+    let (hard_left, silent) = amplitudes(ChannelPanning {
+        triangle: -1.0,
+        ..Default::default()
+    })?;
+    assert_eq!(hard_left, left);
+    assert_eq!(silent, 0);
+
+    // Halfway to the right keeps the right side and halves the left
+    let (half, full) = amplitudes(ChannelPanning {
+        triangle: 0.5,
+        ..Default::default()
+    })?;
+    assert_eq!(full, left);
+    assert!(half.abs_diff(left / 2) < 50);
+
+    Ok(())
+}
+
+#[test]
+fn master_volume_and_clipping() -> anyhow::Result<()> {
+    use sabicom::{context::Bus, Config};
+
+    // Range of the output with the triangle playing
+    let range = |volume: f32, soft_clipping: bool| -> anyhow::Result<(i16, i16)> {
+        let config = Config {
+            volume,
+            soft_clipping,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        nes.ctx.write(0x4015, 0x04);
+        nes.ctx.write(0x4008, 0xff);
+        nes.ctx.write(0x400a, 0xfd);
+        nes.ctx.write(0x400b, 0x00);
+        nes.exec_frame(false);
+        nes.exec_frame(false);
+
+        let samples = nes.audio_buffer().samples.iter().map(|s| s.left);
+        Ok((samples.clone().min().unwrap(), samples.max().unwrap()))
+    };
+
+    let (min, max) = range(0.0, false)?;
+    let (half_min, half_max) = range(-6.0, false)?;
+    assert!((half_max - half_min).abs_diff((max - min) / 2) < 50);
+
+    // Hard clipping saturates, and soft clipping stays below the full scale
+    let (min, max) = range(30.0, false)?;
+    assert_eq!((min, max), (i16::MIN, i16::MAX));
+    let (min, max) = range(30.0, true)?;
+    assert!(min > -32000 && max < 32000);
+    assert!(min < -25600 && max > 25600);
+
+    Ok(())
+}
+
+#[test]
+fn audio_filters() -> anyhow::Result<()> {
+    use sabicom::Config;
+
+    // Output level after some frames, with the DMC output at 0, far from its center level
+    let level = |disable_audio_filters: bool| -> anyhow::Result<i16> {
+        let config = Config {
+            disable_audio_filters,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        for _ in 0..5 {
+            nes.exec_frame(false);
+        }
+        Ok(nes.audio_buffer().samples.last().unwrap().left)
+    };
+
+    // The high-pass filters remove the DC offset
+    assert!(level(true)?.abs() > 1000);
+    assert!(level(false)?.abs() < 100);
+
+    Ok(())
+}
+
+#[test]
+fn frame_counter_write_timing() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, IrqSource};
+
+    // CPU cycles from a $4017 write until the frame IRQ, after `skew` cycles of delay
+    let irq_cycles = |skew: usize| -> anyhow::Result<usize> {
+        let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+        for _ in 0..skew {
+            nes.ctx.tick_bus();
+        }
+        nes.ctx.write(0x4017, 0x00);
+        let mut cycles = 0;
+        while !nes.irq_asserted(IrqSource::ApuFrame) {
+            nes.ctx.tick_bus();
+            cycles += 1;
+        }
+        Ok(cycles)
+    };
+
+    // The reset is delayed by 3 or 4 cycles depending on the write parity, and the
+    // IRQ line follows the flag one cycle later
+    let mut cycles = [irq_cycles(0)?, irq_cycles(1)?];
+    cycles.sort();
+    assert_eq!(cycles, [29829 + 3, 29829 + 4]);
+
+    // Switching to 5-step mode clocks the length counters immediately
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.ctx.write(0x4015, 0x01);
+    nes.ctx.write(0x4000, 0x00);
+    nes.ctx.write(0x4003, 0x18); // Length 2
+    nes.ctx.tick_bus();
+    for data in [0x00, 0x80, 0x80] {
+        assert_ne!(nes.ctx.read(0x4015) & 0x01, 0);
+        nes.ctx.write(0x4017, data);
+        for _ in 0..4 {
+            nes.ctx.tick_bus();
+        }
+    }
+    assert_eq!(nes.ctx.read(0x4015) & 0x01, 0);
+
+    Ok(())
+}
+
+#[test]
+fn frame_irq_read_race() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, IrqSource};
+
+    // Reads $4015 `cycles` CPU cycles after a $4017 write. Returns the frame IRQ flag
+    // of the read, and whether the IRQ line is asserted a few cycles later.
+    let read_status = |cycles: usize| -> anyhow::Result<(bool, bool)> {
+        let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+        nes.ctx.write(0x4017, 0x00);
+        for _ in 0..cycles {
+            nes.ctx.tick_bus();
+        }
+        let flag = nes.ctx.read(0x4015) & 0x40 != 0;
+        for _ in 0..4 {
+            nes.ctx.tick_bus();
+        }
+        Ok((flag, nes.irq_asserted(IrqSource::ApuFrame)))
+    };
+
+    // The IRQ line is asserted after `line` cycles, a cycle after the flag is raised
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.ctx.write(0x4017, 0x00);
+    let mut line = 0;
+    while !nes.irq_asserted(IrqSource::ApuFrame) {
+        nes.ctx.tick_bus();
+        line += 1;
+    }
+
+    assert_eq!(read_status(line - 3)?, (false, true));
+    // A read on the cycle the flag is raised sees it, but doesn't clear it
+    assert_eq!(read_status(line - 2)?, (true, true));
+    // The flag is raised again on the next two cycles
+    assert_eq!(read_status(line - 1)?, (true, true));
+    assert_eq!(read_status(line)?, (true, true));
+    assert_eq!(read_status(line + 1)?, (true, false));
+
+    Ok(())
+}
+
+#[test]
+fn length_counter_write_during_clock() -> anyhow::Result<()> {
+    use sabicom::context::Bus;
+
+    // Writes `data` to `addr` on the cycle the frame counter reset clocks the length
+    // counters, then clocks them once more. Returns whether pulse 1 is still active.
+    let write_during_clock = |halt: bool, addr: u16, data: u8| -> anyhow::Result<bool> {
+        let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+        nes.ctx.write(0x4015, 0x01);
+        nes.ctx.write(0x4000, if halt { 0x20 } else { 0x00 });
+        nes.ctx.write(0x4003, 0x18); // Length 2
+        nes.ctx.tick_bus();
+
+        // Written on an APU cycle, so the reset comes 3 cycles later
+        nes.ctx.write(0x4017, 0x80);
+        for _ in 0..2 {
+            nes.ctx.tick_bus();
+        }
+        nes.ctx.write(addr, data);
+        nes.ctx.tick_bus();
+
+        nes.ctx.write(0x4017, 0x80);
+        for _ in 0..4 {
+            nes.ctx.tick_bus();
+        }
+        Ok(nes.ctx.read(0x4015) & 0x01 != 0)
+    };
+
+    // A reload on the clock is ignored, so the counter runs out after two clocks
+    assert!(!write_during_clock(false, 0x4003, 0xf8)?);
+    // Clearing the halt flag on the clock only lets the next one through
+    assert!(write_during_clock(true, 0x4000, 0x00)?);
+    assert!(!write_during_clock(false, 0x4000, 0x00)?);
+
+    Ok(())
+}
+
+#[test]
+fn pal_apu_timing() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, IrqSource};
+
+    let mut rom = make_rom();
+    // iNES byte 10: PAL
+    rom[10] = 2;
+
+    // The frame IRQ comes after the longer PAL sequence
+    let mut nes = Nes::try_from_file(&rom, None, &Default::default())?;
+    nes.ctx.write(0x4017, 0x00);
+    let mut cycles = 0;
+    while !nes.irq_asserted(IrqSource::ApuFrame) {
+        nes.ctx.tick_bus();
+        cycles += 1;
+    }
+    assert!([33253 + 3, 33253 + 4].contains(&cycles));
+
+    // The fastest DMC rate is 50 cycles per bit
+    let mut nes = Nes::try_from_file(&rom, None, &Default::default())?;
+    nes.ctx.write(0x4010, 0x4f);
+    nes.ctx.write(0x4013, 0x00);
+    nes.ctx.write(0x4015, 0x10);
+    let mut fetch = || {
+        let mut cycles = 0;
+        while !nes.ctx.dmc_dma_pending() {
+            nes.ctx.tick_bus();
+            cycles += 1;
+        }
+        nes.ctx.dmc_dma_fetch();
+        cycles
+    };
+    fetch();
+    fetch();
+    assert_eq!(fetch(), 8 * 50);
+
+    Ok(())
+}
+
+#[test]
+fn dmc_buffer_and_irq_timing() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, IrqSource};
+
+    // Runs until the DMC requests a sample byte and performs the DMA, returning the cycles taken
+    let fetch = |nes: &mut Nes| {
+        let mut cycles = 0;
+        while !nes.ctx.dmc_dma_pending() {
+            nes.ctx.tick_bus();
+            cycles += 1;
+        }
+        nes.ctx.dmc_dma_fetch();
+        cycles
+    };
+
+    // The DMA after enabling starts 2 or 3 cycles later depending on the write parity
+    let mut latency = vec![];
+    for skew in 0..2 {
+        let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+        for _ in 0..skew {
+            nes.ctx.tick_bus();
+        }
+        nes.ctx.write(0x4010, 0x0f);
+        nes.ctx.write(0x4013, 0x00);
+        nes.ctx.write(0x4015, 0x10);
+        assert!(!nes.ctx.dmc_dma_pending());
+        latency.push(fetch(&mut nes));
+    }
+    latency.sort();
+    assert_eq!(latency, [2, 3]);
+
+    // A looping 1-byte sample is refilled as soon as the shifter takes the buffer,
+    // which happens every 8 bits of 54 cycles at the fastest rate
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.ctx.write(0x4010, 0x4f);
+    nes.ctx.write(0x4013, 0x00);
+    nes.ctx.write(0x4015, 0x10);
+    fetch(&mut nes);
+    fetch(&mut nes);
+    assert_eq!(fetch(&mut nes), 8 * 54);
+
+    // Disabling keeps the byte in the buffer, so enabling again doesn't fetch until it's played
+    nes.ctx.write(0x4015, 0x00);
+    assert_eq!(nes.ctx.read(0x4015) & 0x10, 0);
+    nes.ctx.write(0x4015, 0x10);
+    assert_eq!(nes.ctx.read(0x4015) & 0x10, 0x10);
+    assert!(fetch(&mut nes) > 3);
+
+    // The IRQ is raised when the last byte is fetched, and cleared by writing $4015
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.ctx.write(0x4010, 0x8f);
+    nes.ctx.write(0x4013, 0x00);
+    nes.ctx.write(0x4015, 0x10);
+    assert!(!nes.irq_asserted(IrqSource::ApuDmc));
+    fetch(&mut nes);
+    assert!(nes.irq_asserted(IrqSource::ApuDmc));
+    assert_eq!(nes.ctx.read(0x4015) & 0x90, 0x80);
+    assert!(nes.irq_asserted(IrqSource::ApuDmc));
+    nes.ctx.write(0x4015, 0x00);
+    assert!(!nes.irq_asserted(IrqSource::ApuDmc));
+
+    Ok(())
+}
+
+#[test]
+fn dmc_dma_double_read() -> anyhow::Result<()> {
+    use sabicom::{
+        context::Bus,
+        nes::{AccuracyProfile, Config},
+    };
+
+    // Values read from $2007 by a program which starts after `pad` cycles of delay,
+    // while the DMC keeps fetching samples
+    let read_2007 = |accuracy: AccuracyProfile, pad: usize| -> anyhow::Result<Vec<u8>> {
+        let config = Config {
+            accuracy,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        warm_up(&mut nes);
+        nes.ctx.write(0x2000, 0x00);
+        nes.ctx.write(0x2001, 0x00);
+        nes.ctx.write(0x2006, 0x00);
+        nes.ctx.write(0x2006, 0x00);
+
+        let mut prg = vec![];
+        if pad % 2 == 1 {
+            prg.extend([0xA5, 0x00]); // LDA $00
+        }
+        prg.resize(prg.len() + (pad - pad % 2 * 3) / 2, 0xEA); // NOP
+        for i in 0..200 {
+            prg.extend([0xAD, 0x07, 0x20, 0x8D, i, 0x02]); // LDA $2007; STA $0200+i
+        }
+        let end = 0x300 + prg.len() as u16;
+        prg.extend([0x4C, end as u8, (end >> 8) as u8]); // JMP *
+        for (i, b) in prg.into_iter().enumerate() {
+            nes.ctx.write(0x300 + i as u16, b);
+        }
+        nes.ctx.cpu_mut().set_pc(0x300);
+
+        // Fastest rate, 17 bytes from $C000
+        nes.ctx.write(0x4010, 0x0f);
+        nes.ctx.write(0x4012, 0x00);
+        nes.ctx.write(0x4013, 0x01);
+        nes.ctx.write(0x4015, 0x10);
+        nes.exec_frame(false);
+
+        Ok((0..200).map(|i| nes.ctx.read(0x200 + i)).collect())
+    };
+
+    // CHR ROM holds consecutive values, so a value is skipped when a read is repeated
+    let skips = |reads: &[u8]| {
+        reads[1..]
+            .windows(2)
+            .filter(|w| w[1] != w[0].wrapping_add(1))
+            .count()
+    };
+
+    let mut skipped = false;
+    for pad in [0, 2, 3, 4, 5, 6, 7] {
+        assert_eq!(skips(&read_2007(AccuracyProfile::Fast, pad)?), 0);
+        skipped |= skips(&read_2007(AccuracyProfile::Accurate, pad)?) > 0;
+    }
+    assert!(skipped);
+
+    Ok(())
+}
+
+#[test]
+fn cpu_interrupt_polling_and_oam_dma() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Cpu, Interrupt, IrqSource, Ppu};
+
+    // Runs a program from $0300 for a frame with rendering, NMI and the frame IRQ disabled
+    let run = |prg: &[u8], irq: bool| -> anyhow::Result<Nes> {
+        let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+        warm_up(&mut nes);
+        nes.ctx.write(0x2000, 0x00);
+        nes.ctx.write(0x2001, 0x00);
+        nes.ctx.write(0x2003, 0x00);
+        nes.ctx.write(0x4017, 0x40);
+        nes.ctx.read(0x4015);
+        for (i, &b) in prg.iter().enumerate() {
+            nes.ctx.write(0x300 + i as u16, b);
+        }
+        nes.ctx.cpu_mut().set_pc(0x300);
+        nes.ctx.set_irq_source(IrqSource::Mapper, irq);
+        nes.exec_frame(false);
+        Ok(nes)
+    };
+
+    #[rustfmt::skip]
+    let prg = [
+        0x58,             // CLI
+        0xE6, 0x10,       // INC $10
+        0xE6, 0x10,       // INC $10
+        0xE6, 0x10,       // INC $10
+        0x4C, 0x07, 0x03, // JMP *
+    ];
+    assert_eq!(run(&prg, false)?.ctx.read(0x10), 3);
+    // CLI takes effect after the next instruction, and RTI right away, so the
+    // pending IRQ keeps the program at the first INC
+    assert_eq!(run(&prg, true)?.ctx.read(0x10), 1);
+
+    // OAM DMA copies the page with the PPU running
+    #[rustfmt::skip]
+    let mut prg = vec![
+        0xA9, 0x04,       // LDA #$04
+        0x8D, 0x14, 0x40, // STA $4014
+        0x4C, 0x05, 0x03, // JMP *
+    ];
+    prg.resize(0x100, 0);
+    prg.extend((0..=0xFF).map(|i: u8| i ^ 0x5A));
+    let nes = run(&prg, false)?;
+    let oam = nes.ctx.ppu().oam();
+    assert!((0..0x100).all(|i| oam[i] == i as u8 ^ 0x5A));
+
+    Ok(())
+}
+
+#[test]
+fn branch_delays_irq() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Cpu};
+
+    // The IRQ handler stores the return address and stops
+    #[rustfmt::skip]
+    let irq = [
+        0xBA,             // TSX
+        0xBD, 0x02, 0x01, // LDA $0102,X
+        0x85, 0x20,       // STA $20
+        0xBD, 0x03, 0x01, // LDA $0103,X
+        0x85, 0x21,       // STA $21
+        0x4C, 0x3B, 0x80, // JMP *
+    ];
+    let mut rom = make_rom();
+    rom[0x10 + 0x30..][..irq.len()].copy_from_slice(&irq);
+    rom[0x10 + 0x7ffe] = 0x30;
+
+    let mut nes = Nes::try_from_file(&rom, None, &Default::default())?;
+    warm_up(&mut nes);
+    nes.ctx.write(0x2000, 0x00);
+    let state = nes.save_state();
+
+    // Returns the opcode where the frame IRQ returns to, when it arrives during a sequence
+    // of taken branches and NOPs started `pad` cycles later
+    let mut return_opcode = |pad: usize| -> anyhow::Result<u8> {
+        nes.load_state(&state)?;
+
+        #[rustfmt::skip]
+        let mut prg = vec![
+            0xA9, 0x00,       // LDA #$00
+            0x8D, 0x17, 0x40, // STA $4017
+            0xAD, 0x15, 0x40, // LDA $4015
+            0x58,             // CLI
+            0xA0, 0x17,       // LDY #23
+            0xA2, 0x00,       // LDX #0
+            0xCA,             // DEX
+            0xD0, 0xFD,       // BNE -3
+            0x88,             // DEY
+            0xD0, 0xF8,       // BNE -8
+        ];
+        if pad % 2 == 1 {
+            prg.extend([0xA5, 0x00]); // LDA $00
+        }
+        prg.resize(prg.len() + (pad - pad % 2 * 3) / 2, 0xEA); // NOP
+        prg.extend([0xA0, 0x01]); // LDY #1
+        for _ in 0..60 {
+            prg.extend([0xD0, 0x00, 0xEA]); // BNE +0; NOP
+        }
+        let end = 0x300 + prg.len() as u16;
+        prg.extend([0x4C, end as u8, (end >> 8) as u8]); // JMP *
+        for (i, b) in prg.into_iter().enumerate() {
+            nes.ctx.write(0x300 + i as u16, b);
+        }
+        nes.ctx.write(0x21, 0x00);
+        nes.ctx.cpu_mut().set_pc(0x300);
+        while nes.ctx.read(0x21) == 0 {
+            nes.exec_frame(false);
+        }
+
+        let ret = u16::from_le_bytes([nes.ctx.read(0x20), nes.ctx.read(0x21)]);
+        assert!((0x300..end).contains(&ret));
+        Ok(nes.ctx.read(ret))
+    };
+
+    // Over a period of the sequence, the IRQ is taken after the NOP if it arrives during
+    // the NOP or the operand fetch of the branch, which delays it by an instruction
+    let mut after_nop = 0;
+    for pad in 2..=6 {
+        if return_opcode(pad)? == 0xD0 {
+            after_nop += 1;
+        }
+    }
+    assert_eq!(after_nop, 3);
+
+    Ok(())
+}
+
+#[test]
+fn nmi_hijacks_brk() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Cpu};
+
+    // The NMI handler counts the NMIs which have the B flag pushed, that is, hijacked BRKs
+    #[rustfmt::skip]
+    let nmi = [
+        0x48,             // PHA
+        0xBA,             // TSX
+        0xBD, 0x02, 0x01, // LDA $0102,X
+        0x29, 0x10,       // AND #$10
+        0xF0, 0x02,       // BEQ +2
+        0xE6, 0x12,       // INC $12
+        0x68,             // PLA
+        0x40,             // RTI
+    ];
+    let mut rom = make_rom();
+    rom[0x10 + 0x20..][..nmi.len()].copy_from_slice(&nmi);
+    rom[0x10 + 0x7ffa] = 0x20;
+
+    let mut nes = Nes::try_from_file(&rom, None, &Default::default())?;
+    warm_up(&mut nes);
+
+    // BRK in a loop, whose handler is RTI
+    #[rustfmt::skip]
+    let prg = [
+        0x00, 0x00,       // BRK
+        0x4C, 0x00, 0x03, // JMP $0300
+    ];
+    for (i, &b) in prg.iter().enumerate() {
+        nes.ctx.write(0x300 + i as u16, b);
+    }
+    nes.ctx.write(0x12, 0);
+    nes.ctx.cpu_mut().set_pc(0x300);
+    for _ in 0..60 {
+        nes.exec_frame(false);
+    }
+    assert!(nes.ctx.read(0x12) > 0);
+
+    Ok(())
+}
+
+#[test]
+fn oam_dma_with_dmc_dma() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Cpu, Timing};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    nes.ctx.write(0x2000, 0x00);
+    let state = nes.save_state();
+
+    // Extra CPU cycles taken by OAM DMA and the following NOP, started after `loops`
+    // iterations of a delay loop and `pad` cycles from enabling DMC. The second DMC fetch
+    // happens around 1250 cycles later.
+    let mut extra_cycles = |loops: u8, pad: usize| -> anyhow::Result<u64> {
+        nes.load_state(&state)?;
+
+        let mut prg = vec![0xA2, loops, 0xCA, 0xD0, 0xFD]; // LDX #loops; DEX; BNE -3
+        if pad % 2 == 1 {
+            prg.extend([0xA5, 0x00]); // LDA $00
+        }
+        prg.resize(prg.len() + (pad - pad % 2 * 3) / 2, 0xEA); // NOP
+        prg.extend([0x8D, 0x14, 0x40, 0xEA]); // STA $4014; NOP
+        let end = 0x300 + prg.len() as u16;
+        prg.extend([0x4C, end as u8, (end >> 8) as u8]); // JMP *
+        for (i, b) in prg.into_iter().enumerate() {
+            nes.ctx.write(0x300 + i as u16, b);
+        }
+        nes.ctx.cpu_mut().set_pc(0x300);
+
+        nes.ctx.write(0x4010, 0x08);
+        nes.ctx.write(0x4012, 0x00);
+        nes.ctx.write(0x4013, 0x01);
+        nes.ctx.write(0x4015, 0x10);
+
+        // Runs until the NOP after STA $4014 is done, which the DMA halts
+        let mut start = None;
+        while nes.ctx.cpu().pc() != end {
+            nes.ctx.tick_cpu();
+            if nes.ctx.cpu().pc() == end - 1 && start.is_none() {
+                start = Some(nes.ctx.now());
+            }
+        }
+        // A halt cycle, an alignment cycle to start on an even cycle, and 256 reads and writes
+        let start = start.unwrap();
+        Ok(nes.ctx.now() - start - 2 - (513 + (start + 1) % 2))
+    };
+
+    // Without a DMC fetch, the DMA takes 513 or 514 cycles
+    assert_eq!(extra_cycles(100, 0)?, 0);
+    // A DMC fetch in the middle takes a get cycle, then OAM DMA needs an alignment cycle
+    assert_eq!(extra_cycles(200, 0)?, 2);
+    assert_eq!(extra_cycles(200, 3)?, 2);
+
+    // Near the end, the DMC halt and dummy cycles overlap the last OAM DMA cycles
+    // or need to be run after them
+    let mut extras = vec![];
+    for loops in 145..=146 {
+        for pad in [0, 2, 3, 4, 6] {
+            extras.push(extra_cycles(loops, pad)?);
+        }
+    }
+    assert!(extras.contains(&1));
+    assert!(extras.contains(&3));
+
+    Ok(())
+}
+
+#[test]
+fn cpu_jam() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Cpu, Ppu};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    assert!(!nes.cpu_jammed());
+
+    #[rustfmt::skip]
+    let prg = [
+        0xE6, 0x10, // INC $10
+        0x02,       // KIL
+        0xE6, 0x10, // INC $10
+    ];
+    for (i, &b) in prg.iter().enumerate() {
+        nes.ctx.write(0x300 + i as u16, b);
+    }
+    nes.ctx.write(0x10, 0);
+    nes.ctx.cpu_mut().set_pc(0x300);
+
+    // NMI doesn't wake the CPU, while the PPU keeps running
+    let frame = nes.ctx.ppu().frame();
+    nes.exec_frame(false);
+    nes.exec_frame(false);
+    assert!(nes.cpu_jammed());
+    assert_eq!(nes.ctx.read(0x10), 1);
+    assert_eq!(nes.ctx.cpu().pc(), 0x302);
+    assert_eq!(nes.ctx.ppu().frame(), frame + 2);
+    assert!(nes.debug_dump().contains("\"jammed\": true"));
+
+    nes.soft_reset();
+    assert!(!nes.cpu_jammed());
+    nes.exec_frame(false);
+    assert!(!nes.cpu_jammed());
+
+    Ok(())
+}
+
+#[test]
+fn cpu_state_access() -> anyhow::Result<()> {
+    use sabicom::{
+        context::{Bus, Cpu},
+        cpu::CpuState,
+    };
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    nes.ctx.write(0x2000, 0x00);
+
+    #[rustfmt::skip]
+    let prg = [
+        0x69, 0x01, // ADC #$01
+        0x85, 0x10, // STA $10
+        0x84, 0x11, // STY $11
+        0x08,       // PHP
+    ];
+    for (i, &b) in prg.iter().enumerate() {
+        nes.ctx.write(0x300 + i as u16, b);
+    }
+
+    let cycle = nes.cpu_state().cycle;
+    nes.set_cpu_state(&CpuState {
+        a: 0x41,
+        x: 0x22,
+        y: 0x33,
+        s: 0xF0,
+        pc: 0x300,
+        p: 0x05,
+        cycle: 0,
+    });
+    let state = nes.cpu_state();
+    assert_eq!((state.pc, state.p, state.cycle), (0x300, 0x05, cycle));
+    assert!(state.carry() && state.interrupt_disable() && !state.zero());
+
+    while nes.ctx.cpu().pc() != 0x307 {
+        nes.ctx.tick_cpu();
+    }
+    let state = nes.cpu_state();
+    assert_eq!(
+        (state.a, state.x, state.y, state.s),
+        (0x43, 0x22, 0x33, 0xEF)
+    );
+    assert_eq!(state.p, 0x04);
+    assert_eq!(state.cycle, cycle + 2 + 3 + 3 + 3);
+    assert_eq!((nes.ctx.read(0x10), nes.ctx.read(0x11)), (0x43, 0x33));
+    assert_eq!(nes.ctx.read(0x1F0), 0x34);
+
+    Ok(())
+}
+
+#[test]
+fn breakpoints() -> anyhow::Result<()> {
+    use sabicom::{
+        context::{Bus, Cpu, Ppu},
+        debugger::{Breakpoint, BreakpointKind},
+    };
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    nes.ctx.write(0x2000, 0x00);
+    nes.ctx.write(0x2001, 0x00);
+
+    #[rustfmt::skip]
+    let prg = [
+        0xA9, 0x01,       // LDA #$01
+        0x85, 0x10,       // STA $10
+        0xE6, 0x10,       // INC $10
+        0xA9, 0x20,       // LDA #$20
+        0x8D, 0x06, 0x20, // STA $2006
+        0xA9, 0x05,       // LDA #$05
+        0x8D, 0x06, 0x20, // STA $2006
+        0x8D, 0x07, 0x20, // STA $2007
+        0x4C, 0x00, 0x03, // JMP $0300
+    ];
+    for (i, &b) in prg.iter().enumerate() {
+        nes.ctx.write(0x300 + i as u16, b);
+    }
+    nes.ctx.cpu_mut().set_pc(0x300);
+
+    // Execution breakpoints stop before the instruction every time it runs
+    let frame = nes.ctx.ppu().frame();
+    let id = nes.add_breakpoint(Breakpoint::exec(0x302));
+    nes.exec_frame(false);
+    let hit = nes.breakpoint_hit().unwrap();
+    assert_eq!(
+        (hit.id, hit.kind, hit.addr, hit.data),
+        (id, BreakpointKind::Exec, 0x302, None)
+    );
+    assert_eq!(nes.cpu_state().pc, 0x302);
+    assert_eq!(hit.cycle, nes.cpu_cycle());
+
+    nes.exec_frame(false);
+    let next = nes.breakpoint_hit().unwrap();
+    assert_eq!(next.addr, 0x302);
+    assert_eq!(next.cycle - hit.cycle, 3 + 5 + 2 + 4 + 2 + 4 + 4 + 3 + 2);
+    assert!(nes.remove_breakpoint(id));
+    assert!(!nes.remove_breakpoint(id));
+
+    // Watchpoints stop after the instruction, and see the dummy write of INC
+    let id = nes.add_breakpoint(Breakpoint::new(BreakpointKind::CpuWrite, 0x10..=0x10));
+    nes.exec_frame(false);
+    let hit = nes.breakpoint_hit().unwrap();
+    assert_eq!(
+        (hit.id, hit.addr, hit.data, hit.pc),
+        (id, 0x10, Some(1), 0x302)
+    );
+    assert_eq!(nes.cpu_state().pc, 0x304);
+    nes.exec_frame(false);
+    let hit = nes.breakpoint_hit().unwrap();
+    assert_eq!((hit.data, hit.pc), (Some(1), 0x304));
+    nes.clear_breakpoints();
+
+    nes.add_breakpoint(Breakpoint::new(BreakpointKind::PpuWrite, 0x2000..=0x23FF));
+    nes.exec_frame(false);
+    let hit = nes.breakpoint_hit().unwrap();
+    assert_eq!((hit.addr, hit.data, hit.pc), (0x2005, Some(0x05), 0x310));
+    assert_eq!(nes.breakpoints().len(), 1);
+    nes.clear_breakpoints();
+
+    assert_eq!(nes.ctx.ppu().frame(), frame);
+    nes.exec_frame(false);
+    assert_eq!(nes.breakpoint_hit(), None);
+    assert_eq!(nes.ctx.ppu().frame(), frame + 1);
+
+    Ok(())
+}
+
+#[test]
+fn stepping() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Cpu, Ppu};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    nes.ctx.write(0x2000, 0x00);
+
+    #[rustfmt::skip]
+    let prg = [
+        0xA9, 0x01,       // LDA #$01
+        0x85, 0x10,       // STA $10
+        0xE6, 0x10,       // INC $10
+        0x4C, 0x00, 0x03, // JMP $0300
+    ];
+    for (i, &b) in prg.iter().enumerate() {
+        nes.ctx.write(0x300 + i as u16, b);
+    }
+    nes.ctx.cpu_mut().set_pc(0x300);
+
+    for (pc, cycles) in [(0x302, 2), (0x304, 3), (0x306, 5), (0x300, 3), (0x302, 2)] {
+        let cycle = nes.cpu_cycle();
+        nes.step_instruction();
+        assert_eq!(nes.cpu_state().pc, pc);
+        assert_eq!(nes.cpu_cycle() - cycle, cycles);
+    }
+
+    let cycle = nes.cpu_cycle();
+    nes.run_cpu_cycles(1000);
+    assert!((1000..1005).contains(&(nes.cpu_cycle() - cycle)));
+
+    let frame = nes.ctx.ppu().frame();
+    nes.run_to_scanline(100);
+    assert_eq!(nes.ctx.ppu().line(), 100);
+    assert!(nes.ctx.ppu().dot() < 5 * 3);
+    nes.run_to_scanline(100);
+    assert_eq!(nes.ctx.ppu().line(), 100);
+    assert_eq!(nes.ctx.ppu().frame(), frame + 1);
+
+    Ok(())
+}
+
+#[test]
+fn trace_hook() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Cpu, Ppu};
+    use std::sync::{Arc, Mutex};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    nes.ctx.write(0x2000, 0x00);
+
+    #[rustfmt::skip]
+    let prg = [
+        0xA9, 0x01,       // LDA #$01
+        0x85, 0x10,       // STA $10
+        0x4C, 0x00, 0x03, // JMP $0300
+    ];
+    for (i, &b) in prg.iter().enumerate() {
+        nes.ctx.write(0x300 + i as u16, b);
+    }
+    nes.ctx.cpu_mut().set_pc(0x300);
+
+    let asm = nes.disassemble(0x300, 3);
+    let asm = asm.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+    assert_eq!(asm, ["LDA #$01", "STA $10", "JMP $0300"]);
+    // I/O registers can't be read without side effects
+    assert!(nes.disassemble(0x2000, 1).is_empty());
+
+    let log = Arc::new(Mutex::new(vec![]));
+    nes.set_trace_hook({
+        let log = log.clone();
+        move |record| log.lock().unwrap().push(*record)
+    });
+    // The hook is kept across state loads
+    let state = nes.save_state();
+    nes.load_state(&state)?;
+
+    for _ in 0..3 {
+        nes.step_instruction();
+    }
+    let records = std::mem::take(&mut *log.lock().unwrap());
+    let pcs = records.iter().map(|r| r.pc).collect::<Vec<_>>();
+    assert_eq!(pcs, [0x300, 0x302, 0x304]);
+    assert_eq!(records[0].opcode_bytes(), [0xA9, 0x01]);
+    assert_eq!(records[2].opcode_bytes(), [0x4C, 0x00, 0x03]);
+    assert_eq!(records[1].state.a, 0x01);
+    assert_eq!(records[1].state.cycle, records[0].state.cycle + 2);
+    assert_eq!(records[2].frame, nes.ctx.ppu().frame());
+    assert!(records[2].line < 262 && records[2].dot < 341);
+
+    nes.clear_trace_hook();
+    nes.step_instruction();
+    assert!(log.lock().unwrap().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn unstable_opcodes() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Cpu};
+
+    #[rustfmt::skip]
+    let prg = [
+        0xA9, 0x00,       // LDA #$00
+        0xA2, 0xFF,       // LDX #$FF
+        0x8B, 0xFF,       // XAA #$FF
+        0x85, 0x10,       // STA $10
+        0xA9, 0x00,       // LDA #$00
+        0xAB, 0xF3,       // ATX #$F3
+        0x86, 0x11,       // STX $11
+        0xA0, 0x05,       // LDY #$05
+        0xA2, 0x10,       // LDX #$10
+        0x9C, 0x00, 0x02, // SYA $0200,X
+        0x9C, 0xF8, 0x02, // SYA $02F8,X
+        0xA9, 0xFF,       // LDA #$FF
+        0xA2, 0xFF,       // LDX #$FF
+        0xA0, 0x01,       // LDY #$01
+        0x9B, 0x00, 0x03, // XAS $0300,Y
+    ];
+
+    let run = |magic: Option<u8>| -> anyhow::Result<Nes> {
+        let config = sabicom::Config {
+            unstable_opcode_magic: magic,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        warm_up(&mut nes);
+        nes.ctx.write(0x2000, 0x00);
+        for addr in [0x0108, 0x0210, 0x0308] {
+            nes.ctx.write(addr, 0xAA);
+        }
+        for (i, &b) in prg.iter().enumerate() {
+            nes.ctx.write(0x400 + i as u16, b);
+        }
+        nes.ctx.cpu_mut().set_pc(0x400);
+        while nes.ctx.cpu().pc() != 0x400 + prg.len() as u16 {
+            nes.step_instruction();
+        }
+        Ok(nes)
+    };
+
+    let mut nes = run(None)?;
+    assert_eq!((nes.ctx.read(0x10), nes.ctx.read(0x11)), (0xFF, 0xF3));
+
+    // The stored value is Y & ($02 + 1), which becomes the high byte on a page crossing
+    assert_eq!(nes.ctx.read(0x0210), 0x01);
+    assert_eq!(nes.ctx.read(0x0108), 0x01);
+    assert_eq!(nes.ctx.read(0x0308), 0xAA);
+
+    // XAS also sets S to A & X
+    assert_eq!(nes.ctx.read(0x0301), 0x04);
+    assert_eq!(nes.cpu_state().s, 0xFF);
+
+    let mut nes = run(Some(0xEE))?;
+    assert_eq!((nes.ctx.read(0x10), nes.ctx.read(0x11)), (0xEE, 0xE2));
+
+    Ok(())
+}
+
+#[test]
+fn cycle_profiling() -> anyhow::Result<()> {
+    use sabicom::profiler::ProfileAddr;
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    assert!(nes.profile_report().is_empty());
+
+    nes.set_profiling(true);
+    nes.exec_frame(false);
+    let report = nes.profile_report();
+
+    // The main loop is INX; JMP $8014, and NMI runs RTI once a frame
+    let rom = |addr| ProfileAddr {
+        prg_bank: Some(0),
+        addr,
+    };
+    assert_eq!(report.len(), 3);
+    assert_eq!((report[0].addr, report[1].addr), (rom(0x8015), rom(0x8014)));
+    assert_eq!(report[0].cycles, report[0].executions * 3);
+    assert_eq!(report[1].cycles, report[1].executions * 2);
+    assert!(report[0].executions.abs_diff(report[1].executions) <= 1);
+    assert_eq!(
+        (report[2].addr, report[2].cycles, report[2].executions),
+        (rom(0x8018), 6, 1)
+    );
+
+    // All cycles but the NMI entry are spent on the instructions
+    let total = report.iter().map(|e| e.cycles).sum::<u64>() + 7;
+    assert!(total.abs_diff(29781) <= 3, "{total}");
+
+    nes.clear_profile();
+    assert!(nes.profile_report().is_empty());
+    nes.set_profiling(false);
+    nes.exec_frame(false);
+    assert!(nes.profile_report().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn pc_and_write_history() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Cpu};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    nes.ctx.write(0x2000, 0x00);
+
+    #[rustfmt::skip]
+    let prg = [
+        0xA9, 0x05, // LDA #$05
+        0x85, 0x10, // STA $10
+        0xE6, 0x10, // INC $10
+        0x02,       // KIL
+    ];
+    for (i, &b) in prg.iter().enumerate() {
+        nes.ctx.write(0x300 + i as u16, b);
+    }
+    nes.ctx.cpu_mut().set_pc(0x300);
+    nes.set_pc_history_capacity(3);
+    nes.set_write_history_capacity(2);
+
+    nes.exec_frame(false);
+    assert!(nes.cpu_jammed());
+    assert_eq!(nes.pc_history(), [0x302, 0x304, 0x306]);
+
+    // INC writes the old value before the new one
+    let writes = nes.write_history();
+    let writes = writes
+        .iter()
+        .map(|w| (w.pc, w.addr, w.data))
+        .collect::<Vec<_>>();
+    assert_eq!(writes, [(0x304, 0x10, 0x05), (0x304, 0x10, 0x06)]);
+
+    nes.clear_history();
+    assert!(nes.pc_history().is_empty() && nes.write_history().is_empty());
+
+    Ok(())
+}
+
+#[cfg(feature = "archive")]
+#[test]
+fn load_from_archives() -> anyhow::Result<()> {
+    use sabicom::Rom;
+    use std::io::Write;
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    let rom = make_rom();
+    let prg_rom = |dat: &[u8]| Rom::from_bytes(dat).unwrap().prg_rom;
+
+    let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    gz.write_all(&rom)?;
+    assert_eq!(prg_rom(&gz.finish()?), prg_rom(&rom));
+
+    // The first file with a ROM extension is taken
+    let mut zip = ZipWriter::new(std::io::Cursor::new(vec![]));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file("readme.txt", options)?;
+    zip.write_all(b"NES\x1a")?;
+    zip.start_file("Game.NES", options)?;
+    zip.write_all(&rom)?;
+    zip.start_file("other.nes", options)?;
+    zip.write_all(b"")?;
+    let zip = zip.finish()?.into_inner();
+    assert_eq!(prg_rom(&zip), prg_rom(&rom));
+
+    let mut zip = ZipWriter::new(std::io::Cursor::new(vec![]));
+    zip.start_file("readme.txt", SimpleFileOptions::default())?;
+    let zip = zip.finish()?.into_inner();
+    assert!(Rom::from_bytes(&zip).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn header_database_override() -> anyhow::Result<()> {
+    use sabicom::{header_db::HeaderDatabase, Config, Rom};
+    use std::sync::Arc;
+
+    // A bad header: CNROM with 16KB PRG ROM, 24KB CHR ROM and horizontal mirroring.
+    // The data is changed so that other tests don't find the game in the database.
+    let mut dat = make_rom();
+    dat[4] = 1;
+    dat[5] = 3;
+    dat[6] = 0x30;
+    dat[0x10 + 0x100] = 0x42;
+    let crc = Rom::from_bytes(&dat)?.prg_chr_crc32();
+
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<nes20db>
+  <game>
+    <!-- Test Game -->
+    <prgrom size="32768" crc32="00000000"/>
+    <chrrom size="8192" crc32="00000000"/>
+    <rom size="40960" crc32="{crc:08X}"/>
+    <prgnvram size="8192"/>
+    <pcb mapper="0" submapper="0" mirroring="V" battery="1"/>
+    <console type="0" region="1"/>
+  </game>
+</nes20db>"#
+    );
+    let db = HeaderDatabase::from_xml(&xml)?;
+    assert_eq!(db.size(), 1);
+    assert_eq!(db.get(crc).unwrap().mapper_id, 0);
+    let db = Arc::new(db);
+
+    let info = |config: &Config| {
+        let nes = Nes::try_from_file(&dat, None, config).unwrap();
+        let info = nes.game_info();
+        let get = |key: &str| info.iter().find(|(k, _)| k == key).unwrap().1.clone();
+        (
+            get("Mapper ID"),
+            get("Mirroring"),
+            get("Battery"),
+            get("PRG ROM Size"),
+        )
+    };
+    let config = Config {
+        header_database: Some(db),
+        ..Default::default()
+    };
+    let fixed = info(&config);
+    let as_is = info(&Config {
+        disable_header_database: true,
+        ..config.clone()
+    });
+
+    assert_eq!(
+        fixed,
+        (
+            "0 (0)".into(),
+            "Vertical".into(),
+            "Yes".into(),
+            "32.0 kiB".into()
+        )
+    );
+    assert_eq!(
+        as_is,
+        (
+            "3 (0)".into(),
+            "Horizontal".into(),
+            "No".into(),
+            "16.0 kiB".into()
+        )
+    );
+
+    // Reloaded ROMs are fixed too
+    let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+    nes.reload_rom(&dat, false)?;
+    let mirroring = ("Mirroring".to_string(), "Vertical".to_string());
+    assert!(nes.game_info().contains(&mirroring));
+
+    assert!(HeaderDatabase::from_xml("<nes20db><game>").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn rewind() -> anyhow::Result<()> {
+    use sabicom::{context::Ppu, rewind::RewindConfig, Config};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.exec_frame(false);
+    assert_eq!(nes.rewind_frames(1)?, 0);
+
+    let config = Config {
+        rewind: Some(RewindConfig {
+            interval: 2,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+    let mut states = vec![];
+    for _ in 0..10 {
+        nes.exec_frame(false);
+        states.push((nes.ctx.ppu().frame(), nes.save_state()));
+    }
+    let start = states[0].0;
+    let state_at = |frame: u64| &states.iter().find(|(f, _)| *f == frame).unwrap().1;
+
+    // Lands on the captured state of an even frame
+    let end = nes.ctx.ppu().frame();
+    let rewound = nes.rewind_frames(3)?;
+    let frame = nes.ctx.ppu().frame();
+    assert!((3..5).contains(&rewound));
+    assert_eq!(frame, end - rewound);
+    assert_eq!(frame % 2, 0);
+    assert_eq!(&nes.save_state(), state_at(frame));
+
+    // Emulation continues from there, and newer states are captured again
+    nes.exec_frame(false);
+    nes.exec_frame(false);
+    assert_eq!(nes.rewind_frames(0)?, 0);
+    assert_eq!(nes.ctx.ppu().frame(), frame + 2);
+    // The odd frame in between is not captured
+    assert_eq!(nes.rewind_frames(1)?, 2);
+    assert_eq!(&nes.save_state(), state_at(frame));
+
+    let rewound = nes.rewind_frames(100)?;
+    let oldest = nes.ctx.ppu().frame();
+    assert!(oldest <= start + 1);
+    assert_eq!(&nes.save_state(), state_at(oldest));
+    assert!(rewound > 0);
+
+    Ok(())
+}
+
+#[test]
+fn save_state_file() -> anyhow::Result<()> {
+    use sabicom::{context::Ppu, savestate::SaveStateFile};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    nes.exec_frame(true);
+
+    let file = nes.save_state_file();
+    let dat = file.to_bytes();
+    let metadata = SaveStateFile::read_metadata(&dat)?;
+    assert_eq!(metadata, file.metadata);
+    assert_eq!(metadata.frame, nes.ctx.ppu().frame());
+    assert_eq!(metadata.core_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(
+        (metadata.thumbnail.width, metadata.thumbnail.height),
+        (128, 120)
+    );
+    assert_eq!(metadata.thumbnail.rgb.len(), 128 * 120 * 3);
+    assert!(metadata.timestamp > 0);
+
+    nes.exec_frame(true);
+    nes.load_state_file(&SaveStateFile::from_bytes(&dat)?)?;
+    assert_eq!(nes.save_state(), file.state);
+    assert!(SaveStateFile::from_bytes(&file.state).is_err());
+
+    // States of other ROMs are rejected
+    let mut rom = make_rom();
+    *rom.last_mut().unwrap() ^= 1;
+    let mut other = Nes::try_from_file(&rom, None, &Default::default())?;
+    assert!(other.validate_state(&metadata).is_err());
+    assert!(other.load_state_file(&file).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn versioned_state() -> anyhow::Result<()> {
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    let state = nes.save_state();
+    let (header, payload) = state.split_at(8);
+    assert_eq!(&header[..4], b"SBST");
+    assert_eq!(header[4..], sabicom::savestate::STATE_VERSION.to_le_bytes());
+
+    // Version 0 states are bare bincode of the context
+    let legacy = bincode::serialize(&nes.ctx)?;
+    nes.exec_frame(false);
+    nes.load_state(&legacy)?;
+    assert_eq!(nes.save_state(), state);
+
+    // Fields unknown to this version are ignored
+    let mut value: serde_json::Value = rmp_serde::from_slice(payload)?;
+    value
+        .as_object_mut()
+        .unwrap()
+        .insert("added_later".into(), 42.into());
+    let newer = [header, &rmp_serde::to_vec_named(&value)?].concat();
+    nes.exec_frame(false);
+    nes.load_state(&newer)?;
+    assert_eq!(nes.save_state(), state);
+
+    let mut future = state.clone();
+    future[4] += 1;
+    assert!(nes.load_state(&future).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn chr_write_hook() -> anyhow::Result<()> {
+    use sabicom::context::MemoryController;
+    use std::sync::{Arc, Mutex};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    let writes = Arc::new(Mutex::new(vec![]));
+    let w = writes.clone();
+    nes.set_chr_write_hook(move |addr, old, new| w.lock().unwrap().push((addr, old, new)));
+
+    nes.ctx.write_chr(0x2005, 0x42);
+    nes.ctx.write_chr(0x2005, 0x43);
+    // Writing the same value doesn't change the byte
+    nes.ctx.write_chr(0x2005, 0x43);
+    assert_eq!(
+        *writes.lock().unwrap(),
+        [(0x2005, 0x00, 0x42), (0x2005, 0x42, 0x43)]
+    );
+
+    // The hook is kept across resets and state loads. Resets clear the nametables.
+    let state = nes.save_state();
+    nes.ctx.write_chr(0x2005, 0x44);
+    nes.load_state(&state)?;
+    writes.lock().unwrap().clear();
+    nes.ctx.write_chr(0x2400, 0x01);
+    nes.reset();
+    nes.ctx.write_chr(0x2400, 0x02);
+    assert_eq!(
+        *writes.lock().unwrap(),
+        [(0x2400, 0x00, 0x01), (0x2400, 0x00, 0x02)]
+    );
+
+    nes.clear_chr_write_hook();
+    nes.ctx.write_chr(0x2400, 0x03);
+    assert_eq!(writes.lock().unwrap().len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn reload_rom() -> anyhow::Result<()> {
+    // NES 2.0 header with battery backed PRG RAM of 64 << `shift` bytes
+    let rom = |shift: u8| {
+        let mut dat = make_rom();
+        dat[6] |= 0x02;
+        dat[7] |= 0x08;
+        dat[10] = shift << 4;
+        dat
+    };
+
+    let mut nes = Nes::try_from_file(&rom(7), None, &Default::default())?;
+    nes.prg_ram_mut()[0x123] = 0x45;
+
+    nes.reload_rom(&rom(7), true)?;
+    assert_eq!(nes.prg_ram()[0x123], 0x45);
+    nes.reload_rom(&rom(7), false)?;
+    assert_eq!(nes.prg_ram()[0x123], 0x00);
+
+    // PRG RAM of a different size starts cleared
+    nes.prg_ram_mut()[0x123] = 0x45;
+    nes.reload_rom(&rom(6), true)?;
+    assert_eq!(nes.prg_ram().len(), 0x1000);
+    assert_eq!(nes.prg_ram()[0x123], 0x00);
+
+    Ok(())
+}
+
+#[test]
+fn rom_watcher() -> anyhow::Result<()> {
+    use sabicom::RomWatcher;
+    use std::time::{Duration, SystemTime};
+
+    let path = std::env::temp_dir().join(format!("sabicom-watch-{}.nes", std::process::id()));
+    std::fs::write(&path, make_rom())?;
+
+    let mut watcher = RomWatcher::new(&path);
+    assert_eq!(watcher.path(), path);
+    assert_eq!(watcher.poll()?, None);
+
+    let mut dat = make_rom();
+    dat[0x10] = 0xEA;
+    std::fs::write(&path, &dat)?;
+    let file = std::fs::File::options().write(true).open(&path)?;
+    file.set_modified(SystemTime::now() + Duration::from_secs(10))?;
+    drop(file);
+    assert_eq!(watcher.poll()?, Some(dat));
+    assert_eq!(watcher.poll()?, None);
+
+    // A missing file is not an error, since build tools may be rewriting it
+    std::fs::remove_file(&path)?;
+    assert_eq!(watcher.poll()?, None);
+
+    Ok(())
+}
+
+#[test]
+fn debug_dump() -> anyhow::Result<()> {
+    use sabicom::context::{Cpu, Ppu};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    nes.ctx.cpu_mut().set_pc(0x8014);
+
+    let dump: serde_json::Value = serde_json::from_str(&nes.debug_dump())?;
+    assert_eq!(dump["cpu"]["reg"]["pc"], 0x8014);
+    assert_eq!(dump["cpu"]["jammed"], false);
+    assert_eq!(dump["cpu_cycle"], nes.cpu_cycle());
+    assert_eq!(dump["ppu"]["frame"], nes.ctx.ppu().frame());
+    assert_eq!(dump["ppu"]["reg"]["nmi_enable"], true);
+    // NROM maps the 32KB PRG ROM as is
+    assert_eq!(
+        dump["memory"]["prg_banks_8k"],
+        serde_json::json!([0, 1, 2, 3])
+    );
+    assert_eq!(dump["interrupt"]["irq_mapper"], false);
+    assert!(dump["mapper_writes"].is_array());
+
+    Ok(())
+}
+
+#[test]
+fn dot_renderer_matches_line_renderer() -> anyhow::Result<()> {
+    use sabicom::{
+        context::{Bus, MemoryController},
+        nes::{AccuracyProfile, Config},
+    };
+
+    let render = |accuracy| -> anyhow::Result<Vec<_>> {
+        let config = Config {
+            accuracy,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        nes.exec_frame(true);
+
+        for i in 0..0x800 {
+            nes.ctx.write_chr(0x2000 + i, (i * 7 + i / 32) as u8);
+        }
+        for i in 0..0x20 {
+            nes.ctx.write_chr(0x3f00 + i, (i * 5) as u8);
+        }
+
+        nes.ctx.write(0x2005, 13);
+        nes.ctx.write(0x2005, 21);
+
+        nes.exec_frame(true);
+        nes.exec_frame(true);
+        Ok(nes.frame_buffer().buffer.clone())
+    };
+
+    let line = render(AccuracyProfile::Fast)?;
+    let dot = render(AccuracyProfile::Accurate)?;
+    assert!(line == dot);
+
+    Ok(())
+}
+
+#[test]
+fn take_completed_frame() -> anyhow::Result<()> {
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    assert!(nes.take_completed_frame().is_none());
+
+    nes.exec_frame(true);
+    nes.exec_frame(true);
+    let expected = nes.frame_buffer().buffer.clone();
+
+    // Run half a frame; the completed frame must not change while rendering
+    for _ in 0..15000 {
+        nes.ctx.tick_cpu();
+    }
+    assert!(nes.frame_buffer().buffer == expected);
+
+    let frame = nes.take_completed_frame().unwrap();
+    assert_eq!((frame.width, frame.height), (256, 240));
+    assert!(frame.buffer == expected);
+    assert!(nes.take_completed_frame().is_none());
+
+    nes.exec_frame(true);
+    assert!(nes.take_completed_frame().is_some());
+
+    Ok(())
+}
+
+#[test]
+fn console_model_ports() -> anyhow::Result<()> {
+    use meru_interface::InputData;
+    use sabicom::{apu::ConsoleModel, context::Bus, header_db::HeaderDatabase, Config, Rom};
+    use std::sync::Arc;
+
+    let mic_input = InputData {
+        controllers: vec![vec![], vec![("Microphone".to_string(), true)]],
+    };
+
+    // `LDA $4016` leaves the upper byte of the address on the bus before the read
+    let read_port = |nes: &mut Nes, addr: u16| {
+        nes.ctx.write(0x0000, (addr >> 8) as u8);
+        nes.ctx.read(addr)
+    };
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    assert_eq!(nes.console_model(), ConsoleModel::NesFrontLoader);
+    nes.set_input(&mic_input);
+    assert_eq!(read_port(&mut nes, 0x4016), 0x40);
+
+    let config = Config {
+        console_model: Some(ConsoleModel::Famicom),
+        ..Default::default()
+    };
+    let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+    nes.set_input(&mic_input);
+    assert_eq!(read_port(&mut nes, 0x4016), 0x44);
+    assert_eq!(read_port(&mut nes, 0x4017), 0x40);
+
+    // Games for Famicom expansion port devices, told by the header database
+    let mut dat = make_rom();
+    dat[0x10 + 0x200] = 0x42;
+    let crc = Rom::from_bytes(&dat)?.prg_chr_crc32();
+    let xml = format!(
+        r#"<nes20db>
+  <game>
+    <rom size="40960" crc32="{crc:08X}"/>
+    <prgrom size="32768"/>
+    <chrrom size="8192"/>
+    <pcb mapper="0" submapper="0" mirroring="H" battery="0"/>
+    <console type="0" region="0"/>
+    <expansion type="3"/>
+  </game>
+</nes20db>"#
+    );
+    let config = Config {
+        header_database: Some(Arc::new(HeaderDatabase::from_xml(&xml)?)),
+        ..Default::default()
+    };
+    let nes = Nes::try_from_file(&dat, None, &config)?;
+    assert_eq!(nes.console_model(), ConsoleModel::Famicom);
+    let nes = Nes::try_from_file(&make_rom(), None, &config)?;
+    assert_eq!(nes.console_model(), ConsoleModel::NesFrontLoader);
+
+    Ok(())
+}
+
+#[test]
+fn four_player_adapters() -> anyhow::Result<()> {
+    use meru_interface::InputData;
+    use sabicom::{apu::FourPlayerAdapter, context::Bus, Config};
+
+    // A on controller 1, B on 2, Select on 3 and Start on 4
+    let input = InputData {
+        controllers: ["A", "B", "Select", "Start"]
+            .iter()
+            .map(|key| vec![(key.to_string(), true)])
+            .collect(),
+    };
+
+    // Strobes the controllers and reads 24 bits from bit `bit` of each port
+    let read_bits = |nes: &mut Nes, bit: u8| {
+        nes.ctx.write(0x4016, 1);
+        nes.ctx.write(0x4016, 0);
+        [0x4016, 0x4017].map(|addr| {
+            (0..24).fold(0u32, |acc, i| {
+                acc | ((nes.ctx.read(addr) >> bit & 1) as u32) << i
+            })
+        })
+    };
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.set_input(&input);
+    assert_eq!(read_bits(&mut nes, 0), [0xffff01, 0xffff02]);
+
+    let config = Config {
+        four_player_adapter: Some(FourPlayerAdapter::FourScore),
+        ..Default::default()
+    };
+    let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+    nes.set_input(&input);
+    assert_eq!(read_bits(&mut nes, 0), [0x100401, 0x200802]);
+
+    let config = Config {
+        four_player_adapter: Some(FourPlayerAdapter::Famicom),
+        ..Default::default()
+    };
+    let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+    nes.set_input(&input);
+    assert_eq!(read_bits(&mut nes, 0), [0xffff01, 0xffff02]);
+    assert_eq!(read_bits(&mut nes, 1), [0xffff04, 0xffff08]);
+
+    // NES 2.0 header with the Four Score as the default expansion device
+    let mut rom = make_rom();
+    rom[7] |= 0x08;
+    rom[15] = 0x02;
+    let mut nes = Nes::try_from_file(&rom, None, &Default::default())?;
+    assert_eq!(nes.four_player_adapter(), FourPlayerAdapter::FourScore);
+    nes.set_input(&input);
+    assert_eq!(read_bits(&mut nes, 0), [0x100401, 0x200802]);
+
+    let config = Config {
+        four_player_adapter: Some(FourPlayerAdapter::None),
+        ..Default::default()
+    };
+    let mut nes = Nes::try_from_file(&rom, None, &config)?;
+    nes.set_input(&input);
+    assert_eq!(read_bits(&mut nes, 0), [0xffff01, 0xffff02]);
+
+    Ok(())
+}
+
+#[test]
+fn turbo_buttons() -> anyhow::Result<()> {
+    use meru_interface::InputData;
+    use sabicom::{context::Bus, Config};
+
+    let config = Config {
+        turbo_period: Some(3),
+        ..Default::default()
+    };
+    let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+    nes.set_input(&InputData {
+        controllers: vec![vec![("Turbo A".to_string(), true)]],
+    });
+
+    // A toggles every 3 frames, whenever the game reads the controller
+    let mut presses = vec![];
+    for _ in 0..12 {
+        nes.exec_frame(false);
+        nes.ctx.write(0x4016, 1);
+        nes.ctx.write(0x4016, 0);
+        presses.push(nes.ctx.read(0x4016) & 1);
+    }
+    let pattern = [1, 1, 1, 0, 0, 0].repeat(3);
+    assert!((0..6).any(|ofs| presses == pattern[ofs..ofs + 12]));
+
+    Ok(())
+}
+
+#[test]
+fn input_provider() -> anyhow::Result<()> {
+    use sabicom::{context::Bus, util::Input};
+    use std::sync::{Arc, Mutex};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+
+    // A is pressed on every other strobe
+    let strobes = Arc::new(Mutex::new(vec![]));
+    let log = strobes.clone();
+    nes.set_input_provider(move |cycle| {
+        let mut log = log.lock().unwrap();
+        log.push(cycle);
+        let mut input = Input::default();
+        input.pad[0].a = log.len() % 2 == 1;
+        input
+    });
+
+    let read_a = |nes: &mut Nes| {
+        nes.ctx.write(0x4016, 1);
+        nes.ctx.write(0x4016, 0);
+        nes.ctx.read(0x4016) & 1
+    };
+    let reads = [(); 3].map(|_| read_a(&mut nes));
+    assert_eq!(reads, [1, 0, 1]);
+    assert_eq!(strobes.lock().unwrap().len(), 3);
+
+    // The provider gets the CPU cycle of each strobe
+    let last = *strobes.lock().unwrap().last().unwrap();
+    nes.exec_frame(false);
+    read_a(&mut nes);
+    assert!(*strobes.lock().unwrap().last().unwrap() > last);
+
+    nes.clear_input_provider();
+    assert_eq!(read_a(&mut nes), 0);
+    assert_eq!(strobes.lock().unwrap().len(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn expansion_device() -> anyhow::Result<()> {
+    use sabicom::{context::Bus, expansion::ExpansionDevice};
+    use std::sync::{Arc, Mutex};
+
+    // Drives all data bits while OUT1 is set
+    struct Device(Arc<Mutex<Vec<u8>>>);
+    impl ExpansionDevice for Device {
+        fn strobe(&mut self, out: u8) {
+            self.0.lock().unwrap().push(out);
+        }
+        fn read(&mut self, _port: usize) -> u8 {
+            match self.0.lock().unwrap().last() {
+                Some(out) if out & 2 != 0 => 0xff,
+                _ => 0x00,
+            }
+        }
+    }
+
+    let strobes = Arc::new(Mutex::new(vec![]));
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.set_expansion_device(Device(strobes.clone()));
+
+    // Only D1 of $4016 and D1-D4 of $4017 come from the expansion port
+    nes.ctx.write(0x4016, 0xfe);
+    assert_eq!(nes.ctx.read(0x4016) & 0x1e, 0x02);
+    assert_eq!(nes.ctx.read(0x4017) & 0x1e, 0x1e);
+    nes.ctx.write(0x4016, 0x04);
+    assert_eq!(nes.ctx.read(0x4016) & 0x1e, 0x00);
+    assert_eq!(nes.ctx.read(0x4017) & 0x1e, 0x00);
+    assert_eq!(*strobes.lock().unwrap(), [6, 4]);
+
+    // The device stays plugged in across resets
+    nes.reset();
+    nes.ctx.write(0x4016, 0x01);
+    assert_eq!(strobes.lock().unwrap().last(), Some(&1));
+
+    assert!(nes.take_expansion_device().is_some());
+    nes.ctx.write(0x4016, 0x00);
+    assert_eq!(strobes.lock().unwrap().len(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn apu_open_bus() -> anyhow::Result<()> {
+    use meru_interface::InputData;
+    use sabicom::{
+        context::{Bus, Cpu},
+        nes::{AccuracyProfile, Config},
+    };
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.ctx.write(0x0000, 0xa5);
+    assert_eq!(nes.ctx.read(0x4000), 0xa5);
+    assert_eq!(nes.ctx.read(0x4014), 0xa5);
+    // Controller ports only drive the lower bits
+    nes.ctx.write(0x0000, 0xff);
+    assert_eq!(nes.ctx.read(0x4016) & 0xe0, 0xe0);
+    // $4015 returns open bus on bit 5 without driving the bus itself
+    nes.ctx.write(0x0000, 0x20);
+    assert_eq!(nes.ctx.read(0x4015) & 0x20, 0x20);
+    nes.ctx.write(0x0000, 0xff);
+    assert_ne!(nes.ctx.read(0x4015), 0xff);
+    assert_eq!(nes.ctx.read(0x4000), 0xff);
+
+    // `LDA $4016` leaves $40 from its operand on the bus, so games like Paperboy
+    // see $41 for a pressed button
+    warm_up(&mut nes);
+    nes.ctx.write(0x2000, 0x00);
+    #[rustfmt::skip]
+    let prg = [
+        0xAD, 0x16, 0x40, // LDA $4016
+        0xAE, 0x16, 0x40, // LDX $4016
+    ];
+    for (i, &b) in prg.iter().enumerate() {
+        nes.ctx.write(0x300 + i as u16, b);
+    }
+    nes.set_input(&InputData {
+        controllers: vec![vec![("A".to_string(), true)]],
+    });
+    nes.ctx.write(0x4016, 1);
+    nes.ctx.write(0x4016, 0);
+    nes.ctx.cpu_mut().set_pc(0x300);
+    nes.step_instruction();
+    nes.step_instruction();
+    let state = nes.cpu_state();
+    assert_eq!((state.a, state.x), (0x41, 0x40));
+
+    // Without open bus emulation, reads see the upper byte of the address
+    let config = Config {
+        accuracy: AccuracyProfile::Fast,
+        ..Default::default()
+    };
+    let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+    nes.ctx.write(0x0000, 0xa5);
+    assert_eq!(nes.ctx.read(0x4000), 0x40);
+
+    Ok(())
+}
+
+#[test]
+fn sprite_limit_and_overflow() -> anyhow::Result<()> {
+    use sabicom::{
+        context::{Bus, MemoryController},
+        nes::{AccuracyProfile, Config},
+    };
+
+    let run = |accuracy| -> anyhow::Result<(Nes, u8)> {
+        let config = Config {
+            accuracy,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        nes.exec_frame(true);
+
+        // 9 sprites on the same line, written with rendering disabled
+        nes.ctx.write(0x2001, 0x00);
+        nes.ctx.write(0x2003, 0);
+        for i in 0..64 {
+            let (y, x) = if i < 9 { (50, i * 20) } else { (0xf0, 0) };
+            for b in [y, 0x0f, 0, x] {
+                nes.ctx.write(0x2004, b);
+            }
+        }
+        nes.ctx.write_chr(0x3f13, 0x16);
+        nes.ctx.write(0x2001, 0x1e);
+
+        nes.exec_frame(true);
+        for _ in 0..100 * 114 {
+            nes.ctx.tick_cpu();
+        }
+        let status = nes.ctx.read(0x2002);
+        Ok((nes, status))
+    };
+
+    let sprite_color = |nes: &Nes, x: usize| nes.frame_buffer().pixel(x, 52).clone();
+
+    let (nes, status) = run(AccuracyProfile::Accurate)?;
+    assert_ne!(status & 0x20, 0);
+    assert!(sprite_color(&nes, 140) == sprite_color(&nes, 0));
+    assert!(sprite_color(&nes, 160) != sprite_color(&nes, 0));
+
+    let (nes, status) = run(AccuracyProfile::Fast)?;
+    assert_ne!(status & 0x20, 0);
+    assert!(sprite_color(&nes, 140) == sprite_color(&nes, 0));
+    assert!(sprite_color(&nes, 160) != sprite_color(&nes, 0));
+
+    Ok(())
+}
+
+#[test]
+fn acknowledge_irq() -> anyhow::Result<()> {
+    use sabicom::context::IrqSource;
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+
+    // Frame IRQ is not inhibited at power-on, and the CPU keeps it pending with I flag set
+    nes.exec_frame(true);
+    nes.exec_frame(true);
+    assert!(nes.irq_asserted(IrqSource::ApuFrame));
+    assert!(!nes.irq_asserted(IrqSource::ApuDmc));
+    assert!(!nes.irq_asserted(IrqSource::Mapper));
+
+    nes.acknowledge_irq(IrqSource::ApuFrame);
+    assert!(IrqSource::ALL.iter().all(|&s| !nes.irq_asserted(s)));
+
+    Ok(())
+}
+
+#[test]
+fn color_emphasis() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, MemoryController};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.exec_frame(true);
+    for i in 0..0x20 {
+        nes.ctx.write_chr(0x3f00 + i, 0x10);
+    }
+    nes.exec_frame(true);
+    let plain = nes.frame_buffer().pixel(0, 100).clone();
+
+    // Emphasize blue
+    nes.ctx.write(0x2001, 0x9e);
+    nes.exec_frame(true);
+    let emphasized = nes.frame_buffer().pixel(0, 100).clone();
+
+    assert!(emphasized.r < plain.r);
+    assert!(emphasized.g < plain.g);
+    assert_eq!(emphasized.b, plain.b);
+
+    Ok(())
+}
+
+#[test]
+fn power_on_palette() -> anyhow::Result<()> {
+    use sabicom::{context::MemoryController, memory::PowerOnPalette, nes::Config};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    assert_eq!(nes.power_on_palette(), &PowerOnPalette::Blargg);
+    assert_eq!(nes.ctx.read_chr(0x3f00), 0x09);
+
+    // Takes effect at the next reset, and is kept in save states
+    nes.set_config(&Config {
+        power_on_palette: PowerOnPalette::White,
+        ..Default::default()
+    });
+    assert_eq!(nes.ctx.read_chr(0x3f00), 0x09);
+    nes.reset();
+    assert_eq!(nes.power_on_palette(), &PowerOnPalette::White);
+    assert_eq!(nes.ctx.read_chr(0x3f1f), 0x30);
+
+    let state = nes.save_state();
+    let mut nes2 = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes2.load_state(&state)?;
+    assert_eq!(nes2.power_on_palette(), &PowerOnPalette::White);
+    assert_eq!(nes2.ctx.read_chr(0x3f1f), 0x30);
+
+    let custom = PowerOnPalette::Custom(vec![0x21, 0x42]);
+    assert_eq!(custom.palette()[..3], [0x21, 0x02, 0x0f]);
+
+    Ok(())
+}
+
+#[test]
+fn mapper_write_log() -> anyhow::Result<()> {
+    use sabicom::context::Bus;
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.ctx.write(0x8000, 0x01);
+    assert!(nes.mapper_write_log().is_empty());
+
+    nes.set_mapper_write_log_capacity(2);
+    nes.exec_frame(true);
+    nes.ctx.write(0x8000, 0x01);
+    nes.ctx.write(0x6000, 0x02);
+    nes.ctx.write(0xa000, 0x03);
+    nes.ctx.write(0xe000, 0x04);
+
+    let log = nes.mapper_write_log();
+    assert_eq!(log.len(), 2);
+    assert_eq!((log[0].addr, log[0].data), (0xa000, 0x03));
+    assert_eq!((log[1].addr, log[1].data), (0xe000, 0x04));
+    assert_eq!(log[1].frame, 1);
+
+    // Kept across resets
+    nes.reset();
+    assert_eq!(nes.mapper_write_log().len(), 2);
+    nes.clear_mapper_write_log();
+    assert!(nes.mapper_write_log().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn movie_console_events() -> anyhow::Result<()> {
+    use sabicom::{
+        context::{Bus, Ppu},
+        movie::{ConsoleEvent, Movie},
+        util::Input,
+    };
+
+    let mut movie = Movie::new();
+    for i in 0..6 {
+        let event = match i {
+            2 => Some(ConsoleEvent::SoftReset),
+            4 => Some(ConsoleEvent::PowerCycle),
+            _ => None,
+        };
+        movie.record(&Input::default(), event);
+    }
+    let movie: Movie = serde_json::from_str(&serde_json::to_string(&movie)?)?;
+    assert_eq!(
+        movie.events().collect::<Vec<_>>(),
+        [(2, ConsoleEvent::SoftReset), (4, ConsoleEvent::PowerCycle)]
+    );
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    for (i, frame) in movie.frames.iter().enumerate() {
+        if i == 2 || i == 4 {
+            nes.ctx.write(0x0000, 0x42);
+        }
+        nes.play_movie_frame(frame, false);
+        match i {
+            // Soft reset keeps RAM and the PPU frame counter
+            2 => {
+                assert_eq!(nes.ctx.read(0x0000), 0x42);
+                assert_eq!(nes.ctx.ppu().frame(), 3);
+            }
+            4 => {
+                assert_eq!(nes.ctx.read(0x0000), 0x00);
+                assert_eq!(nes.ctx.ppu().frame(), 1);
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn oam_access_during_rendering() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Ppu};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.exec_frame(true);
+
+    nes.ctx.write(0x2001, 0x00);
+    nes.ctx.write(0x2003, 0x00);
+    for i in 0..0x20 {
+        nes.ctx.write(0x2004, i);
+    }
+    nes.ctx.write(0x2001, 0x1e);
+    while nes.ctx.ppu().line() != 245 {
+        nes.ctx.tick_cpu();
+    }
+    // Start rendering with OAMADDR = $13 corrupts OAM $00-$07 with $10-$17
+    nes.ctx.write(0x2003, 0x13);
+    nes.exec_frame(true);
+
+    // Reads during secondary OAM clear return $FF
+    while !(nes.ctx.ppu().line() == 10 && (8..48).contains(&nes.ctx.ppu().dot())) {
+        nes.ctx.tick_cpu();
+    }
+    assert_eq!(nes.ctx.read(0x2004), 0xff);
+
+    // Writes during rendering are ignored
+    nes.ctx.write(0x2004, 0x55);
+
+    nes.ctx.write(0x2001, 0x00);
+    nes.ctx.write(0x2003, 0x00);
+    let oam = (0..0x20)
+        .map(|_| {
+            let ret = nes.ctx.read(0x2004);
+            nes.ctx.write(0x2004, ret);
+            ret
+        })
+        .collect::<Vec<_>>();
+    let expected = (0x10..0x18).chain(0x08..0x20).collect::<Vec<u8>>();
+    // Byte 2 of each sprite does not have bits 2-4
+    let expected = expected
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if i % 4 == 2 { b & 0xe3 } else { b })
+        .collect::<Vec<_>>();
+    assert_eq!(oam, expected);
+
+    Ok(())
+}
+
+#[test]
+fn odd_frame_dot_skip() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Ppu};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+
+    let frame_dots = |nes: &mut Nes| {
+        let frame = nes.ctx.ppu().frame();
+        let mut dots = 0;
+        while nes.ctx.ppu().frame() == frame {
+            nes.ctx.tick_ppu();
+            dots += 1;
+        }
+        (frame % 2, dots)
+    };
+
+    // Finish the current frame
+    frame_dots(&mut nes);
+    let mut dots = [frame_dots(&mut nes), frame_dots(&mut nes)];
+    dots.sort();
+    assert_eq!(dots, [(0, 341 * 262), (1, 341 * 262 - 1)]);
+
+    nes.ctx.write(0x2001, 0x00);
+    assert_eq!(frame_dots(&mut nes).1, 341 * 262);
+    assert_eq!(frame_dots(&mut nes).1, 341 * 262);
+
+    Ok(())
+}
+
+#[test]
+fn hard_pause() -> anyhow::Result<()> {
+    use sabicom::context::Ppu;
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    let mut nes2 = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+
+    nes.exec_frame(true);
+    nes2.exec_frame(true);
+
+    let target = nes.cpu_cycle() + 12345;
+    nes.set_hard_pause(Some(target));
+    nes.exec_frame(true);
+    assert!(nes.is_hard_paused());
+    assert_eq!(nes.cpu_cycle(), target);
+    assert_eq!(nes.ctx.ppu().frame(), 1);
+
+    // Resuming completes the frame as if it was not paused
+    nes.exec_frame(true);
+    nes2.exec_frame(true);
+    assert!(!nes.is_hard_paused());
+    assert_eq!(nes.cpu_cycle(), nes2.cpu_cycle());
+    assert!(nes.frame_buffer().buffer == nes2.frame_buffer().buffer);
+    assert!(nes.audio_buffer().samples == nes2.audio_buffer().samples);
+
+    Ok(())
+}
+
+#[test]
+fn hard_pause_mid_instruction_state() -> anyhow::Result<()> {
+    use sabicom::context::Cpu;
+
+    let mut nes2 = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes2);
+    let start = nes2.cpu_cycle();
+    nes2.exec_frame(true);
+
+    // Consecutive cycles, some of which are in the middle of an instruction
+    let mut suspended = 0;
+    for offset in 1000..1008 {
+        let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+        warm_up(&mut nes);
+        assert_eq!(nes.cpu_cycle(), start);
+        nes.set_hard_pause(Some(start + offset));
+        nes.exec_frame(true);
+        assert_eq!(nes.cpu_cycle(), start + offset);
+        suspended += nes.ctx.cpu().suspended() as usize;
+
+        // The stopped instruction resumes in another instance from a save state
+        let state = nes.save_state();
+        let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+        nes.load_state(&state)?;
+        assert_eq!(nes.cpu_cycle(), start + offset);
+        nes.exec_frame(true);
+        assert_eq!(nes.cpu_cycle(), nes2.cpu_cycle());
+        assert!(nes.frame_buffer().buffer == nes2.frame_buffer().buffer);
+        assert_eq!(nes.ctx.cpu().state(), nes2.ctx.cpu().state());
+    }
+    assert!(suspended > 0);
+
+    Ok(())
+}
+
+#[test]
+fn pal_timing() -> anyhow::Result<()> {
+    use sabicom::{
+        consts::Region,
+        context::{Ppu, Timing},
+        nes::Config,
+        ppu::PpuModel,
+    };
+
+    let mut rom = make_rom();
+    // iNES byte 10: PAL
+    rom[10] = 2;
+    let mut nes = Nes::try_from_file(&rom, None, &Default::default())?;
+    assert_eq!(nes.region(), Region::Pal);
+    assert_eq!(nes.ctx.ppu().model(), PpuModel::Rp2c07);
+
+    nes.exec_frame(true);
+    let start = nes.ctx.now();
+    for _ in 0..10 {
+        nes.exec_frame(true);
+    }
+    // 341 * 312 PPU clocks per frame, 3.2 PPU clocks per CPU clock
+    let cycles = nes.ctx.now() - start;
+    assert!(cycles.abs_diff(341 * 312 * 10 * 5 / 16) < 8);
+    assert_eq!(nes.audio_buffer().samples.len(), 960);
+
+    // Override by config
+    let config = Config {
+        region: Some(Region::Ntsc),
+        ..Default::default()
+    };
+    let nes = Nes::try_from_file(&rom, None, &config)?;
+    assert_eq!(nes.region(), Region::Ntsc);
+    assert_eq!(nes.ctx.ppu().model(), PpuModel::Rp2c02);
+
+    Ok(())
+}
+
+#[test]
+fn logging_categories() {
+    use sabicom::logging::{self, Category};
+
+    assert_eq!(Category::Nestest.target(), "sabicom::nestest");
+    assert!(Category::ALL.iter().all(|&c| logging::is_enabled(c)));
+
+    logging::disable(Category::Mapper);
+    assert!(!logging::is_enabled(Category::Mapper));
+    assert!(logging::is_enabled(Category::Sprite));
+
+    logging::enable(Category::Mapper);
+    assert!(logging::is_enabled(Category::Mapper));
+}
+
+#[test]
+fn overscan_crop() -> anyhow::Result<()> {
+    use sabicom::{nes::Overscan, Config};
+
+    let config = Config {
+        overscan: Overscan {
+            top: 8,
+            bottom: 8,
+            left: 2,
+            right: 0,
+        },
+        ..Default::default()
+    };
+    let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+    for _ in 0..3 {
+        nes.exec_frame(true);
+    }
+
+    let full = nes.frame_buffer();
+    let cropped = nes.cropped_frame_buffer();
+    assert_eq!((cropped.width, cropped.height), (254, 224));
+    for y in 0..224 {
+        let row = &full.buffer[(y + 8) * 256 + 2..(y + 9) * 256];
+        assert!(cropped.buffer[y * 254..(y + 1) * 254] == *row);
+    }
+
+    // Oversized margins produce an empty buffer instead of panicking
+    let empty = Overscan {
+        top: 200,
+        bottom: 200,
+        ..Default::default()
+    }
+    .crop(full);
+    assert_eq!((empty.width, empty.height), (256, 0));
+    Ok(())
+}
+
+#[test]
+fn ntsc_filter() -> anyhow::Result<()> {
+    use sabicom::{
+        nes::Overscan,
+        ntsc::{NtscFilter, NTSC_WIDTH},
+        Config,
+    };
+
+    let config = Config {
+        ntsc_filter: Some(NtscFilter::default()),
+        overscan: Overscan {
+            left: 4,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+    for _ in 0..3 {
+        nes.exec_frame(true);
+    }
+
+    let fb = nes.frame_buffer();
+    assert_eq!((fb.width, fb.height), (NTSC_WIDTH, 240));
+    assert_eq!(nes.cropped_frame_buffer().width, NTSC_WIDTH - 8);
+
+    // Flat gray is decoded without color
+    let mut gray = meru_interface::FrameBuffer::new(0, 0);
+    NtscFilter::default().apply(&[0x10; 256 * 240], 0, &mut gray);
+    let c = gray.pixel(NTSC_WIDTH / 2, 120);
+    assert!(c.r == c.g && c.g == c.b && c.r > 0x80);
+
+    Ok(())
+}
+
+#[test]
+fn frame_output_formats() -> anyhow::Result<()> {
+    use sabicom::{context::MemoryController, Config};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    for i in 0..0x20 {
+        nes.ctx.write_chr(0x3f00 + i, i as u8 * 3);
+    }
+    warm_up(&mut nes);
+    assert!(nes.frame_buffer_rgba().is_empty());
+
+    nes.set_config(&Config {
+        rgba_output: true,
+        ..Default::default()
+    });
+    nes.exec_frame(true);
+
+    let fb = nes.frame_buffer();
+    let rgba = nes.frame_buffer_rgba();
+    let indices = nes.index_buffer();
+    assert_eq!(rgba.len(), 256 * 240 * 4);
+    assert_eq!(indices.len(), 256 * 240);
+    let mut colors = std::collections::HashMap::new();
+    for (i, c) in fb.buffer.iter().enumerate() {
+        assert_eq!(&rgba[i * 4..i * 4 + 4], &[c.r, c.g, c.b, 0xff]);
+        // No emphasis, and each index always maps to the same color
+        assert_eq!(indices[i] >> 6, 0);
+        let rgb = *colors.entry(indices[i]).or_insert((c.r, c.g, c.b));
+        assert_eq!(rgb, (c.r, c.g, c.b));
+    }
+    assert!(colors.len() > 1);
+
+    Ok(())
+}
+
+#[test]
+fn palette_generation() {
+    use sabicom::palette::{generate_palette, PaletteParams};
+
+    let palette = generate_palette(&PaletteParams::default());
+    assert_eq!(palette.len(), 0x200);
+
+    // Grays have no chroma and $0F is black
+    for index in [0x00, 0x10, 0x20, 0x30] {
+        let c = &palette[index];
+        assert!(c.r == c.g && c.g == c.b);
+    }
+    assert!(palette[0x0f] == meru_interface::Color::new(0, 0, 0));
+    assert!(palette[0x30] == meru_interface::Color::new(255, 255, 255));
+
+    // $16 is red and $1A is green
+    assert!(palette[0x16].r > palette[0x16].g && palette[0x16].r > palette[0x16].b);
+    assert!(palette[0x1a].g > palette[0x1a].r && palette[0x1a].g > palette[0x1a].b);
+
+    // Red emphasis darkens green
+    assert!(palette[0x40 | 0x1a].g < palette[0x1a].g);
+
+    let gray = generate_palette(&PaletteParams {
+        saturation: 0.0,
+        ..Default::default()
+    });
+    let c = &gray[0x16];
+    assert!(c.r == c.g && c.g == c.b);
+
+    let bright = generate_palette(&PaletteParams {
+        brightness: 0.2,
+        ..Default::default()
+    });
+    assert!(bright[0x10].r > palette[0x10].r);
+}
+
+#[test]
+fn nametable_viewer() -> anyhow::Result<()> {
+    use sabicom::{
+        context::{Bus, MemoryController},
+        ppu::ScrollRect,
+    };
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    for i in 0..0x3c0 {
+        nes.ctx.write_chr(0x2000 + i, i as u8);
+    }
+    for i in 0..0x20 {
+        nes.ctx.write_chr(0x3f00 + i, i as u8);
+    }
+    nes.exec_frame(true);
+
+    let view = nes.render_nametables();
+    assert_eq!((view.width, view.height), (512, 480));
+
+    // Nothing is scrolled, so the screen shows the first nametable
+    let rect = nes.scroll_rect();
+    assert_eq!(
+        rect,
+        ScrollRect {
+            x: 0,
+            y: 0,
+            width: 256,
+            height: 240
+        }
+    );
+    let fb = nes.frame_buffer();
+    for y in 16..240 {
+        for x in 0..256 {
+            assert!(view.pixel(x, y) == fb.pixel(x, y));
+        }
+    }
+
+    // Horizontal mirroring
+    for y in 0..480 {
+        for x in 0..256 {
+            assert!(view.pixel(x, y) == view.pixel(x + 256, y));
+        }
+    }
+
+    // Scroll to the middle of the second nametable
+    nes.ctx.read(0x2002);
+    nes.ctx.write(0x2000, 0x81);
+    nes.ctx.write(0x2005, 0x84);
+    nes.ctx.write(0x2005, 0x13);
+    let rect = nes.scroll_rect();
+    assert_eq!((rect.x, rect.y), (256 + 0x84, 0x13));
+
+    Ok(())
+}
+
+#[test]
+fn sprite_viewer() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Ppu};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.exec_frame(true);
+
+    nes.ctx.write(0x2001, 0x00);
+    nes.ctx.write(0x2003, 0);
+    for data in [0x10, 0x01, 0x41, 0x20, 0x30, 0x01, 0xa2, 0x40] {
+        nes.ctx.write(0x2004, data);
+    }
+    assert_eq!(&nes.ctx.ppu().oam()[..4], &[0x10, 0x01, 0x41, 0x20]);
+
+    let sprites = nes.sprites();
+    assert_eq!(sprites.len(), 64);
+
+    let s = &sprites[0];
+    assert_eq!((s.x, s.y, s.tile, s.palette), (0x20, 0x10, 0x01, 1));
+    assert!(s.flip_h && !s.flip_v && !s.behind_bg);
+    assert_eq!(s.height, 8);
+    // Tile 1 row 1 is $11, $19, so the rightmost pixel appears leftmost when flipped
+    assert_eq!(s.pattern[8], 3);
+    assert_eq!(s.pattern[15], 0);
+
+    let s = &sprites[1];
+    assert_eq!(s.palette, 2);
+    assert!(!s.flip_h && s.flip_v && s.behind_bg);
+    // Row 0 shows row 7 of the tile: $17, $1F
+    assert_eq!(&s.pattern[..8], &[0, 0, 0, 3, 2, 3, 3, 3]);
+
+    Ok(())
+}
+
+#[test]
+fn scanline_hook() -> anyhow::Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    nes.exec_frame(true);
+
+    let lines = Arc::new(Mutex::new(vec![]));
+    let l = lines.clone();
+    nes.set_scanline_hook(100, move |ppu| {
+        l.lock().unwrap().push((ppu.line(), ppu.dot()))
+    });
+
+    nes.exec_frame(true);
+    let mut seen = lines.lock().unwrap().clone();
+    assert!(seen.iter().all(|&(_, dot)| dot == 100));
+    seen.sort();
+    assert_eq!(seen.len(), 262);
+    assert!(seen.iter().enumerate().all(|(i, &(line, _))| line == i));
+
+    // The hook is kept across state loads
+    let state = nes.save_state();
+    nes.load_state(&state)?;
+    lines.lock().unwrap().clear();
+    nes.exec_frame(true);
+    assert_eq!(lines.lock().unwrap().len(), 262);
+
+    nes.clear_scanline_hook();
+    lines.lock().unwrap().clear();
+    nes.exec_frame(true);
+    assert!(lines.lock().unwrap().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn mid_line_scroll_split() -> anyhow::Result<()> {
+    use sabicom::{
+        context::{Bus, MemoryController, Ppu},
+        nes::{AccuracyProfile, Config},
+    };
+
+    fn run_to(nes: &mut Nes, line: usize, dot: usize) {
+        while !(nes.ctx.ppu().line() == line && nes.ctx.ppu().dot() >= dot) {
+            nes.ctx.tick_cpu();
+        }
+    }
+
+    // Renders a frame which sets fine X scroll to 3 at dot 128 of line 100
+    fn split_frame(nes: &mut Nes) -> Vec<meru_interface::Color> {
+        run_to(nes, 245, 0);
+        nes.ctx.read(0x2002);
+        nes.ctx.write(0x2005, 0);
+        nes.ctx.write(0x2005, 0);
+        nes.exec_frame(true);
+        run_to(nes, 100, 128);
+        nes.ctx.write(0x2005, 3);
+        nes.exec_frame(true);
+        nes.frame_buffer().buffer[100 * 256..101 * 256].to_vec()
+    }
+
+    for accuracy in [AccuracyProfile::Accurate, AccuracyProfile::Fast] {
+        let config = Config {
+            accuracy,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        nes.exec_frame(true);
+        for i in 0..0x3c0 {
+            nes.ctx.write_chr(0x2000 + i, i as u8);
+        }
+        nes.exec_frame(true);
+        nes.exec_frame(true);
+        let reference = nes.frame_buffer().buffer[100 * 256..101 * 256].to_vec();
+
+        // The per-line renderer switches to the per-dot one after seeing a split
+        if accuracy == AccuracyProfile::Fast {
+            let line = split_frame(&mut nes);
+            assert!(line == reference);
+        }
+
+        let line = split_frame(&mut nes);
+        assert!(line[..120] == reference[..120]);
+        assert!(line[140..250] == reference[143..253]);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn scroll_renderers_agree() -> anyhow::Result<()> {
+    use sabicom::{
+        context::{Bus, MemoryController, Ppu},
+        nes::{AccuracyProfile, Config},
+    };
+
+    let mut frames = vec![];
+    for accuracy in [AccuracyProfile::Accurate, AccuracyProfile::Fast] {
+        let config = Config {
+            accuracy,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        nes.exec_frame(true);
+        for i in 0..0x800 {
+            nes.ctx.write_chr(0x2000 + i, (i * 7) as u8);
+        }
+        while nes.ctx.ppu().line() != 245 {
+            nes.ctx.tick_cpu();
+        }
+        // Scroll to (0x1d3, 0x27) across the nametable boundary
+        nes.ctx.read(0x2002);
+        nes.ctx.write(0x2000, 0x81);
+        nes.ctx.write(0x2005, 0xd3);
+        nes.ctx.write(0x2005, 0x27);
+        nes.exec_frame(true);
+        nes.exec_frame(true);
+        frames.push(nes.frame_buffer().buffer[16 * 256..].to_vec());
+    }
+    assert!(frames[0] == frames[1]);
+
+    Ok(())
+}
+
+#[test]
+fn sprite0_hit_timing() -> anyhow::Result<()> {
+    use sabicom::{
+        context::{Bus, Ppu},
+        nes::{AccuracyProfile, Config},
+    };
+
+    let mut hits = vec![];
+    for accuracy in [AccuracyProfile::Accurate, AccuracyProfile::Fast] {
+        let config = Config {
+            accuracy,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        nes.exec_frame(true);
+        while nes.ctx.ppu().line() != 245 {
+            nes.ctx.tick_cpu();
+        }
+        nes.ctx.write(0x2001, 0x00);
+        nes.ctx.write(0x2003, 0x00);
+        // Sprite 0 at (50, 100) using tile $FF, whose left pixels are opaque
+        for data in [99, 0xff, 0x00, 50] {
+            nes.ctx.write(0x2004, data);
+        }
+        nes.ctx.write(0x2001, 0x1e);
+        nes.exec_frame(true);
+        nes.exec_frame(true);
+        let hit = nes.sprite0_hit().unwrap();
+        // Reported for the last completed frame
+        assert_eq!(hit.frame, nes.ctx.ppu().frame() - 1);
+        hits.push(hit);
+
+        // Hiding the background in the middle of the line before the hit prevents it
+        while !(nes.ctx.ppu().line() == 100 && nes.ctx.ppu().dot() >= 20) {
+            nes.ctx.tick_cpu();
+        }
+        nes.ctx.write(0x2001, 0x16);
+        nes.exec_frame(true);
+        assert_eq!(nes.sprite0_hit(), None, "{accuracy:?}");
+    }
+
+    // Tile 0 of the background is opaque from x = 4 in row 4, so the first pixel where
+    // both are opaque is x = 52 on line 100, which is output at dot 53
+    assert_eq!(hits[0], hits[1]);
+    assert_eq!((hits[0].line, hits[0].dot), (100, 53));
+
+    Ok(())
+}
+
+#[test]
+fn sprite_limit() -> anyhow::Result<()> {
+    use sabicom::{
+        context::{Bus, MemoryController, Ppu},
+        nes::{AccuracyProfile, Config},
+    };
+
+    for accuracy in [AccuracyProfile::Accurate, AccuracyProfile::Fast] {
+        for disable_sprite_limit in [false, true] {
+            let config = Config {
+                accuracy,
+                disable_sprite_limit,
+                ..Default::default()
+            };
+            let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+            // Sprite colors are $30, and everything else is $0F
+            for i in 0..0x20 {
+                let color = if i >= 0x10 && i % 4 != 0 { 0x30 } else { 0x0f };
+                nes.ctx.write_chr(0x3f00 + i, color);
+            }
+            warm_up(&mut nes);
+            while nes.ctx.ppu().line() != 245 {
+                nes.ctx.tick_cpu();
+            }
+            nes.ctx.write(0x2001, 0x00);
+            nes.ctx.write(0x2003, 0x00);
+            // 10 sprites at y = 100 using tile $FF, whose first row has 5 opaque pixels
+            for i in 0..64 {
+                let oam = if i < 10 {
+                    [99, 0xff, 0x00, i * 16 + 8]
+                } else {
+                    [0xff; 4]
+                };
+                for data in oam {
+                    nes.ctx.write(0x2004, data);
+                }
+            }
+            nes.ctx.write(0x2001, 0x1e);
+            nes.exec_frame(true);
+            nes.exec_frame(true);
+
+            let line = &nes.index_buffer()[100 * 256..101 * 256];
+            let pixels = line.iter().filter(|&&p| p == 0x30).count();
+            let sprites = if disable_sprite_limit { 10 } else { 8 };
+            assert_eq!(pixels, sprites * 5, "{accuracy:?}");
+
+            while nes.ctx.ppu().line() != 200 {
+                nes.ctx.tick_cpu();
+            }
+            assert_ne!(nes.ctx.read(0x2002) & 0x20, 0, "{accuracy:?}");
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn sprite_priority_quirk() -> anyhow::Result<()> {
+    use sabicom::{
+        context::{Bus, MemoryController, Ppu},
+        nes::{AccuracyProfile, Config},
+    };
+
+    for accuracy in [AccuracyProfile::Accurate, AccuracyProfile::Fast] {
+        for hidden in [false, true] {
+            let config = Config {
+                accuracy,
+                ..Default::default()
+            };
+            let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+            // Background of tile $FF, whose row 4 has 6 opaque pixels
+            for i in 0..0x3c0 {
+                nes.ctx.write_chr(0x2000 + i, 0xff);
+            }
+            for (i, color) in [(0x01, 0x21), (0x11, 0x16), (0x15, 0x2a)] {
+                for j in 0..3 {
+                    nes.ctx.write_chr(0x3f00 + i + j, color);
+                }
+            }
+            warm_up(&mut nes);
+            while nes.ctx.ppu().line() != 245 {
+                nes.ctx.tick_cpu();
+            }
+            nes.ctx.write(0x2001, 0x00);
+            nes.ctx.write(0x2003, 0x00);
+            // Sprite 0 behind the background hides sprite 1 in front of it at (48, 100)
+            let sprite0 = if hidden { 99 } else { 0xff };
+            for i in 0..64 {
+                let oam = match i {
+                    0 => [sprite0, 0xff, 0x20, 48],
+                    1 => [99, 0xff, 0x01, 48],
+                    _ => [0xff; 4],
+                };
+                for data in oam {
+                    nes.ctx.write(0x2004, data);
+                }
+            }
+            nes.ctx.write(0x2001, 0x1e);
+            nes.exec_frame(true);
+            nes.exec_frame(true);
+
+            let line = &nes.index_buffer()[100 * 256..101 * 256];
+            let expected = if hidden { 0x21 } else { 0x2a };
+            assert!(line[48..53].iter().all(|&p| p == expected), "{accuracy:?}");
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn ppudata_during_rendering() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, MemoryController, Ppu};
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+    warm_up(&mut nes);
+    while !(nes.ctx.ppu().line() == 100 && nes.ctx.ppu().dot() >= 300) {
+        nes.ctx.tick_cpu();
+    }
+
+    // Increment by 32 normally, but rendering increments coarse X and fine Y: $2000 -> $3001
+    nes.ctx.write(0x2000, 0x84);
+    nes.ctx.read(0x2002);
+    nes.ctx.write(0x2006, 0x20);
+    nes.ctx.write(0x2006, 0x00);
+    nes.ctx.read(0x2007);
+    nes.ctx.write(0x2001, 0x00);
+    nes.ctx.write(0x2007, 0xab);
+
+    assert_eq!(nes.ctx.read_chr(0x2001), 0xab);
+    assert_ne!(nes.ctx.read_chr(0x2020), 0xab);
+
+    Ok(())
+}
+
+#[test]
+fn vblank_read_race() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, Interrupt, Ppu};
+
+    // (dot of the $2002 read, vblank flag read, NMI occurs)
+    let cases = [
+        (0, false, true),
+        (1, false, false),
+        (2, true, false),
+        (3, true, false),
+        (4, true, true),
+    ];
+
+    for (dot, flag, nmi) in cases {
+        let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+        warm_up(&mut nes);
+        while nes.ctx.ppu().line() != 240 {
+            nes.ctx.tick_cpu();
+        }
+        while !(nes.ctx.ppu().line() == 241 && nes.ctx.ppu().dot() == dot) {
+            nes.ctx.tick_ppu();
+        }
+
+        let status = nes.ctx.read(0x2002);
+        for _ in 0..10 {
+            nes.ctx.tick_ppu();
+        }
+
+        assert_eq!(status & 0x80 != 0, flag, "dot {dot}");
+        assert_eq!(nes.ctx.nmi_edge(), nmi, "dot {dot}");
+        if dot <= 1 {
+            // The flag is either suppressed or already cleared by a later read
+            let set = nes.ctx.read(0x2002) & 0x80 != 0;
+            assert_eq!(set, dot == 0, "dot {dot}");
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn ppu_warm_up() -> anyhow::Result<()> {
+    use sabicom::context::{Bus, MemoryController};
+
+    let write_vram = |nes: &mut Nes, addr: u16, data: u8| {
+        nes.ctx.read(0x2002);
+        nes.ctx.write(0x2006, (addr >> 8) as u8);
+        nes.ctx.write(0x2006, addr as u8);
+        nes.ctx.write(0x2007, data);
+    };
+
+    let mut nes = Nes::try_from_file(&make_rom(), None, &Default::default())?;
+
+    // PPUADDR writes before the end of the first vblank are ignored
+    write_vram(&mut nes, 0x2100, 0xab);
+    assert_ne!(nes.ctx.read_chr(0x2100), 0xab);
+    nes.exec_frame(true);
+    write_vram(&mut nes, 0x2100, 0xab);
+    assert_eq!(nes.ctx.read_chr(0x2100), 0xab);
+
+    // Reset starts the warm-up again
+    nes.soft_reset();
+    write_vram(&mut nes, 0x2200, 0xcd);
+    assert_ne!(nes.ctx.read_chr(0x2200), 0xcd);
+
+    Ok(())
+}
+
+#[test]
+fn triangle_ultrasonic() -> anyhow::Result<()> {
+    use sabicom::{context::Bus, Config};
+
+    // Output range of the last frame, with the triangle parked at the highest frequency
+    let range = |mute_ultrasonic_triangle: bool| -> anyhow::Result<(i16, i16)> {
+        let config = Config {
+            disable_audio_filters: true,
+            mute_ultrasonic_triangle,
+            ..Default::default()
+        };
+        let mut nes = Nes::try_from_file(&make_rom(), None, &config)?;
+        nes.ctx.write(0x4015, 0x04);
+        nes.ctx.write(0x4008, 0xff);
+        nes.ctx.write(0x400a, 0x00);
+        nes.ctx.write(0x400b, 0x00);
+        for _ in 0..3 {
+            nes.exec_frame(false);
+        }
+        let samples = &nes.audio_buffer().samples;
+        let min = samples.iter().map(|s| s.left).min().unwrap();
+        let max = samples.iter().map(|s| s.left).max().unwrap();
+        Ok((min, max))
+    };
+
+    // The low-pass filtering leaves the average level of the waveform
+    let (min, max) = range(false)?;
+    assert!(max - min < 100);
+    // Muting drops to the level of silence
+    let (muted, muted_max) = range(true)?;
+    assert_eq!(muted, muted_max);
+    assert!((min - muted).abs() > 1000);
+
+    Ok(())
+}